@@ -0,0 +1,267 @@
+//!
+//! # Benchmarks
+//!
+//! Every case is run with some value sizes and cache capacities,
+//! the `IN_MEM_CNT` of the crate is one of the capacities.
+//!
+//! - `cargo bench`
+//! - `cargo bench -- mapx/get_cold`, run the matched cases only
+//!
+
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use fundb::{helper::close_db, mapx, unique_path, vecx, Mapx, Vecx};
+use ruc::*;
+use serde::{Deserialize, Serialize};
+
+// Number of entries of a prepared collection,
+// more than the default cache capacities, so some of them are always on disk.
+const N: usize = 30_000;
+
+const VALUE_SIZES: [usize; 3] = [16, 256, 4096];
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+struct Sample {
+    idx: usize,
+    data: Vec<u8>,
+}
+
+fn gen_sample(idx: usize, size: usize) -> Sample {
+    Sample {
+        idx,
+        data: vec![idx as u8; size],
+    }
+}
+
+// `(value size, cache capacity)` of all cases.
+fn params(in_mem_cnt: usize) -> impl Iterator<Item = (usize, usize)> {
+    let mut caps = vec![1, 1024, in_mem_cnt];
+    caps.sort_unstable();
+    caps.dedup();
+
+    let mut params = vec![];
+    for size in VALUE_SIZES.iter() {
+        for cap in caps.iter() {
+            params.push((*size, *cap));
+        }
+    }
+    params.into_iter()
+}
+
+fn id(size: usize, cap: usize) -> BenchmarkId {
+    BenchmarkId::from_parameter(format!("{}B/cap{}", size, cap))
+}
+
+// Filled one by one, so the last `cap` entries are cached.
+fn new_mapx(size: usize, cap: usize, n: usize) -> Mapx<usize, Sample> {
+    let mut db = pnk!(Mapx::new(unique_path!(), Some(cap), true));
+    (0..n).for_each(|i| {
+        db.insert(i, gen_sample(i, size));
+    });
+    db
+}
+
+fn new_vecx(size: usize, cap: usize, n: usize) -> Vecx<Sample> {
+    let mut db = pnk!(Vecx::new(unique_path!(), Some(cap), true));
+    (0..n).for_each(|i| db.push(gen_sample(i, size)));
+    db
+}
+
+// The collections of the `reopen` cases are not temporary,
+// which are removed after being closed.
+fn remove_closed(path: &str) {
+    pnk!(close_db(path));
+    pnk!(std::fs::remove_dir_all(path).c(d!()));
+}
+
+fn bench_mapx(c: &mut Criterion) {
+    let mut group = c.benchmark_group("mapx/insert");
+    for (size, cap) in params(mapx::IN_MEM_CNT) {
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_function(id(size, cap), |b| {
+            let mut db = new_mapx(size, cap, 0);
+            let mut i = 0;
+            b.iter(|| {
+                db.insert(i, gen_sample(i, size));
+                i += 1;
+            })
+        });
+    }
+    group.finish();
+
+    // The last inserted entries, which are cached.
+    let mut group = c.benchmark_group("mapx/get_hot");
+    for (size, cap) in params(mapx::IN_MEM_CNT) {
+        let db = new_mapx(size, cap, N);
+        let mut i = 0;
+        group.bench_function(id(size, cap), |b| {
+            b.iter(|| {
+                i = (i + 1) % cap.min(N);
+                pnk!(db.get(&(N - 1 - i)))
+            })
+        });
+    }
+    group.finish();
+
+    // The entries inserted before the cache was full, which are read from disk.
+    let mut group = c.benchmark_group("mapx/get_cold");
+    for (size, cap) in params(mapx::IN_MEM_CNT).filter(|(_, cap)| *cap < N) {
+        let db = new_mapx(size, cap, N);
+        let mut i = 0;
+        group.bench_function(id(size, cap), |b| {
+            b.iter(|| {
+                i = (i + 1) % (N - cap);
+                pnk!(db.get(&i))
+            })
+        });
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("mapx/iter");
+    group.sample_size(10);
+    for (size, cap) in params(mapx::IN_MEM_CNT) {
+        let db = new_mapx(size, cap, N);
+        group.throughput(Throughput::Elements(N as u64));
+        group.bench_function(id(size, cap), |b| b.iter(|| db.iter().count()));
+    }
+    group.finish();
+
+    // Open a closed map by its serialized meta, which fills the cache.
+    let mut group = c.benchmark_group("mapx/reopen");
+    group.sample_size(10);
+    for (size, cap) in params(mapx::IN_MEM_CNT) {
+        let path = unique_path!();
+        let mut db = pnk!(Mapx::new(path.clone(), Some(cap), false));
+        (0..N).for_each(|i| {
+            db.insert(i, gen_sample(i, size));
+        });
+        let meta = pnk!(serde_json::to_string(&db));
+        drop(db);
+        group.bench_function(id(size, cap), |b| {
+            b.iter_batched(
+                || pnk!(close_db(&path)),
+                |_| pnk!(serde_json::from_str::<Mapx<usize, Sample>>(&meta)),
+                BatchSize::PerIteration,
+            )
+        });
+        remove_closed(&path);
+    }
+    group.finish();
+
+    // Modify an entry by `get_mut`, which is written back when dropped.
+    let mut group = c.benchmark_group("mapx/value_mut");
+    for (size, cap) in params(mapx::IN_MEM_CNT) {
+        let mut db = new_mapx(size, cap, N);
+        let mut i = 0;
+        group.bench_function(id(size, cap), |b| {
+            b.iter(|| {
+                i = (i + 1) % N;
+                pnk!(db.get_mut(&i)).idx += 1;
+            })
+        });
+    }
+    group.finish();
+}
+
+fn bench_vecx(c: &mut Criterion) {
+    let mut group = c.benchmark_group("vecx/push");
+    for (size, cap) in params(vecx::IN_MEM_CNT) {
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_function(id(size, cap), |b| {
+            let mut db = new_vecx(size, cap, 0);
+            let mut i = 0;
+            b.iter(|| {
+                db.push(gen_sample(i, size));
+                i += 1;
+            })
+        });
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("vecx/get_hot");
+    for (size, cap) in params(vecx::IN_MEM_CNT) {
+        let db = new_vecx(size, cap, N);
+        let mut i = 0;
+        group.bench_function(id(size, cap), |b| {
+            b.iter(|| {
+                i = (i + 1) % cap.min(N);
+                pnk!(db.get(N - 1 - i))
+            })
+        });
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("vecx/get_cold");
+    for (size, cap) in params(vecx::IN_MEM_CNT).filter(|(_, cap)| *cap < N) {
+        let db = new_vecx(size, cap, N);
+        let mut i = 0;
+        group.bench_function(id(size, cap), |b| {
+            b.iter(|| {
+                i = (i + 1) % (N - cap);
+                pnk!(db.get(i))
+            })
+        });
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("vecx/iter");
+    group.sample_size(10);
+    for (size, cap) in params(vecx::IN_MEM_CNT) {
+        let db = new_vecx(size, cap, N);
+        group.throughput(Throughput::Elements(N as u64));
+        group.bench_function(id(size, cap), |b| b.iter(|| db.iter().count()));
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("vecx/reopen");
+    group.sample_size(10);
+    for (size, cap) in params(vecx::IN_MEM_CNT) {
+        let path = unique_path!();
+        let mut db = pnk!(Vecx::new(path.clone(), Some(cap), false));
+        (0..N).for_each(|i| db.push(gen_sample(i, size)));
+        let meta = pnk!(serde_json::to_string(&db));
+        drop(db);
+        group.bench_function(id(size, cap), |b| {
+            b.iter_batched(
+                || pnk!(close_db(&path)),
+                |_| pnk!(serde_json::from_str::<Vecx<Sample>>(&meta)),
+                BatchSize::PerIteration,
+            )
+        });
+        remove_closed(&path);
+    }
+    group.finish();
+}
+
+// The codec of values, JSON is the one in use.
+fn bench_codec(c: &mut Criterion) {
+    let mut group = c.benchmark_group("codec/encode");
+    for size in VALUE_SIZES.iter().copied() {
+        let v = gen_sample(size, size);
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::new("json", size), &v, |b, v| {
+            b.iter(|| pnk!(serde_json::to_vec(v)))
+        });
+        group.bench_with_input(BenchmarkId::new("bincode", size), &v, |b, v| {
+            b.iter(|| pnk!(bincode::serialize(v)))
+        });
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("codec/decode");
+    for size in VALUE_SIZES.iter().copied() {
+        let v = gen_sample(size, size);
+        let json = pnk!(serde_json::to_vec(&v));
+        let bin = pnk!(bincode::serialize(&v));
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::new("json", size), &json, |b, bytes| {
+            b.iter(|| pnk!(serde_json::from_slice::<Sample>(bytes)))
+        });
+        group.bench_with_input(BenchmarkId::new("bincode", size), &bin, |b, bytes| {
+            b.iter(|| pnk!(bincode::deserialize::<Sample>(bytes)))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_mapx, bench_vecx, bench_codec);
+criterion_main!(benches);
@@ -0,0 +1,52 @@
+//!
+//! # fundb-server
+//!
+//! Serve the collections in a data directory to other processes:
+//!
+//! - `fundb-server <data dir> <host:port>`
+//! - `fundb-server <data dir> unix:<socket path>`
+//!
+
+use fundb::net::Server;
+use ruc::*;
+use std::{env, net::TcpListener, process};
+
+fn main() {
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    if 2 != args.len() {
+        eprintln!("Usage: fundb-server <data dir> <host:port | unix:socket path>");
+        process::exit(1);
+    }
+
+    let server = Server::new(args[0].clone());
+    let ret = match args[1].strip_prefix("unix:") {
+        Some(path) => serve_unix(&server, path),
+        None => TcpListener::bind(&args[1])
+            .c(d!())
+            .and_then(|l| server.serve_tcp(l).c(d!())),
+    };
+    if let Err(e) = ret {
+        e.print();
+        process::exit(1);
+    }
+}
+
+#[cfg(unix)]
+fn serve_unix(server: &std::sync::Arc<Server>, path: &str) -> Result<()> {
+    use std::{fs, os::unix::fs::FileTypeExt, os::unix::net::UnixListener};
+
+    // A socket left by a previous run.
+    if let Ok(meta) = fs::metadata(path) {
+        if meta.file_type().is_socket() {
+            fs::remove_file(path).c(d!())?;
+        }
+    }
+    UnixListener::bind(path)
+        .c(d!())
+        .and_then(|l| server.serve_unix(l).c(d!()))
+}
+
+#[cfg(not(unix))]
+fn serve_unix(_: &std::sync::Arc<Server>, _: &str) -> Result<()> {
+    Err(eg!("Unix sockets are not supported on this platform"))
+}
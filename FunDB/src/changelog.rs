@@ -0,0 +1,411 @@
+//!
+//! # Change Log for Replication
+//!
+//! A collection with its change log enabled records every change,
+//! with a sequence number increasing by one, before writing the change itself;
+//! after an unclean exit, the changes recorded since the collection was opened
+//! last time are written again when it is opened, so none of them is lost.
+//! The changes can be read by `changes_since`, then applied to another collection,
+//! possibly in another process, by a [Follower](self::Follower).
+//!
+//! Nested collections and merge operands are not recorded, the result of a merge
+//! is recorded instead.
+//!
+
+use crate::{
+    kv::{Kv, KvBatch, KvIter},
+    schema::META_TREE,
+};
+use lazy_static::lazy_static;
+use ruc::*;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    convert::TryInto,
+    marker::PhantomData,
+    mem,
+    ops::Bound,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, Weak,
+    },
+};
+
+const CHANGE_LOG_TREE: &str = "____change_log____";
+
+// Set in the `META_TREE` if the change log is enabled,
+// the value is the smallest sequence number which is kept.
+const CHANGE_LOG_KEY: &[u8] = b"change_log";
+
+// The sequence number of the last change applied to a follower.
+const APPLIED_SEQ_KEY: &[u8] = b"applied_seq";
+
+// The sequence number of the last change recorded
+// when the collection was opened last time.
+const OPENED_SEQ_KEY: &[u8] = b"opened_seq";
+
+// Number of the changes written again in one batch.
+const REDO_BATCH_SIZE: usize = 1024;
+
+lazy_static! {
+    // All handles of a database share one change log.
+    static ref CHANGE_LOG_MAP: Mutex<HashMap<String, Weak<ChangeLog>>> =
+        Mutex::new(HashMap::new());
+}
+
+/// A change of a collection, the key of a `Vecx` is the index of an item.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub enum Op<K, V> {
+    /// Insert or overwrite an entry, or push an item.
+    Insert(K, V),
+    /// Remove an entry.
+    Remove(K),
+}
+
+/// A recorded change with its sequence number, which starts from one.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Change<K, V> {
+    /// The sequence number.
+    pub seq: u64,
+    /// The change.
+    pub op: Op<K, V>,
+}
+
+// The encoded key, and the JSON of the value.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) enum RawOp {
+    Insert(Vec<u8>, Vec<u8>),
+    Remove(Vec<u8>),
+}
+
+//////////////////////////////////////////////
+// Begin of the implementation of ChangeLog //
+/********************************************/
+
+// The change log of a top-level collection.
+#[derive(Debug)]
+pub(crate) struct ChangeLog {
+    meta: Kv,
+    tree: Kv,
+    enabled: AtomicBool,
+    // Whether the lost changes have been written again.
+    recovered: AtomicBool,
+    // Held while a change is being recorded,
+    // so the changes are stored in the order of their sequence numbers.
+    next_seq: Mutex<u64>,
+}
+
+impl ChangeLog {
+    // Get the change log of the database in `path`,
+    // which is shared by all handles of it.
+    pub(crate) fn get(path: &str, db: &Kv) -> Result<Arc<ChangeLog>> {
+        let mut map = CHANGE_LOG_MAP.lock().unwrap();
+        if let Some(log) = map.get(path).and_then(|l| l.upgrade()) {
+            return Ok(log);
+        }
+
+        let meta = db.open_tree(META_TREE).c(d!())?;
+        let tree = db.open_tree(CHANGE_LOG_TREE).c(d!())?;
+        let first = meta.get(CHANGE_LOG_KEY).c(d!())?.map(|v| decode_seq(&v));
+        let last = match tree.iter().next_back() {
+            Some(kv) => kv.c(d!()).map(|(k, _)| decode_seq(&k))?,
+            None => 0,
+        };
+
+        let log = Arc::new(ChangeLog {
+            meta,
+            tree,
+            enabled: AtomicBool::new(first.is_some()),
+            recovered: AtomicBool::new(false),
+            next_seq: Mutex::new(first.unwrap_or(1).max(last + 1)),
+        });
+        map.insert(path.to_owned(), Arc::downgrade(&log));
+        Ok(log)
+    }
+
+    #[inline(always)]
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Acquire)
+    }
+
+    pub(crate) fn enable(&self) -> Result<()> {
+        let next_seq = self.next_seq.lock().unwrap();
+        if !self.is_enabled() {
+            self.meta
+                .insert(CHANGE_LOG_KEY, &next_seq.to_be_bytes()[..])
+                .c(d!())?;
+            self.enabled.store(true, Ordering::Release);
+        }
+        Ok(())
+    }
+
+    // The sequence number of the last recorded change, zero if none.
+    pub(crate) fn last_seq(&self) -> u64 {
+        *self.next_seq.lock().unwrap() - 1
+    }
+
+    // Record the changes, which will be written right after.
+    pub(crate) fn append(&self, ops: impl IntoIterator<Item = RawOp>) -> Result<()> {
+        let mut next_seq = self.next_seq.lock().unwrap();
+        let mut batch = KvBatch::default();
+        let mut seq = *next_seq;
+        for op in ops {
+            batch.insert(&seq.to_be_bytes()[..], bincode::serialize(&op).c(d!())?);
+            seq += 1;
+        }
+        if seq != *next_seq {
+            self.tree.apply_batch(batch).c(d!())?;
+            *next_seq = seq;
+        }
+        Ok(())
+    }
+
+    // Write again the changes recorded since the collection was opened last time
+    // if it was not closed cleanly, some of them may be lost since each change
+    // is recorded right before it is written; `redo` converts a change into
+    // the writes of the data. Return the number of the changes written again.
+    //
+    // It is done once by the first handle in this process.
+    pub(crate) fn recover<F>(&self, data: &Kv, dirty: bool, mut redo: F) -> Result<usize>
+    where
+        F: FnMut(&mut KvBatch, RawOp) -> Result<()>,
+    {
+        let next_seq = self.next_seq.lock().unwrap();
+        if self.recovered.swap(true, Ordering::AcqRel) || !self.is_enabled() {
+            return Ok(0);
+        }
+
+        let opened = self
+            .meta
+            .get(OPENED_SEQ_KEY)
+            .c(d!())?
+            .map(|v| decode_seq(&v))
+            .unwrap_or(0);
+        let mut cnt = 0;
+        if dirty {
+            let mut batch = KvBatch::default();
+            let lo = (opened + 1).to_be_bytes();
+            for kv in self.tree.range(Bound::Included(&lo[..]), Bound::Unbounded) {
+                let op = bincode::deserialize(&kv.c(d!())?.1).c(d!())?;
+                redo(&mut batch, op).c(d!())?;
+                cnt += 1;
+                if 0 == cnt % REDO_BATCH_SIZE {
+                    data.apply_batch(mem::take(&mut batch)).c(d!())?;
+                }
+            }
+            data.apply_batch(batch).c(d!())?;
+        }
+
+        if opened != *next_seq - 1 {
+            self.meta
+                .insert(OPENED_SEQ_KEY, &(*next_seq - 1).to_be_bytes()[..])
+                .c(d!())?;
+        }
+        Ok(cnt)
+    }
+
+    // The changes whose sequence numbers are greater than `seq`.
+    pub(crate) fn since<K, V>(&self, seq: u64) -> Result<ChangeIter<K, V>> {
+        if !self.is_enabled() {
+            return Err(eg!("the change log is not enabled"));
+        }
+        let first = self.first_seq().c(d!())?;
+        if seq.saturating_add(1) < first {
+            return Err(eg!(format!(
+                "the changes before {} have been compacted",
+                first
+            )));
+        }
+
+        let lo = seq.saturating_add(1).to_be_bytes();
+        Ok(ChangeIter {
+            iter: self.tree.range(Bound::Included(&lo[..]), Bound::Unbounded),
+            _pd: PhantomData,
+        })
+    }
+
+    // Remove the changes before `seq`, return the number of them.
+    pub(crate) fn compact(&self, seq: u64) -> Result<usize> {
+        let next_seq = self.next_seq.lock().unwrap();
+        let seq = seq.min(*next_seq);
+        let first = self.first_seq().c(d!())?;
+        if seq <= first {
+            return Ok(0);
+        }
+
+        let mut batch = KvBatch::default();
+        let mut cnt = 0;
+        let hi = seq.to_be_bytes();
+        for kv in self.tree.range(Bound::Unbounded, Bound::Excluded(&hi[..])) {
+            batch.remove(kv.c(d!())?.0.to_vec());
+            cnt += 1;
+        }
+        self.tree.apply_batch(batch).c(d!())?;
+        self.meta
+            .insert(CHANGE_LOG_KEY, &seq.to_be_bytes()[..])
+            .c(d!())?;
+        Ok(cnt)
+    }
+
+    fn first_seq(&self) -> Result<u64> {
+        self.meta
+            .get(CHANGE_LOG_KEY)
+            .c(d!())
+            .map(|v| v.map(|v| decode_seq(&v)).unwrap_or(1))
+    }
+}
+
+// Drop the shared change log of a crashed database.
+#[cfg(test)]
+pub(crate) fn forget(path: &str) {
+    CHANGE_LOG_MAP.lock().unwrap().remove(path);
+}
+
+#[inline(always)]
+fn decode_seq(bytes: &[u8]) -> u64 {
+    u64::from_be_bytes(bytes[..8].try_into().unwrap())
+}
+
+// Get the sequence number of the last change applied to `db` as a follower.
+pub(crate) fn load_applied_seq(db: &Kv) -> Result<u64> {
+    db.open_tree(META_TREE)
+        .c(d!())?
+        .get(APPLIED_SEQ_KEY)
+        .c(d!())
+        .map(|v| v.map(|v| decode_seq(&v)).unwrap_or(0))
+}
+
+pub(crate) fn store_applied_seq(db: &Kv, seq: u64) -> Result<()> {
+    db.open_tree(META_TREE)
+        .c(d!())?
+        .insert(APPLIED_SEQ_KEY, &seq.to_be_bytes()[..])
+        .c(d!())
+        .map(|_| ())
+}
+
+/******************************************/
+// End of the implementation of ChangeLog //
+////////////////////////////////////////////
+
+/// Iter over the recorded changes of a collection,
+/// a change which fails to be read or decoded is returned as an error.
+pub struct ChangeIter<K, V> {
+    iter: KvIter,
+    _pd: PhantomData<(K, V)>,
+}
+
+impl<K, V> Iterator for ChangeIter<K, V>
+where
+    K: DeserializeOwned,
+    V: DeserializeOwned,
+{
+    type Item = Result<Change<K, V>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.iter.next().map(|kv| {
+            let (seq, op) = kv.c(d!())?;
+            let op = match bincode::deserialize(&op).c(d!())? {
+                RawOp::Insert(k, v) => Op::Insert(
+                    bincode::deserialize(&k).c(d!())?,
+                    serde_json::from_slice(&v).c(d!())?,
+                ),
+                RawOp::Remove(k) => Op::Remove(bincode::deserialize(&k).c(d!())?),
+            };
+            Ok(Change {
+                seq: decode_seq(&seq),
+                op,
+            })
+        })
+    }
+}
+
+/// A collection which can apply the changes of another one.
+pub trait Replica {
+    /// The type of keys.
+    type Key;
+    /// The type of values.
+    type Value;
+
+    /// The sequence number of the last change applied, zero if none.
+    fn applied_seq(&self) -> Result<u64>;
+
+    /// Apply a change and record its sequence number,
+    /// applying the same change again is harmless.
+    fn apply_change(&mut self, change: Change<Self::Key, Self::Value>) -> Result<()>;
+}
+
+/////////////////////////////////////////////
+// Begin of the implementation of Follower //
+/*******************************************/
+
+/// Keep a local collection in step with a leader by applying its changes in order,
+/// the progress is persisted in the local database.
+///
+/// The changes can be sent in any way, e.g. serialized by serde through a pipe.
+#[derive(Debug)]
+pub struct Follower<C: Replica> {
+    local: C,
+    last_seq: u64,
+}
+
+impl<C: Replica> Follower<C> {
+    /// Follow from the last change applied to `local`.
+    pub fn new(local: C) -> Result<Self> {
+        let last_seq = local.applied_seq().c(d!())?;
+        Ok(Follower { local, last_seq })
+    }
+
+    /// The sequence number of the last change applied,
+    /// the next one to request is `changes_since(last_seq)`.
+    pub fn last_seq(&self) -> u64 {
+        self.last_seq
+    }
+
+    /// Get the local collection.
+    pub fn local(&self) -> &C {
+        &self.local
+    }
+
+    /// Stop following and return the local collection.
+    pub fn into_inner(self) -> C {
+        self.local
+    }
+
+    /// Apply a change, return `false` if it has been applied;
+    /// it fails if some changes before it are missing.
+    pub fn apply(&mut self, change: Change<C::Key, C::Value>) -> Result<bool> {
+        if change.seq <= self.last_seq {
+            return Ok(false);
+        }
+        if change.seq != self.last_seq + 1 {
+            return Err(eg!(format!(
+                "the changes from {} to {} are missing",
+                self.last_seq + 1,
+                change.seq - 1
+            )));
+        }
+
+        let seq = change.seq;
+        self.local.apply_change(change).c(d!())?;
+        self.last_seq = seq;
+        Ok(true)
+    }
+
+    /// Apply all changes in order, return the number of the applied ones.
+    pub fn apply_all<I>(&mut self, changes: I) -> Result<usize>
+    where
+        I: IntoIterator<Item = Change<C::Key, C::Value>>,
+    {
+        let mut cnt = 0;
+        for change in changes {
+            if self.apply(change).c(d!())? {
+                cnt += 1;
+            }
+        }
+        Ok(cnt)
+    }
+}
+
+/*****************************************/
+// End of the implementation of Follower //
+///////////////////////////////////////////
@@ -0,0 +1,277 @@
+//!
+//! # Online Backups
+//!
+//! A [Database](self::Database) is the data directory of a top-level collection,
+//! along with all collections nested in it.
+//!
+//! A checkpoint is a consistent copy of a database taken while it is being written:
+//! all entries are copied without blocking the writers, then the writes are blocked
+//! only to copy the entries written during the first pass again.
+//!
+
+use crate::{
+    helper::*,
+    kv::{self, Dirty, Kv, KvBatch, Storage},
+    mapx::recount_nested,
+    serde::replace_data_path,
+};
+use ruc::*;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs, io,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+// Written at last, a checkpoint without it is incomplete.
+const MANIFEST_FILE: &str = "____checkpoint____";
+
+// Max number of entries copied in one batch.
+const COPY_BATCH_SIZE: usize = 1024;
+
+/// The description of a checkpoint, stored along with it.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Checkpoint {
+    /// The data path of the source database.
+    pub source: String,
+    /// The storage backend, which is used by the restored database too.
+    pub storage: Storage,
+    /// When it is taken, in seconds since the UNIX epoch.
+    pub created_at: u64,
+    /// The last id generated by the source database,
+    /// the ones generated by the restored database are greater.
+    pub last_id: u64,
+    /// The keyspaces, the default one goes first.
+    pub trees: Vec<TreeDigest>,
+}
+
+/// The number of entries and the hash of a keyspace.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct TreeDigest {
+    /// `None` for the default keyspace.
+    pub name: Option<String>,
+    /// Number of entries.
+    pub len: usize,
+    /// FNV-1a of all keys and values.
+    pub hash: u64,
+}
+
+impl Checkpoint {
+    /// Read the description of the checkpoint in `dir`.
+    pub fn load(dir: &str) -> Result<Self> {
+        let manifest = fs::read(format!("{}/{}", dir, MANIFEST_FILE))
+            .c(d!(format!("no complete checkpoint in {}", dir)))?;
+        serde_json::from_slice(&manifest).c(d!())
+    }
+
+    fn store(&self, dir: &str) -> Result<()> {
+        let path = format!("{}/{}", dir, MANIFEST_FILE);
+        let tmp_path = format!("{}.tmp", path);
+        fs::write(&tmp_path, serde_json::to_vec_pretty(self).c(d!())?).c(d!())?;
+        fs::rename(&tmp_path, &path).c(d!())
+    }
+}
+
+/////////////////////////////////////////////
+// Begin of the implementation of Database //
+/*******************************************/
+
+/// The database of a top-level collection.
+#[derive(Debug, Clone)]
+pub struct Database {
+    path: String,
+    kv: Kv,
+    storage: Storage,
+    journal: Arc<kv::Journal>,
+}
+
+impl Database {
+    /// Get the database in `path`, which is shared with the collections
+    /// opened by this process, or opened read-only if there are none.
+    ///
+    /// The writes from other processes are not seen by the checkpoints.
+    pub fn open(path: &str) -> Result<Self> {
+        let (kv, storage, journal) = kv_open_journaled(path).c(d!())?;
+        Ok(Database {
+            path: path.to_owned(),
+            kv,
+            storage,
+            journal,
+        })
+    }
+
+    /// Get the data path.
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Get the storage backend.
+    pub fn storage(&self) -> Storage {
+        self.storage
+    }
+
+    /// Copy all entries, counters and metadata into `dest_dir`,
+    /// which must be empty or not exist.
+    ///
+    /// The writers are blocked only while the entries
+    /// written during the copying are being copied again.
+    /// The snapshot is taken between two writes to the storage, so the last change
+    /// recorded in the change log may be copied without its data.
+    pub fn checkpoint(&self, dest_dir: &str) -> Result<Checkpoint> {
+        if !self.storage.is_persistent() {
+            return Err(eg!(format!("{} is not persistent", self.path)));
+        }
+        check_empty_dir(dest_dir).c(d!())?;
+        let dest = kv::open(dest_dir, false, self.storage).c(d!())?;
+
+        self.journal.start().c(d!())?;
+        let copied = copy_db(&self.kv, &dest, None);
+        let last_id = self.journal.finish(|dirty| {
+            copied.c(d!())?;
+            copy_dirty(&self.kv, &dest, dirty).c(d!())?;
+            self.kv.generate_id().c(d!())
+        })?;
+        dest.flush().c(d!())?;
+
+        let checkpoint = Checkpoint {
+            source: self.path.clone(),
+            storage: self.storage,
+            created_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .c(d!())?
+                .as_secs(),
+            last_id,
+            trees: digest(&dest).c(d!())?,
+        };
+        checkpoint.store(dest_dir).c(d!())?;
+        Ok(checkpoint)
+    }
+
+    /// Check the checkpoint in `checkpoint_dir`, restore it into `path`,
+    /// which must be empty or not exist, and open it for writing.
+    ///
+    /// The collections nested in it are moved to `path` too,
+    /// the checkpoint itself is left untouched.
+    pub fn restore_from_checkpoint(checkpoint_dir: &str, path: &str) -> Result<Self> {
+        let checkpoint = Checkpoint::load(checkpoint_dir).c(d!())?;
+        let src = kv::open_read_only(checkpoint_dir, checkpoint.storage).c(d!())?;
+        if digest(&src).c(d!())? != checkpoint.trees {
+            return Err(eg!(format!(
+                "the checkpoint in {} is broken",
+                checkpoint_dir
+            )));
+        }
+
+        check_empty_dir(path).c(d!())?;
+        let db = kv_open(path, false, checkpoint.storage).c(d!())?;
+        copy_db(&src, &db, Some((&checkpoint.source, path))).c(d!())?;
+        db.raise_id_floor(checkpoint.last_id + 1).c(d!())?;
+
+        recount_nested(&db).c(d!())?;
+        write_db_len(&format!("{}/____cnter____", path), db.len()).c(d!())?;
+        db.flush().c(d!())?;
+
+        Database::open(path).c(d!())
+    }
+}
+
+/*****************************************/
+// End of the implementation of Database //
+///////////////////////////////////////////
+
+fn check_empty_dir(dir: &str) -> Result<()> {
+    match fs::read_dir(dir) {
+        Ok(mut entries) => {
+            if entries.next().is_some() {
+                return Err(eg!(format!("{} is not empty", dir)));
+            }
+            Ok(())
+        }
+        Err(e) if io::ErrorKind::NotFound == e.kind() => Ok(()),
+        Err(e) => Err(e).c(d!()),
+    }
+}
+
+// Copy all keyspaces, the paths of the nested collections are replaced if `rewrite` is set.
+fn copy_db(src: &Kv, dst: &Kv, rewrite: Option<(&str, &str)>) -> Result<()> {
+    copy_tree(src, dst, rewrite).c(d!())?;
+    for name in src.tree_names().c(d!())? {
+        copy_tree(
+            &src.open_tree(&name).c(d!())?,
+            &dst.open_tree(&name).c(d!())?,
+            rewrite,
+        )
+        .c(d!())?;
+    }
+    Ok(())
+}
+
+fn copy_tree(src: &Kv, dst: &Kv, rewrite: Option<(&str, &str)>) -> Result<()> {
+    let mut batch = KvBatch::default();
+    let mut cnt = 0;
+    for kv in src.iter() {
+        let (k, v) = kv.c(d!())?;
+        match rewrite.and_then(|(from, to)| replace_data_path(&v, from, to)) {
+            Some(v) => batch.insert(k.to_vec(), v),
+            None => batch.insert(k.to_vec(), v.to_vec()),
+        }
+        cnt += 1;
+        if 0 == cnt % COPY_BATCH_SIZE {
+            dst.apply_batch(batch).c(d!())?;
+            batch = KvBatch::default();
+        }
+    }
+    dst.apply_batch(batch).c(d!())
+}
+
+// Copy the current values of the keys written during the copying.
+fn copy_dirty(src: &Kv, dst: &Kv, dirty: Dirty) -> Result<()> {
+    let mut trees = HashMap::new();
+    for (tree, k) in dirty {
+        trees.entry(tree).or_insert_with(Vec::new).push(k);
+    }
+
+    for (tree, keys) in trees {
+        let (src, dst) = match tree {
+            Some(name) => (src.open_tree(&name).c(d!())?, dst.open_tree(&name).c(d!())?),
+            None => (Arc::clone(src), Arc::clone(dst)),
+        };
+        let mut batch = KvBatch::default();
+        for k in keys {
+            match src.get(&k).c(d!())? {
+                Some(v) => batch.insert(k, v.to_vec()),
+                None => batch.remove(k),
+            }
+        }
+        dst.apply_batch(batch).c(d!())?;
+    }
+    Ok(())
+}
+
+// The digests of all keyspaces, the named ones are sorted by their names.
+fn digest(db: &Kv) -> Result<Vec<TreeDigest>> {
+    let mut names = db.tree_names().c(d!())?;
+    names.sort();
+
+    let mut ret = vec![digest_tree(db, None).c(d!())?];
+    for name in names {
+        let tree = db.open_tree(&name).c(d!())?;
+        ret.push(digest_tree(&tree, Some(name)).c(d!())?);
+    }
+    Ok(ret)
+}
+
+fn digest_tree(tree: &Kv, name: Option<String>) -> Result<TreeDigest> {
+    let mut len = 0;
+    let mut hash = FNV_OFFSET;
+    for kv in tree.iter() {
+        let (k, v) = kv.c(d!())?;
+        for bytes in [&k[..], &v[..]].iter() {
+            hash = fnv1a(hash, &(bytes.len() as u64).to_le_bytes());
+            hash = fnv1a(hash, bytes);
+        }
+        len += 1;
+    }
+    Ok(TreeDigest { name, len, hash })
+}
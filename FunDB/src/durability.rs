@@ -0,0 +1,217 @@
+//!
+//! # Durability Modes
+//!
+//! Decide when the writes of a collection and its length counter are flushed to disk.
+//!
+
+use crate::{helper::*, kv::Kv, stats::StatsCounter};
+use ruc::*;
+use serde::{Deserialize, Serialize};
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+/// How the writes of a collection are persisted,
+/// it is always flushed when the last clone of it is dropped.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+pub enum Durability {
+    /// Flush after every write.
+    Sync,
+    /// Flush every `interval_ms` milliseconds in a background thread,
+    /// and after every `writes` writes, zero means no limit on both of them.
+    Periodic {
+        /// Interval of the background flushes.
+        interval_ms: u64,
+        /// Max number of writes between two flushes.
+        writes: usize,
+    },
+    /// Flush only when `flush_data` is called.
+    Manual,
+}
+
+/// Flush every 500ms, the same as the default of sled.
+impl Default for Durability {
+    fn default() -> Self {
+        Durability::Periodic {
+            interval_ms: 500,
+            writes: 0,
+        }
+    }
+}
+
+// Persist the data and the length counter of a top-level collection,
+// shared by all clones of it.
+#[derive(Debug)]
+pub(crate) struct Flusher {
+    state: Arc<FlushState>,
+    worker: Option<JoinHandle<()>>,
+}
+
+#[derive(Debug)]
+struct FlushState {
+    db: Kv,
+    data_path: String,
+    // Whether the counter file is written, nothing is persisted
+    // in the data path of a read-only or in-memory collection.
+    write_cnter: bool,
+    cnter: AtomicUsize,
+    // The lock is held during writing the counter file.
+    dirty: Mutex<Dirty>,
+    writes: AtomicUsize,
+    durability: Durability,
+    stats: Arc<StatsCounter>,
+    stopped: Mutex<bool>,
+    stop_signal: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct Dirty {
+    // Whether the counter file is marked as dirty.
+    marked: bool,
+    // Number of the writes between `begin_write` and `end_write`,
+    // the counter can not be written as clean until they are finished.
+    writing: usize,
+}
+
+impl Flusher {
+    pub(crate) fn new(
+        db: Kv,
+        data_path: &str,
+        write_cnter: bool,
+        cnter: usize,
+        durability: Durability,
+        stats: Arc<StatsCounter>,
+    ) -> Self {
+        let state = Arc::new(FlushState {
+            db,
+            data_path: data_path.to_owned(),
+            write_cnter,
+            cnter: AtomicUsize::new(cnter),
+            dirty: Mutex::default(),
+            writes: AtomicUsize::new(0),
+            durability,
+            stats,
+            stopped: Mutex::new(false),
+            stop_signal: Condvar::new(),
+        });
+
+        let worker = match durability {
+            Durability::Periodic { interval_ms, .. } if 0 < interval_ms => {
+                let state = Arc::clone(&state);
+                Some(thread::spawn(move || {
+                    state.run(Duration::from_millis(interval_ms))
+                }))
+            }
+            _ => None,
+        };
+
+        Flusher { state, worker }
+    }
+
+    pub(crate) fn durability(&self) -> Durability {
+        self.state.durability
+    }
+
+    // Must be called before every write to the database,
+    // a crash before the next flush will cause the counter to be recounted.
+    #[inline(always)]
+    pub(crate) fn begin_write(&self) {
+        let mut dirty = self.state.dirty.lock().unwrap();
+        if !dirty.marked {
+            if let Some(cnter_path) = self.state.cnter_path() {
+                pnk!(mark_db_len_dirty(
+                    &cnter_path,
+                    self.state.cnter.load(Ordering::SeqCst)
+                ));
+            }
+            dirty.marked = true;
+        }
+        dirty.writing += 1;
+    }
+
+    // Must be called after every write with the new length.
+    #[inline(always)]
+    pub(crate) fn end_write(&self, len: usize) {
+        {
+            let mut dirty = self.state.dirty.lock().unwrap();
+            self.state.cnter.store(len, Ordering::SeqCst);
+            dirty.writing -= 1;
+        }
+        match self.state.durability {
+            Durability::Sync => pnk!(self.flush()),
+            Durability::Periodic { writes, .. }
+                if 0 < writes && writes <= 1 + self.state.writes.fetch_add(1, Ordering::SeqCst) =>
+            {
+                pnk!(self.flush());
+            }
+            _ => {}
+        }
+    }
+
+    pub(crate) fn flush(&self) -> Result<()> {
+        self.state.flush().c(d!())
+    }
+}
+
+impl Drop for Flusher {
+    fn drop(&mut self) {
+        *self.state.stopped.lock().unwrap() = true;
+        self.state.stop_signal.notify_all();
+        if let Some(worker) = self.worker.take() {
+            // A panicked worker has nothing to clean up.
+            let _ = worker.join();
+        }
+        if let Err(e) = self.flush() {
+            e.print();
+        }
+    }
+}
+
+impl FlushState {
+    fn run(&self, interval: Duration) {
+        let mut stopped = self.stopped.lock().unwrap();
+        while !*stopped {
+            stopped = self.stop_signal.wait_timeout(stopped, interval).unwrap().0;
+            if !*stopped {
+                if let Err(e) = self.flush() {
+                    e.print();
+                }
+            }
+        }
+    }
+
+    fn cnter_path(&self) -> Option<String> {
+        Some(format!("{}/____cnter____", self.data_path)).filter(|_| self.write_cnter)
+    }
+
+    fn flush(&self) -> Result<()> {
+        trace_span!(DEBUG, "fundb.flush", path = %self.data_path);
+        // The length counted all finished writes, which are covered by this flush,
+        // it can not be taken if some writes are not finished yet.
+        let (was_dirty, len) = {
+            let mut dirty = self.dirty.lock().unwrap();
+            if 0 < dirty.writing {
+                (false, 0)
+            } else {
+                let was_dirty = dirty.marked;
+                dirty.marked = false;
+                (was_dirty, self.cnter.load(Ordering::SeqCst))
+            }
+        };
+        self.writes.store(0, Ordering::SeqCst);
+
+        self.stats.flush(|| self.db.flush()).c(d!())?;
+
+        // Keep the dirty mark if there are new writes during flushing.
+        let dirty = self.dirty.lock().unwrap();
+        if let (true, false, Some(cnter_path)) = (was_dirty, dirty.marked, self.cnter_path()) {
+            write_db_len(&cnter_path, len).c(d!())?;
+        }
+        Ok(())
+    }
+}
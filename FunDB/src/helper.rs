@@ -2,15 +2,37 @@
 //! # Common Types and Macros
 //!
 
+use crate::kv::{self, Journal, JournaledKv, Kv, Storage};
 use lazy_static::lazy_static;
 use ruc::*;
 use serde::{de::DeserializeOwned, Serialize};
-use std::{borrow::Cow, cmp::Ordering, convert::TryInto, env, fmt, fs, mem, ops::Deref};
+use std::{
+    borrow::Cow,
+    cmp::Ordering,
+    collections::HashMap,
+    convert::TryInto,
+    env, fmt,
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    mem,
+    ops::Deref,
+    path::Path,
+    process,
+    sync::{Arc, Mutex, Weak},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 // define a cache directory
 lazy_static! {
     /// read from env, set /tmp if not exist
     pub static ref CACHE_DIR: String = env::var("FUNDB_DIR").unwrap_or_else(|_| "/tmp".to_owned());
+
+    // All opened databases, a path can only be opened once by sled,
+    // so nested collections must share the handle of their parent.
+    static ref DB_MAP: Mutex<HashMap<String, OpenedDb>> = Mutex::new(HashMap::new());
+
+    // The guards of all opened top-level collections.
+    static ref GUARD_MAP: Mutex<HashMap<String, Weak<DbGuard>>> = Mutex::new(HashMap::new());
 }
 
 /// try print and panic twice
@@ -260,16 +282,360 @@ where
 // End of the implementation of Value(returned by `self.get`) for Vecx/Mapx //
 //////////////////////////////////////////////////////////////////////////////
 
+// The FNV-1a hash, which is stable across processes and platforms.
+pub(crate) const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0100_0000_01b3;
+
 #[inline(always)]
-pub(crate) fn sled_open(path: &str, is_tmp: bool) -> Result<sled::Db> {
+pub(crate) fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    bytes
+        .iter()
+        .fold(hash, |h, b| (h ^ *b as u64).wrapping_mul(FNV_PRIME))
+}
+
+// Max number of entries shown by the `Debug` output of a collection.
+pub(crate) const DEBUG_PREVIEW_CNT: usize = 8;
+
+/// A collection opened by `open_read_only`,
+/// only the methods of `&T` are available.
+#[derive(Debug, Clone)]
+pub struct ReadOnly<T>(T);
+
+impl<T> ReadOnly<T> {
+    pub(crate) fn new(inner: T) -> Self {
+        ReadOnly(inner)
+    }
+}
+
+impl<T> Deref for ReadOnly<T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+#[derive(Debug)]
+struct OpenedDb {
+    storage: Storage,
+    kv: Kv,
+    // `None` if it is opened read-only or nothing is persisted.
+    lock: Option<LockFile>,
+    journal: Arc<Journal>,
+}
+
+// Open a database for writing, it fails if another process holds the lock of it.
+#[inline(always)]
+pub(crate) fn kv_open(path: &str, is_tmp: bool, storage: Storage) -> Result<Kv> {
     // todo!()
-    pnk!(fs::DirBuilder::new().recursive(true).create(path));
-    let config = sled::Config::default()
-        .path(path.to_owned())
-        .temporary(is_tmp);
-    config
-        .open()
-        .c(d!(format!("Failed to open db on path: {}", path)))
+    let mut db_map = DB_MAP.lock().unwrap();
+    if let Some(db) = db_map.get_mut(path) {
+        if db.storage != storage {
+            return Err(eg!(format!(
+                "{} has been opened with the {:?} storage",
+                path, db.storage
+            )));
+        }
+        if db.lock.is_none() && storage.is_persistent() {
+            db.lock = Some(LockFile::acquire(path).c(d!())?);
+            // A read-only log keeps the torn tail, which must be discarded before appending.
+            if Storage::Log == storage {
+                db.kv = JournaledKv::wrap(
+                    kv::open(path, is_tmp, storage).c(d!())?,
+                    Arc::clone(&db.journal),
+                );
+            }
+        }
+        return Ok(Arc::clone(&db.kv));
+    }
+
+    let lock = if storage.is_persistent() {
+        Some(LockFile::acquire(path).c(d!())?)
+    } else {
+        None
+    };
+    let journal = Arc::<Journal>::default();
+    let kv = JournaledKv::wrap(
+        kv::open(path, is_tmp, storage).c(d!())?,
+        Arc::clone(&journal),
+    );
+    db_map.insert(
+        path.to_owned(),
+        OpenedDb {
+            storage,
+            kv: Arc::clone(&kv),
+            lock,
+            journal,
+        },
+    );
+
+    Ok(kv)
+}
+
+// Open an existing database without taking the lock,
+// a sled instance can not be opened if it is locked by another process.
+pub(crate) fn kv_open_read_only(path: &str) -> Result<(Kv, Storage)> {
+    kv_open_journaled(path)
+        .c(d!())
+        .map(|(kv, storage, _)| (kv, storage))
+}
+
+// Like `kv_open_read_only`, along with the journal of the database.
+pub(crate) fn kv_open_journaled(path: &str) -> Result<(Kv, Storage, Arc<Journal>)> {
+    let mut db_map = DB_MAP.lock().unwrap();
+    if let Some(db) = db_map.get(path) {
+        return Ok((Arc::clone(&db.kv), db.storage, Arc::clone(&db.journal)));
+    }
+
+    let storage = Storage::detect(path).c(d!(format!("no database in {}", path)))?;
+    if let (Storage::Sled, Some(pid)) = (storage, LockFile::holder(path)) {
+        return Err(eg!(format!(
+            "{} is locked by the process {}, which can not be shared by sled",
+            path, pid
+        )));
+    }
+
+    let journal = Arc::<Journal>::default();
+    let kv = JournaledKv::wrap(
+        kv::open_read_only(path, storage).c(d!())?,
+        Arc::clone(&journal),
+    );
+    db_map.insert(
+        path.to_owned(),
+        OpenedDb {
+            storage,
+            kv: Arc::clone(&kv),
+            lock: None,
+            journal: Arc::clone(&journal),
+        },
+    );
+
+    Ok((kv, storage, journal))
+}
+
+// Get an opened database and whether it is read-only,
+// open it for writing if it has not been opened.
+pub(crate) fn kv_reopen(path: &str, storage: Storage) -> Result<(Kv, bool)> {
+    if let Some(db) = DB_MAP.lock().unwrap().get(path) {
+        return Ok((
+            Arc::clone(&db.kv),
+            db.lock.is_none() && db.storage.is_persistent(),
+        ));
+    }
+    kv_open(path, false, storage).c(d!()).map(|kv| (kv, false))
+}
+
+// An exclusive lock of a database for the writers,
+// which contains the pid of the holder.
+#[derive(Debug)]
+struct LockFile {
+    path: String,
+}
+
+impl LockFile {
+    fn path_of(dir: &str) -> String {
+        format!("{}/____lock____", dir)
+    }
+
+    fn acquire(dir: &str) -> Result<Self> {
+        fs::DirBuilder::new().recursive(true).create(dir).c(d!())?;
+        let path = Self::path_of(dir);
+
+        // Write the pid before the lock is visible.
+        let tmp_path = format!("{}.{}", path, process::id());
+        OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&tmp_path)
+            .and_then(|mut f| f.write_all(process::id().to_string().as_bytes()))
+            .c(d!())?;
+
+        let ret = (|| {
+            for _ in 0..2 {
+                match fs::hard_link(&tmp_path, &path) {
+                    Ok(_) => return Ok(LockFile { path: path.clone() }),
+                    Err(e) if io::ErrorKind::AlreadyExists == e.kind() => {
+                        if let Some(pid) = Self::holder(dir) {
+                            return Err(eg!(format!("{} is locked by the process {}", dir, pid)));
+                        }
+                        // Left by a dead process.
+                        match fs::remove_file(&path) {
+                            Err(e) if io::ErrorKind::NotFound != e.kind() => {
+                                return Err(e).c(d!());
+                            }
+                            _ => {}
+                        }
+                    }
+                    Err(e) => return Err(e).c(d!()),
+                }
+            }
+            Err(eg!(format!("failed to lock {}", dir)))
+        })();
+
+        let _ = fs::remove_file(&tmp_path);
+        ret
+    }
+
+    // The pid of the alive process holding the lock of `dir`.
+    fn holder(dir: &str) -> Option<u32> {
+        fs::read_to_string(Self::path_of(dir))
+            .ok()
+            .and_then(|pid| pid.trim().parse::<u32>().ok())
+            .filter(|pid| *pid != process::id() && process_is_alive(*pid))
+    }
+}
+
+impl Drop for LockFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn process_is_alive(pid: u32) -> bool {
+    Path::new(&format!("/proc/{}", pid)).exists()
+}
+
+// Can not tell, assume it is alive.
+#[cfg(not(target_os = "linux"))]
+fn process_is_alive(_pid: u32) -> bool {
+    true
+}
+
+// Shared by all top-level collections opened on the same path,
+// a temporary database is removed when the last of them is dropped.
+#[derive(Debug)]
+pub(crate) struct DbGuard {
+    path: String,
+    is_tmp: bool,
+}
+
+impl DbGuard {
+    // Get the guard of `path`, the first opener decides whether it is temporary.
+    pub(crate) fn get(path: &str, is_tmp: bool) -> Arc<DbGuard> {
+        let mut guard_map = GUARD_MAP.lock().unwrap();
+        if let Some(guard) = guard_map.get(path).and_then(|g| g.upgrade()) {
+            return guard;
+        }
+
+        let guard = Arc::new(DbGuard {
+            path: path.to_owned(),
+            is_tmp,
+        });
+        guard_map.insert(path.to_owned(), Arc::downgrade(&guard));
+        guard
+    }
+
+    // Remove the database, `self` must be the only handle of it.
+    pub(crate) fn destroy(self: Arc<Self>) -> Result<()> {
+        let mut guard =
+            Arc::try_unwrap(self).map_err(|g| eg!(format!("{} is still in use", g.path)))?;
+        guard.is_tmp = false;
+        remove_db(&guard.path).c(d!())
+    }
+}
+
+impl Drop for DbGuard {
+    fn drop(&mut self) {
+        {
+            let mut guard_map = GUARD_MAP.lock().unwrap();
+            // A new guard may have been created by another opener.
+            if guard_map.get(&self.path).map(|g| 0 < g.strong_count()) == Some(true) {
+                return;
+            }
+            guard_map.remove(&self.path);
+        }
+
+        if self.is_tmp {
+            if let Err(e) = remove_db(&self.path) {
+                e.print();
+            }
+        }
+    }
+}
+
+// Close the database and remove all its data.
+fn remove_db(path: &str) -> Result<()> {
+    DB_MAP.lock().unwrap().remove(path);
+    match fs::remove_dir_all(path) {
+        Err(e) if io::ErrorKind::NotFound != e.kind() => Err(e).c(d!()),
+        _ => Ok(()),
+    }
+}
+
+/// Close the database in `path`, which is read from disk by the next opener,
+/// it fails if any collection of it is still alive.
+pub fn close_db(path: &str) -> Result<()> {
+    let mut db_map = DB_MAP.lock().unwrap();
+    if let Some(db) = db_map.get(path) {
+        if 1 < Arc::strong_count(&db.kv) {
+            return Err(eg!(format!("{} is still in use", path)));
+        }
+        db.kv.flush().c(d!())?;
+        db_map.remove(path);
+    }
+    Ok(())
+}
+
+// Forget an opened database as if this process had been killed,
+// nothing is flushed and the lock file is left.
+#[cfg(test)]
+pub(crate) fn crash_db(path: &str) {
+    if let Some(db) = DB_MAP.lock().unwrap().remove(path) {
+        mem::forget(db.lock);
+    }
+    crate::changelog::forget(path);
+}
+
+/// Remove the directories created by `unique_path!` more than `max_age` ago,
+/// except the ones opened by this process, return the number of them.
+///
+/// The ones opened by other processes are not recognized,
+/// so `max_age` should be longer than the life of them.
+pub fn gc_unique_paths(max_age: Duration) -> Result<usize> {
+    let root = format!("{}/.fundb", *CACHE_DIR);
+    if !Path::new(&root).exists() {
+        return Ok(0);
+    }
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .c(d!())?
+        .as_secs();
+    let opened = DB_MAP.lock().unwrap().keys().cloned().collect::<Vec<_>>();
+
+    let mut cnt = 0;
+    for entry in fs::read_dir(&root).c(d!())? {
+        let entry = entry.c(d!())?;
+        let ts = match entry
+            .file_name()
+            .to_str()
+            .and_then(|n| n.parse::<u64>().ok())
+        {
+            Some(ts) => ts,
+            None => continue,
+        };
+        let dir = format!("{}/{}/", root, ts);
+        if now.saturating_sub(ts) <= max_age.as_secs() || opened.iter().any(|p| p.starts_with(&dir))
+        {
+            continue;
+        }
+        fs::remove_dir_all(entry.path()).c(d!())?;
+        cnt += 1;
+    }
+
+    Ok(cnt)
+}
+
+// Sum of the sizes on disk of all opened databases.
+pub(crate) fn opened_size_on_disk() -> u64 {
+    DB_MAP
+        .lock()
+        .unwrap()
+        .values()
+        .map(|db| db.kv.size_on_disk())
+        .sum()
 }
 
 #[inline(always)]
@@ -283,5 +649,22 @@ pub(crate) fn read_db_len(path: &str) -> Result<usize> {
 #[inline(always)]
 pub(crate) fn write_db_len(path: &str, len: usize) -> Result<()> {
     // todo!()
+    trace_event!(DEBUG, path = %path, len = len, "fundb.write_db_len");
     fs::write(path, &usize::to_le_bytes(len)[..]).c(d!("write file failed"))
 }
+
+// The counter is followed by a non-zero byte if it may be stale,
+// which is ignored by `read_db_len`.
+#[inline(always)]
+pub(crate) fn mark_db_len_dirty(path: &str, len: usize) -> Result<()> {
+    let mut bytes = usize::to_le_bytes(len).to_vec();
+    bytes.push(1);
+    fs::write(path, bytes).c(d!("write file failed"))
+}
+
+#[inline(always)]
+pub(crate) fn db_len_is_dirty(path: &str) -> Result<bool> {
+    fs::read(path)
+        .c(d!("read file failed."))
+        .map(|bytes| bytes.get(mem::size_of::<usize>()).map(|b| 0 != *b) == Some(true))
+}
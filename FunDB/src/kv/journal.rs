@@ -0,0 +1,177 @@
+//!
+//! # Journal of the Writes during a Checkpoint
+//!
+//! Every database opened by this process is wrapped by a [JournaledKv](self::JournaledKv),
+//! which records the keys written while a checkpoint is copying the database,
+//! so the checkpoint can copy them again at last to get a consistent snapshot.
+//!
+
+use super::{Kv, KvBackend, KvBatch, KvIter, MergeFn};
+use ruc::*;
+use sled::IVec;
+use std::{
+    collections::HashSet,
+    mem,
+    ops::Bound,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, RwLock,
+    },
+};
+
+/// The written keys, with the names of their keyspaces,
+/// `None` for the default keyspace.
+pub(crate) type Dirty = HashSet<(Option<String>, Vec<u8>)>;
+
+// Shared by all keyspaces of a database.
+#[derive(Debug, Default)]
+pub(crate) struct Journal {
+    // Held shared by every write, and exclusively
+    // when a checkpoint starts recording or takes its snapshot.
+    gate: RwLock<()>,
+    recording: AtomicBool,
+    dirty: Mutex<Dirty>,
+}
+
+impl Journal {
+    // Start recording the written keys, only one checkpoint can run at a time.
+    pub(crate) fn start(&self) -> Result<()> {
+        let _gate = self.gate.write().unwrap();
+        if self.recording.swap(true, Ordering::AcqRel) {
+            return Err(eg!("another checkpoint is running"));
+        }
+        self.dirty.lock().unwrap().clear();
+        Ok(())
+    }
+
+    // Stop recording, the written keys are handled by `ops` with all writes blocked.
+    pub(crate) fn finish<R>(&self, ops: impl FnOnce(Dirty) -> R) -> R {
+        let _gate = self.gate.write().unwrap();
+        self.recording.store(false, Ordering::Release);
+        ops(mem::take(&mut *self.dirty.lock().unwrap()))
+    }
+
+    fn write<'a, R>(
+        &self,
+        tree: &Option<String>,
+        keys: impl IntoIterator<Item = &'a [u8]>,
+        ops: impl FnOnce() -> R,
+    ) -> R {
+        let _gate = self.gate.read().unwrap();
+        let ret = ops();
+        if self.recording.load(Ordering::Acquire) {
+            let mut dirty = self.dirty.lock().unwrap();
+            for k in keys {
+                dirty.insert((tree.clone(), k.to_vec()));
+            }
+        }
+        ret
+    }
+}
+
+/// A keyspace whose writes are recorded by the [Journal](self::Journal) of its database.
+#[derive(Debug)]
+pub(crate) struct JournaledKv {
+    inner: Kv,
+    // `None` for the default keyspace.
+    tree: Option<String>,
+    journal: Arc<Journal>,
+}
+
+impl JournaledKv {
+    // Wrap the default keyspace of a database.
+    pub(crate) fn wrap(inner: Kv, journal: Arc<Journal>) -> Kv {
+        Arc::new(JournaledKv {
+            inner,
+            tree: None,
+            journal,
+        })
+    }
+}
+
+impl KvBackend for JournaledKv {
+    fn get(&self, key: &[u8]) -> Result<Option<IVec>> {
+        self.inner.get(key)
+    }
+
+    fn insert(&self, key: &[u8], value: &[u8]) -> Result<Option<IVec>> {
+        self.journal
+            .write(&self.tree, Some(key), || self.inner.insert(key, value))
+    }
+
+    fn remove(&self, key: &[u8]) -> Result<Option<IVec>> {
+        self.journal
+            .write(&self.tree, Some(key), || self.inner.remove(key))
+    }
+
+    fn contains_key(&self, key: &[u8]) -> Result<bool> {
+        self.inner.contains_key(key)
+    }
+
+    fn range(&self, lo: Bound<&[u8]>, hi: Bound<&[u8]>) -> KvIter {
+        self.inner.range(lo, hi)
+    }
+
+    fn iter(&self) -> KvIter {
+        self.inner.iter()
+    }
+
+    fn scan_prefix(&self, prefix: &[u8]) -> KvIter {
+        self.inner.scan_prefix(prefix)
+    }
+
+    fn apply_batch(&self, batch: KvBatch) -> Result<()> {
+        let keys = batch.ops.iter().map(|(k, _)| k.clone()).collect::<Vec<_>>();
+        self.journal
+            .write(&self.tree, keys.iter().map(|k| k.as_slice()), || {
+                self.inner.apply_batch(batch)
+            })
+    }
+
+    fn set_merge_operator(&self, f: MergeFn) {
+        self.inner.set_merge_operator(f)
+    }
+
+    fn merge(&self, key: &[u8], operand: &[u8]) -> Result<Option<IVec>> {
+        self.journal
+            .write(&self.tree, Some(key), || self.inner.merge(key, operand))
+    }
+
+    fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.inner.flush()
+    }
+
+    fn open_tree(&self, name: &str) -> Result<Kv> {
+        self.inner.open_tree(name).c(d!()).map(|inner| {
+            Arc::new(JournaledKv {
+                inner,
+                tree: Some(name.to_owned()),
+                journal: Arc::clone(&self.journal),
+            }) as Kv
+        })
+    }
+
+    fn tree_names(&self) -> Result<Vec<String>> {
+        self.inner.tree_names()
+    }
+
+    fn generate_id(&self) -> Result<u64> {
+        self.inner.generate_id()
+    }
+
+    fn raise_id_floor(&self, floor: u64) -> Result<()> {
+        self.inner.raise_id_floor(floor)
+    }
+
+    fn size_on_disk(&self) -> u64 {
+        self.inner.size_on_disk()
+    }
+}
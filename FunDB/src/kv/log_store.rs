@@ -0,0 +1,386 @@
+//!
+//! # The Append-Only Log Backend
+//!
+//! Every keyspace is a log file of frames, each frame is written by one call:
+//!
+//! - `[payload length: u32][payload]`
+//! - payload: `[op: u8][key length: u32][value length: u32][key][value]...`
+//!
+//! All integers are little-endian, a torn frame at the tail is discarded on open.
+//! A payload longer than `u32::MAX` bytes is rejected instead of being written.
+//! Only the positions of values are kept in memory.
+//!
+
+use super::{CursorIter, Kv, KvBackend, KvBatch, KvIter, MergeFn, Merger};
+use ruc::*;
+use sled::IVec;
+use std::{
+    collections::{BTreeMap, HashMap},
+    convert::TryInto,
+    fs::{self, File, OpenOptions},
+    io::{BufReader, Read, Seek, SeekFrom, Write},
+    mem,
+    ops::Bound,
+    sync::{Arc, Mutex},
+};
+
+const DEFAULT_TREE: &str = "____default____";
+const IDS_FILE: &str = "____ids____";
+
+const OP_REMOVE: u8 = 0;
+const OP_INSERT: u8 = 1;
+
+const LEN_SIZE: usize = mem::size_of::<u32>();
+const OP_HEADER_SIZE: usize = 1 + 2 * LEN_SIZE;
+
+/// A keyspace stored in an append-only log file.
+#[derive(Debug, Clone)]
+pub struct LogKv {
+    tree: Arc<Mutex<LogTree>>,
+    db: Arc<LogDb>,
+}
+
+#[derive(Debug)]
+struct LogDb {
+    dir: String,
+    // A torn frame may be being written by the writer, keep it.
+    read_only: bool,
+    trees: Mutex<HashMap<String, Arc<Mutex<LogTree>>>>,
+    next_id: Mutex<u64>,
+}
+
+#[derive(Debug)]
+struct LogTree {
+    file: File,
+    // The offset and the length of the value of each key.
+    index: BTreeMap<Vec<u8>, (u64, usize)>,
+    end: u64,
+    merger: Merger,
+}
+
+impl LogKv {
+    /// Open the default keyspace of the log files in `path`.
+    pub fn open(path: &str) -> Result<Self> {
+        Self::open_inner(path, false).c(d!())
+    }
+
+    /// Open the default keyspace for reading,
+    /// the files are left untouched even if the tail is torn.
+    pub fn open_read_only(path: &str) -> Result<Self> {
+        Self::open_inner(path, true).c(d!())
+    }
+
+    fn open_inner(path: &str, read_only: bool) -> Result<Self> {
+        fs::DirBuilder::new().recursive(true).create(path).c(d!())?;
+
+        let ids_path = format!("{}/{}", path, IDS_FILE);
+        let next_id = match fs::read(&ids_path) {
+            Ok(bytes) => u64::from_le_bytes(
+                bytes
+                    .get(..mem::size_of::<u64>())
+                    .c(d!("invalid id file"))?
+                    .try_into()
+                    .unwrap(),
+            ),
+            Err(_) => 0,
+        };
+
+        let db = Arc::new(LogDb {
+            dir: path.to_owned(),
+            read_only,
+            trees: Mutex::new(HashMap::new()),
+            next_id: Mutex::new(next_id),
+        });
+        let tree = db.tree(DEFAULT_TREE).c(d!())?;
+
+        Ok(LogKv { tree, db })
+    }
+
+    #[inline(always)]
+    fn append(&self, ops: &[(&[u8], Option<&[u8]>)]) -> Result<()> {
+        self.tree.lock().unwrap().append(ops).c(d!())
+    }
+}
+
+pub(super) fn default_tree_path(dir: &str) -> String {
+    format!("{}/{}.log", dir, DEFAULT_TREE)
+}
+
+impl LogDb {
+    fn store_next_id(&self, next_id: u64) -> Result<()> {
+        fs::write(format!("{}/{}", self.dir, IDS_FILE), next_id.to_le_bytes()).c(d!())
+    }
+
+    fn tree(&self, name: &str) -> Result<Arc<Mutex<LogTree>>> {
+        let mut trees = self.trees.lock().unwrap();
+        if let Some(tree) = trees.get(name) {
+            return Ok(Arc::clone(tree));
+        }
+        let tree = LogTree::load(&format!("{}/{}.log", self.dir, name), self.read_only)
+            .c(d!())
+            .map(|t| Arc::new(Mutex::new(t)))?;
+        trees.insert(name.to_owned(), Arc::clone(&tree));
+        Ok(tree)
+    }
+}
+
+impl LogTree {
+    // Replay the log to rebuild the index.
+    fn load(path: &str, read_only: bool) -> Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)
+            .c(d!())?;
+
+        let mut index = BTreeMap::new();
+        let mut end = 0;
+        let mut reader = BufReader::new(&file);
+        let mut len = [0; LEN_SIZE];
+        while reader.read_exact(&mut len).is_ok() {
+            let mut payload = vec![0; u32::from_le_bytes(len) as usize];
+            if reader.read_exact(&mut payload).is_err() {
+                break;
+            }
+            let base = end + LEN_SIZE as u64;
+            for (pos, op, k, v) in parse_ops(&payload).c(d!())? {
+                if OP_INSERT == op {
+                    index.insert(k.to_vec(), (base + pos as u64, v.len()));
+                } else {
+                    index.remove(k);
+                }
+            }
+            end = base + payload.len() as u64;
+        }
+
+        // Discard the torn frame.
+        if !read_only && end < file.metadata().c(d!())?.len() {
+            file.set_len(end).c(d!())?;
+        }
+
+        Ok(LogTree {
+            file,
+            index,
+            end,
+            merger: Merger::default(),
+        })
+    }
+
+    fn read(&mut self, offset: u64, len: usize) -> Result<IVec> {
+        let mut v = vec![0; len];
+        self.file.seek(SeekFrom::Start(offset)).c(d!())?;
+        self.file.read_exact(&mut v).c(d!())?;
+        Ok(IVec::from(v))
+    }
+
+    fn get(&mut self, key: &[u8]) -> Result<Option<IVec>> {
+        match self.index.get(key).copied() {
+            Some((offset, len)) => self.read(offset, len).c(d!()).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn append(&mut self, ops: &[(&[u8], Option<&[u8]>)]) -> Result<()> {
+        // Every length in the frame fits in the `u32` if the payload does.
+        let size = ops.iter().fold(0_u64, |size, (k, v)| {
+            size + (OP_HEADER_SIZE + k.len() + v.map_or(0, |v| v.len())) as u64
+        });
+        if u64::from(u32::MAX) < size {
+            return Err(eg!(format!(
+                "a frame of {} bytes exceeds the limit of the log",
+                size
+            )));
+        }
+
+        let mut payload = Vec::with_capacity(size as usize);
+        let mut positions = Vec::with_capacity(ops.len());
+        for (k, v) in ops.iter() {
+            payload.push(if v.is_some() { OP_INSERT } else { OP_REMOVE });
+            let v = v.unwrap_or_default();
+            payload.extend_from_slice(&(k.len() as u32).to_le_bytes());
+            payload.extend_from_slice(&(v.len() as u32).to_le_bytes());
+            payload.extend_from_slice(k);
+            positions.push(payload.len());
+            payload.extend_from_slice(v);
+        }
+
+        let mut frame = Vec::with_capacity(LEN_SIZE + payload.len());
+        frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        frame.extend_from_slice(&payload);
+        if let Err(e) = self.file.write_all(&frame) {
+            // Keep the log clean of the partially written frame.
+            let _ = self.file.set_len(self.end);
+            return Err(e).c(d!());
+        }
+
+        let base = self.end + LEN_SIZE as u64;
+        for ((k, v), pos) in ops.iter().zip(positions) {
+            if let Some(v) = v {
+                self.index.insert(k.to_vec(), (base + pos as u64, v.len()));
+            } else {
+                self.index.remove(*k);
+            }
+        }
+        self.end += frame.len() as u64;
+        Ok(())
+    }
+}
+
+// `(value position, op, key, value)`
+type Op<'a> = (usize, u8, &'a [u8], &'a [u8]);
+
+// Split a payload into operations.
+fn parse_ops(payload: &[u8]) -> Result<Vec<Op>> {
+    let mut ops = vec![];
+    let mut pos = 0;
+    while pos < payload.len() {
+        let header = payload.get(pos..pos + OP_HEADER_SIZE).c(d!("broken log"))?;
+        let klen = u32::from_le_bytes(header[1..1 + LEN_SIZE].try_into().unwrap()) as usize;
+        let vlen = u32::from_le_bytes(header[1 + LEN_SIZE..].try_into().unwrap()) as usize;
+        let k_pos = pos + OP_HEADER_SIZE;
+        let v_pos = k_pos + klen;
+        let k = payload.get(k_pos..v_pos).c(d!("broken log"))?;
+        let v = payload.get(v_pos..v_pos + vlen).c(d!("broken log"))?;
+        ops.push((v_pos, header[0], k, v));
+        pos = v_pos + vlen;
+    }
+    Ok(ops)
+}
+
+impl KvBackend for LogKv {
+    fn get(&self, key: &[u8]) -> Result<Option<IVec>> {
+        self.tree.lock().unwrap().get(key).c(d!())
+    }
+
+    fn insert(&self, key: &[u8], value: &[u8]) -> Result<Option<IVec>> {
+        let mut tree = self.tree.lock().unwrap();
+        let old = tree.get(key).c(d!())?;
+        tree.append(&[(key, Some(value))]).c(d!()).map(|_| old)
+    }
+
+    fn remove(&self, key: &[u8]) -> Result<Option<IVec>> {
+        let mut tree = self.tree.lock().unwrap();
+        let old = tree.get(key).c(d!())?;
+        if old.is_some() {
+            tree.append(&[(key, None)]).c(d!())?;
+        }
+        Ok(old)
+    }
+
+    fn contains_key(&self, key: &[u8]) -> Result<bool> {
+        Ok(self.tree.lock().unwrap().index.contains_key(key))
+    }
+
+    fn range(&self, lo: Bound<&[u8]>, hi: Bound<&[u8]>) -> KvIter {
+        let tree = Arc::clone(&self.tree);
+        Box::new(CursorIter::new(lo, hi, move |lo, hi, rev| {
+            let mut tree = tree.lock().unwrap();
+            let mut range = tree.index.range::<[u8], _>((lo, hi));
+            let (k, (offset, len)) = if rev { range.next_back() } else { range.next() }
+                .map(|(k, pos)| (IVec::from(k.as_slice()), *pos))?;
+            Some(tree.read(offset, len).c(d!()).map(|v| (k, v)))
+        }))
+    }
+
+    fn apply_batch(&self, batch: KvBatch) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let ops = batch
+            .ops
+            .iter()
+            .map(|(k, v)| (k.as_slice(), v.as_deref()))
+            .collect::<Vec<_>>();
+        self.append(&ops).c(d!())
+    }
+
+    fn set_merge_operator(&self, f: MergeFn) {
+        self.tree.lock().unwrap().merger = Merger(Some(f));
+    }
+
+    fn merge(&self, key: &[u8], operand: &[u8]) -> Result<Option<IVec>> {
+        let mut tree = self.tree.lock().unwrap();
+        let old = tree.get(key).c(d!())?;
+        let new = tree.merger.merge(key, old.as_deref(), operand).c(d!())?;
+        match new.as_deref() {
+            Some(v) => tree.append(&[(key, Some(v))]).c(d!())?,
+            None if old.is_some() => tree.append(&[(key, None)]).c(d!())?,
+            None => {}
+        }
+        Ok(new.map(IVec::from))
+    }
+
+    fn len(&self) -> usize {
+        self.tree.lock().unwrap().index.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.tree.lock().unwrap().index.is_empty()
+    }
+
+    fn flush(&self) -> Result<()> {
+        let trees = self.db.trees.lock().unwrap();
+        for tree in trees.values() {
+            tree.lock().unwrap().file.sync_data().c(d!())?;
+        }
+        Ok(())
+    }
+
+    fn open_tree(&self, name: &str) -> Result<Kv> {
+        self.db.tree(name).c(d!()).map(|tree| {
+            Arc::new(LogKv {
+                tree,
+                db: Arc::clone(&self.db),
+            }) as Kv
+        })
+    }
+
+    fn tree_names(&self) -> Result<Vec<String>> {
+        let mut names = self
+            .db
+            .trees
+            .lock()
+            .unwrap()
+            .keys()
+            .filter(|name| DEFAULT_TREE != *name)
+            .cloned()
+            .collect::<Vec<_>>();
+        for entry in fs::read_dir(&self.db.dir).c(d!())? {
+            let name = entry.c(d!())?.file_name();
+            if let Some(name) = name.to_str().and_then(|n| n.strip_suffix(".log")) {
+                if DEFAULT_TREE != name && !names.iter().any(|n| n == name) {
+                    names.push(name.to_owned());
+                }
+            }
+        }
+        Ok(names)
+    }
+
+    fn generate_id(&self) -> Result<u64> {
+        let mut next_id = self.db.next_id.lock().unwrap();
+        let id = *next_id;
+        self.db.store_next_id(id + 1).c(d!())?;
+        *next_id += 1;
+        Ok(id)
+    }
+
+    fn raise_id_floor(&self, floor: u64) -> Result<()> {
+        let mut next_id = self.db.next_id.lock().unwrap();
+        if *next_id < floor {
+            self.db.store_next_id(floor).c(d!())?;
+            *next_id = floor;
+        }
+        Ok(())
+    }
+
+    fn size_on_disk(&self) -> u64 {
+        self.db
+            .trees
+            .lock()
+            .unwrap()
+            .values()
+            .map(|t| t.lock().unwrap().end)
+            .sum()
+    }
+}
@@ -0,0 +1,137 @@
+//!
+//! # The In-Memory Backend
+//!
+
+use super::{CursorIter, Kv, KvBackend, KvBatch, KvIter, MergeFn, Merger};
+use ruc::*;
+use sled::IVec;
+use std::{
+    collections::{BTreeMap, HashMap},
+    ops::Bound,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, RwLock,
+    },
+};
+
+type Index = Arc<RwLock<MemTree>>;
+
+#[derive(Debug, Default)]
+struct MemTree {
+    entries: BTreeMap<Vec<u8>, IVec>,
+    merger: Merger,
+}
+
+/// A keyspace of some `BTreeMap`s in memory,
+/// all data is lost when the last handle is dropped.
+#[derive(Debug, Clone, Default)]
+pub struct MemKv {
+    tree: Index,
+    trees: Arc<Mutex<HashMap<String, Index>>>,
+    ids: Arc<AtomicU64>,
+}
+
+impl KvBackend for MemKv {
+    fn get(&self, key: &[u8]) -> Result<Option<IVec>> {
+        Ok(self.tree.read().unwrap().entries.get(key).cloned())
+    }
+
+    fn insert(&self, key: &[u8], value: &[u8]) -> Result<Option<IVec>> {
+        Ok(self
+            .tree
+            .write()
+            .unwrap()
+            .entries
+            .insert(key.to_vec(), IVec::from(value)))
+    }
+
+    fn remove(&self, key: &[u8]) -> Result<Option<IVec>> {
+        Ok(self.tree.write().unwrap().entries.remove(key))
+    }
+
+    fn range(&self, lo: Bound<&[u8]>, hi: Bound<&[u8]>) -> KvIter {
+        let tree = Arc::clone(&self.tree);
+        Box::new(CursorIter::new(lo, hi, move |lo, hi, rev| {
+            let tree = tree.read().unwrap();
+            let mut range = tree.entries.range::<[u8], _>((lo, hi));
+            if rev { range.next_back() } else { range.next() }
+                .map(|(k, v)| Ok((IVec::from(k.as_slice()), v.clone())))
+        }))
+    }
+
+    fn apply_batch(&self, batch: KvBatch) -> Result<()> {
+        let entries = &mut self.tree.write().unwrap().entries;
+        for (k, v) in batch.ops {
+            if let Some(v) = v {
+                entries.insert(k, IVec::from(v));
+            } else {
+                entries.remove(&k);
+            }
+        }
+        Ok(())
+    }
+
+    fn set_merge_operator(&self, f: MergeFn) {
+        self.tree.write().unwrap().merger = Merger(Some(f));
+    }
+
+    fn merge(&self, key: &[u8], operand: &[u8]) -> Result<Option<IVec>> {
+        let mut tree = self.tree.write().unwrap();
+        let old = tree.entries.get(key).map(|v| &v[..]);
+        let new = tree
+            .merger
+            .merge(key, old, operand)
+            .c(d!())?
+            .map(IVec::from);
+        if let Some(v) = new.as_ref() {
+            tree.entries.insert(key.to_vec(), v.clone());
+        } else {
+            tree.entries.remove(key);
+        }
+        Ok(new)
+    }
+
+    fn len(&self) -> usize {
+        self.tree.read().unwrap().entries.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.tree.read().unwrap().entries.is_empty()
+    }
+
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn open_tree(&self, name: &str) -> Result<Kv> {
+        let tree = Arc::clone(
+            self.trees
+                .lock()
+                .unwrap()
+                .entry(name.to_owned())
+                .or_default(),
+        );
+        Ok(Arc::new(MemKv {
+            tree,
+            trees: Arc::clone(&self.trees),
+            ids: Arc::clone(&self.ids),
+        }))
+    }
+
+    fn tree_names(&self) -> Result<Vec<String>> {
+        Ok(self.trees.lock().unwrap().keys().cloned().collect())
+    }
+
+    fn generate_id(&self) -> Result<u64> {
+        Ok(self.ids.fetch_add(1, Ordering::SeqCst))
+    }
+
+    fn raise_id_floor(&self, floor: u64) -> Result<()> {
+        self.ids.fetch_max(floor, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn size_on_disk(&self) -> u64 {
+        0
+    }
+}
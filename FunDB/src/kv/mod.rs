@@ -0,0 +1,324 @@
+//!
+//! # Storage Backends
+//!
+//! All collections are built on the ordered key-value store abstracted by
+//! [KvBackend](self::KvBackend), which is selected by [Storage](self::Storage)
+//! when a collection is created.
+//!
+
+mod journal;
+mod log_store;
+mod mem_store;
+mod sled_store;
+#[cfg(test)]
+mod test;
+
+pub use log_store::LogKv;
+pub use mem_store::MemKv;
+pub use sled_store::SledKv;
+
+pub(crate) use journal::{Dirty, Journal, JournaledKv};
+
+use ruc::*;
+use serde::{Deserialize, Serialize};
+use sled::IVec;
+use std::{fmt, ops::Bound, path::Path, sync::Arc};
+
+/// A shared handle of some keyspace.
+pub type Kv = Arc<dyn KvBackend>;
+
+/// Iter over the entries of a keyspace in the order of keys.
+pub type KvIter = Box<dyn DoubleEndedIterator<Item = Result<(IVec, IVec)>>>;
+
+/// Combine the old value of a key with an operand into the new value,
+/// the arguments are the key, the old value and the operand,
+/// `None` means removing the key.
+pub type MergeFn = Arc<dyn Fn(&[u8], Option<&[u8]>, &[u8]) -> Option<Vec<u8>> + Send + Sync>;
+
+/// Which backend stores the data of a collection.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+pub enum Storage {
+    /// A sled instance in the data path.
+    Sled,
+    /// A `BTreeMap` living in the memory of this process,
+    /// mostly for tests, nothing is written to the data path.
+    Memory,
+    /// Append-only log files in the data path,
+    /// indexed by an in-memory `BTreeMap`.
+    Log,
+}
+
+/// Sled, the in-memory store must be asked for explicitly.
+impl Default for Storage {
+    fn default() -> Self {
+        Storage::Sled
+    }
+}
+
+impl Storage {
+    /// Whether anything is persisted in the data path.
+    pub fn is_persistent(self) -> bool {
+        Storage::Memory != self
+    }
+
+    // Tell the storage of an existing database by its files.
+    pub(crate) fn detect(path: &str) -> Option<Self> {
+        if Path::new(&log_store::default_tree_path(path)).exists() {
+            Some(Storage::Log)
+        } else if Path::new(&format!("{}/db", path)).exists() {
+            Some(Storage::Sled)
+        } else {
+            None
+        }
+    }
+}
+
+/// An ordered key-value keyspace, like a `sled::Tree`,
+/// every database has a default keyspace and any number of named ones.
+pub trait KvBackend: Send + Sync + fmt::Debug {
+    /// Get the value of `key`.
+    fn get(&self, key: &[u8]) -> Result<Option<IVec>>;
+
+    /// Set the value of `key`, return the old one.
+    fn insert(&self, key: &[u8], value: &[u8]) -> Result<Option<IVec>>;
+
+    /// Remove `key`, return the old value.
+    fn remove(&self, key: &[u8]) -> Result<Option<IVec>>;
+
+    /// Whether `key` exists.
+    fn contains_key(&self, key: &[u8]) -> Result<bool> {
+        self.get(key).c(d!()).map(|v| v.is_some())
+    }
+
+    /// Iterate over the entries within the given bounds.
+    fn range(&self, lo: Bound<&[u8]>, hi: Bound<&[u8]>) -> KvIter;
+
+    /// Iterate over all entries.
+    fn iter(&self) -> KvIter {
+        self.range(Bound::Unbounded, Bound::Unbounded)
+    }
+
+    /// Iterate over the entries whose keys start with `prefix`.
+    fn scan_prefix(&self, prefix: &[u8]) -> KvIter {
+        let end = prefix_end(prefix);
+        let hi = end
+            .as_deref()
+            .map(Bound::Excluded)
+            .unwrap_or(Bound::Unbounded);
+        self.range(Bound::Included(prefix), hi)
+    }
+
+    /// Apply all operations of `batch` atomically.
+    fn apply_batch(&self, batch: KvBatch) -> Result<()>;
+
+    /// Set the merge operator of this keyspace, which is not persisted.
+    fn set_merge_operator(&self, f: MergeFn);
+
+    /// Merge `operand` into the value of `key` atomically, return the new value,
+    /// it fails if no merge operator is set.
+    fn merge(&self, key: &[u8], operand: &[u8]) -> Result<Option<IVec>>;
+
+    /// Number of entries.
+    fn len(&self) -> usize;
+
+    /// Whether there are no entries.
+    fn is_empty(&self) -> bool {
+        self.iter().next().is_none()
+    }
+
+    /// Persist all keyspaces of the database.
+    fn flush(&self) -> Result<()>;
+
+    /// Open a named keyspace of the same database.
+    fn open_tree(&self, name: &str) -> Result<Kv>;
+
+    /// Names of all named keyspaces of the database, opened or not.
+    fn tree_names(&self) -> Result<Vec<String>>;
+
+    /// Generate an unique id within the database, it never decreases.
+    fn generate_id(&self) -> Result<u64>;
+
+    /// Make the ids generated from now on not less than `floor`.
+    fn raise_id_floor(&self, floor: u64) -> Result<()>;
+
+    /// Size of the whole database on disk, in bytes.
+    fn size_on_disk(&self) -> u64;
+}
+
+/// Operations to be applied atomically.
+#[derive(Debug, Default, Clone)]
+pub struct KvBatch {
+    ops: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+}
+
+impl KvBatch {
+    /// Set the value of `key`.
+    pub fn insert(&mut self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) {
+        self.ops.push((key.into(), Some(value.into())));
+    }
+
+    /// Remove `key`.
+    pub fn remove(&mut self, key: impl Into<Vec<u8>>) {
+        self.ops.push((key.into(), None));
+    }
+
+    /// Whether there are no operations.
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+}
+
+// The merge operator of a keyspace of the non-sled backends,
+// or of a collection whose changes are recorded.
+#[derive(Clone, Default)]
+pub(crate) struct Merger(pub(crate) Option<MergeFn>);
+
+impl Merger {
+    pub(crate) fn merge(
+        &self,
+        key: &[u8],
+        old: Option<&[u8]>,
+        operand: &[u8],
+    ) -> Result<Option<Vec<u8>>> {
+        self.0
+            .as_ref()
+            .c(d!("no merge operator is set"))
+            .map(|f| f(key, old, operand))
+    }
+}
+
+impl fmt::Debug for Merger {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(if self.0.is_some() {
+            "Merger(Some)"
+        } else {
+            "Merger(None)"
+        })
+    }
+}
+
+// Open the default keyspace of the database in `path`,
+// use `helper::kv_open` instead to share the opened ones.
+pub(crate) fn open(path: &str, is_tmp: bool, storage: Storage) -> Result<Kv> {
+    match storage {
+        Storage::Sled => SledKv::open(path, is_tmp)
+            .c(d!())
+            .map(|kv| Arc::new(kv) as Kv),
+        Storage::Memory => Ok(Arc::new(MemKv::default())),
+        Storage::Log => LogKv::open(path).c(d!()).map(|kv| Arc::new(kv) as Kv),
+    }
+}
+
+// Open the default keyspace of an existing database for reading.
+pub(crate) fn open_read_only(path: &str, storage: Storage) -> Result<Kv> {
+    match storage {
+        Storage::Log => LogKv::open_read_only(path)
+            .c(d!())
+            .map(|kv| Arc::new(kv) as Kv),
+        _ => open(path, false, storage).c(d!()),
+    }
+}
+
+// The smallest key greater than all keys starting with `prefix`,
+// `None` if there is no such key.
+pub(crate) fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return Some(end);
+        }
+    }
+    None
+}
+
+// Whether there is no key within the bounds,
+// `BTreeMap::range` panics on such bounds.
+fn is_empty_range(lo: Bound<&[u8]>, hi: Bound<&[u8]>) -> bool {
+    match (lo, hi) {
+        (Bound::Included(l), Bound::Included(h)) => l > h,
+        (Bound::Included(l), Bound::Excluded(h))
+        | (Bound::Excluded(l), Bound::Included(h))
+        | (Bound::Excluded(l), Bound::Excluded(h)) => l >= h,
+        _ => false,
+    }
+}
+
+fn as_slice(b: &Bound<Vec<u8>>) -> Bound<&[u8]> {
+    match b {
+        Bound::Included(k) => Bound::Included(k.as_slice()),
+        Bound::Excluded(k) => Bound::Excluded(k.as_slice()),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+fn to_owned(b: Bound<&[u8]>) -> Bound<Vec<u8>> {
+    match b {
+        Bound::Included(k) => Bound::Included(k.to_vec()),
+        Bound::Excluded(k) => Bound::Excluded(k.to_vec()),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+// A lazy iterator over an ordered index behind a lock,
+// it seeks from the last yielded key every time,
+// so the lock is never held between two steps.
+struct CursorIter<F>
+where
+    F: Fn(Bound<&[u8]>, Bound<&[u8]>, bool) -> Option<Result<(IVec, IVec)>>,
+{
+    lo: Bound<Vec<u8>>,
+    hi: Bound<Vec<u8>>,
+    // Get the first entry within the bounds, or the last one if reversed.
+    seek: F,
+    // Stop after an error.
+    failed: bool,
+}
+
+impl<F> CursorIter<F>
+where
+    F: Fn(Bound<&[u8]>, Bound<&[u8]>, bool) -> Option<Result<(IVec, IVec)>>,
+{
+    fn new(lo: Bound<&[u8]>, hi: Bound<&[u8]>, seek: F) -> Self {
+        CursorIter {
+            lo: to_owned(lo),
+            hi: to_owned(hi),
+            seek,
+            failed: false,
+        }
+    }
+
+    fn step(&mut self, rev: bool) -> Option<Result<(IVec, IVec)>> {
+        let (lo, hi) = (as_slice(&self.lo), as_slice(&self.hi));
+        if self.failed || is_empty_range(lo, hi) {
+            return None;
+        }
+        let ret = (self.seek)(lo, hi, rev);
+        match ret {
+            Some(Ok((ref k, _))) if rev => self.hi = Bound::Excluded(k.to_vec()),
+            Some(Ok((ref k, _))) => self.lo = Bound::Excluded(k.to_vec()),
+            Some(Err(_)) => self.failed = true,
+            None => {}
+        }
+        ret
+    }
+}
+
+impl<F> Iterator for CursorIter<F>
+where
+    F: Fn(Bound<&[u8]>, Bound<&[u8]>, bool) -> Option<Result<(IVec, IVec)>>,
+{
+    type Item = Result<(IVec, IVec)>;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.step(false)
+    }
+}
+
+impl<F> DoubleEndedIterator for CursorIter<F>
+where
+    F: Fn(Bound<&[u8]>, Bound<&[u8]>, bool) -> Option<Result<(IVec, IVec)>>,
+{
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.step(true)
+    }
+}
@@ -0,0 +1,164 @@
+//!
+//! # The Sled Backend
+//!
+
+use super::{Kv, KvBackend, KvBatch, KvIter, MergeFn};
+use ruc::*;
+use sled::IVec;
+use std::{
+    convert::TryInto,
+    fs,
+    ops::Bound,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+// The tree to store the offset added to the ids generated by sled,
+// which can not be set directly.
+const IDS_TREE: &str = "____ids____";
+const ID_OFFSET_KEY: &[u8] = b"offset";
+
+/// A tree of a sled instance.
+#[derive(Debug, Clone)]
+pub struct SledKv {
+    db: sled::Db,
+    tree: sled::Tree,
+    // Shared by all trees of the instance,
+    // the lock is held while the offset is being raised.
+    id_offset: Arc<(Mutex<()>, AtomicU64)>,
+}
+
+impl SledKv {
+    /// Open the default tree of the sled instance in `path`,
+    /// a temporary instance is removed when it is dropped.
+    pub fn open(path: &str, is_tmp: bool) -> Result<Self> {
+        fs::DirBuilder::new().recursive(true).create(path).c(d!())?;
+        let db = sled::Config::default()
+            .path(path)
+            .temporary(is_tmp)
+            .open()
+            .c(d!(format!("Failed to open db on path: {}", path)))?;
+        let id_offset = db
+            .open_tree(IDS_TREE)
+            .c(d!())?
+            .get(ID_OFFSET_KEY)
+            .c(d!())?
+            .map(|v| u64::from_be_bytes(v[..].try_into().unwrap()))
+            .unwrap_or(0);
+        Ok(SledKv {
+            tree: (*db).clone(),
+            db,
+            id_offset: Arc::new((Mutex::new(()), AtomicU64::new(id_offset))),
+        })
+    }
+}
+
+impl KvBackend for SledKv {
+    fn get(&self, key: &[u8]) -> Result<Option<IVec>> {
+        self.tree.get(key).c(d!())
+    }
+
+    fn insert(&self, key: &[u8], value: &[u8]) -> Result<Option<IVec>> {
+        self.tree.insert(key, value).c(d!())
+    }
+
+    fn remove(&self, key: &[u8]) -> Result<Option<IVec>> {
+        self.tree.remove(key).c(d!())
+    }
+
+    fn contains_key(&self, key: &[u8]) -> Result<bool> {
+        self.tree.contains_key(key).c(d!())
+    }
+
+    fn range(&self, lo: Bound<&[u8]>, hi: Bound<&[u8]>) -> KvIter {
+        Box::new(self.tree.range::<&[u8], _>((lo, hi)).map(|kv| kv.c(d!())))
+    }
+
+    fn scan_prefix(&self, prefix: &[u8]) -> KvIter {
+        Box::new(self.tree.scan_prefix(prefix).map(|kv| kv.c(d!())))
+    }
+
+    fn apply_batch(&self, batch: KvBatch) -> Result<()> {
+        let mut b = sled::Batch::default();
+        for (k, v) in batch.ops {
+            if let Some(v) = v {
+                b.insert(k, v);
+            } else {
+                b.remove(k);
+            }
+        }
+        self.tree.apply_batch(b).c(d!())
+    }
+
+    fn set_merge_operator(&self, f: MergeFn) {
+        self.tree
+            .set_merge_operator(move |key: &[u8], old: Option<&[u8]>, operand: &[u8]| {
+                f(key, old, operand)
+            });
+    }
+
+    fn merge(&self, key: &[u8], operand: &[u8]) -> Result<Option<IVec>> {
+        self.tree.merge(key, operand).c(d!())
+    }
+
+    fn len(&self) -> usize {
+        self.tree.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.tree.is_empty()
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.db.flush().c(d!()).map(|_| ())
+    }
+
+    fn open_tree(&self, name: &str) -> Result<Kv> {
+        self.db.open_tree(name).c(d!()).map(|tree| {
+            Arc::new(SledKv {
+                db: self.db.clone(),
+                tree,
+                id_offset: Arc::clone(&self.id_offset),
+            }) as Kv
+        })
+    }
+
+    fn tree_names(&self) -> Result<Vec<String>> {
+        let default = self.db.name();
+        self.db
+            .tree_names()
+            .into_iter()
+            .filter(|name| *name != default && name != IDS_TREE.as_bytes())
+            .map(|name| String::from_utf8(name.to_vec()).c(d!()))
+            .collect()
+    }
+
+    fn generate_id(&self) -> Result<u64> {
+        self.db
+            .generate_id()
+            .c(d!())
+            .map(|id| id + self.id_offset.1.load(Ordering::Acquire))
+    }
+
+    fn raise_id_floor(&self, floor: u64) -> Result<()> {
+        let _lock = self.id_offset.0.lock().unwrap();
+        let id = self.db.generate_id().c(d!())?;
+        let offset = self.id_offset.1.load(Ordering::Acquire);
+        if id + offset < floor {
+            let offset = floor - id;
+            self.db
+                .open_tree(IDS_TREE)
+                .c(d!())?
+                .insert(ID_OFFSET_KEY, &offset.to_be_bytes()[..])
+                .c(d!())?;
+            self.id_offset.1.store(offset, Ordering::Release);
+        }
+        Ok(())
+    }
+
+    fn size_on_disk(&self) -> u64 {
+        self.db.size_on_disk().unwrap_or(0)
+    }
+}
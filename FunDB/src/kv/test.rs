@@ -0,0 +1,133 @@
+//!
+//! # Test Cases
+//!
+
+use super::*;
+use std::{fs::OpenOptions, io::Write};
+
+fn keys(iter: impl Iterator<Item = Result<(IVec, IVec)>>) -> Vec<Vec<u8>> {
+    iter.map(|kv| pnk!(kv).0.to_vec()).collect()
+}
+
+fn check(kv: Kv) {
+    assert!(kv.is_empty());
+    assert!(pnk!(kv.insert(b"b", b"2")).is_none());
+    assert_eq!(b"2", &pnk!(pnk!(kv.insert(b"b", b"3")))[..]);
+    pnk!(kv.insert(b"a", b"1"));
+    pnk!(kv.insert(&[0xff, 0xff], b"4"));
+    pnk!(kv.insert(&[0xff, 0xff, 0], b"5"));
+
+    assert_eq!(4, kv.len());
+    assert_eq!(b"3", &pnk!(pnk!(kv.get(b"b")))[..]);
+    assert!(pnk!(kv.contains_key(b"a")));
+    assert!(!pnk!(kv.contains_key(b"c")));
+
+    assert_eq!(
+        vec![
+            b"a".to_vec(),
+            b"b".to_vec(),
+            vec![0xff, 0xff],
+            vec![0xff, 0xff, 0]
+        ],
+        keys(kv.iter())
+    );
+    assert_eq!(
+        vec![vec![0xff, 0xff, 0], vec![0xff, 0xff]],
+        keys(kv.iter().rev().take(2))
+    );
+    assert_eq!(
+        vec![b"b".to_vec()],
+        keys(kv.range(Bound::Excluded(b"a"), Bound::Excluded(&[0xff, 0xff])))
+    );
+    assert_eq!(
+        vec![vec![0xff, 0xff], vec![0xff, 0xff, 0]],
+        keys(kv.scan_prefix(&[0xff, 0xff]))
+    );
+
+    let mut iter = kv.iter();
+    assert_eq!(b"a", &pnk!(pnk!(iter.next())).0[..]);
+    assert_eq!(&[0xff, 0xff, 0], &pnk!(pnk!(iter.next_back())).0[..]);
+    assert_eq!(2, iter.count());
+
+    let mut batch = KvBatch::default();
+    batch.remove(b"a".to_vec());
+    batch.insert(b"c".to_vec(), b"6".to_vec());
+    pnk!(kv.apply_batch(batch));
+    assert!(pnk!(kv.get(b"a")).is_none());
+    assert_eq!(b"6", &pnk!(pnk!(kv.get(b"c")))[..]);
+    assert_eq!(b"3", &pnk!(pnk!(kv.remove(b"b")))[..]);
+    assert!(pnk!(kv.remove(b"b")).is_none());
+    assert_eq!(3, kv.len());
+
+    let tree = pnk!(kv.open_tree("sub"));
+    assert!(tree.is_empty());
+    pnk!(tree.insert(b"a", b"7"));
+    assert_eq!(b"7", &pnk!(pnk!(pnk!(kv.open_tree("sub")).get(b"a")))[..]);
+    assert!(pnk!(kv.get(b"a")).is_none());
+
+    // Concatenate the operands, an empty one removes the key.
+    assert!(tree.merge(b"m", b"1").is_err());
+    tree.set_merge_operator(Arc::new(|_, old, operand| {
+        if operand.is_empty() {
+            return None;
+        }
+        let mut v = old.map(|v| v.to_vec()).unwrap_or_default();
+        v.extend_from_slice(operand);
+        Some(v)
+    }));
+    assert_eq!(b"1", &pnk!(pnk!(tree.merge(b"m", b"1")))[..]);
+    assert_eq!(b"12", &pnk!(pnk!(tree.merge(b"m", b"2")))[..]);
+    assert_eq!(b"12", &pnk!(pnk!(tree.get(b"m")))[..]);
+    assert!(pnk!(tree.merge(b"m", b"")).is_none());
+    assert!(!pnk!(tree.contains_key(b"m")));
+    assert!(kv.merge(b"m", b"1").is_err());
+
+    let id = pnk!(kv.generate_id());
+    assert!(id < pnk!(tree.generate_id()));
+    pnk!(tree.raise_id_floor(id + 100));
+    let raised = pnk!(kv.generate_id());
+    assert!(raised >= id + 100);
+    let id = raised;
+    pnk!(kv.raise_id_floor(0));
+    assert!(id < pnk!(tree.generate_id()));
+    assert!(pnk!(kv.tree_names()).iter().all(|name| name == "sub"));
+    pnk!(kv.flush());
+}
+
+#[test]
+fn t_kv_backends() {
+    check(pnk!(open(&crate::unique_path!(), true, Storage::Sled)));
+    check(pnk!(open(&crate::unique_path!(), false, Storage::Memory)));
+    check(pnk!(open(&crate::unique_path!(), false, Storage::Log)));
+}
+
+#[test]
+fn t_kv_log_reopen() {
+    let path = crate::unique_path!();
+
+    {
+        let kv = pnk!(LogKv::open(&path));
+        pnk!(kv.insert(b"a", b"1"));
+        pnk!(kv.insert(b"b", b"2"));
+        pnk!(kv.insert(b"a", b"3"));
+        pnk!(kv.remove(b"b"));
+        pnk!(pnk!(kv.open_tree("sub")).insert(b"x", b"y"));
+        assert_eq!(0, pnk!(kv.generate_id()));
+    }
+
+    // A torn frame at the tail.
+    let mut file = pnk!(OpenOptions::new()
+        .append(true)
+        .open(format!("{}/____default____.log", &path)));
+    pnk!(file.write_all(&[100, 0, 0, 0, 1]));
+
+    let kv = pnk!(LogKv::open(&path));
+    assert_eq!(1, kv.len());
+    assert_eq!(b"3", &pnk!(pnk!(kv.get(b"a")))[..]);
+    assert_eq!(b"y", &pnk!(pnk!(pnk!(kv.open_tree("sub")).get(b"x")))[..]);
+    assert_eq!(1, pnk!(kv.generate_id()));
+
+    pnk!(kv.insert(b"c", b"4"));
+    let kv = pnk!(LogKv::open(&path));
+    assert_eq!(2, kv.len());
+}
@@ -6,9 +6,21 @@
 #![deny(missing_docs)]
 #![allow(clippy::upper_case_acronyms)]
 
+#[macro_use]
+mod trace;
+
+pub mod changelog;
+pub mod database;
+pub mod durability;
 pub mod helper;
+pub mod kv;
 pub mod mapx;
+pub mod merge;
+#[cfg(feature = "server")]
+pub mod net;
+pub mod schema;
 mod serde;
+pub mod stats;
 pub mod vecx;
 
 pub use mapx::Mapx;
@@ -2,17 +2,62 @@
 //! # Disk Storage Implementation
 //!
 
-use crate::helper::*;
+use crate::{
+    changelog::{self, ChangeIter, ChangeLog, RawOp},
+    durability::{Durability, Flusher},
+    helper::*,
+    kv::{prefix_end, Kv, KvBatch, KvIter, MergeFn, Merger, Storage},
+    merge::MergeOperator,
+    schema::{self, Schema},
+    serde::nested_ids,
+    stats::{Stats, StatsCounter},
+};
+use lazy_static::lazy_static;
 use ruc::*;
 use serde::{de::DeserializeOwned, Serialize};
 use sled::IVec;
 use std::{
+    any::TypeId,
+    collections::{HashMap, HashSet},
+    convert::TryInto,
     fmt, fs,
     hash::Hash,
-    iter::{DoubleEndedIterator, Iterator},
+    iter::{self, DoubleEndedIterator, Iterator},
     marker::PhantomData,
+    mem,
+    ops::Bound,
+    path::Path,
+    sync::{Arc, Mutex, Weak},
 };
 
+// All nested collections of a database share this tree,
+// each of them occupies the key range of its own prefix.
+const NESTED_TREE: &str = "____nested____";
+
+// Length counters of the nested collections, keyed by their prefixes.
+const NESTED_CNTER_TREE: &str = "____nested_cnter____";
+
+type NestedCnters = HashMap<(String, u64), Weak<Mutex<usize>>>;
+
+lazy_static! {
+    // The lengths of the opened nested collections, keyed by their data paths and ids,
+    // every handle of a nested collection writes the same key range,
+    // so they must share one counter.
+    static ref NESTED_CNTERS: Mutex<NestedCnters> = Mutex::new(HashMap::new());
+}
+
+// Where the length counter of a collection is persisted.
+#[derive(Debug, Clone)]
+enum CnterLoc {
+    // The `____cnter____` file of a top-level collection,
+    // written according to its durability mode.
+    File(Arc<Flusher>),
+    // An entry of the `NESTED_CNTER_TREE` of a nested collection,
+    // and the length shared by all handles of it, which is written
+    // to the tree along with every change while the lock is held.
+    Tree(Kv, Arc<Mutex<usize>>),
+}
+
 // To solve the problem of unlimited memory usage,
 // use this to replace the original in-memory `HashMap<_, _>`.
 #[derive(Debug, Clone)]
@@ -21,10 +66,31 @@ where
     K: Clone + Eq + PartialEq + Hash + Serialize + DeserializeOwned + fmt::Debug,
     V: Clone + Eq + PartialEq + Serialize + DeserializeOwned + fmt::Debug,
 {
-    db: sled::Db,
+    db: Kv,
+    // The default tree of `db` for top-level collections,
+    // or the `NESTED_TREE` for nested ones.
+    tree: Kv,
+    storage: Storage,
+    // Empty for top-level collections.
+    prefix: Vec<u8>,
     data_path: String,
-    cnter_path: String,
+    cnter_loc: CnterLoc,
+    // The length of a top-level collection, nested ones use the shared one in `cnter_loc`.
     cnter: usize,
+    // Whether the values written are collections nested in this database.
+    holds_nested: bool,
+    stats: Arc<StatsCounter>,
+    // Nested collections always use the default schema.
+    schema: Arc<Schema>,
+    // `None` for nested and read-only collections,
+    // dropped after the flusher to remove a temporary database at last.
+    guard: Option<Arc<DbGuard>>,
+    read_only: bool,
+    // `None` for nested collections.
+    change_log: Option<Arc<ChangeLog>>,
+    // The operand type of the merge operator set by this handle,
+    // and the operator to compute the merged value before recording it.
+    merge_operand: Option<(TypeId, Merger)>,
     _pd0: PhantomData<K>,
     _pd1: PhantomData<V>,
 }
@@ -41,82 +107,447 @@ where
     // If an old database exists,
     // it will use it directly;
     // Or it will create a new one.
+    //
+    // Without a `schema`, the version persisted in the database is used.
     #[inline(always)]
-    pub(super) fn load_or_create(path: String, is_tmp: bool) -> Result<Self> {
-        let db = sled_open(&path, is_tmp).c(d!())?;
+    pub(super) fn load_or_create(
+        path: String,
+        is_tmp: bool,
+        durability: Durability,
+        schema: Option<Schema>,
+        storage: Storage,
+    ) -> Result<Self> {
+        trace_span!(DEBUG, "fundb.load_or_create", path = %path, storage = ?storage);
+        let db = kv_open(&path, is_tmp, storage).c(d!())?;
+        let guard = DbGuard::get(&path, is_tmp);
+        let change_log = ChangeLog::get(&path, &db).c(d!())?;
         let cnter_path = format!("{}/____cnter____", &path);
+        let dirty = storage.is_persistent()
+            && Path::new(&cnter_path).exists()
+            && db_len_is_dirty(&cnter_path).c(d!())?;
 
-        let cnter = if db.iter().next().is_none() {
+        // The lost changes were written with the persisted schema version.
+        let stored = Schema::new(schema::load_version(&db).c(d!())?);
+        // Some of the redone changes may have been written before the crash,
+        // so the nested collections held by any value of a redone key are dropped,
+        // except the ones held by its final value, after all changes are redone like `apply`.
+        let mut dropped = HashSet::new();
+        let mut last = HashMap::new();
+        change_log
+            .recover(&db, dirty, |batch, op| {
+                let k = match op {
+                    RawOp::Insert(ref k, _) | RawOp::Remove(ref k) => k.clone(),
+                };
+                if !last.contains_key(&k) {
+                    if let Some(old) = db.get(&k).c(d!())? {
+                        dropped.extend(nested_ids(&old, &path));
+                    }
+                }
+                match op {
+                    RawOp::Insert(k, v) => {
+                        let v = stored.encode(v);
+                        let ids = nested_ids(&v, &path);
+                        dropped.extend(ids.iter().copied());
+                        last.insert(k.clone(), ids);
+                        batch.insert(k, v);
+                    }
+                    RawOp::Remove(k) => {
+                        last.insert(k.clone(), vec![]);
+                        batch.remove(k);
+                    }
+                }
+                Ok(())
+            })
+            .c(d!())?;
+        let kept = last.into_values().flatten().collect::<HashSet<_>>();
+        for id in dropped.difference(&kept) {
+            clear_nested_by_id(&db, &path, *id).c(d!())?;
+        }
+
+        let schema = if let Some(schema) = schema {
+            schema.apply_to(&db).c(d!()).map(|_| schema)?
+        } else {
+            stored
+        };
+
+        let cnter = if !storage.is_persistent() {
+            db.len()
+        } else if db.is_empty() {
             fs::File::create(&cnter_path)
                 .c(d!())
                 .and_then(|_| write_db_len(&cnter_path, 0).c(d!()))
                 .map(|_| 0)?
+        } else if dirty {
+            // Not flushed before the last exit, recount it.
+            let cnter = db.len();
+            write_db_len(&cnter_path, cnter).c(d!())?;
+            cnter
         } else {
             read_db_len(&cnter_path).c(d!())?
         };
 
+        let stats = Arc::<StatsCounter>::default();
+        let flusher = Flusher::new(
+            Arc::clone(&db),
+            &path,
+            storage.is_persistent(),
+            cnter,
+            durability,
+            Arc::clone(&stats),
+        );
+
+        let holds_nested = holds_nested(&db, &path);
+
         Ok(Mapx {
+            tree: Arc::clone(&db),
             db,
+            storage,
+            prefix: vec![],
             data_path: path,
-            cnter_path,
+            cnter_loc: CnterLoc::File(Arc::new(flusher)),
             cnter,
+            holds_nested,
+            stats,
+            schema: Arc::new(schema),
+            guard: Some(guard),
+            read_only: false,
+            change_log: Some(change_log),
+            merge_operand: None,
             _pd0: PhantomData,
             _pd1: PhantomData,
         })
     }
 
+    // Open an existing database without writing anything to it,
+    // the length is counted from the data since the counter may be stale.
+    pub(super) fn load_read_only(path: String) -> Result<Self> {
+        let (db, storage) = kv_open_read_only(&path).c(d!())?;
+        let schema = schema::load_version(&db).c(d!()).map(Schema::new)?;
+        let change_log = ChangeLog::get(&path, &db).c(d!())?;
+        let cnter = db.len();
+
+        let stats = Arc::<StatsCounter>::default();
+        let flusher = Flusher::new(
+            Arc::clone(&db),
+            &path,
+            false,
+            cnter,
+            Durability::Manual,
+            Arc::clone(&stats),
+        );
+
+        let holds_nested = holds_nested(&db, &path);
+
+        Ok(Mapx {
+            tree: Arc::clone(&db),
+            db,
+            storage,
+            prefix: vec![],
+            data_path: path,
+            cnter_loc: CnterLoc::File(Arc::new(flusher)),
+            cnter,
+            holds_nested,
+            stats,
+            schema: Arc::new(schema),
+            guard: None,
+            read_only: true,
+            change_log: Some(change_log),
+            merge_operand: None,
+            _pd0: PhantomData,
+            _pd1: PhantomData,
+        })
+    }
+
+    // Create a new empty collection nested in the same database,
+    // it will be assigned an unique prefix in the `NESTED_TREE`.
+    pub(super) fn create_nested<K2, V2>(&self) -> Result<Mapx<K2, V2>>
+    where
+        K2: Clone + Eq + PartialEq + Hash + Serialize + DeserializeOwned + fmt::Debug,
+        V2: Clone + Eq + PartialEq + Serialize + DeserializeOwned + fmt::Debug,
+    {
+        if self.read_only {
+            return Err(eg!(format!("{} is opened read-only", self.data_path)));
+        }
+        let id = self.db.generate_id().c(d!())?;
+        let nested = Mapx::load_nested(
+            &self.db,
+            self.data_path.clone(),
+            self.storage,
+            id,
+            self.read_only,
+        )
+        .c(d!())?;
+        nested.persist_len();
+        Ok(nested)
+    }
+
+    // Open an existing nested collection by its id.
+    #[inline(always)]
+    pub(super) fn load_nested(
+        db: &Kv,
+        path: String,
+        storage: Storage,
+        id: u64,
+        read_only: bool,
+    ) -> Result<Self> {
+        let tree = db.open_tree(NESTED_TREE).c(d!())?;
+        let cnter_tree = db.open_tree(NESTED_CNTER_TREE).c(d!())?;
+        let prefix = id.to_be_bytes().to_vec();
+        let shared = nested_cnter(&cnter_tree, &path, id).c(d!())?;
+
+        Ok(Mapx {
+            db: Arc::clone(db),
+            tree,
+            storage,
+            prefix,
+            data_path: path,
+            cnter_loc: CnterLoc::Tree(cnter_tree, shared),
+            cnter: 0,
+            holds_nested: false,
+            stats: Arc::default(),
+            schema: Arc::default(),
+            guard: None,
+            read_only,
+            change_log: None,
+            merge_operand: None,
+            _pd0: PhantomData,
+            _pd1: PhantomData,
+        })
+    }
+
+    pub(super) fn storage(&self) -> Storage {
+        self.storage
+    }
+
+    // The durability mode of a top-level collection,
+    // `None` for nested ones which are flushed along with their outer collection.
+    pub(super) fn durability(&self) -> Option<Durability> {
+        match self.cnter_loc {
+            CnterLoc::File(ref flusher) => Some(flusher.durability()),
+            CnterLoc::Tree(..) => None,
+        }
+    }
+
+    pub(super) fn schema_version(&self) -> u32 {
+        self.schema.version()
+    }
+
+    // The id of a nested collection, `None` for top-level ones.
+    pub(super) fn nested_id(&self) -> Option<u64> {
+        if self.prefix.is_empty() {
+            None
+        } else {
+            Some(u64::from_be_bytes(self.prefix[..].try_into().unwrap()))
+        }
+    }
+
+    // Remove all data of a top-level collection,
+    // it fails if any other clones of it are alive.
+    pub(super) fn destroy(self) -> Result<()> {
+        let guard = self
+            .guard
+            .clone()
+            .c(d!("a nested collection can not be destroyed"))?;
+        // One is held by `self`.
+        if 2 < Arc::strong_count(&guard) {
+            return Err(eg!(format!("{} is still in use", self.data_path)));
+        }
+        drop(self);
+        guard.destroy().c(d!())
+    }
+
     // Get the storage path
     pub(super) fn get_data_path(&self) -> &str {
         self.data_path.as_str()
     }
 
+    #[inline(always)]
+    pub(super) fn stats_counter(&self) -> &StatsCounter {
+        &self.stats
+    }
+
+    // The size on disk is the one of the whole sled instance,
+    // which is shared with the nested collections.
+    pub(super) fn stats(&self) -> Stats {
+        self.stats.snapshot(self.db.size_on_disk())
+    }
+
     // Imitate the behavior of 'HashMap<_>.get(...)'
     #[inline(always)]
     pub(super) fn get(&self, key: &K) -> Option<V> {
-        self.db
-            .get(&pnk!(bincode::serialize(key)))
-            .ok()
-            .flatten()
-            .map(|bytes| pnk!(serde_json::from_slice(&bytes)))
+        let key = self.encode_key(key);
+        trace_span!(TRACE, "fundb.get", path = %self.data_path, key_size = key.len());
+        self.tree.get(&key).ok().flatten().map(|bytes| {
+            trace_event!(TRACE, value_size = bytes.len());
+            self.decode_value(&bytes)
+        })
+    }
+
+    // Get the keys one by one in the order of their encoded forms,
+    // so that neighbouring keys are looked up in turn.
+    pub(super) fn get_many<'a>(&self, keys: impl IntoIterator<Item = &'a K>) -> Vec<Option<V>>
+    where
+        K: 'a,
+    {
+        let mut keys = keys
+            .into_iter()
+            .map(|k| self.encode_key(k))
+            .enumerate()
+            .collect::<Vec<_>>();
+        trace_span!(TRACE, "fundb.get_many", path = %self.data_path, len = keys.len());
+        keys.sort_by(|a, b| a.1.cmp(&b.1));
+
+        let mut values = vec![None; keys.len()];
+        for (i, k) in keys {
+            values[i] = self
+                .tree
+                .get(&k)
+                .ok()
+                .flatten()
+                .map(|bytes| self.decode_value(&bytes));
+        }
+        values
     }
 
     // Imitate the behavior of 'HashMap<_>.len()'.
     #[inline(always)]
     pub(super) fn len(&self) -> usize {
-        debug_assert_eq!(pnk!(read_db_len(&self.cnter_path)), self.cnter);
-        debug_assert_eq!(self.db.len(), self.cnter);
-        self.cnter
+        match self.cnter_loc {
+            CnterLoc::File(_) => {
+                debug_assert_eq!(self.db.len(), self.cnter);
+                self.cnter
+            }
+            CnterLoc::Tree(_, ref shared) => {
+                let cnter = shared.lock().unwrap();
+                debug_assert_eq!(self.tree.scan_prefix(&self.prefix).count(), *cnter);
+                *cnter
+            }
+        }
     }
 
     // A helper func
     #[inline(always)]
     pub(super) fn is_empty(&self) -> bool {
-        self.iter().next().is_none()
+        0 == self.cnter()
+    }
+
+    // Whether the values written by this handle are nested collections.
+    #[inline(always)]
+    pub(super) fn holds_nested(&self) -> bool {
+        self.holds_nested
     }
 
     // Imitate the behavior of 'HashMap<_>.insert(...)'.
     #[inline(always)]
     pub(super) fn insert(&mut self, key: K, value: V) -> Option<V> {
-        self.set_value(key, value)
-            .map(|v| pnk!(serde_json::from_slice(&v)))
+        self.set_value(&key, &value).map(|v| self.decode_value(&v))
     }
 
     // Similar with `insert`, but ignore if the old value is exist.
     #[inline(always)]
-    pub(super) fn set_value(&mut self, key: K, value: V) -> Option<IVec> {
-        pnk!(self
-            .db
-            .insert(
-                pnk!(bincode::serialize(&key)),
-                pnk!(serde_json::to_vec(&value))
-            )
-            .map(|v| {
-                if v.is_none() {
-                    self.cnter += 1;
-                    pnk!(write_db_len(&self.cnter_path, self.cnter));
+    pub(super) fn set_value(&mut self, key: &K, value: &V) -> Option<IVec> {
+        let key = self.encode_key(key);
+        let value = self.encode_value(value);
+        trace_span!(
+            TRACE,
+            "fundb.insert",
+            path = %self.data_path,
+            key_size = key.len(),
+            value_size = value.len()
+        );
+        self.stats.written(key.len() + value.len());
+        if !self.holds_nested && !nested_ids(&value, &self.data_path).is_empty() {
+            self.holds_nested = true;
+        }
+        self.before_write();
+        self.log_insert(&key, &value);
+        pnk!(self.tree.insert(&key, &value).map(|v| {
+            if let Some(old) = v.as_ref() {
+                // An overwritten nested collection is dropped,
+                // unless it is being written back by a `ValueMut`.
+                self.clear_nested(old, Some(&value));
+            } else {
+                self.add_len(1);
+            }
+            self.after_write();
+            v
+        }))
+    }
+
+    // Insert all entries with one atomic batch,
+    // the counter will be written only once.
+    pub(super) fn extend<I>(&mut self, iter: I)
+    where
+        I: IntoIterator<Item = (K, V)>,
+    {
+        self.apply(iter.into_iter().map(|(k, v)| (k, Some(v))));
+    }
+
+    // Apply all changes with one atomic batch, `None` means removing the key,
+    // the counter will be written only once.
+    //
+    // The nested collections held by the replaced values live in other keyspaces,
+    // which are removed after the batch, so nothing is lost if the batch fails,
+    // but they are left behind if this process is killed between them.
+    pub(super) fn apply<I>(&mut self, changes: I)
+    where
+        I: IntoIterator<Item = (K, Option<V>)>,
+    {
+        let mut batch = KvBatch::default();
+        // Whether each key exists before and after the batch.
+        let mut touched = HashMap::new();
+        let mut ops = vec![];
+        let mut replaced = vec![];
+        self.before_write();
+
+        for (k, v) in changes {
+            let k = self.encode_key(&k);
+            let v = v.map(|v| self.encode_value(&v));
+            self.stats
+                .written(k.len() + v.as_ref().map(|v| v.len()).unwrap_or(0));
+            let old = pnk!(self.tree.get(&k));
+            if let Some(old) = old.as_ref() {
+                replaced.push((old.clone(), v.clone()));
+            }
+            touched.entry(k.clone()).or_insert((old.is_some(), false)).1 = v.is_some();
+            if let Some(v) = v.as_ref() {
+                if !self.holds_nested && !nested_ids(v, &self.data_path).is_empty() {
+                    self.holds_nested = true;
                 }
-                v
-            }))
+            }
+            if self.logging().is_some() {
+                match v.as_ref() {
+                    Some(v) => ops.push(RawOp::Insert(k.clone(), schema::split(v).1.to_vec())),
+                    None if old.is_some() => ops.push(RawOp::Remove(k.clone())),
+                    None => {}
+                }
+            }
+            match v {
+                Some(v) => batch.insert(k, v),
+                None => batch.remove(k),
+            }
+        }
+
+        if let Some(log) = self.logging() {
+            pnk!(log.append(ops));
+        }
+        pnk!(self.tree.apply_batch(batch));
+        for (old, new) in replaced {
+            self.clear_nested(&old, new.as_deref());
+        }
+
+        let (added, removed) =
+            touched
+                .values()
+                .fold((0, 0), |(added, removed), existed| match existed {
+                    (false, true) => (added + 1, removed),
+                    (true, false) => (added, removed + 1),
+                    _ => (added, removed),
+                });
+        if added != removed {
+            self.add_len(added as isize - removed as isize);
+        }
+        self.after_write();
     }
 
     // Imitate the behavior of '.iter()'
@@ -124,35 +555,426 @@ where
     pub(super) fn iter(&self) -> MapxIter<K, V> {
         // todo!()
         MapxIter {
-            iter: self.db.iter(),
+            iter: self.raw_iter(),
             _pd0: PhantomData,
             _pd1: PhantomData,
         }
     }
 
+    // Iterate over the encoded entries without decoding them,
+    // the prefix of a nested collection is stripped from the keys.
+    #[inline(always)]
+    pub(super) fn raw_iter(&self) -> MapxRawIter {
+        MapxRawIter {
+            iter: self.tree.scan_prefix(&self.prefix),
+            prefix_len: self.prefix.len(),
+            remaining: self.cnter(),
+            stats: Arc::clone(&self.stats),
+            schema: Arc::clone(&self.schema),
+        }
+    }
+
+    // Iterate over the encoded entries beyond the encoded key `cursor`,
+    // which are the greater ones, or the smaller ones if `rev` is set.
+    pub(super) fn raw_iter_from(&self, cursor: Option<&[u8]>, rev: bool) -> MapxRawRange {
+        let end = prefix_end(&self.prefix);
+        let cursor = cursor.map(|c| [&self.prefix[..], c].concat());
+        let mut lo = Bound::Included(&self.prefix[..]);
+        let mut hi = end
+            .as_deref()
+            .map(Bound::Excluded)
+            .unwrap_or(Bound::Unbounded);
+        if let Some(c) = cursor.as_deref() {
+            if rev {
+                hi = Bound::Excluded(c);
+            } else {
+                lo = Bound::Excluded(c);
+            }
+        }
+
+        MapxRawRange(MapxRawIter {
+            iter: self.tree.range(lo, hi),
+            prefix_len: self.prefix.len(),
+            remaining: self.cnter(),
+            stats: Arc::clone(&self.stats),
+            schema: Arc::clone(&self.schema),
+        })
+    }
+
+    // Iterate over the encoded entries whose keys are within the bounds,
+    // which are compared in their encoded forms.
+    #[cfg(feature = "server")]
+    pub(super) fn raw_range(&self, lo: Bound<&K>, hi: Bound<&K>) -> MapxRawRange {
+        let encode = |b: Bound<&K>| match b {
+            Bound::Included(k) => Bound::Included(self.encode_key(k)),
+            Bound::Excluded(k) => Bound::Excluded(self.encode_key(k)),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        let (lo, hi) = (encode(lo), encode(hi));
+        let end = prefix_end(&self.prefix);
+        let lo = match lo {
+            Bound::Included(ref k) => Bound::Included(&k[..]),
+            Bound::Excluded(ref k) => Bound::Excluded(&k[..]),
+            Bound::Unbounded => Bound::Included(&self.prefix[..]),
+        };
+        let hi = match hi {
+            Bound::Included(ref k) => Bound::Included(&k[..]),
+            Bound::Excluded(ref k) => Bound::Excluded(&k[..]),
+            Bound::Unbounded => end
+                .as_deref()
+                .map(Bound::Excluded)
+                .unwrap_or(Bound::Unbounded),
+        };
+
+        MapxRawRange(MapxRawIter {
+            iter: self.tree.range(lo, hi),
+            prefix_len: self.prefix.len(),
+            remaining: self.cnter(),
+            stats: Arc::clone(&self.stats),
+            schema: Arc::clone(&self.schema),
+        })
+    }
+
+    // Upgrade all records of older schema versions in one atomic batch,
+    // return the number of them.
+    pub(super) fn migrate_all(&self) -> Result<usize> {
+        let mut batch = KvBatch::default();
+        let mut ops = vec![];
+        let mut cnt = 0;
+        for kv in self.tree.scan_prefix(&self.prefix) {
+            let (k, v) = kv.c(d!())?;
+            if !self.schema.is_current(&v) {
+                let v: V = self.schema.decode(&v).c(d!())?;
+                let v = self.encode_value(&v);
+                self.stats.written(k.len() + v.len());
+                if self.logging().is_some() {
+                    ops.push(RawOp::Insert(k.to_vec(), schema::split(&v).1.to_vec()));
+                }
+                batch.insert(k.to_vec(), v);
+                cnt += 1;
+            }
+        }
+
+        if 0 < cnt {
+            self.before_write();
+            if let Some(log) = self.logging() {
+                log.append(ops).c(d!())?;
+            }
+            self.tree.apply_batch(batch).c(d!())?;
+            self.after_write();
+        }
+        Ok(cnt)
+    }
+
+    // Write back the value of an existing key,
+    // it needs no `&mut self` since the counter keeps unchanged.
+    pub(super) fn write_back(&self, key: &K, value: &V) {
+        let key = self.encode_key(key);
+        let value = self.encode_value(value);
+        self.stats.written(key.len() + value.len());
+        self.before_write();
+        self.log_insert(&key, &value);
+        if let Some(old) = pnk!(self.tree.insert(&key, &value)) {
+            self.clear_nested(&old, Some(&value));
+        }
+        self.after_write();
+    }
+
     pub(super) fn contains_key(&self, key: &K) -> bool {
-        pnk!(self.db.contains_key(pnk!(bincode::serialize(key))))
+        pnk!(self.tree.contains_key(&self.encode_key(key)))
     }
 
     pub(super) fn remove(&mut self, key: &K) -> Option<V> {
-        self.unset_value(key)
-            .map(|v| pnk!(serde_json::from_slice(&v)))
+        self.unset_value(key).map(|v| self.decode_value(&v))
     }
 
     pub(super) fn unset_value(&mut self, key: &K) -> Option<IVec> {
-        pnk!(self.db.remove(pnk!(bincode::serialize(&key))).map(|v| {
-            if v.is_some() {
-                self.cnter -= 1;
-                pnk!(write_db_len(&self.cnter_path, self.cnter));
+        let key = self.encode_key(key);
+        trace_span!(TRACE, "fundb.remove", path = %self.data_path, key_size = key.len());
+        self.before_write();
+        if self.logging().is_some() && pnk!(self.tree.contains_key(&key)) {
+            self.log_remove(&key);
+        }
+        pnk!(self.tree.remove(&key).map(|v| {
+            if let Some(old) = v.as_ref() {
+                trace_event!(TRACE, value_size = old.len());
+                self.clear_nested(old, None);
+                self.add_len(-1);
             }
+            self.after_write();
             v
         }))
     }
 
+    // The operator is shared by all handles of the tree,
+    // so nested collections can not have their own ones.
+    pub(super) fn set_merge_operator<M>(&mut self, op: M) -> Result<()>
+    where
+        M: MergeOperator<V>,
+        V: 'static,
+    {
+        if !self.prefix.is_empty() {
+            return Err(eg!("a nested collection can not have a merge operator"));
+        }
+        let schema = Arc::clone(&self.schema);
+        let f: MergeFn = Arc::new(move |_, old, operand| {
+            // Errors can not be returned from here,
+            // a record or an operand which fails to decode keeps the value unchanged.
+            let keep = || old.map(|v| v.to_vec());
+            let old_v = match old.map(|v| schema.decode::<V>(v)).transpose() {
+                Ok(v) => v,
+                Err(_) => return keep(),
+            };
+            let operand = match serde_json::from_slice(operand) {
+                Ok(o) => o,
+                Err(_) => return keep(),
+            };
+            op.merge(old_v, operand)
+                .map(|v| schema.encode(pnk!(serde_json::to_vec(&v))))
+        });
+        self.tree.set_merge_operator(Arc::clone(&f));
+        self.merge_operand = Some((TypeId::of::<M::Operand>(), Merger(Some(f))));
+        Ok(())
+    }
+
+    // Return the new value, the operand must be of the type of the operator.
+    pub(super) fn merge<O>(&mut self, key: &K, operand: &O) -> Result<Option<V>>
+    where
+        O: Serialize + 'static,
+    {
+        let merger = match self.merge_operand {
+            None => return Err(eg!("no merge operator is set")),
+            Some((id, _)) if id != TypeId::of::<O>() => {
+                return Err(eg!(format!(
+                    "the operand type of the merge operator is not {}",
+                    std::any::type_name::<O>()
+                )));
+            }
+            Some((_, ref merger)) => merger.clone(),
+        };
+
+        let key = self.encode_key(key);
+        let operand = self.stats.encode(|| serde_json::to_vec(operand)).c(d!())?;
+        self.stats.written(key.len() + operand.len());
+        self.before_write();
+
+        let merged = if self.logging().is_some() {
+            self.merge_logged(&key, &operand, &merger)
+        } else {
+            self.tree
+                .contains_key(&key)
+                .and_then(|existed| self.tree.merge(&key, &operand).map(|v| (existed, v)))
+        };
+        let (existed, new) = match merged {
+            Ok(r) => r,
+            Err(e) => {
+                self.after_write();
+                return Err(e).c(d!());
+            }
+        };
+
+        match (existed, new.is_some()) {
+            (false, true) => self.add_len(1),
+            (true, false) => self.add_len(-1),
+            _ => {}
+        }
+        self.after_write();
+        Ok(new.map(|v| self.decode_value(&v)))
+    }
+
+    // The merged value is recorded before it is written,
+    // so it is computed here instead of by the tree.
+    fn merge_logged(
+        &self,
+        key: &[u8],
+        operand: &[u8],
+        merger: &Merger,
+    ) -> Result<(bool, Option<IVec>)> {
+        let old = self.tree.get(key).c(d!())?;
+        let new = merger.merge(key, old.as_deref(), operand).c(d!())?;
+        match new.as_ref() {
+            Some(v) => {
+                self.log_insert(key, v);
+                self.tree.insert(key, v).c(d!())?;
+            }
+            None if old.is_some() => {
+                self.log_remove(key);
+                self.tree.remove(key).c(d!())?;
+            }
+            None => {}
+        }
+        Ok((old.is_some(), new.map(IVec::from)))
+    }
+
+    // Record the changes from now on, nested collections can not be recorded alone.
+    pub(super) fn enable_change_log(&self) -> Result<()> {
+        self.change_log
+            .as_ref()
+            .c(d!("the changes of a nested collection can not be recorded"))?
+            .enable()
+            .c(d!())
+    }
+
+    // The sequence number of the last recorded change, `None` if it is not enabled.
+    pub(super) fn change_log_seq(&self) -> Option<u64> {
+        self.logging().map(|log| log.last_seq())
+    }
+
+    pub(super) fn changes_since(&self, seq: u64) -> Result<ChangeIter<K, V>> {
+        self.change_log
+            .as_ref()
+            .c(d!("the change log is not enabled"))?
+            .since(seq)
+            .c(d!())
+    }
+
+    pub(super) fn compact_change_log(&self, seq: u64) -> Result<usize> {
+        self.logging()
+            .c(d!("the change log is not enabled"))?
+            .compact(seq)
+            .c(d!())
+    }
+
+    // The progress of a follower is kept in the database of a top-level collection.
+    pub(super) fn applied_seq(&self) -> Result<u64> {
+        if !self.prefix.is_empty() {
+            return Err(eg!("a nested collection can not be a follower"));
+        }
+        changelog::load_applied_seq(&self.db).c(d!())
+    }
+
+    pub(super) fn set_applied_seq(&self, seq: u64) -> Result<()> {
+        if !self.prefix.is_empty() {
+            return Err(eg!("a nested collection can not be a follower"));
+        }
+        changelog::store_applied_seq(&self.db, seq).c(d!())
+    }
+
+    // The change log if it is enabled.
+    #[inline(always)]
+    fn logging(&self) -> Option<&ChangeLog> {
+        self.change_log.as_deref().filter(|log| log.is_enabled())
+    }
+
+    #[inline(always)]
+    fn log_insert(&self, key: &[u8], value: &[u8]) {
+        if let Some(log) = self.logging() {
+            let op = RawOp::Insert(key.to_vec(), schema::split(value).1.to_vec());
+            pnk!(log.append(iter::once(op)));
+        }
+    }
+
+    #[inline(always)]
+    fn log_remove(&self, key: &[u8]) {
+        if let Some(log) = self.logging() {
+            pnk!(log.append(iter::once(RawOp::Remove(key.to_vec()))));
+        }
+    }
+
+    #[inline(always)]
+    fn encode_key(&self, key: &K) -> Vec<u8> {
+        let mut k = self.prefix.clone();
+        self.stats
+            .encode(|| pnk!(bincode::serialize_into(&mut k, key)));
+        k
+    }
+
+    #[inline(always)]
+    fn encode_value(&self, value: &V) -> Vec<u8> {
+        self.stats
+            .encode(|| self.schema.encode(pnk!(serde_json::to_vec(value))))
+    }
+
+    #[inline(always)]
+    fn decode_value(&self, value: &[u8]) -> V {
+        self.stats.read(value.len());
+        self.stats.decode(|| pnk!(self.schema.decode(value)))
+    }
+
+    #[inline(always)]
+    fn before_write(&self) {
+        if self.read_only {
+            panic!("{} is opened read-only", self.data_path);
+        }
+        if let CnterLoc::File(ref flusher) = self.cnter_loc {
+            flusher.begin_write();
+        }
+    }
+
+    #[inline(always)]
+    fn after_write(&self) {
+        if let CnterLoc::File(ref flusher) = self.cnter_loc {
+            flusher.end_write(self.cnter);
+        }
+    }
+
+    // The length without checking it against the data.
+    #[inline(always)]
+    fn cnter(&self) -> usize {
+        match self.cnter_loc {
+            CnterLoc::File(_) => self.cnter,
+            CnterLoc::Tree(_, ref shared) => *shared.lock().unwrap(),
+        }
+    }
+
+    // Count the added or removed entries,
+    // the length of a nested collection is persisted at once.
+    #[inline(always)]
+    fn add_len(&mut self, n: isize) {
+        match self.cnter_loc {
+            CnterLoc::File(_) => self.cnter = (self.cnter as isize + n) as usize,
+            CnterLoc::Tree(ref cnter_tree, ref shared) => {
+                let mut cnter = shared.lock().unwrap();
+                *cnter = (*cnter as isize + n) as usize;
+                pnk!(cnter_tree.insert(&self.prefix, &usize::to_le_bytes(*cnter)[..]));
+            }
+        }
+    }
+
+    // Write the length of a new nested collection.
+    fn persist_len(&self) {
+        if let CnterLoc::Tree(ref cnter_tree, ref shared) = self.cnter_loc {
+            let cnter = shared.lock().unwrap();
+            pnk!(cnter_tree.insert(&self.prefix, &usize::to_le_bytes(*cnter)[..]));
+        }
+    }
+
+    // If the removed or replaced value holds collections nested in this database,
+    // remove all their entries recursively, except the ones still held by the new value,
+    // e.g. a struct written back with the same nested map in one of its fields.
+    fn clear_nested(&self, old: &[u8], new: Option<&[u8]>) {
+        if Some(old) == new {
+            return;
+        }
+        let kept = new
+            .map(|v| nested_ids(v, &self.data_path))
+            .unwrap_or_default();
+        for id in nested_ids(old, &self.data_path) {
+            if !kept.contains(&id) {
+                pnk!(clear_nested_by_id(&self.db, &self.data_path, id));
+            }
+        }
+    }
+
     /// Flush data to disk
     #[inline(always)]
     pub fn flush(&self) {
-        pnk!(self.db.flush());
+        match self.cnter_loc {
+            CnterLoc::File(ref flusher) => pnk!(flusher.flush()),
+            CnterLoc::Tree(..) => {
+                pnk!(self.stats.flush(|| self.db.flush()));
+            }
+        }
+    }
+
+    // Write an entry without counting it,
+    // as if this process had been killed between `begin_write` and `end_write`.
+    #[cfg(test)]
+    pub(super) fn set_value_unfinished(&mut self, key: &K, value: &V) {
+        self.before_write();
+        pnk!(self
+            .tree
+            .insert(&self.encode_key(key), &self.encode_value(value)));
     }
 }
 
@@ -160,17 +982,184 @@ where
 // End of the self-implementation of backend::Mapx //
 /////////////////////////////////////////////////////
 
+fn clear_nested_by_id(db: &Kv, data_path: &str, id: u64) -> Result<()> {
+    let tree = db.open_tree(NESTED_TREE).c(d!())?;
+    let prefix = id.to_be_bytes();
+
+    let mut batch = KvBatch::default();
+    for kv in tree.scan_prefix(&prefix) {
+        let (k, v) = kv.c(d!())?;
+        for id in nested_ids(&v, data_path) {
+            clear_nested_by_id(db, data_path, id).c(d!())?;
+        }
+        batch.remove(k.to_vec());
+    }
+    tree.apply_batch(batch).c(d!())?;
+
+    // The handles still alive see an empty collection.
+    let shared = NESTED_CNTERS
+        .lock()
+        .unwrap()
+        .get(&(data_path.to_owned(), id))
+        .and_then(|c| c.upgrade());
+    let _cnter = shared.as_ref().map(|c| {
+        let mut cnter = c.lock().unwrap();
+        *cnter = 0;
+        cnter
+    });
+    db.open_tree(NESTED_CNTER_TREE)
+        .c(d!())?
+        .remove(&prefix)
+        .c(d!())
+        .map(|_| ())
+}
+
+// Whether the values of a collection are nested collections,
+// all values of a `Mapx` have the same type, so the first one is checked.
+fn holds_nested(tree: &Kv, data_path: &str) -> bool {
+    tree.iter()
+        .next()
+        .and_then(|kv| kv.ok())
+        .map(|(_, v)| !nested_ids(&v, data_path).is_empty())
+        .unwrap_or(false)
+}
+
+// The length of a nested collection shared by all its handles in this process,
+// loaded from the `NESTED_CNTER_TREE` by the first one.
+fn nested_cnter(cnter_tree: &Kv, data_path: &str, id: u64) -> Result<Arc<Mutex<usize>>> {
+    let mut cnters = NESTED_CNTERS.lock().unwrap();
+    let key = (data_path.to_owned(), id);
+    if let Some(shared) = cnters.get(&key).and_then(|c| c.upgrade()) {
+        return Ok(shared);
+    }
+
+    let cnter = cnter_tree
+        .get(&id.to_be_bytes())
+        .c(d!())?
+        .map(|len| usize::from_le_bytes(len[..mem::size_of::<usize>()].try_into().unwrap()))
+        .unwrap_or(0);
+    let shared = Arc::new(Mutex::new(cnter));
+    // Drop the counters of the closed collections.
+    cnters.retain(|_, c| 0 < c.strong_count());
+    cnters.insert(key, Arc::downgrade(&shared));
+    Ok(shared)
+}
+
+// Recount the lengths of all nested collections of a database,
+// which may be stale in a copy taken between two writes.
+pub(crate) fn recount_nested(db: &Kv) -> Result<()> {
+    let tree = db.open_tree(NESTED_TREE).c(d!())?;
+    let cnter_tree = db.open_tree(NESTED_CNTER_TREE).c(d!())?;
+
+    let mut batch = KvBatch::default();
+    for kv in cnter_tree.iter() {
+        let (prefix, len) = kv.c(d!())?;
+        let cnt = tree.scan_prefix(&prefix).count();
+        if len[..] != usize::to_le_bytes(cnt)[..] {
+            batch.insert(prefix.to_vec(), &usize::to_le_bytes(cnt)[..]);
+        }
+    }
+    cnter_tree.apply_batch(batch).c(d!())
+}
+
 ///////////////////////////////////////////////////////////
 // Begin of the implementation of Iter for backend::Mapx //
 /*********************************************************/
 
+// Iter over the encoded entries of [Mapx](self::Mapx).
+pub(super) struct MapxRawIter {
+    iter: KvIter,
+    prefix_len: usize,
+    // The iterator is always created by a borrowed collection,
+    // so its length is known exactly if it visits all entries,
+    // which is the upper bound of the length of a `MapxRawRange`.
+    remaining: usize,
+    stats: Arc<StatsCounter>,
+    schema: Arc<Schema>,
+}
+
+impl MapxRawIter {
+    #[inline(always)]
+    fn strip(&mut self, kv: Result<(IVec, IVec)>) -> Option<(IVec, IVec)> {
+        kv.ok().map(|(k, v)| {
+            self.remaining = self.remaining.saturating_sub(1);
+            self.stats.read(k.len() + v.len());
+            (k.subslice(self.prefix_len, k.len() - self.prefix_len), v)
+        })
+    }
+
+    // Decode a key yielded by this iterator.
+    #[inline(always)]
+    pub(super) fn decode_key<K: DeserializeOwned>(&self, k: &[u8]) -> K {
+        self.stats.decode(|| pnk!(bincode::deserialize(k)))
+    }
+
+    // Decode a value yielded by this iterator.
+    #[inline(always)]
+    pub(super) fn decode_value<V: DeserializeOwned>(&self, v: &[u8]) -> V {
+        self.stats.decode(|| pnk!(self.schema.decode(v)))
+    }
+}
+
+impl Iterator for MapxRawIter {
+    type Item = (IVec, IVec);
+    fn next(&mut self) -> Option<Self::Item> {
+        self.iter.next().and_then(|kv| self.strip(kv))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl DoubleEndedIterator for MapxRawIter {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.iter.next_back().and_then(|kv| self.strip(kv))
+    }
+}
+
+impl ExactSizeIterator for MapxRawIter {}
+
+// Iter over the encoded entries from a cursor or within a range,
+// only the upper bound of its length is known.
+pub(super) struct MapxRawRange(MapxRawIter);
+
+impl MapxRawRange {
+    #[inline(always)]
+    pub(super) fn decode_key<K: DeserializeOwned>(&self, k: &[u8]) -> K {
+        self.0.decode_key(k)
+    }
+
+    #[inline(always)]
+    pub(super) fn decode_value<V: DeserializeOwned>(&self, v: &[u8]) -> V {
+        self.0.decode_value(v)
+    }
+}
+
+impl Iterator for MapxRawRange {
+    type Item = (IVec, IVec);
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, Some(self.0.remaining))
+    }
+}
+
+impl DoubleEndedIterator for MapxRawRange {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.0.next_back()
+    }
+}
+
 // Iter over [Mapx](self::Mapx).
 pub(super) struct MapxIter<K, V>
 where
     K: Clone + Eq + PartialEq + Hash + Serialize + DeserializeOwned + fmt::Debug,
     V: Clone + Eq + PartialEq + Serialize + DeserializeOwned + fmt::Debug,
 {
-    pub(super) iter: sled::Iter,
+    pub(super) iter: MapxRawIter,
     _pd0: PhantomData<K>,
     _pd1: PhantomData<V>,
 }
@@ -183,12 +1172,13 @@ where
     type Item = (K, V);
     fn next(&mut self) -> Option<Self::Item> {
         // todo!()
-        self.iter.next().map(|v| v.ok()).flatten().map(|(k, v)| {
-            (
-                pnk!(bincode::deserialize(&k)),
-                pnk!(serde_json::from_slice(&v)),
-            )
-        })
+        self.iter
+            .next()
+            .map(|(k, v)| (self.iter.decode_key(&k), self.iter.decode_value(&v)))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.iter.size_hint()
     }
 }
 
@@ -201,14 +1191,7 @@ where
         // todo!()
         self.iter
             .next_back()
-            .map(|v| v.ok())
-            .flatten()
-            .map(|(k, v)| {
-                (
-                    pnk!(bincode::deserialize(&k)),
-                    pnk!(serde_json::from_slice(&v)),
-                )
-            })
+            .map(|(k, v)| (self.iter.decode_key(&k), self.iter.decode_value(&v)))
     }
 }
 
@@ -233,8 +1216,7 @@ where
     V: Clone + Eq + PartialEq + Serialize + DeserializeOwned + fmt::Debug,
 {
     fn eq(&self, other: &Mapx<K, V>) -> bool {
-        // todo!()
-        self.iter().zip(other.iter()).all(|(i, j)| i == j)
+        self.len() == other.len() && self.iter().zip(other.iter()).all(|(i, j)| i == j)
     }
 }
 
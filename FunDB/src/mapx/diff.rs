@@ -0,0 +1,197 @@
+//!
+//! # Differences between Two Maps
+//!
+
+use super::{backend::MapxRawIter, Mapx};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sled::IVec;
+use std::{cmp::Ordering, collections::HashMap, fmt, hash::Hash, marker::PhantomData, mem};
+
+/// A difference between two maps, from the view of the first one.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub enum Diff<K, V> {
+    /// The key exists in the other map only.
+    Added(K, V),
+    /// The key exists in this map only.
+    Removed(K, V),
+    /// The values of the key are different.
+    Changed {
+        /// The key.
+        key: K,
+        /// The value in this map.
+        old: V,
+        /// The value in the other map.
+        new: V,
+    },
+}
+
+impl<K, V> Diff<K, V> {
+    /// The key of this difference.
+    pub fn key(&self) -> &K {
+        match self {
+            Diff::Added(k, _) | Diff::Removed(k, _) => k,
+            Diff::Changed { key, .. } => key,
+        }
+    }
+}
+
+//////////////////////////////////////////////
+// Begin of the implementation of Mapx diff //
+/********************************************/
+
+impl<K, V> Mapx<K, V>
+where
+    K: Clone + Eq + PartialEq + Hash + Serialize + DeserializeOwned + fmt::Debug,
+    V: Clone + Eq + PartialEq + Serialize + DeserializeOwned + fmt::Debug,
+{
+    /// Iterate over the differences from `self` to `other`,
+    /// in the order of the encoded keys.
+    ///
+    /// Both maps are walked once side by side on disk,
+    /// only the values whose encoded bytes differ are decoded.
+    pub fn diff<'a>(&'a self, other: &'a Mapx<K, V>) -> MapxDiff<'a, K, V> {
+        MapxDiff::new(self.in_disk.raw_iter(), other.in_disk.raw_iter())
+    }
+
+    /// Apply the differences of another pair of maps to `self` in one atomic batch,
+    /// e.g. `let d: Vec<_> = a.diff(&b).collect(); a.apply_diff(d);` makes `a` equal to `b`.
+    ///
+    /// It is a three-way merge: a difference is applied only if `self` agrees with
+    /// the old state, a key already in the new state is skipped,
+    /// others are conflicts which are returned without being applied.
+    pub fn apply_diff<I>(&mut self, diff: I) -> Vec<Diff<K, V>>
+    where
+        I: IntoIterator<Item = Diff<K, V>>,
+    {
+        let mut changes = HashMap::new();
+        let mut conflicts = vec![];
+
+        for d in diff {
+            // The state of the key after the earlier differences.
+            let cur = match changes.get(d.key()) {
+                Some(v) => Option::clone(v),
+                None => self.get(d.key()).map(|v| v.into_inner().into_owned()),
+            };
+            match (d, cur) {
+                (Diff::Added(k, v), None) => {
+                    changes.insert(k, Some(v));
+                }
+                (Diff::Removed(k, old), Some(cur)) if old == cur => {
+                    changes.insert(k, None);
+                }
+                (Diff::Changed { key, old, new }, Some(cur)) if old == cur => {
+                    changes.insert(key, Some(new));
+                }
+                (Diff::Added(_, v), Some(cur)) | (Diff::Changed { new: v, .. }, Some(cur))
+                    if v == cur => {}
+                (Diff::Removed(..), None) => {}
+                (d, _) => conflicts.push(d),
+            }
+        }
+
+        if !changes.is_empty() {
+            // The cached values are outdated.
+            for k in changes.keys() {
+                self.in_mem.remove(k);
+            }
+            self.in_disk.apply(changes);
+        }
+        conflicts
+    }
+}
+
+/// Iter over the differences between two [Mapx](super::Mapx)s.
+pub struct MapxDiff<'a, K, V>
+where
+    K: Clone + Eq + PartialEq + Hash + Serialize + DeserializeOwned + fmt::Debug,
+    V: Clone + Eq + PartialEq + Serialize + DeserializeOwned + fmt::Debug,
+{
+    left: MapxRawIter,
+    left_next: Option<(IVec, IVec)>,
+    right: MapxRawIter,
+    right_next: Option<(IVec, IVec)>,
+    _pd: PhantomData<&'a Mapx<K, V>>,
+}
+
+impl<'a, K, V> MapxDiff<'a, K, V>
+where
+    K: Clone + Eq + PartialEq + Hash + Serialize + DeserializeOwned + fmt::Debug,
+    V: Clone + Eq + PartialEq + Serialize + DeserializeOwned + fmt::Debug,
+{
+    fn new(mut left: MapxRawIter, mut right: MapxRawIter) -> Self {
+        MapxDiff {
+            left_next: left.next(),
+            left,
+            right_next: right.next(),
+            right,
+            _pd: PhantomData,
+        }
+    }
+
+    fn next_left(&mut self) -> Option<(IVec, IVec)> {
+        let next = self.left.next();
+        mem::replace(&mut self.left_next, next)
+    }
+
+    fn next_right(&mut self) -> Option<(IVec, IVec)> {
+        let next = self.right.next();
+        mem::replace(&mut self.right_next, next)
+    }
+}
+
+impl<'a, K, V> Iterator for MapxDiff<'a, K, V>
+where
+    K: Clone + Eq + PartialEq + Hash + Serialize + DeserializeOwned + fmt::Debug,
+    V: Clone + Eq + PartialEq + Serialize + DeserializeOwned + fmt::Debug,
+{
+    type Item = Diff<K, V>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let ord = match (&self.left_next, &self.right_next) {
+                (None, None) => return None,
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (Some((l, _)), Some((r, _))) => l.cmp(r),
+            };
+
+            match ord {
+                Ordering::Less => {
+                    let (k, v) = self.next_left()?;
+                    return Some(Diff::Removed(
+                        self.left.decode_key(&k),
+                        self.left.decode_value(&v),
+                    ));
+                }
+                Ordering::Greater => {
+                    let (k, v) = self.next_right()?;
+                    return Some(Diff::Added(
+                        self.right.decode_key(&k),
+                        self.right.decode_value(&v),
+                    ));
+                }
+                Ordering::Equal => {
+                    let (k, old) = self.next_left()?;
+                    let (_, new) = self.next_right()?;
+                    if old == new {
+                        continue;
+                    }
+                    // The encoded bytes may differ in schema versions only.
+                    let old: V = self.left.decode_value(&old);
+                    let new: V = self.right.decode_value(&new);
+                    if old != new {
+                        return Some(Diff::Changed {
+                            key: self.left.decode_key(&k),
+                            old,
+                            new,
+                        });
+                    }
+                }
+            }
+        }
+    }
+}
+
+/******************************************/
+// End of the implementation of Mapx diff //
+////////////////////////////////////////////
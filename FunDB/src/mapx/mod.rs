@@ -5,23 +5,41 @@
 //!
 
 mod backend;
+mod diff;
+mod overlay;
+mod query;
+mod sharded;
 #[cfg(test)]
 mod test;
 
+pub(crate) use backend::recount_nested;
+pub use diff::{Diff, MapxDiff};
+pub use overlay::Overlay;
+pub use query::{Cursor, Page, Query, QueryIter, QueryKeys};
+pub use sharded::{ShardStats, ShardedMapx};
+
 use crate::{
+    changelog::{Change, ChangeIter, Op, Replica},
+    durability::Durability,
     helper::*,
+    kv::Storage,
+    merge::MergeOperator,
+    schema::Schema,
     serde::{FunDBMeta, FunDBVisitor},
+    stats::Stats,
 };
 use ruc::*;
 use serde::{de::DeserializeOwned, Serialize};
+use sled::IVec;
 use std::{
     borrow::Cow,
     cmp::Ordering,
     collections::{hash_map, HashMap},
     fmt,
     hash::Hash,
-    iter::Iterator,
-    mem::ManuallyDrop,
+    iter::{DoubleEndedIterator, ExactSizeIterator, Iterator},
+    marker::PhantomData,
+    mem::{self, ManuallyDrop},
     ops::{Deref, DerefMut},
 };
 
@@ -36,7 +54,7 @@ pub const IN_MEM_CNT: usize = 1;
 
 /// To solve the problem of unlimited memory usage,
 /// use this to replace the original in-memory `HashMap<_, _>`.
-#[derive(Eq, PartialEq, Debug, Clone)]
+#[derive(Clone)]
 pub struct Mapx<K, V>
 where
     K: Clone + Eq + PartialEq + Hash + Serialize + DeserializeOwned + fmt::Debug,
@@ -59,10 +77,84 @@ where
     /// Create an instance.
     #[inline(always)]
     pub fn new(path: String, imc: Option<usize>, is_tmp: bool) -> Result<Self> {
-        let in_disk = backend::Mapx::load_or_create(path, is_tmp).c(d!())?;
+        Self::new_with_durability(path, imc, is_tmp, Durability::default()).c(d!())
+    }
+
+    /// Create an instance with the given durability mode.
+    pub fn new_with_durability(
+        path: String,
+        imc: Option<usize>,
+        is_tmp: bool,
+        durability: Durability,
+    ) -> Result<Self> {
+        Self::open(path, imc, is_tmp, durability, None, Storage::default()).c(d!())
+    }
+
+    /// Create an instance on the given storage backend.
+    pub fn new_with_storage(
+        path: String,
+        imc: Option<usize>,
+        is_tmp: bool,
+        storage: Storage,
+    ) -> Result<Self> {
+        Self::open(path, imc, is_tmp, Durability::default(), None, storage).c(d!())
+    }
+
+    /// Create an instance with the given schema,
+    /// the records of older versions will be upgraded when they are read.
+    ///
+    /// An instance created by other constructors, or restored by serde,
+    /// uses the schema version persisted in the database without any migrations.
+    pub fn new_with_schema(
+        path: String,
+        imc: Option<usize>,
+        is_tmp: bool,
+        durability: Durability,
+        schema: Schema,
+    ) -> Result<Self> {
+        Self::open(
+            path,
+            imc,
+            is_tmp,
+            durability,
+            Some(schema),
+            Storage::default(),
+        )
+        .c(d!())
+    }
+
+    fn open(
+        path: String,
+        imc: Option<usize>,
+        is_tmp: bool,
+        durability: Durability,
+        schema: Option<Schema>,
+        storage: Storage,
+    ) -> Result<Self> {
+        let in_disk =
+            backend::Mapx::load_or_create(path, is_tmp, durability, schema, storage).c(d!())?;
+        Ok(Mapx::with_cache(in_disk, imc.unwrap_or(IN_MEM_CNT)))
+    }
+
+    /// Open an existing map without the writer lock,
+    /// many readers and at most one writer may open the same path at the same time.
+    ///
+    /// The returned map has no mutating methods; the entries written by a writer
+    /// in another process after this call are not guaranteed to be visible.
+    pub fn open_read_only(path: String) -> Result<ReadOnly<Self>> {
+        backend::Mapx::load_read_only(path)
+            .c(d!())
+            .map(|in_disk| ReadOnly::new(Mapx::with_cache(in_disk, IN_MEM_CNT)))
+    }
 
-        let mut in_mem = HashMap::with_capacity(IN_MEM_CNT);
-        let mut cnter = IN_MEM_CNT;
+    // Preload the last `in_mem_cnt` entries into memory.
+    fn with_cache(in_disk: backend::Mapx<K, V>, in_mem_cnt: usize) -> Self {
+        let mut in_mem = HashMap::with_capacity(in_mem_cnt);
+        let mut cnter = if in_disk.holds_nested() {
+            0
+        } else {
+            in_mem_cnt
+        };
         let mut data = in_disk.iter().rev();
         while cnter > 0 {
             if let Some((k, v)) = data.next() {
@@ -73,11 +165,45 @@ where
             cnter -= 1;
         }
 
-        Ok(Mapx {
+        Mapx {
             in_mem,
-            in_mem_cnt: imc.unwrap_or(IN_MEM_CNT),
+            in_mem_cnt,
             in_disk,
-        })
+        }
+    }
+
+    /// Create an instance with the entries of `iter`,
+    /// which are added to the existing ones in `path` if any.
+    pub fn collect_into<I>(path: String, iter: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = (K, V)>,
+    {
+        let mut db = Self::new(path, None, false).c(d!())?;
+        db.extend(iter);
+        Ok(db)
+    }
+
+    /// Create an empty map which shares the database of `self`,
+    /// used as the values of a nested collection like `Mapx<K, Mapx<K2, V2>>`.
+    ///
+    /// Its entries are stored as a prefixed key range of the same sled instance,
+    /// and will be removed recursively when it is removed from its outer map.
+    /// Nested maps never cache entries in memory.
+    pub fn new_nested<K2, V2>(&self) -> Result<Mapx<K2, V2>>
+    where
+        K2: Clone + Eq + PartialEq + Hash + Serialize + DeserializeOwned + fmt::Debug,
+        V2: Clone + Eq + PartialEq + Serialize + DeserializeOwned + fmt::Debug,
+    {
+        self.in_disk.create_nested().c(d!()).map(Mapx::from_nested)
+    }
+
+    #[inline(always)]
+    fn from_nested(in_disk: backend::Mapx<K, V>) -> Self {
+        Mapx {
+            in_mem: HashMap::new(),
+            in_mem_cnt: 0,
+            in_disk,
+        }
     }
 
     /// Get the database storage path
@@ -85,28 +211,122 @@ where
         self.in_disk.get_data_path()
     }
 
+    /// Get the storage backend.
+    pub fn storage(&self) -> Storage {
+        self.in_disk.storage()
+    }
+
+    /// Close this map and remove its data directory,
+    /// it fails on nested maps or if any other clones of it are alive,
+    /// this handle is closed anyway.
+    ///
+    /// A temporary map is removed automatically when its last clone is dropped.
+    pub fn destroy(self) -> Result<()> {
+        self.in_disk.destroy().c(d!())
+    }
+
+    /// Get the schema version of the values.
+    pub fn schema_version(&self) -> u32 {
+        self.in_disk.schema_version()
+    }
+
+    /// Upgrade all values of older schema versions on disk in one atomic batch,
+    /// return the number of them.
+    pub fn migrate_all(&mut self) -> Result<usize> {
+        self.in_disk.migrate_all().c(d!())
+    }
+
+    /// Get the durability mode, `None` for nested maps,
+    /// which are flushed along with their outer map.
+    pub fn durability(&self) -> Option<Durability> {
+        self.in_disk.durability()
+    }
+
     /// Imitate the behavior of 'HashMap<_>.get(...)'
     ///
     /// Any faster/better choice other than JSON ?
     #[inline(always)]
     pub fn get(&self, key: &K) -> Option<Value<V>> {
-        self.in_mem
-            .get(key)
+        self.get_mem(key)
             .map(Cow::Borrowed)
             .or_else(|| self.in_disk.get(key).map(Cow::Owned))
             .map(Value::new)
     }
 
+    /// Get the values of many keys in one call,
+    /// the keys missing in the cache are read from disk in their sorted order,
+    /// and the entries read from disk will not be cached.
+    #[inline(always)]
+    pub fn get_many(&self, keys: &[K]) -> Vec<Option<Value<V>>> {
+        let mut values = keys
+            .iter()
+            .map(|k| self.get_mem(k).map(|v| Value::new(Cow::Borrowed(v))))
+            .collect::<Vec<_>>();
+        let missed = values
+            .iter()
+            .enumerate()
+            .filter(|(_, v)| v.is_none())
+            .map(|(i, _)| i)
+            .collect::<Vec<_>>();
+        let loaded = self.in_disk.get_many(missed.iter().map(|i| &keys[*i]));
+        for (i, v) in missed.into_iter().zip(loaded) {
+            values[i] = v.map(|v| Value::new(Cow::Owned(v)));
+        }
+        values
+    }
+
     /// Imitate the behavior of 'HashMap<_>.get_mut(...)'
     ///
     /// Any faster/better choice other than JSON ?
     #[inline(always)]
     pub fn get_mut(&mut self, key: &K) -> Option<ValueMut<K, V>> {
-        self.in_mem
-            .get(key)
-            .cloned()
-            .or_else(|| self.in_disk.get(key))
-            .map(move |v| ValueMut::new(self, key.clone(), v))
+        self.take_value(key)
+            .map(move |(v, cached)| ValueMut::loaded(self, key.clone(), v, cached))
+    }
+
+    /// Modify the value of `key` in place by `f`, return the result of `f`,
+    /// or `None` if `key` does not exist.
+    ///
+    /// The value is written back once after `f` returns, if it is changed.
+    pub fn update<F, R>(&mut self, key: &K, f: F) -> Option<R>
+    where
+        F: FnOnce(&mut V) -> R,
+    {
+        self.get_mut(key).map(|mut v| {
+            let mut value = (*v).clone();
+            let r = f(&mut value);
+            if value != *v {
+                *v = value;
+            }
+            r
+        })
+    }
+
+    /// Set the operator used by `merge`, the built-in ones are in [merge](crate::merge).
+    ///
+    /// It is not persisted, set it again every time the map is opened;
+    /// nested maps can not have merge operators.
+    pub fn set_merge_operator<M>(&mut self, op: M) -> Result<()>
+    where
+        M: MergeOperator<V>,
+        V: 'static,
+    {
+        self.in_disk.set_merge_operator(op).c(d!())
+    }
+
+    /// Combine the value of `key` with `operand` atomically by the merge operator,
+    /// return the new value.
+    ///
+    /// `operand` must be exactly of the operand type of the operator,
+    /// e.g. `&1u64` instead of `&1` for `Add` over `u64` values.
+    pub fn merge<O>(&mut self, key: &K, operand: &O) -> Result<Option<V>>
+    where
+        O: Serialize + 'static,
+    {
+        let new = self.in_disk.merge(key, operand).c(d!())?;
+        // The cached value is outdated.
+        self.in_mem.remove(key);
+        Ok(new)
     }
 
     /// Imitate the behavior of 'HashMap<_>.len()'.
@@ -124,46 +344,194 @@ where
     /// Imitate the behavior of 'HashMap<_>.insert(...)'.
     #[inline(always)]
     pub fn insert(&mut self, key: K, value: V) -> Option<V> {
-        self.mgmt_memory();
-        if let Some(v) = self.in_mem.insert(key.clone(), value.clone()) {
-            self.in_disk.set_value(key, value);
-            Some(v)
-        } else {
-            self.in_disk.insert(key, value)
+        if 0 == self.in_mem_cnt {
+            return self.in_disk.insert(key, value);
         }
+
+        self.mgmt_memory();
+        let old = match self.in_mem.remove(&key) {
+            Some(v) => {
+                self.in_disk.set_value(&key, &value);
+                Some(v)
+            }
+            None => self.in_disk.insert(key.clone(), value.clone()),
+        };
+        self.cache(key, value);
+        old
+    }
+
+    /// Imitate the behavior of 'HashMap<_>.extend(...)',
+    /// used to bulk-load lots of entries.
+    ///
+    /// All entries are written in one atomic batch,
+    /// and they will not be cached except for the existing ones.
+    pub fn extend<I>(&mut self, iter: I)
+    where
+        I: IntoIterator<Item = (K, V)>,
+    {
+        let in_mem = &mut self.in_mem;
+        self.in_disk.extend(iter.into_iter().inspect(|(k, v)| {
+            if let Some(cached) = in_mem.get_mut(k) {
+                *cached = v.clone();
+            }
+        }));
     }
 
     /// Similar with `insert`, but ignore if the old value is exist.
     #[inline(always)]
     pub fn set_value(&mut self, key: K, value: V) {
+        if 0 == self.in_mem_cnt {
+            self.in_disk.set_value(&key, &value);
+            return;
+        }
+
         self.mgmt_memory();
-        self.in_disk.set_value(key.clone(), value.clone());
-        self.in_mem.insert(key, value);
+        self.in_disk.set_value(&key, &value);
+        self.cache(key, value);
+    }
+
+    // Handles of nested collections are not cached,
+    // every `get` opens a fresh one sharing the state of the others.
+    #[inline(always)]
+    fn cache(&mut self, key: K, value: V) {
+        if !self.in_disk.holds_nested() {
+            self.in_mem.insert(key, value);
+        }
     }
 
     // Will get a random key since we use HashMap
     fn mgmt_memory(&mut self) {
-        if self.in_mem.len() > IN_MEM_CNT {
+        if self.in_mem.len() > self.in_mem_cnt {
             pnk!(self
                 .in_mem
                 .keys()
                 .next()
                 .cloned()
                 .and_then(|k| self.in_mem.remove(&k)));
+            self.in_disk.stats_counter().cache_eviction();
+            trace_event!(TRACE, path = %self.get_data_path(), "fundb.evict");
+        }
+    }
+
+    // Look up the memory cache only, and count the hit or miss.
+    #[inline(always)]
+    fn get_mem(&self, key: &K) -> Option<&V> {
+        let v = self.in_mem.get(key);
+        if v.is_some() {
+            self.in_disk.stats_counter().cache_hit();
+        } else {
+            self.in_disk.stats_counter().cache_miss();
         }
+        v
     }
 
-    /// Imitate the behavior of '.entry(...).or_insert(...)'
+    // Take the value out of the memory cache to modify it without cloning,
+    // or read it from disk; also return whether it was cached.
+    #[inline(always)]
+    fn take_value(&mut self, key: &K) -> Option<(V, bool)> {
+        match self.in_mem.remove(key) {
+            Some(v) => {
+                self.in_disk.stats_counter().cache_hit();
+                Some((v, true))
+            }
+            None => {
+                self.in_disk.stats_counter().cache_miss();
+                self.in_disk.get(key).map(|v| (v, false))
+            }
+        }
+    }
+
+    /// Imitate the behavior of '.entry(...)',
+    /// the key is looked up only once.
     #[inline(always)]
     pub fn entry(&mut self, key: K) -> Entry<'_, K, V> {
-        Entry { key, db: self }
+        match self.take_value(&key) {
+            Some((v, cached)) => Entry::Occupied(OccupiedEntry {
+                value: ValueMut::loaded(self, key, v, cached),
+            }),
+            None => Entry::Vacant(VacantEntry { key, db: self }),
+        }
     }
 
     /// Imitate the behavior of '.iter()'
     #[inline(always)]
-    pub fn iter(&self) -> Box<dyn Iterator<Item = (K, V)> + '_> {
+    pub fn iter(&self) -> MapxIter<'_, K, V> {
         // todo!()
-        Box::new(self.in_disk.iter().map(|(k, v)| (k, v)))
+        MapxIter {
+            iter: self.in_disk.raw_iter(),
+            in_mem: &self.in_mem,
+        }
+    }
+
+    // Get at most `limit` entries whose encoded keys are within the bounds,
+    // in the order of `iter`.
+    #[cfg(feature = "server")]
+    pub(crate) fn get_encoded_range(
+        &self,
+        lo: std::ops::Bound<&K>,
+        hi: std::ops::Bound<&K>,
+        limit: usize,
+    ) -> Vec<(K, V)> {
+        let mut iter = self.in_disk.raw_range(lo, hi);
+        let entries = iter.by_ref().take(limit).collect::<Vec<_>>();
+        entries
+            .into_iter()
+            .map(|(k, v)| {
+                let k = iter.decode_key(&k);
+                let v = self
+                    .in_mem
+                    .get(&k)
+                    .cloned()
+                    .unwrap_or_else(|| iter.decode_value(&v));
+                (k, v)
+            })
+            .collect()
+    }
+
+    /// Imitate the behavior of '.iter_mut()',
+    /// each value will be written back when it is dropped if it has been mutably borrowed.
+    #[inline(always)]
+    pub fn iter_mut(&mut self) -> MapxIterMut<'_, K, V> {
+        MapxIterMut {
+            iter: self.in_disk.raw_iter(),
+            in_mem: &mut self.in_mem,
+            in_disk: &self.in_disk,
+        }
+    }
+
+    /// Imitate the behavior of '.keys()'
+    #[inline(always)]
+    pub fn keys(&self) -> MapxKeys<K> {
+        MapxKeys {
+            iter: self.in_disk.raw_iter(),
+            _pd: PhantomData,
+        }
+    }
+
+    /// Imitate the behavior of '.values()'
+    #[inline(always)]
+    pub fn values(&self) -> MapxValues<V> {
+        MapxValues {
+            iter: self.in_disk.raw_iter(),
+            _pd: PhantomData,
+        }
+    }
+
+    /// Iterate over the encoded entries without any decoding.
+    #[inline(always)]
+    pub fn raw_iter(&self) -> MapxRawIter {
+        MapxRawIter {
+            iter: self.in_disk.raw_iter(),
+        }
+    }
+
+    /// Iterate over the entries cached in memory only,
+    /// they are in random order.
+    #[inline(always)]
+    pub fn iter_mem(&self) -> MapxIterMem<'_, K, V> {
+        MapxIterMem {
+            iter: self.in_mem.iter(),
+        }
     }
 
     /// Check if a key is exists.
@@ -200,6 +568,64 @@ where
     pub fn flush_data(&self) {
         self.in_disk.flush();
     }
+
+    /// Get the statistics of this map since it was opened,
+    /// a nested map reports the size on disk of its outer map.
+    pub fn stats(&self) -> Stats {
+        self.in_disk.stats()
+    }
+
+    /// Record every change of this map from now on, for all handles of it,
+    /// it stays enabled after reopening; see [changelog](crate::changelog).
+    ///
+    /// The changes of nested maps can not be recorded.
+    pub fn enable_change_log(&mut self) -> Result<()> {
+        self.in_disk.enable_change_log().c(d!())
+    }
+
+    /// The sequence number of the last recorded change,
+    /// `None` if the change log is not enabled.
+    pub fn change_log_seq(&self) -> Option<u64> {
+        self.in_disk.change_log_seq()
+    }
+
+    /// Iterate over the recorded changes whose sequence numbers are greater than `seq`,
+    /// `changes_since(0)` returns all of them.
+    ///
+    /// It fails if some of them have been removed by `compact_change_log`.
+    pub fn changes_since(&self, seq: u64) -> Result<ChangeIter<K, V>> {
+        self.in_disk.changes_since(seq).c(d!())
+    }
+
+    /// Remove the recorded changes before `seq`, return the number of them,
+    /// the followers should have applied them.
+    pub fn compact_change_log(&mut self, seq: u64) -> Result<usize> {
+        self.in_disk.compact_change_log(seq).c(d!())
+    }
+}
+
+impl<K, V> Replica for Mapx<K, V>
+where
+    K: Clone + Eq + PartialEq + Hash + Serialize + DeserializeOwned + fmt::Debug,
+    V: Clone + Eq + PartialEq + Serialize + DeserializeOwned + fmt::Debug,
+{
+    type Key = K;
+    type Value = V;
+
+    fn applied_seq(&self) -> Result<u64> {
+        self.in_disk.applied_seq().c(d!())
+    }
+
+    fn apply_change(&mut self, change: Change<K, V>) -> Result<()> {
+        if self.in_disk.nested_id().is_some() {
+            return Err(eg!("a nested map can not be a follower"));
+        }
+        match change.op {
+            Op::Insert(k, v) => self.set_value(k, v),
+            Op::Remove(k) => self.unset_value(&k),
+        }
+        self.in_disk.set_applied_seq(change.seq).c(d!())
+    }
 }
 
 /*******************************************/
@@ -210,7 +636,8 @@ where
 // Begin of the implementation of ValueMut(returned by `self.get_mut`) for Mapx //
 /********************************************************************************/
 
-/// Returned by `<Mapx>.get_mut(...)`
+/// Returned by `<Mapx>.get_mut(...)`,
+/// the value is written back when it is dropped if it has been mutably borrowed.
 #[derive(Eq, Debug)]
 pub struct ValueMut<'a, K, V>
 where
@@ -220,6 +647,9 @@ where
     mapx: &'a mut Mapx<K, V>,
     key: ManuallyDrop<K>,
     value: ManuallyDrop<V>,
+    dirty: bool,
+    // Taken out of the memory cache, to be put back when dropped.
+    cached: bool,
 }
 
 impl<'a, K, V> ValueMut<'a, K, V>
@@ -227,11 +657,25 @@ where
     K: Clone + Eq + PartialEq + Hash + Serialize + DeserializeOwned + fmt::Debug,
     V: Clone + Eq + PartialEq + Serialize + DeserializeOwned + fmt::Debug,
 {
+    // A new entry, which is always written.
     fn new(mapx: &'a mut Mapx<K, V>, key: K, value: V) -> Self {
         ValueMut {
             mapx,
             key: ManuallyDrop::new(key),
             value: ManuallyDrop::new(value),
+            dirty: true,
+            cached: false,
+        }
+    }
+
+    // An existing entry, which is written only if it is changed.
+    fn loaded(mapx: &'a mut Mapx<K, V>, key: K, value: V, cached: bool) -> Self {
+        ValueMut {
+            mapx,
+            key: ManuallyDrop::new(key),
+            value: ManuallyDrop::new(value),
+            dirty: false,
+            cached,
         }
     }
 
@@ -239,6 +683,21 @@ where
     pub fn clone_inner(self) -> V {
         ManuallyDrop::into_inner(self.value.clone())
     }
+
+    // Remove the entry from the map instead of writing it back.
+    fn into_removed(self) -> (K, V) {
+        let mut me = ManuallyDrop::new(self);
+        // The `drop()` of `me` will never be called,
+        // so the key and value can be taken only once.
+        let (k, v) = unsafe {
+            (
+                ManuallyDrop::take(&mut me.key),
+                ManuallyDrop::take(&mut me.value),
+            )
+        };
+        me.mapx.unset_value(&k);
+        (k, v)
+    }
 }
 
 ///
@@ -258,7 +717,11 @@ where
                 ManuallyDrop::take(&mut self.value),
             )
         };
-        self.mapx.set_value(k, v);
+        if self.dirty {
+            self.mapx.set_value(k, v);
+        } else if self.cached {
+            self.mapx.in_mem.insert(k, v);
+        }
     }
 }
 
@@ -280,6 +743,7 @@ where
     V: Clone + Eq + PartialEq + Serialize + DeserializeOwned + fmt::Debug,
 {
     fn deref_mut(&mut self) -> &mut Self::Target {
+        self.dirty = true;
         &mut self.value
     }
 }
@@ -323,13 +787,15 @@ where
 /*************************************************/
 
 /// Imitate the `btree_map/hash_map::Entry`.
-pub struct Entry<'a, K, V>
+pub enum Entry<'a, K, V>
 where
     K: fmt::Debug + Clone + 'a + Eq + PartialEq + Hash + Serialize + DeserializeOwned,
     V: fmt::Debug + Clone + 'a + Eq + PartialEq + Serialize + DeserializeOwned,
 {
-    key: K,
-    db: &'a mut Mapx<K, V>,
+    /// An occupied entry.
+    Occupied(OccupiedEntry<'a, K, V>),
+    /// A vacant entry.
+    Vacant(VacantEntry<'a, K, V>),
 }
 
 impl<'a, K, V> Entry<'a, K, V>
@@ -339,10 +805,10 @@ where
 {
     /// Imitate the `btree_map/hash_map::Entry.or_insert(...)`.
     pub fn or_insert(self, default: V) -> ValueMut<'a, K, V> {
-        if !self.db.contains_key(&self.key) {
-            self.db.set_value(self.key.clone(), default);
+        match self {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => e.insert(default),
         }
-        pnk!(self.db.get_mut(&self.key))
     }
 
     /// Imitate the `btree_map/hash_map::Entry.or_insert_with(...)`.
@@ -350,10 +816,134 @@ where
     where
         F: FnOnce() -> V,
     {
-        if !self.db.contains_key(&self.key) {
-            self.db.set_value(self.key.clone(), default());
+        match self {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => e.insert(default()),
         }
-        pnk!(self.db.get_mut(&self.key))
+    }
+
+    /// Imitate the `btree_map/hash_map::Entry.or_insert_with_key(...)`.
+    pub fn or_insert_with_key<F>(self, default: F) -> ValueMut<'a, K, V>
+    where
+        F: FnOnce(&K) -> V,
+    {
+        match self {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => {
+                let v = default(&e.key);
+                e.insert(v)
+            }
+        }
+    }
+
+    /// Imitate the `btree_map/hash_map::Entry.or_default()`.
+    pub fn or_default(self) -> ValueMut<'a, K, V>
+    where
+        V: Default,
+    {
+        self.or_insert_with(V::default)
+    }
+
+    /// Imitate the `btree_map/hash_map::Entry.and_modify(...)`.
+    pub fn and_modify<F>(mut self, f: F) -> Self
+    where
+        F: FnOnce(&mut V),
+    {
+        if let Entry::Occupied(ref mut e) = self {
+            f(e.get_mut());
+        }
+        self
+    }
+
+    /// Imitate the `btree_map/hash_map::Entry.key()`.
+    pub fn key(&self) -> &K {
+        match self {
+            Entry::Occupied(e) => e.key(),
+            Entry::Vacant(e) => e.key(),
+        }
+    }
+}
+
+/// Imitate the `btree_map/hash_map::OccupiedEntry`,
+/// the changes will be written back when it is dropped.
+pub struct OccupiedEntry<'a, K, V>
+where
+    K: fmt::Debug + Clone + 'a + Eq + PartialEq + Hash + Serialize + DeserializeOwned,
+    V: fmt::Debug + Clone + 'a + Eq + PartialEq + Serialize + DeserializeOwned,
+{
+    value: ValueMut<'a, K, V>,
+}
+
+impl<'a, K, V> OccupiedEntry<'a, K, V>
+where
+    K: fmt::Debug + Clone + 'a + Eq + PartialEq + Hash + Serialize + DeserializeOwned,
+    V: fmt::Debug + Clone + 'a + Eq + PartialEq + Serialize + DeserializeOwned,
+{
+    /// Imitate the `btree_map/hash_map::OccupiedEntry.key()`.
+    pub fn key(&self) -> &K {
+        &self.value.key
+    }
+
+    /// Imitate the `btree_map/hash_map::OccupiedEntry.get()`.
+    pub fn get(&self) -> &V {
+        &self.value
+    }
+
+    /// Imitate the `btree_map/hash_map::OccupiedEntry.get_mut()`.
+    pub fn get_mut(&mut self) -> &mut V {
+        &mut self.value
+    }
+
+    /// Imitate the `btree_map/hash_map::OccupiedEntry.into_mut()`.
+    pub fn into_mut(self) -> ValueMut<'a, K, V> {
+        self.value
+    }
+
+    /// Imitate the `btree_map/hash_map::OccupiedEntry.insert(...)`.
+    pub fn insert(&mut self, value: V) -> V {
+        mem::replace(&mut self.value, value)
+    }
+
+    /// Imitate the `btree_map/hash_map::OccupiedEntry.remove()`.
+    pub fn remove(self) -> V {
+        self.remove_entry().1
+    }
+
+    /// Imitate the `btree_map/hash_map::OccupiedEntry.remove_entry()`.
+    pub fn remove_entry(self) -> (K, V) {
+        self.value.into_removed()
+    }
+}
+
+/// Imitate the `btree_map/hash_map::VacantEntry`.
+pub struct VacantEntry<'a, K, V>
+where
+    K: fmt::Debug + Clone + 'a + Eq + PartialEq + Hash + Serialize + DeserializeOwned,
+    V: fmt::Debug + Clone + 'a + Eq + PartialEq + Serialize + DeserializeOwned,
+{
+    key: K,
+    db: &'a mut Mapx<K, V>,
+}
+
+impl<'a, K, V> VacantEntry<'a, K, V>
+where
+    K: fmt::Debug + Clone + 'a + Eq + PartialEq + Hash + Serialize + DeserializeOwned,
+    V: fmt::Debug + Clone + 'a + Eq + PartialEq + Serialize + DeserializeOwned,
+{
+    /// Imitate the `btree_map/hash_map::VacantEntry.key()`.
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    /// Imitate the `btree_map/hash_map::VacantEntry.into_key()`.
+    pub fn into_key(self) -> K {
+        self.key
+    }
+
+    /// Imitate the `btree_map/hash_map::VacantEntry.insert(...)`,
+    /// the value will be written when the returned `ValueMut` is dropped.
+    pub fn insert(self, value: V) -> ValueMut<'a, K, V> {
+        ValueMut::new(self.db, self.key, value)
     }
 }
 
@@ -365,27 +955,286 @@ where
 // Begin of the implementation of Iter for Mapx //
 /************************************************/
 
-/// Iter over [Mapx](self::Mapx).
-pub struct MapxIter<K, V>
+/// Iter over [Mapx](self::Mapx),
+/// the values cached in memory will not be decoded again.
+pub struct MapxIter<'a, K, V>
+where
+    K: 'a + Clone + Eq + PartialEq + Hash + Serialize + DeserializeOwned + fmt::Debug,
+    V: 'a + Clone + Eq + PartialEq + Serialize + DeserializeOwned + fmt::Debug,
+{
+    iter: backend::MapxRawIter,
+    in_mem: &'a HashMap<K, V>,
+}
+
+impl<'a, K, V> MapxIter<'a, K, V>
+where
+    K: 'a + Clone + Eq + PartialEq + Hash + Serialize + DeserializeOwned + fmt::Debug,
+    V: 'a + Clone + Eq + PartialEq + Serialize + DeserializeOwned + fmt::Debug,
+{
+    #[inline(always)]
+    fn decode(&self, (k, v): (IVec, IVec)) -> (K, V) {
+        let k = self.iter.decode_key(&k);
+        let v = self
+            .in_mem
+            .get(&k)
+            .cloned()
+            .unwrap_or_else(|| self.iter.decode_value(&v));
+        (k, v)
+    }
+}
+
+impl<'a, K, V> Iterator for MapxIter<'a, K, V>
+where
+    K: 'a + Clone + Eq + PartialEq + Hash + Serialize + DeserializeOwned + fmt::Debug,
+    V: 'a + Clone + Eq + PartialEq + Serialize + DeserializeOwned + fmt::Debug,
+{
+    type Item = (K, V);
+    fn next(&mut self) -> Option<Self::Item> {
+        self.iter.next().map(|kv| self.decode(kv))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.iter.size_hint()
+    }
+}
+
+impl<'a, K, V> DoubleEndedIterator for MapxIter<'a, K, V>
+where
+    K: 'a + Clone + Eq + PartialEq + Hash + Serialize + DeserializeOwned + fmt::Debug,
+    V: 'a + Clone + Eq + PartialEq + Serialize + DeserializeOwned + fmt::Debug,
+{
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.iter.next_back().map(|kv| self.decode(kv))
+    }
+}
+
+impl<'a, K, V> ExactSizeIterator for MapxIter<'a, K, V>
+where
+    K: 'a + Clone + Eq + PartialEq + Hash + Serialize + DeserializeOwned + fmt::Debug,
+    V: 'a + Clone + Eq + PartialEq + Serialize + DeserializeOwned + fmt::Debug,
+{
+}
+
+/// Iter over the keys of [Mapx](self::Mapx), the values are never decoded.
+pub struct MapxKeys<K>
 where
     K: Clone + Eq + PartialEq + Hash + Serialize + DeserializeOwned + fmt::Debug,
-    V: Clone + Eq + PartialEq + Serialize + DeserializeOwned + fmt::Debug,
 {
-    iter: backend::MapxIter<K, V>,
+    iter: backend::MapxRawIter,
+    _pd: PhantomData<K>,
 }
 
-impl<K, V> Iterator for MapxIter<K, V>
+impl<K> Iterator for MapxKeys<K>
 where
     K: Clone + Eq + PartialEq + Hash + Serialize + DeserializeOwned + fmt::Debug,
+{
+    type Item = K;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.iter.next().map(|(k, _)| self.iter.decode_key(&k))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.iter.size_hint()
+    }
+}
+
+impl<K> DoubleEndedIterator for MapxKeys<K>
+where
+    K: Clone + Eq + PartialEq + Hash + Serialize + DeserializeOwned + fmt::Debug,
+{
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.iter.next_back().map(|(k, _)| self.iter.decode_key(&k))
+    }
+}
+
+impl<K> ExactSizeIterator for MapxKeys<K> where
+    K: Clone + Eq + PartialEq + Hash + Serialize + DeserializeOwned + fmt::Debug
+{
+}
+
+/// Iter over the values of [Mapx](self::Mapx), the keys are never decoded.
+pub struct MapxValues<V>
+where
     V: Clone + Eq + PartialEq + Serialize + DeserializeOwned + fmt::Debug,
 {
-    type Item = (K, V);
+    iter: backend::MapxRawIter,
+    _pd: PhantomData<V>,
+}
+
+impl<V> Iterator for MapxValues<V>
+where
+    V: Clone + Eq + PartialEq + Serialize + DeserializeOwned + fmt::Debug,
+{
+    type Item = V;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.iter.next().map(|(_, v)| self.iter.decode_value(&v))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.iter.size_hint()
+    }
+}
+
+impl<V> DoubleEndedIterator for MapxValues<V>
+where
+    V: Clone + Eq + PartialEq + Serialize + DeserializeOwned + fmt::Debug,
+{
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.iter
+            .next_back()
+            .map(|(_, v)| self.iter.decode_value(&v))
+    }
+}
+
+impl<V> ExactSizeIterator for MapxValues<V> where
+    V: Clone + Eq + PartialEq + Serialize + DeserializeOwned + fmt::Debug
+{
+}
+
+/// Iter over the encoded entries of [Mapx](self::Mapx) without any decoding,
+/// the keys are encoded by `bincode` and the values are encoded by `serde_json`,
+/// prefixed with a zero byte and the big-endian `u32` schema version if it is not zero.
+pub struct MapxRawIter {
+    iter: backend::MapxRawIter,
+}
+
+impl Iterator for MapxRawIter {
+    type Item = (IVec, IVec);
     fn next(&mut self) -> Option<Self::Item> {
         self.iter.next()
     }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.iter.size_hint()
+    }
 }
 
-/// Iter over [Mapx](self::Mapx).
+impl DoubleEndedIterator for MapxRawIter {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.iter.next_back()
+    }
+}
+
+impl ExactSizeIterator for MapxRawIter {}
+
+/// Mutable iter over [Mapx](self::Mapx),
+/// the iterated entries are evicted from the memory cache.
+pub struct MapxIterMut<'a, K, V>
+where
+    K: 'a + Clone + Eq + PartialEq + Hash + Serialize + DeserializeOwned + fmt::Debug,
+    V: 'a + Clone + Eq + PartialEq + Serialize + DeserializeOwned + fmt::Debug,
+{
+    iter: backend::MapxRawIter,
+    in_mem: &'a mut HashMap<K, V>,
+    in_disk: &'a backend::Mapx<K, V>,
+}
+
+impl<'a, K, V> MapxIterMut<'a, K, V>
+where
+    K: 'a + Clone + Eq + PartialEq + Hash + Serialize + DeserializeOwned + fmt::Debug,
+    V: 'a + Clone + Eq + PartialEq + Serialize + DeserializeOwned + fmt::Debug,
+{
+    #[inline(always)]
+    fn decode(&mut self, (k, v): (IVec, IVec)) -> (K, ValueIterMut<'a, K, V>) {
+        let k = self.iter.decode_key(&k);
+        let v = self
+            .in_mem
+            .remove(&k)
+            .unwrap_or_else(|| self.iter.decode_value(&v));
+        (
+            k.clone(),
+            ValueIterMut {
+                in_disk: self.in_disk,
+                key: k,
+                value: v,
+                dirty: false,
+            },
+        )
+    }
+}
+
+impl<'a, K, V> Iterator for MapxIterMut<'a, K, V>
+where
+    K: 'a + Clone + Eq + PartialEq + Hash + Serialize + DeserializeOwned + fmt::Debug,
+    V: 'a + Clone + Eq + PartialEq + Serialize + DeserializeOwned + fmt::Debug,
+{
+    type Item = (K, ValueIterMut<'a, K, V>);
+    fn next(&mut self) -> Option<Self::Item> {
+        self.iter.next().map(|kv| self.decode(kv))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.iter.size_hint()
+    }
+}
+
+impl<'a, K, V> DoubleEndedIterator for MapxIterMut<'a, K, V>
+where
+    K: 'a + Clone + Eq + PartialEq + Hash + Serialize + DeserializeOwned + fmt::Debug,
+    V: 'a + Clone + Eq + PartialEq + Serialize + DeserializeOwned + fmt::Debug,
+{
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.iter.next_back().map(|kv| self.decode(kv))
+    }
+}
+
+impl<'a, K, V> ExactSizeIterator for MapxIterMut<'a, K, V>
+where
+    K: 'a + Clone + Eq + PartialEq + Hash + Serialize + DeserializeOwned + fmt::Debug,
+    V: 'a + Clone + Eq + PartialEq + Serialize + DeserializeOwned + fmt::Debug,
+{
+}
+
+/// Returned by `<MapxIterMut>.next()`,
+/// the value will be written back when it is dropped if it has been mutably borrowed.
+#[derive(Debug)]
+pub struct ValueIterMut<'a, K, V>
+where
+    K: Clone + Eq + PartialEq + Hash + Serialize + DeserializeOwned + fmt::Debug,
+    V: Clone + Eq + PartialEq + Serialize + DeserializeOwned + fmt::Debug,
+{
+    in_disk: &'a backend::Mapx<K, V>,
+    key: K,
+    value: V,
+    dirty: bool,
+}
+
+impl<'a, K, V> Drop for ValueIterMut<'a, K, V>
+where
+    K: Clone + Eq + PartialEq + Hash + Serialize + DeserializeOwned + fmt::Debug,
+    V: Clone + Eq + PartialEq + Serialize + DeserializeOwned + fmt::Debug,
+{
+    fn drop(&mut self) {
+        if self.dirty {
+            self.in_disk.write_back(&self.key, &self.value);
+        }
+    }
+}
+
+impl<'a, K, V> Deref for ValueIterMut<'a, K, V>
+where
+    K: Clone + Eq + PartialEq + Hash + Serialize + DeserializeOwned + fmt::Debug,
+    V: Clone + Eq + PartialEq + Serialize + DeserializeOwned + fmt::Debug,
+{
+    type Target = V;
+
+    fn deref(&self) -> &Self::Target {
+        &self.value
+    }
+}
+
+impl<'a, K, V> DerefMut for ValueIterMut<'a, K, V>
+where
+    K: Clone + Eq + PartialEq + Hash + Serialize + DeserializeOwned + fmt::Debug,
+    V: Clone + Eq + PartialEq + Serialize + DeserializeOwned + fmt::Debug,
+{
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.dirty = true;
+        &mut self.value
+    }
+}
+
+/// Iter over the entries of [Mapx](self::Mapx) cached in memory.
 pub struct MapxIterMem<'a, K, V>
 where
     K: 'a + Clone + Eq + PartialEq + Hash + Serialize + DeserializeOwned + fmt::Debug,
@@ -403,12 +1252,93 @@ where
     fn next(&mut self) -> Option<Self::Item> {
         self.iter.next().map(|(k, v)| (k.clone(), v.clone()))
     }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.iter.size_hint()
+    }
+}
+
+impl<'a, K, V> ExactSizeIterator for MapxIterMem<'a, K, V>
+where
+    K: 'a + Clone + Eq + PartialEq + Hash + Serialize + DeserializeOwned + fmt::Debug,
+    V: 'a + Clone + Eq + PartialEq + Serialize + DeserializeOwned + fmt::Debug,
+{
 }
 
 /**********************************************/
 // End of the implementation of Iter for Mapx //
 ////////////////////////////////////////////////
 
+/////////////////////////////////////////////////////////
+// Begin of the implementation of std traits for Mapx //
+/*******************************************************/
+
+impl<K, V> Extend<(K, V)> for Mapx<K, V>
+where
+    K: Clone + Eq + PartialEq + Hash + Serialize + DeserializeOwned + fmt::Debug,
+    V: Clone + Eq + PartialEq + Serialize + DeserializeOwned + fmt::Debug,
+{
+    fn extend<I>(&mut self, iter: I)
+    where
+        I: IntoIterator<Item = (K, V)>,
+    {
+        Mapx::extend(self, iter)
+    }
+}
+
+impl<'a, K, V> IntoIterator for &'a Mapx<K, V>
+where
+    K: Clone + Eq + PartialEq + Hash + Serialize + DeserializeOwned + fmt::Debug,
+    V: Clone + Eq + PartialEq + Serialize + DeserializeOwned + fmt::Debug,
+{
+    type Item = (K, V);
+    type IntoIter = MapxIter<'a, K, V>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+/// Compare the entries on disk, the memory caches are ignored.
+impl<K, V> PartialEq for Mapx<K, V>
+where
+    K: Clone + Eq + PartialEq + Hash + Serialize + DeserializeOwned + fmt::Debug,
+    V: Clone + Eq + PartialEq + Serialize + DeserializeOwned + fmt::Debug,
+{
+    fn eq(&self, other: &Mapx<K, V>) -> bool {
+        self.in_disk == other.in_disk
+    }
+}
+
+impl<K, V> Eq for Mapx<K, V>
+where
+    K: Clone + Eq + PartialEq + Hash + Serialize + DeserializeOwned + fmt::Debug,
+    V: Clone + Eq + PartialEq + Serialize + DeserializeOwned + fmt::Debug,
+{
+}
+
+/// Show the first `DEBUG_PREVIEW_CNT` entries only.
+impl<K, V> fmt::Debug for Mapx<K, V>
+where
+    K: Clone + Eq + PartialEq + Hash + Serialize + DeserializeOwned + fmt::Debug,
+    V: Clone + Eq + PartialEq + Serialize + DeserializeOwned + fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Mapx")
+            .field("data_path", &self.get_data_path())
+            .field("len", &self.len())
+            .field(
+                "head",
+                &self.iter().take(DEBUG_PREVIEW_CNT).collect::<Vec<_>>(),
+            )
+            .finish()
+    }
+}
+
+/*****************************************************/
+// End of the implementation of std traits for Mapx //
+///////////////////////////////////////////////////////
+
 ///////////////////////////////////////////////////////////////////
 // Begin of the implementation of Serialize/Deserialize for Mapx //
 /*****************************************************************/
@@ -422,12 +1352,19 @@ where
     where
         S: serde::Serializer,
     {
+        let nested_id = self.in_disk.nested_id();
         let v = pnk!(serde_json::to_string(&FunDBMeta {
             in_mem_cnt: self.in_mem_cnt,
             data_path: self.get_data_path(),
+            nested_id,
+            durability: self.durability(),
+            storage: Some(self.storage()),
         }));
 
-        self.flush_data();
+        // A nested map is flushed along with its outer map.
+        if nested_id.is_none() {
+            self.flush_data();
+        }
         serializer.serialize_str(&v)
     }
 }
@@ -443,10 +1380,24 @@ where
     {
         deserializer.deserialize_str(FunDBVisitor).map(|meta| {
             let meta = pnk!(serde_json::from_str::<FunDBMeta>(&meta));
-            pnk!(Mapx::new(
+            let storage = meta.storage.unwrap_or_default();
+            if let Some(id) = meta.nested_id {
+                let (db, read_only) = pnk!(kv_reopen(meta.data_path, storage));
+                return Mapx::from_nested(pnk!(backend::Mapx::load_nested(
+                    &db,
+                    meta.data_path.to_owned(),
+                    storage,
+                    id,
+                    read_only,
+                )));
+            }
+            pnk!(Mapx::open(
                 meta.data_path.to_owned(),
                 Some(meta.in_mem_cnt),
-                false
+                false,
+                meta.durability.unwrap_or_default(),
+                None,
+                storage
             ))
         })
     }
@@ -0,0 +1,222 @@
+//!
+//! # A Copy-on-Write Staging Overlay for Mapx
+//!
+
+use super::Mapx;
+use crate::helper::*;
+use serde::{de::DeserializeOwned, Serialize};
+use std::{borrow::Cow, collections::HashMap, fmt, hash::Hash};
+
+// A map which an overlay can be stacked on.
+trait Layer<K, V>
+where
+    K: Clone + Eq + PartialEq + Hash + Serialize + DeserializeOwned + fmt::Debug,
+    V: Clone + Eq + PartialEq + Serialize + DeserializeOwned + fmt::Debug,
+{
+    fn get(&self, key: &K) -> Option<Value<V>>;
+
+    fn contains_key(&self, key: &K) -> bool;
+
+    fn len(&self) -> usize;
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (K, V)> + '_>;
+
+    // `None` means removing the key.
+    fn apply(&mut self, changes: HashMap<K, Option<V>>);
+}
+
+/////////////////////////////////////////////////////
+// Begin of the implementation of Overlay for Mapx //
+/***************************************************/
+
+/// Buffer the changes of a [Mapx](super::Mapx) in memory,
+/// which are written atomically by `commit`, or discarded if it is dropped.
+///
+/// Reads see the buffered changes first, then the underlying map;
+/// overlays can be stacked by `overlay()` for sub-transactions.
+pub struct Overlay<'a, K, V>
+where
+    K: Clone + Eq + PartialEq + Hash + Serialize + DeserializeOwned + fmt::Debug,
+    V: Clone + Eq + PartialEq + Serialize + DeserializeOwned + fmt::Debug,
+{
+    base: &'a mut dyn Layer<K, V>,
+    changes: HashMap<K, Option<V>>,
+}
+
+impl<K, V> Mapx<K, V>
+where
+    K: Clone + Eq + PartialEq + Hash + Serialize + DeserializeOwned + fmt::Debug,
+    V: Clone + Eq + PartialEq + Serialize + DeserializeOwned + fmt::Debug,
+{
+    /// Stage changes over this map without writing them.
+    pub fn overlay(&mut self) -> Overlay<'_, K, V> {
+        Overlay::new(self)
+    }
+}
+
+impl<'a, K, V> Overlay<'a, K, V>
+where
+    K: Clone + Eq + PartialEq + Hash + Serialize + DeserializeOwned + fmt::Debug,
+    V: Clone + Eq + PartialEq + Serialize + DeserializeOwned + fmt::Debug,
+{
+    fn new(base: &'a mut dyn Layer<K, V>) -> Self {
+        Overlay {
+            base,
+            changes: HashMap::new(),
+        }
+    }
+
+    /// Stage changes over this overlay,
+    /// they are merged into this one when committed.
+    pub fn overlay(&mut self) -> Overlay<'_, K, V> {
+        Overlay::new(self)
+    }
+
+    /// Imitate the behavior of 'HashMap<_>.get(...)'
+    pub fn get(&self, key: &K) -> Option<Value<V>> {
+        match self.changes.get(key) {
+            Some(v) => v.as_ref().map(|v| Value::new(Cow::Borrowed(v))),
+            None => self.base.get(key),
+        }
+    }
+
+    /// Imitate the behavior of 'HashMap<_>.contains_key(...)'
+    pub fn contains_key(&self, key: &K) -> bool {
+        match self.changes.get(key) {
+            Some(v) => v.is_some(),
+            None => self.base.contains_key(key),
+        }
+    }
+
+    /// Imitate the behavior of 'HashMap<_>.len()',
+    /// every staged key is looked up in the underlying map.
+    pub fn len(&self) -> usize {
+        let (added, removed) = self
+            .changes
+            .iter()
+            .fold((0, 0), |(added, removed), (k, v)| {
+                match (self.base.contains_key(k), v.is_some()) {
+                    (false, true) => (added + 1, removed),
+                    (true, false) => (added, removed + 1),
+                    _ => (added, removed),
+                }
+            });
+        self.base.len() + added - removed
+    }
+
+    /// A helper func
+    pub fn is_empty(&self) -> bool {
+        0 == self.len()
+    }
+
+    /// Imitate the behavior of 'HashMap<_>.insert(...)'.
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        let old = self.get(&key).map(|v| v.into_inner().into_owned());
+        self.changes.insert(key, Some(value));
+        old
+    }
+
+    /// Similar with `insert`, but ignore if the old value is exist.
+    pub fn set_value(&mut self, key: K, value: V) {
+        self.changes.insert(key, Some(value));
+    }
+
+    /// Imitate the behavior of 'HashMap<_>.remove(...)'.
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let old = self.get(key).map(|v| v.into_inner().into_owned());
+        self.unset_value(key);
+        old
+    }
+
+    /// Similar with `remove`, but ignore if the old value is exist.
+    pub fn unset_value(&mut self, key: &K) {
+        if self.base.contains_key(key) {
+            self.changes.insert(key.clone(), None);
+        } else {
+            self.changes.remove(key);
+        }
+    }
+
+    /// Iterate over the underlying entries which are not changed,
+    /// then the staged ones, all are in random order.
+    pub fn iter(&self) -> impl Iterator<Item = (K, V)> + '_ {
+        let changes = &self.changes;
+        self.base
+            .iter()
+            .filter(move |(k, _)| !changes.contains_key(k))
+            .chain(
+                changes
+                    .iter()
+                    .filter_map(|(k, v)| v.as_ref().map(|v| (k.clone(), v.clone()))),
+            )
+    }
+
+    /// Write all staged changes to the underlying map,
+    /// a `Mapx` receives them in one atomic batch.
+    ///
+    /// The collections nested in the replaced or removed values are not in the batch,
+    /// they are removed after it, and left behind if this process is killed in between.
+    pub fn commit(self) {
+        self.base.apply(self.changes);
+    }
+}
+
+/*************************************************/
+// End of the implementation of Overlay for Mapx //
+///////////////////////////////////////////////////
+
+impl<K, V> Layer<K, V> for Mapx<K, V>
+where
+    K: Clone + Eq + PartialEq + Hash + Serialize + DeserializeOwned + fmt::Debug,
+    V: Clone + Eq + PartialEq + Serialize + DeserializeOwned + fmt::Debug,
+{
+    fn get(&self, key: &K) -> Option<Value<V>> {
+        Mapx::get(self, key)
+    }
+
+    fn contains_key(&self, key: &K) -> bool {
+        Mapx::contains_key(self, key)
+    }
+
+    fn len(&self) -> usize {
+        Mapx::len(self)
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (K, V)> + '_> {
+        Box::new(Mapx::iter(self))
+    }
+
+    fn apply(&mut self, changes: HashMap<K, Option<V>>) {
+        // The cached values are outdated.
+        for k in changes.keys() {
+            self.in_mem.remove(k);
+        }
+        self.in_disk.apply(changes);
+    }
+}
+
+impl<'a, K, V> Layer<K, V> for Overlay<'a, K, V>
+where
+    K: Clone + Eq + PartialEq + Hash + Serialize + DeserializeOwned + fmt::Debug,
+    V: Clone + Eq + PartialEq + Serialize + DeserializeOwned + fmt::Debug,
+{
+    fn get(&self, key: &K) -> Option<Value<V>> {
+        Overlay::get(self, key)
+    }
+
+    fn contains_key(&self, key: &K) -> bool {
+        Overlay::contains_key(self, key)
+    }
+
+    fn len(&self) -> usize {
+        Overlay::len(self)
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (K, V)> + '_> {
+        Box::new(Overlay::iter(self))
+    }
+
+    fn apply(&mut self, changes: HashMap<K, Option<V>>) {
+        self.changes.extend(changes);
+    }
+}
@@ -0,0 +1,292 @@
+//!
+//! # Filtered Queries over Mapx
+//!
+
+use super::{backend::MapxRawRange, Mapx};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sled::IVec;
+use std::{fmt, hash::Hash, ops::RangeBounds};
+
+type KeyFilter<'a, K> = Box<dyn Fn(&K) -> bool + 'a>;
+type Filter<'a, K, V> = Box<dyn Fn(&K, &V) -> bool + 'a>;
+
+/// The position after the last entry returned by a query,
+/// which can be saved to get the next page later.
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub struct Cursor(Vec<u8>);
+
+/// A page of the results of a query.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Page<K, V> {
+    /// The entries in this page.
+    pub items: Vec<(K, V)>,
+    /// Where the next page starts, `None` if there are no more entries.
+    pub next: Option<Cursor>,
+}
+
+///////////////////////////////////////////////////
+// Begin of the implementation of Query for Mapx //
+/*************************************************/
+
+/// A query over a [Mapx](super::Mapx), built by `mapx.query()`.
+///
+/// The entries are visited in the order of their encoded keys like `iter()`,
+/// the key filters are checked before the values are decoded,
+/// so a query filtering on keys only never decodes the skipped values.
+pub struct Query<'a, K, V>
+where
+    K: Clone + Eq + PartialEq + Hash + Serialize + DeserializeOwned + fmt::Debug,
+    V: Clone + Eq + PartialEq + Serialize + DeserializeOwned + fmt::Debug,
+{
+    map: &'a Mapx<K, V>,
+    key_filters: Vec<KeyFilter<'a, K>>,
+    filters: Vec<Filter<'a, K, V>>,
+    offset: usize,
+    limit: Option<usize>,
+    reverse: bool,
+    cursor: Option<Cursor>,
+}
+
+impl<K, V> Mapx<K, V>
+where
+    K: Clone + Eq + PartialEq + Hash + Serialize + DeserializeOwned + fmt::Debug,
+    V: Clone + Eq + PartialEq + Serialize + DeserializeOwned + fmt::Debug,
+{
+    /// Build a query over all entries.
+    pub fn query(&self) -> Query<'_, K, V> {
+        Query {
+            map: self,
+            key_filters: vec![],
+            filters: vec![],
+            offset: 0,
+            limit: None,
+            reverse: false,
+            cursor: None,
+        }
+    }
+}
+
+impl<'a, K, V> Query<'a, K, V>
+where
+    K: Clone + Eq + PartialEq + Hash + Serialize + DeserializeOwned + fmt::Debug,
+    V: Clone + Eq + PartialEq + Serialize + DeserializeOwned + fmt::Debug,
+{
+    /// Keep the entries whose keys are within `range`.
+    ///
+    /// The range is not pushed down to the storage:
+    /// the keys are not stored in the order of `Ord`,
+    /// so all entries are still visited and the range is checked on every key like `filter_key`.
+    pub fn range<R>(self, range: R) -> Self
+    where
+        K: Ord,
+        R: RangeBounds<K> + 'a,
+    {
+        self.filter_key(move |k| range.contains(k))
+    }
+
+    /// Keep the entries whose keys satisfy `f`, the values are not decoded.
+    pub fn filter_key(mut self, f: impl Fn(&K) -> bool + 'a) -> Self {
+        self.key_filters.push(Box::new(f));
+        self
+    }
+
+    /// Keep the entries satisfying `f`.
+    pub fn filter(mut self, f: impl Fn(&K, &V) -> bool + 'a) -> Self {
+        self.filters.push(Box::new(f));
+        self
+    }
+
+    /// Return at most `n` entries.
+    pub fn limit(mut self, n: usize) -> Self {
+        self.limit = Some(n);
+        self
+    }
+
+    /// Skip the first `n` matched entries.
+    pub fn offset(mut self, n: usize) -> Self {
+        self.offset = n;
+        self
+    }
+
+    /// Visit the entries in the reversed order.
+    pub fn reverse(mut self) -> Self {
+        self.reverse = !self.reverse;
+        self
+    }
+
+    /// Start after the position of a previous query,
+    /// in the direction of this query.
+    pub fn after(mut self, cursor: Cursor) -> Self {
+        self.cursor = Some(cursor);
+        self
+    }
+
+    /// Iterate over the matched entries.
+    pub fn iter(self) -> QueryIter<'a, K, V> {
+        let iter = self
+            .map
+            .in_disk
+            .raw_iter_from(self.cursor.as_ref().map(|c| &c.0[..]), self.reverse);
+        QueryIter {
+            skip: self.offset,
+            left: self.limit,
+            query: self,
+            iter,
+            last: None,
+        }
+    }
+
+    /// Iterate over the keys of the matched entries,
+    /// the values are decoded only if there are filters on them.
+    pub fn keys(self) -> QueryKeys<'a, K, V> {
+        QueryKeys { iter: self.iter() }
+    }
+
+    /// The number of the matched entries.
+    pub fn count(self) -> usize {
+        self.keys().count()
+    }
+
+    /// Get a page of at most `limit` entries,
+    /// the next page can be got by the same query `after(page.next)`.
+    pub fn page(self) -> Page<K, V> {
+        let paged = self.limit.is_some();
+        let mut iter = self.iter();
+        let items = iter.by_ref().collect::<Vec<_>>();
+
+        // Look ahead for one more entry.
+        iter.left = None;
+        let next = if paged && iter.next_match(false).is_some() {
+            items.last().and(iter.cursor())
+        } else {
+            None
+        };
+        Page { items, next }
+    }
+}
+
+/// Iter over the entries matched by a [Query](self::Query).
+pub struct QueryIter<'a, K, V>
+where
+    K: Clone + Eq + PartialEq + Hash + Serialize + DeserializeOwned + fmt::Debug,
+    V: Clone + Eq + PartialEq + Serialize + DeserializeOwned + fmt::Debug,
+{
+    query: Query<'a, K, V>,
+    iter: MapxRawRange,
+    skip: usize,
+    left: Option<usize>,
+    // The encoded key of the last returned entry.
+    last: Option<IVec>,
+}
+
+impl<'a, K, V> QueryIter<'a, K, V>
+where
+    K: Clone + Eq + PartialEq + Hash + Serialize + DeserializeOwned + fmt::Debug,
+    V: Clone + Eq + PartialEq + Serialize + DeserializeOwned + fmt::Debug,
+{
+    /// The position after the last returned entry,
+    /// `None` if nothing has been returned.
+    pub fn cursor(&self) -> Option<Cursor> {
+        self.last.as_ref().map(|k| Cursor(k.to_vec()))
+    }
+
+    // Find the next matched entry, decode its value if `with_value` is set.
+    fn next_match(&mut self, with_value: bool) -> Option<(IVec, K, Option<V>)> {
+        if Some(0) == self.left {
+            return None;
+        }
+        loop {
+            let (k, v) = if self.query.reverse {
+                self.iter.next_back()?
+            } else {
+                self.iter.next()?
+            };
+            let key = self.iter.decode_key::<K>(&k);
+            if !self.query.key_filters.iter().all(|f| f(&key)) {
+                continue;
+            }
+
+            let mut value = None;
+            if !self.query.filters.is_empty() {
+                let v = self.decode_value(&key, &v);
+                if !self.query.filters.iter().all(|f| f(&key, &v)) {
+                    continue;
+                }
+                value = Some(v);
+            }
+            if 0 < self.skip {
+                self.skip -= 1;
+                continue;
+            }
+
+            if with_value && value.is_none() {
+                value = Some(self.decode_value(&key, &v));
+            }
+            if let Some(left) = self.left.as_mut() {
+                *left -= 1;
+            }
+            return Some((k, key, value));
+        }
+    }
+
+    // The values cached in memory are not decoded again.
+    fn decode_value(&self, key: &K, v: &[u8]) -> V {
+        self.query
+            .map
+            .in_mem
+            .get(key)
+            .cloned()
+            .unwrap_or_else(|| self.iter.decode_value(v))
+    }
+}
+
+impl<'a, K, V> Iterator for QueryIter<'a, K, V>
+where
+    K: Clone + Eq + PartialEq + Hash + Serialize + DeserializeOwned + fmt::Debug,
+    V: Clone + Eq + PartialEq + Serialize + DeserializeOwned + fmt::Debug,
+{
+    type Item = (K, V);
+    fn next(&mut self) -> Option<Self::Item> {
+        let (k, key, value) = self.next_match(true)?;
+        self.last = Some(k);
+        value.map(|v| (key, v))
+    }
+}
+
+/// Iter over the keys of the entries matched by a [Query](self::Query).
+pub struct QueryKeys<'a, K, V>
+where
+    K: Clone + Eq + PartialEq + Hash + Serialize + DeserializeOwned + fmt::Debug,
+    V: Clone + Eq + PartialEq + Serialize + DeserializeOwned + fmt::Debug,
+{
+    iter: QueryIter<'a, K, V>,
+}
+
+impl<'a, K, V> QueryKeys<'a, K, V>
+where
+    K: Clone + Eq + PartialEq + Hash + Serialize + DeserializeOwned + fmt::Debug,
+    V: Clone + Eq + PartialEq + Serialize + DeserializeOwned + fmt::Debug,
+{
+    /// The position after the last returned key,
+    /// `None` if nothing has been returned.
+    pub fn cursor(&self) -> Option<Cursor> {
+        self.iter.cursor()
+    }
+}
+
+impl<'a, K, V> Iterator for QueryKeys<'a, K, V>
+where
+    K: Clone + Eq + PartialEq + Hash + Serialize + DeserializeOwned + fmt::Debug,
+    V: Clone + Eq + PartialEq + Serialize + DeserializeOwned + fmt::Debug,
+{
+    type Item = K;
+    fn next(&mut self) -> Option<Self::Item> {
+        let (k, key, _) = self.iter.next_match(false)?;
+        self.iter.last = Some(k);
+        Some(key)
+    }
+}
+
+/***********************************************/
+// End of the implementation of Query for Mapx //
+/////////////////////////////////////////////////
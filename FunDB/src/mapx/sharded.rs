@@ -0,0 +1,423 @@
+//!
+//! # A Mapx Spread over Many Directories
+//!
+
+use super::Mapx;
+use crate::{
+    helper::{fnv1a, Value, FNV_OFFSET},
+    kv::Storage,
+    stats::Stats,
+};
+use ruc::*;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{collections::HashMap, fmt, fs, hash::Hash, io, panic, thread};
+
+// Stored in the directory of every shard.
+const MANIFEST_FILE: &str = "____shard____";
+
+// The position of a shard, written when it is created or resharded.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+struct ShardMeta {
+    index: usize,
+    count: usize,
+    // Set on the shards of both layouts until a reshard finishes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    resharding: Option<Resharding>,
+}
+
+// The shard paths before and after a reshard.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+struct Resharding {
+    from: Vec<String>,
+    to: Vec<String>,
+}
+
+impl ShardMeta {
+    fn new(index: usize, count: usize) -> Self {
+        ShardMeta {
+            index,
+            count,
+            resharding: None,
+        }
+    }
+
+    // Whether the shard can be opened as the `index`th one of `paths`.
+    fn accepts(&self, paths: &[String], index: usize) -> bool {
+        (self.index, self.count) == (index, paths.len())
+            || matches!(&self.resharding, Some(r) if r.from == paths || r.to == paths)
+    }
+
+    fn load(path: &str) -> Result<Option<Self>> {
+        match fs::read(format!("{}/{}", path, MANIFEST_FILE)) {
+            Ok(meta) => serde_json::from_slice(&meta).c(d!()).map(Some),
+            Err(e) if io::ErrorKind::NotFound == e.kind() => Ok(None),
+            Err(e) => Err(e).c(d!()),
+        }
+    }
+
+    fn store(&self, path: &str) -> Result<()> {
+        fs::create_dir_all(path).c(d!())?;
+        let path = format!("{}/{}", path, MANIFEST_FILE);
+        let tmp_path = format!("{}.tmp", path);
+        fs::write(&tmp_path, serde_json::to_vec(self).c(d!())?).c(d!())?;
+        fs::rename(&tmp_path, &path).c(d!())
+    }
+}
+
+/// The statistics of a shard.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct ShardStats {
+    /// The data path of the shard.
+    pub path: String,
+    /// Number of entries.
+    pub len: usize,
+    /// The statistics of the underlying map.
+    pub stats: Stats,
+}
+
+////////////////////////////////////////////////
+// Begin of the implementation of ShardedMapx //
+/**********************************************/
+
+/// A map whose keys are spread over N [Mapx](super::Mapx)
+/// in separate directories, which may be on different disks.
+///
+/// A key goes to the shard picked by the FNV-1a hash of its encoded bytes,
+/// so the same key always goes to the same shard across processes.
+/// The entries are not in any global order.
+#[derive(Debug, Clone)]
+pub struct ShardedMapx<K, V>
+where
+    K: Clone + Eq + PartialEq + Hash + Serialize + DeserializeOwned + fmt::Debug,
+    V: Clone + Eq + PartialEq + Serialize + DeserializeOwned + fmt::Debug,
+{
+    shards: Vec<Mapx<K, V>>,
+    imc: Option<usize>,
+    is_tmp: bool,
+    storage: Storage,
+}
+
+impl<K, V> ShardedMapx<K, V>
+where
+    K: Clone + Eq + PartialEq + Hash + Serialize + DeserializeOwned + fmt::Debug,
+    V: Clone + Eq + PartialEq + Serialize + DeserializeOwned + fmt::Debug,
+{
+    /// Create an instance with one shard in every path of `paths`,
+    /// `imc` is the in-memory count of every shard.
+    ///
+    /// The shards must be opened in the same order with the same number,
+    /// use `reshard` to change them.
+    #[inline(always)]
+    pub fn new(paths: Vec<String>, imc: Option<usize>, is_tmp: bool) -> Result<Self> {
+        Self::new_with_storage(paths, imc, is_tmp, Storage::default()).c(d!())
+    }
+
+    /// Create an instance on the given storage backend.
+    pub fn new_with_storage(
+        paths: Vec<String>,
+        imc: Option<usize>,
+        is_tmp: bool,
+        storage: Storage,
+    ) -> Result<Self> {
+        check_paths(&paths).c(d!())?;
+        let shards = (0..paths.len())
+            .map(|index| open_shard(&paths, index, imc, is_tmp, storage))
+            .collect::<Result<Vec<_>>>()
+            .c(d!())?;
+        Ok(ShardedMapx {
+            shards,
+            imc,
+            is_tmp,
+            storage,
+        })
+    }
+
+    /// Move the entries into the shards in `paths`, return the resharded map.
+    ///
+    /// The paths shared with the current shards are kept, only the entries
+    /// whose shards are changed are moved; the directories of the shards
+    /// not in `paths` are removed at last.
+    ///
+    /// Every entry is written to its new shard before it is removed from the old one.
+    /// If the process is interrupted, both the old and the new shards can be opened
+    /// until this finishes, but the new ones miss the entries not moved yet:
+    /// open the old shards and call this again to finish it. Once all entries are moved,
+    /// the manifests are rewritten one by one and only the new shards can be opened.
+    pub fn reshard(self, paths: Vec<String>) -> Result<Self> {
+        check_paths(&paths).c(d!())?;
+        let count = paths.len();
+
+        let ShardedMapx {
+            shards,
+            imc,
+            is_tmp,
+            storage,
+        } = self;
+
+        // Mark the shards of both layouts before anything is moved.
+        let from = shards
+            .iter()
+            .map(|s| s.get_data_path().to_owned())
+            .collect::<Vec<_>>();
+        let resharding = Resharding {
+            from: from.clone(),
+            to: paths.clone(),
+        };
+        let marked = from
+            .iter()
+            .enumerate()
+            .map(|(index, path)| (index, from.len(), path))
+            .chain(
+                paths
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, path)| !from.contains(path))
+                    .map(|(index, path)| (index, count, path)),
+            );
+        for (index, count, path) in marked {
+            ShardMeta {
+                index,
+                count,
+                resharding: Some(resharding.clone()),
+            }
+            .store(path)
+            .c(d!())?;
+        }
+
+        let mut old = shards
+            .into_iter()
+            .map(|s| (s.get_data_path().to_owned(), s))
+            .collect::<HashMap<_, _>>();
+        let mut shards = paths
+            .iter()
+            .map(|path| match old.remove(path) {
+                Some(s) => Ok(s),
+                None => Mapx::new_with_storage(path.clone(), imc, is_tmp, storage).c(d!()),
+            })
+            .collect::<Result<Vec<_>>>()
+            .c(d!())?;
+
+        // Entries in the kept shards.
+        for index in 0..count {
+            let moved = shards[index]
+                .keys()
+                .map(|k| (shard_index(&k, count), k))
+                .filter(|(to, _)| *to != index)
+                .collect::<Vec<_>>();
+            for (to, k) in moved {
+                let v = shards[index].get(&k).map(|v| v.into_inner().into_owned());
+                if let Some(v) = v {
+                    shards[to].set_value(k.clone(), v);
+                    shards[index].unset_value(&k);
+                }
+            }
+        }
+
+        // Entries in the dropped shards.
+        for s in old.values() {
+            for (k, v) in s.iter() {
+                shards[shard_index(&k, count)].set_value(k, v);
+            }
+        }
+
+        for (index, s) in shards.iter().enumerate() {
+            s.flush_data();
+            ShardMeta::new(index, count)
+                .store(s.get_data_path())
+                .c(d!())?;
+        }
+        for (_, s) in old {
+            s.destroy().c(d!())?;
+        }
+
+        Ok(ShardedMapx {
+            shards,
+            imc,
+            is_tmp,
+            storage,
+        })
+    }
+
+    /// Get the data paths of the shards in order.
+    pub fn paths(&self) -> Vec<&str> {
+        self.shards.iter().map(|s| s.get_data_path()).collect()
+    }
+
+    /// Get the shards in order.
+    pub fn shards(&self) -> &[Mapx<K, V>] {
+        &self.shards
+    }
+
+    /// Number of shards.
+    #[inline(always)]
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// The index of the shard storing `key`.
+    #[inline(always)]
+    pub fn shard_of(&self, key: &K) -> usize {
+        shard_index(key, self.shards.len())
+    }
+
+    /// Imitate the behavior of 'HashMap<_>.get(...)'
+    #[inline(always)]
+    pub fn get(&self, key: &K) -> Option<Value<V>> {
+        self.shards[self.shard_of(key)].get(key)
+    }
+
+    /// Imitate the behavior of 'HashMap<_>.len()'.
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|s| s.len()).sum()
+    }
+
+    /// A helper func
+    pub fn is_empty(&self) -> bool {
+        self.shards.iter().all(|s| s.is_empty())
+    }
+
+    /// Imitate the behavior of 'HashMap<_>.insert(...)'.
+    #[inline(always)]
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        let index = self.shard_of(&key);
+        self.shards[index].insert(key, value)
+    }
+
+    /// Similar with `insert`, but ignore if the old value is exist.
+    #[inline(always)]
+    pub fn set_value(&mut self, key: K, value: V) {
+        let index = self.shard_of(&key);
+        self.shards[index].set_value(key, value)
+    }
+
+    /// Check if a key is exists.
+    #[inline(always)]
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.shards[self.shard_of(key)].contains_key(key)
+    }
+
+    /// Remove a <K, V> from mem and disk.
+    #[inline(always)]
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let index = self.shard_of(key);
+        self.shards[index].remove(key)
+    }
+
+    /// Remove a <K, V> from mem and disk.
+    #[inline(always)]
+    pub fn unset_value(&mut self, key: &K) {
+        let index = self.shard_of(key);
+        self.shards[index].unset_value(key)
+    }
+
+    /// Iterate over the shards one by one.
+    pub fn iter(&self) -> impl Iterator<Item = (K, V)> + '_ {
+        self.shards.iter().flat_map(|s| s.iter())
+    }
+
+    /// Run `f` on every shard in its own thread,
+    /// return the results in the order of the shards,
+    /// a panic of `f` is resumed with its original payload.
+    pub fn par_map_shards<F, R>(&self, f: F) -> Vec<R>
+    where
+        K: Send + Sync,
+        V: Send + Sync,
+        F: Fn(&Mapx<K, V>) -> R + Sync,
+        R: Send,
+    {
+        let f = &f;
+        thread::scope(|scope| {
+            self.shards
+                .iter()
+                .map(|s| scope.spawn(move || f(s)))
+                .collect::<Vec<_>>()
+                .into_iter()
+                .map(|h| h.join().unwrap_or_else(|e| panic::resume_unwind(e)))
+                .collect()
+        })
+    }
+
+    /// Call `f` on every entry, the shards are iterated in parallel.
+    pub fn par_for_each<F>(&self, f: F)
+    where
+        K: Send + Sync,
+        V: Send + Sync,
+        F: Fn(K, V) + Sync,
+    {
+        self.par_map_shards(|s| s.iter().for_each(|(k, v)| f(k, v)));
+    }
+
+    /// Flush all shards to disk in parallel.
+    pub fn flush_data(&self)
+    where
+        K: Send + Sync,
+        V: Send + Sync,
+    {
+        self.par_map_shards(|s| s.flush_data());
+    }
+
+    /// Get the statistics of every shard in order.
+    pub fn shard_stats(&self) -> Vec<ShardStats> {
+        self.shards
+            .iter()
+            .map(|s| ShardStats {
+                path: s.get_data_path().to_owned(),
+                len: s.len(),
+                stats: s.stats(),
+            })
+            .collect()
+    }
+}
+
+/********************************************/
+// End of the implementation of ShardedMapx //
+//////////////////////////////////////////////
+
+fn check_paths(paths: &[String]) -> Result<()> {
+    if paths.is_empty() {
+        return Err(eg!("no shards"));
+    }
+    for (i, path) in paths.iter().enumerate() {
+        if paths[..i].contains(path) {
+            return Err(eg!(format!("duplicate shard {}", path)));
+        }
+    }
+    Ok(())
+}
+
+// A shard without the manifest is accepted only if it is empty,
+// or it is the only shard, e.g. a plain `Mapx` to be resharded;
+// a shard marked by an unfinished reshard is accepted in either layout.
+fn open_shard<K, V>(
+    paths: &[String],
+    index: usize,
+    imc: Option<usize>,
+    is_tmp: bool,
+    storage: Storage,
+) -> Result<Mapx<K, V>>
+where
+    K: Clone + Eq + PartialEq + Hash + Serialize + DeserializeOwned + fmt::Debug,
+    V: Clone + Eq + PartialEq + Serialize + DeserializeOwned + fmt::Debug,
+{
+    let (path, count) = (&paths[index], paths.len());
+    let found = ShardMeta::load(path).c(d!())?;
+    let shard = Mapx::new_with_storage(path.clone(), imc, is_tmp, storage).c(d!())?;
+    match found {
+        Some(meta) if !meta.accepts(paths, index) => Err(eg!(format!(
+            "{} is shard {} of {}, not {} of {}",
+            path, meta.index, meta.count, index, count
+        ))),
+        Some(_) => Ok(shard),
+        None if 1 < count && !shard.is_empty() => Err(eg!(format!("{} is not a shard", path))),
+        None => ShardMeta::new(index, count)
+            .store(path)
+            .c(d!())
+            .map(|_| shard),
+    }
+}
+
+#[inline(always)]
+fn shard_index<K: Serialize>(key: &K, count: usize) -> usize {
+    let key = pnk!(bincode::serialize(key));
+    (fnv1a(FNV_OFFSET, &key) % count as u64) as usize
+}
@@ -3,6 +3,7 @@
 //!
 
 use super::*;
+use rand::{rngs::StdRng, Rng, SeedableRng};
 use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone)]
@@ -62,3 +63,1802 @@ fn t_mapx() {
         assert!(!db_restore.contains_key(&i));
     });
 }
+
+#[test]
+fn t_mapx_nested() {
+    let cnt = 20;
+
+    let db = {
+        let mut db: Mapx<usize, Mapx<usize, Mapx<usize, SampleBlock>>> = crate::new_mapx!();
+
+        (0..cnt).for_each(|i| {
+            let mut inner = pnk!(db.new_nested());
+            (0..cnt).for_each(|j| {
+                let mut innermost = pnk!(db.new_nested());
+                assert!(innermost.insert(j, gen_sample(i + j)).is_none());
+                assert!(inner.insert(j, innermost).is_none());
+            });
+            assert_eq!(cnt, inner.len());
+            assert!(db.insert(i, inner).is_none());
+        });
+
+        assert_eq!(cnt, db.len());
+        pnk!(db.get_mut(&0)).remove(&0);
+        assert_eq!(cnt - 1, pnk!(db.get(&0)).len());
+
+        pnk!(serde_json::to_vec(&db))
+    };
+
+    let mut db_restore = pnk!(serde_json::from_slice::<
+        Mapx<usize, Mapx<usize, Mapx<usize, SampleBlock>>>,
+    >(&db));
+
+    assert_eq!(cnt, db_restore.len());
+    assert!(pnk!(db_restore.get(&0)).get(&0).is_none());
+
+    (1..cnt).for_each(|i| {
+        let inner = pnk!(db_restore.get(&i));
+        assert_eq!(cnt, inner.len());
+        (0..cnt).for_each(|j| {
+            assert_eq!(pnk!(pnk!(inner.get(&j)).get(&j)).idx, i + j);
+        });
+    });
+
+    // the removed outer entries take their nested maps with them
+    let innermost = pnk!(pnk!(db_restore.get(&1)).get(&0))
+        .into_inner()
+        .into_owned();
+    let inner = pnk!(db_restore.remove(&1));
+    assert!(inner.is_empty());
+    assert!(innermost.is_empty());
+    assert!(innermost.get(&0).is_none());
+
+    // so do the overwritten ones
+    let inner = pnk!(db_restore.get(&2)).into_inner().into_owned();
+    let empty = pnk!(db_restore.new_nested());
+    assert!(db_restore.insert(2, empty).is_some());
+    assert!(inner.is_empty());
+    assert!(pnk!(db_restore.get(&2)).is_empty());
+
+    // all handles of a nested map share its length
+    let mut inner = pnk!(db_restore.get(&3)).into_inner().into_owned();
+    let innermost = pnk!(db_restore.new_nested());
+    assert!(inner.insert(cnt, innermost).is_none());
+    assert_eq!(cnt + 1, pnk!(db_restore.get(&3)).len());
+    pnk!(db_restore.get_mut(&3)).remove(&0);
+    assert_eq!(cnt, inner.len());
+    let mut innermost = pnk!(inner.get(&cnt)).into_inner().into_owned();
+    innermost.insert(0, gen_sample(0));
+    assert_eq!(1, pnk!(pnk!(db_restore.get(&3)).get(&cnt)).len());
+}
+
+// Nested maps wrapped in other values are dropped with them.
+#[test]
+fn t_mapx_nested_in_struct() {
+    #[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone)]
+    struct Wrapped {
+        tag: u32,
+        inner: Mapx<usize, usize>,
+    }
+
+    let path = crate::unique_path!();
+    let mut db: Mapx<usize, Wrapped> = pnk!(Mapx::new_with_storage(
+        path.clone(),
+        Some(1),
+        false,
+        Storage::Sled
+    ));
+    let mut pairs: Mapx<usize, (u32, Mapx<usize, usize>)> = pnk!(db.new_nested());
+    for i in 0..4 {
+        let mut inner = pnk!(db.new_nested());
+        (0..10).for_each(|j| inner.set_value(j, j));
+        db.insert(i, Wrapped { tag: 0, inner });
+
+        let mut inner = pnk!(db.new_nested());
+        (0..10).for_each(|j| inner.set_value(j, j));
+        pairs.insert(i, (0, inner));
+    }
+
+    let inner = pnk!(db.get(&0)).inner.clone();
+    assert_eq!(10, inner.len());
+    db.remove(&0);
+    assert!(inner.is_empty());
+    let inner = pnk!(db.get(&1)).inner.clone();
+    let empty = pnk!(db.new_nested());
+    db.insert(
+        1,
+        Wrapped {
+            tag: 1,
+            inner: empty,
+        },
+    );
+    assert!(inner.is_empty());
+    let inner = pnk!(pairs.get(&0)).1.clone();
+    pairs.remove(&0);
+    assert!(inner.is_empty());
+
+    // Kept if it is still held by the new value.
+    pnk!(db.get_mut(&2)).tag = 2;
+    assert_eq!(10, pnk!(db.get(&2)).inner.len());
+    let mut pair = pnk!(pairs.get(&2)).into_inner().into_owned();
+    pair.0 = 2;
+    pairs.insert(2, pair);
+    assert_eq!(10, pnk!(pairs.get(&2)).1.len());
+
+    (0..4).for_each(|i| {
+        db.remove(&i);
+        pairs.remove(&i);
+    });
+    drop(pairs);
+    let (kv, _) = pnk!(crate::helper::kv_open_read_only(&path));
+    assert!(pnk!(kv.open_tree("____nested____")).is_empty());
+    // The length of `pairs` itself is left.
+    assert_eq!(1, pnk!(kv.open_tree("____nested_cnter____")).len());
+}
+
+#[test]
+fn t_mapx_bulk() {
+    let cnt = 200;
+    let mut db = crate::new_mapx!();
+
+    db.insert(0, gen_sample(0));
+    db.extend((0..cnt).map(|i| (i, gen_sample(1 + i))));
+    assert_eq!(cnt, db.len());
+    assert_eq!(pnk!(db.get(&0)).idx, 1);
+
+    // duplicated keys are counted only once
+    db.extend(
+        (cnt..2 * cnt)
+            .chain(cnt..2 * cnt)
+            .map(|i| (i, gen_sample(i))),
+    );
+    assert_eq!(2 * cnt, db.len());
+
+    let keys = (0..3 * cnt).rev().collect::<Vec<_>>();
+    let values = db.get_many(&keys);
+    assert_eq!(pnk!(db.get_many(&[1, 0, 1])[2].as_ref()).idx, 2);
+    assert_eq!(3 * cnt, values.len());
+    values.into_iter().rev().enumerate().for_each(|(i, v)| {
+        if i < cnt {
+            assert_eq!(pnk!(v).idx, 1 + i);
+        } else if i < 2 * cnt {
+            assert_eq!(pnk!(v).idx, i);
+        } else {
+            assert!(v.is_none());
+        }
+    });
+}
+
+#[test]
+fn t_mapx_entry() {
+    let mut db: Mapx<usize, usize> = crate::new_mapx!();
+
+    assert_eq!(*db.entry(0).or_default(), 0);
+    assert_eq!(*db.entry(1).or_insert_with_key(|k| 10 * k), 10);
+    assert_eq!(*db.entry(1).or_insert(100), 10);
+    assert_eq!(2, db.len());
+
+    db.entry(1).and_modify(|v| *v += 1).or_insert(100);
+    assert_eq!(pnk!(db.get(&1)), 11);
+    db.entry(2).and_modify(|v| *v += 1).or_insert(100);
+    assert_eq!(pnk!(db.get(&2)), 100);
+    assert_eq!(3, db.len());
+
+    match db.entry(2) {
+        Entry::Occupied(mut e) => {
+            assert_eq!(*e.key(), 2);
+            assert_eq!(e.insert(200), 100);
+            assert_eq!(*e.get(), 200);
+        }
+        Entry::Vacant(_) => panic!(),
+    }
+    assert_eq!(pnk!(db.get(&2)), 200);
+
+    match db.entry(2) {
+        Entry::Occupied(e) => assert_eq!(e.remove_entry(), (2, 200)),
+        Entry::Vacant(_) => panic!(),
+    }
+    assert!(!db.contains_key(&2));
+    assert_eq!(2, db.len());
+
+    match db.entry(3) {
+        Entry::Occupied(_) => panic!(),
+        Entry::Vacant(e) => {
+            assert_eq!(*e.key(), 3);
+            *e.insert(30) += 3;
+        }
+    }
+    assert_eq!(pnk!(db.get(&3)), 33);
+    assert_eq!(*db.entry(3).key(), 3);
+    assert_eq!(3, db.len());
+}
+
+#[test]
+fn t_mapx_iter() {
+    let cnt = 200;
+    let mut db: Mapx<usize, SampleBlock> = crate::new_mapx!();
+
+    (0..cnt).for_each(|i| {
+        db.insert(i, gen_sample(i));
+    });
+
+    assert_eq!(cnt, db.iter().len());
+    assert_eq!(cnt, db.keys().len());
+    assert_eq!(cnt, db.values().len());
+    assert_eq!(cnt, db.raw_iter().len());
+    assert!(db.iter_mem().len() > 0);
+
+    let mut it = db.iter();
+    it.next();
+    it.next_back();
+    assert_eq!((cnt - 2, Some(cnt - 2)), it.size_hint());
+
+    let mut keys = db.keys().collect::<Vec<_>>();
+    keys.sort_unstable();
+    assert_eq!(keys, (0..cnt).collect::<Vec<_>>());
+
+    db.iter().for_each(|(k, v)| {
+        assert_eq!(k, v.idx);
+    });
+    db.values().rev().for_each(|v| {
+        assert_eq!(v, gen_sample(v.idx));
+    });
+    db.raw_iter().for_each(|(k, v)| {
+        let k = pnk!(bincode::deserialize::<usize>(&k));
+        assert_eq!(pnk!(serde_json::from_slice::<SampleBlock>(&v)).idx, k);
+    });
+
+    db.iter_mut().for_each(|(k, mut v)| {
+        v.idx = 1 + k;
+    });
+    (0..cnt).for_each(|i| {
+        assert_eq!(pnk!(db.get(&i)).idx, 1 + i);
+    });
+    assert_eq!(cnt, db.len());
+}
+
+#[test]
+fn t_mapx_stats() {
+    let mut db: Mapx<usize, SampleBlock> = pnk!(Mapx::new_with_durability(
+        crate::unique_path!(),
+        Some(1),
+        false,
+        Durability::Manual
+    ));
+    assert_eq!(
+        crate::stats::Stats::default(),
+        crate::stats::Stats {
+            size_on_disk: 0,
+            ..db.stats()
+        }
+    );
+
+    (0..10).for_each(|i| {
+        db.insert(i, gen_sample(i));
+    });
+    (0..10).for_each(|i| {
+        assert!(db.get(&i).is_some());
+    });
+    db.flush_data();
+
+    let stats = db.stats();
+    assert!(0 < stats.cache_hits);
+    assert!(0 < stats.cache_misses);
+    assert!(0 < stats.cache_evictions);
+    assert!(0 < stats.bytes_read);
+    assert!(0 < stats.bytes_written);
+    assert_eq!(1, stats.flushes);
+    assert!(0 < stats.size_on_disk || !db.storage().is_persistent());
+
+    let global = crate::stats::global();
+    assert!(stats.bytes_written <= global.bytes_written);
+    assert!(stats.size_on_disk <= global.size_on_disk);
+
+    #[cfg(feature = "prometheus")]
+    {
+        let text = stats.render_prometheus(Some(db.get_data_path()));
+        assert!(text.contains("# TYPE fundb_cache_hits_total counter\n"));
+        assert!(text.contains(&format!(
+            "fundb_flushes_total{{collection=\"{}\"}} 1\n",
+            db.get_data_path()
+        )));
+    }
+}
+
+#[test]
+fn t_mapx_durability() {
+    let path = crate::unique_path!();
+    let cnter_path = format!("{}/____cnter____", &path);
+
+    {
+        let mut db: Mapx<usize, SampleBlock> = pnk!(Mapx::new_with_durability(
+            path.clone(),
+            None,
+            false,
+            Durability::Sync
+        ));
+        assert_eq!(Some(Durability::Sync), db.durability());
+        db.insert(0, gen_sample(0));
+        assert!(!pnk!(crate::helper::db_len_is_dirty(&cnter_path)));
+        assert_eq!(1, pnk!(crate::helper::read_db_len(&cnter_path)));
+    }
+
+    {
+        let mut db: Mapx<usize, SampleBlock> = pnk!(Mapx::new_with_durability(
+            path.clone(),
+            None,
+            false,
+            Durability::Manual
+        ));
+        db.insert(1, gen_sample(1));
+        assert!(pnk!(crate::helper::db_len_is_dirty(&cnter_path)));
+        db.flush_data();
+        assert!(!pnk!(crate::helper::db_len_is_dirty(&cnter_path)));
+        assert_eq!(2, pnk!(crate::helper::read_db_len(&cnter_path)));
+
+        let nested = pnk!(db.new_nested::<usize, usize>());
+        assert!(nested.durability().is_none());
+    }
+
+    // A stale counter left by a crash is recounted.
+    pnk!(crate::helper::mark_db_len_dirty(&cnter_path, 0));
+    let db: Mapx<usize, SampleBlock> = pnk!(Mapx::new(path, None, false));
+    assert_eq!(2, db.len());
+    assert!(!pnk!(crate::helper::db_len_is_dirty(&cnter_path)));
+}
+
+// A flush between `begin_write` and `end_write` must not leave a stale counter as clean.
+#[test]
+fn t_mapx_flush_during_write() {
+    use crate::{durability::Flusher, helper::*, stats::StatsCounter};
+    use std::sync::Arc;
+
+    let path = crate::unique_path!();
+    let cnter_path = format!("{}/____cnter____", &path);
+    let db = pnk!(kv_open(&path, false, Storage::Sled));
+    pnk!(write_db_len(&cnter_path, 0));
+    let flusher = Flusher::new(
+        Arc::clone(&db),
+        &path,
+        true,
+        0,
+        Durability::Manual,
+        Arc::<StatsCounter>::default(),
+    );
+
+    flusher.begin_write();
+    pnk!(db.insert(b"k", b"v"));
+    pnk!(flusher.flush());
+    flusher.end_write(1);
+    assert!(pnk!(db_len_is_dirty(&cnter_path)));
+
+    // Crashed here, the counter is recounted by the next opener.
+    pnk!(flusher.flush());
+    assert!(!pnk!(db_len_is_dirty(&cnter_path)));
+    assert_eq!(1, pnk!(read_db_len(&cnter_path)));
+}
+
+#[test]
+fn t_mapx_schema() {
+    #[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone)]
+    struct SampleBlockV2 {
+        idx: usize,
+        data: Vec<usize>,
+        hash: String,
+    }
+
+    let path = crate::unique_path!();
+
+    {
+        let mut db: Mapx<usize, SampleBlock> = pnk!(Mapx::new(path.clone(), None, false));
+        assert_eq!(0, db.schema_version());
+        (0..10).for_each(|i| {
+            db.insert(i, gen_sample(i));
+        });
+    }
+
+    let schema = || {
+        crate::schema::Schema::new(1).migration((0, |mut v: serde_json::Value| {
+            v["hash"] = serde_json::Value::String(format!("{}", v["idx"]));
+            Ok(v)
+        }))
+    };
+    let upgraded = |i: usize| SampleBlockV2 {
+        idx: i,
+        data: vec![i],
+        hash: format!("{}", i),
+    };
+
+    {
+        let mut db: Mapx<usize, SampleBlockV2> = pnk!(Mapx::new_with_schema(
+            path.clone(),
+            Some(0),
+            false,
+            Durability::default(),
+            schema()
+        ));
+        assert_eq!(1, db.schema_version());
+
+        // Upgraded lazily.
+        (0..10).for_each(|i| {
+            assert_eq!(upgraded(i), pnk!(db.get(&i)).into_inner().into_owned());
+        });
+
+        db.insert(10, upgraded(10));
+        assert_eq!(10, pnk!(db.migrate_all()));
+        assert_eq!(0, pnk!(db.migrate_all()));
+        assert_eq!(11, db.len());
+    }
+
+    // The persisted version is used without a schema.
+    let db: Mapx<usize, SampleBlockV2> = pnk!(Mapx::new(path.clone(), None, false));
+    assert_eq!(1, db.schema_version());
+    assert_eq!(
+        (0..11).map(upgraded).collect::<Vec<_>>(),
+        db.values().collect::<Vec<_>>()
+    );
+
+    // Can not be downgraded.
+    assert!(Mapx::<usize, SampleBlock>::new_with_schema(
+        path,
+        None,
+        false,
+        Durability::default(),
+        crate::schema::Schema::new(0)
+    )
+    .is_err());
+}
+
+#[test]
+fn t_mapx_storage() {
+    for storage in [Storage::Sled, Storage::Memory, Storage::Log]
+        .iter()
+        .copied()
+    {
+        let path = crate::unique_path!();
+        let cnt = 100;
+
+        {
+            let mut db: Mapx<usize, Mapx<usize, SampleBlock>> = pnk!(Mapx::new_with_storage(
+                path.clone(),
+                Some(1),
+                false,
+                storage
+            ));
+            assert_eq!(storage, db.storage());
+            (0..cnt).for_each(|i| {
+                let mut inner = pnk!(db.new_nested());
+                inner.insert(i, gen_sample(i));
+                db.insert(i, inner);
+            });
+            assert!(db.remove(&0).is_some());
+            assert_eq!(cnt - 1, db.len());
+        }
+
+        let db: Mapx<usize, Mapx<usize, SampleBlock>> =
+            pnk!(Mapx::new_with_storage(path.clone(), None, false, storage));
+        assert_eq!(cnt - 1, db.len());
+        (1..cnt).for_each(|i| {
+            let inner = pnk!(db.get(&i)).into_inner().into_owned();
+            assert_eq!(gen_sample(i), pnk!(inner.get(&i)).into_inner().into_owned());
+        });
+
+        let db = pnk!(serde_json::to_vec(&db));
+        let db: Mapx<usize, Mapx<usize, SampleBlock>> = pnk!(serde_json::from_slice(&db));
+        assert_eq!(storage, db.storage());
+        assert_eq!(cnt - 1, db.len());
+    }
+
+    // One path, one storage.
+    let path = crate::unique_path!();
+    let _db: Mapx<usize, usize> = pnk!(Mapx::new_with_storage(
+        path.clone(),
+        None,
+        false,
+        Storage::Memory
+    ));
+    assert!(Mapx::<usize, usize>::new_with_storage(path, None, false, Storage::Log).is_err());
+}
+
+#[test]
+fn t_mapx_lifecycle() {
+    let path = crate::unique_path!();
+    let exists = || std::path::Path::new(&path).exists();
+
+    {
+        let mut db: Mapx<usize, usize> = pnk!(Mapx::new_with_storage(
+            path.clone(),
+            None,
+            true,
+            Storage::Sled
+        ));
+        db.insert(1, 1);
+        let db2 = db.clone();
+        drop(db);
+        assert!(exists());
+        assert_eq!(1, db2.len());
+    }
+    assert!(!exists());
+
+    let mut db: Mapx<usize, usize> = pnk!(Mapx::new_with_storage(
+        path.clone(),
+        None,
+        false,
+        Storage::Log
+    ));
+    db.insert(1, 1);
+    let db2 = db.clone();
+    let nested = pnk!(db.new_nested::<usize, usize>());
+    assert!(nested.destroy().is_err());
+    assert!(db.destroy().is_err());
+    drop(db2);
+    assert!(exists());
+
+    let db: Mapx<usize, usize> = pnk!(Mapx::new_with_storage(
+        path.clone(),
+        None,
+        false,
+        Storage::Log
+    ));
+    assert_eq!(1, db.len());
+    pnk!(db.destroy());
+    assert!(!exists());
+
+    let db: Mapx<usize, usize> = pnk!(Mapx::new_with_storage(
+        path.clone(),
+        None,
+        false,
+        Storage::Log
+    ));
+    assert!(db.is_empty());
+    pnk!(db.destroy());
+}
+
+#[test]
+fn t_gc_unique_paths() {
+    let ts = ts!() - 7200;
+    let orphan = format!(
+        "{}/.fundb/{}/src/test.rs_1_1_1",
+        *crate::helper::CACHE_DIR,
+        ts
+    );
+    pnk!(std::fs::create_dir_all(&orphan));
+
+    let opened = format!(
+        "{}/.fundb/{}/src/test.rs_2_2_2",
+        *crate::helper::CACHE_DIR,
+        ts - 1
+    );
+    let db: Mapx<usize, usize> = pnk!(Mapx::new_with_storage(
+        opened.clone(),
+        None,
+        false,
+        Storage::Sled
+    ));
+
+    assert!(
+        1 <= pnk!(crate::helper::gc_unique_paths(
+            std::time::Duration::from_secs(3600)
+        ))
+    );
+    assert!(!std::path::Path::new(&orphan).exists());
+    assert!(std::path::Path::new(&opened).exists());
+    pnk!(db.destroy());
+}
+
+#[test]
+fn t_close_db() {
+    let path = crate::unique_path!();
+    let mut db: Mapx<usize, usize> = pnk!(Mapx::new_with_storage(
+        path.clone(),
+        None,
+        false,
+        Storage::Sled
+    ));
+    (0..10).for_each(|i| db.set_value(i, i));
+    let meta = pnk!(serde_json::to_string(&db));
+
+    assert!(crate::helper::close_db(&path).is_err());
+    drop(db);
+    pnk!(crate::helper::close_db(&path));
+    // Closed already.
+    pnk!(crate::helper::close_db(&path));
+
+    let db: Mapx<usize, usize> = pnk!(serde_json::from_str(&meta));
+    assert_eq!(10, db.len());
+    assert_eq!(9, *pnk!(db.get(&9)));
+    pnk!(db.destroy());
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn t_mapx_lock() {
+    let path = crate::unique_path!();
+    let lock = format!("{}/____lock____", &path);
+
+    // Held by a live process.
+    pnk!(std::fs::create_dir_all(&path));
+    pnk!(std::fs::write(&lock, "1"));
+    assert!(
+        Mapx::<usize, usize>::new_with_storage(path.clone(), None, false, Storage::Log).is_err()
+    );
+
+    // Left by a dead one.
+    pnk!(std::fs::write(&lock, "4194304"));
+    let mut db: Mapx<usize, usize> = pnk!(Mapx::new_with_storage(
+        path.clone(),
+        None,
+        false,
+        Storage::Log
+    ));
+    assert_eq!(
+        std::process::id().to_string(),
+        pnk!(std::fs::read_to_string(&lock))
+    );
+    db.insert(1, 1);
+    db.insert(2, 2);
+
+    let reader = pnk!(Mapx::<usize, usize>::open_read_only(path.clone()));
+    assert_eq!(2, reader.len());
+    assert_eq!(2, *pnk!(reader.get(&2)));
+    assert!(reader.new_nested::<usize, usize>().is_err());
+
+    assert!(Mapx::<usize, usize>::open_read_only(crate::unique_path!()).is_err());
+}
+
+fn sorted(db: &Mapx<u8, usize>) -> Vec<(u8, usize)> {
+    let mut entries = db.iter().collect::<Vec<_>>();
+    entries.sort_unstable();
+    entries
+}
+
+fn sorted_model(model: &HashMap<u8, usize>) -> Vec<(u8, usize)> {
+    let mut entries = model.iter().map(|(k, v)| (*k, *v)).collect::<Vec<_>>();
+    entries.sort_unstable();
+    entries
+}
+
+// Random operations against a `HashMap`.
+#[test]
+fn t_mapx_model() {
+    for seed in 0..4 {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut db: Mapx<u8, usize> = pnk!(Mapx::new(
+            crate::unique_path!(),
+            Some(rng.gen_range(0..4)),
+            false
+        ));
+        let mut model = HashMap::new();
+
+        for i in 0..1000 {
+            let k = rng.gen_range(0..64);
+            match rng.gen_range(0..10) {
+                0..=3 => assert_eq!(model.insert(k, i), db.insert(k, i), "seed {}", seed),
+                4..=5 => assert_eq!(model.remove(&k), db.remove(&k), "seed {}", seed),
+                6 => assert_eq!(model.get(&k), db.get(&k).as_deref(), "seed {}", seed),
+                7 => assert_eq!(model.contains_key(&k), db.contains_key(&k)),
+                8 => {
+                    let entries = (0..rng.gen_range(0..8))
+                        .map(|j| (rng.gen_range(0..64), i + j))
+                        .collect::<Vec<_>>();
+                    model.extend(entries.iter().copied());
+                    db.extend(entries);
+                }
+                _ => {
+                    // Reopen by the serialized meta.
+                    let meta = pnk!(serde_json::to_string(&db));
+                    db = pnk!(serde_json::from_str(&meta));
+                }
+            }
+            assert_eq!(model.len(), db.len(), "seed {}", seed);
+        }
+
+        assert_eq!(sorted_model(&model), sorted(&db), "seed {}", seed);
+    }
+}
+
+// Cut the log at random points after random writes,
+// the recovered map must be one of the states before the crash.
+#[test]
+fn t_mapx_crash() {
+    for seed in 0..8 {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let path = crate::unique_path!();
+        let open = || {
+            pnk!(Mapx::<u8, usize>::open(
+                path.clone(),
+                Some(1),
+                false,
+                Durability::Manual,
+                None,
+                Storage::Log
+            ))
+        };
+
+        let mut db = open();
+        let mut model = HashMap::new();
+        let mut states = vec![sorted_model(&model)];
+        for i in 0..200 {
+            let k = rng.gen_range(0..32);
+            if rng.gen_bool(0.7) {
+                model.insert(k, i);
+                db.insert(k, i);
+            } else {
+                model.remove(&k);
+                db.remove(&k);
+            }
+            states.push(sorted_model(&model));
+        }
+
+        mem::forget(db);
+        crate::helper::crash_db(&path);
+        let log = format!("{}/____default____.log", &path);
+        let len = pnk!(std::fs::metadata(&log)).len();
+        let file = pnk!(std::fs::OpenOptions::new().write(true).open(&log));
+        pnk!(file.set_len(rng.gen_range(0..=len)));
+
+        let mut db = open();
+        let recovered = sorted(&db);
+        assert_eq!(recovered.len(), db.len(), "seed {}", seed);
+        assert!(states.contains(&recovered), "seed {}", seed);
+
+        // Still writable, and the counter is correct after a clean exit.
+        db.insert(u8::MAX, 0);
+        let len = db.len();
+        assert_eq!(recovered.len() + 1, len);
+        drop(db);
+        crate::helper::crash_db(&path);
+        assert_eq!(len, open().len());
+    }
+}
+
+// A sled map stopped between `begin_write` and `end_write`
+// by `t_mapx_crash_in_write_process`,
+// the counter is left dirty and the length is recounted on reopening.
+#[test]
+fn t_mapx_crash_in_write() {
+    use std::process::Command;
+
+    let path = crate::unique_path!();
+    let cnter_path = format!("{}/____cnter____", &path);
+    let out = pnk!(Command::new(pnk!(std::env::current_exe()))
+        .args([
+            "--exact",
+            "mapx::test::t_mapx_crash_in_write_process",
+            "--ignored",
+        ])
+        .env("FUNDB_CRASH_PATH", &path)
+        .output());
+    assert!(out.status.success());
+    assert!(pnk!(crate::helper::db_len_is_dirty(&cnter_path)));
+    assert_eq!(100, pnk!(crate::helper::read_db_len(&cnter_path)));
+
+    let db = open_crashed_map(path);
+    assert_eq!(110, db.len());
+    assert_eq!(110, db.iter().count());
+    assert_eq!(100, *pnk!(db.get(&100)));
+    assert!(!pnk!(crate::helper::db_len_is_dirty(&cnter_path)));
+}
+
+// Exit in a write without dropping anything, as if this process had been killed.
+#[test]
+#[ignore]
+fn t_mapx_crash_in_write_process() {
+    let path = match std::env::var("FUNDB_CRASH_PATH") {
+        Ok(path) => path,
+        Err(_) => return,
+    };
+    let cnter_path = format!("{}/____cnter____", &path);
+
+    let mut db = open_crashed_map(path);
+    (0..100).for_each(|i| db.set_value(i, i));
+    db.flush_data();
+    assert!(!pnk!(crate::helper::db_len_is_dirty(&cnter_path)));
+
+    db.in_disk.set_value_unfinished(&100, &100);
+    // The writes finished after it are flushed, but not the counter.
+    (101..110).for_each(|i| db.set_value(i, i));
+    db.flush_data();
+    std::process::exit(0);
+}
+
+fn open_crashed_map(path: String) -> Mapx<usize, usize> {
+    pnk!(Mapx::open(
+        path,
+        None,
+        false,
+        Durability::Manual,
+        None,
+        Storage::Sled
+    ))
+}
+
+// The changes recorded but not written before a crash are written on reopening.
+#[test]
+fn t_mapx_crash_change_log() {
+    let path = crate::unique_path!();
+    let open = || {
+        pnk!(Mapx::<u8, usize>::open(
+            path.clone(),
+            Some(1),
+            false,
+            Durability::Manual,
+            None,
+            Storage::Log
+        ))
+    };
+
+    let mut db = open();
+    pnk!(db.enable_change_log());
+    (0..10).for_each(|i| db.set_value(i, i as usize));
+    db.flush_data();
+    drop(db);
+
+    let mut db = open();
+    let log = format!("{}/____default____.log", &path);
+    let len = pnk!(std::fs::metadata(&log)).len();
+    (10..20).for_each(|i| db.set_value(i, i as usize));
+    db.unset_value(&0);
+    pnk!(db.set_merge_operator(crate::merge::Add));
+    pnk!(db.merge(&1, &10usize));
+
+    // Only the changes before the reopening are written.
+    mem::forget(db);
+    crate::helper::crash_db(&path);
+    let file = pnk!(std::fs::OpenOptions::new().write(true).open(&log));
+    pnk!(file.set_len(len));
+
+    let db = open();
+    assert_eq!(19, db.len());
+    assert!(db.get(&0).is_none());
+    assert_eq!(11, *pnk!(db.get(&1)));
+    assert_eq!(19, *pnk!(db.get(&19)));
+    assert_eq!(Some(22), db.change_log_seq());
+}
+
+// The nested maps dropped by the lost changes are dropped on reopening too.
+#[test]
+fn t_mapx_crash_change_log_nested() {
+    let path = crate::unique_path!();
+    let open = || {
+        pnk!(Mapx::<u8, Mapx<u8, usize>>::open(
+            path.clone(),
+            Some(1),
+            false,
+            Durability::Manual,
+            None,
+            Storage::Log
+        ))
+    };
+
+    let mut db = open();
+    pnk!(db.enable_change_log());
+    for i in 0..4 {
+        let mut inner = pnk!(db.new_nested());
+        (0..3).for_each(|j| inner.set_value(j, j as usize));
+        db.insert(i, inner);
+    }
+    db.flush_data();
+    drop(db);
+
+    let mut db = open();
+    let logs = ["____default____", "____nested____", "____nested_cnter____"]
+        .iter()
+        .map(|name| {
+            let log = format!("{}/{}.log", &path, name);
+            let len = pnk!(std::fs::metadata(&log)).len();
+            (log, len)
+        })
+        .collect::<Vec<_>>();
+    db.unset_value(&0);
+    let empty = pnk!(db.new_nested());
+    db.set_value(1, empty);
+
+    // Recorded, but neither the changes nor the removals of the nested maps are written.
+    mem::forget(db);
+    crate::helper::crash_db(&path);
+    for (log, len) in logs {
+        let file = pnk!(std::fs::OpenOptions::new().write(true).open(&log));
+        pnk!(file.set_len(len));
+    }
+
+    let db = open();
+    assert_eq!(3, db.len());
+    assert!(db.get(&0).is_none());
+    assert_eq!(3, pnk!(db.get(&2)).len());
+    let (kv, _) = pnk!(crate::helper::kv_open_read_only(&path));
+    assert_eq!(6, pnk!(kv.open_tree("____nested____")).len());
+}
+
+#[test]
+fn t_mapx_value_mut() {
+    let mut db: Mapx<usize, Vec<usize>> = pnk!(Mapx::new(crate::unique_path!(), Some(2), false));
+    (0..4).for_each(|i| db.set_value(i, vec![i]));
+
+    // Read only, nothing is written.
+    let before = db.stats().bytes_written;
+    assert_eq!(vec![3], *pnk!(db.get_mut(&3)));
+    assert_eq!(vec![0], *pnk!(db.get_mut(&0)));
+    assert_eq!(vec![1], pnk!(db.get_mut(&1)).clone_inner());
+    db.iter_mut().for_each(|(_, v)| assert_eq!(1, v.len()));
+    assert_eq!(Some(1), db.update(&1, |v| v.len()));
+    assert_eq!(
+        Some(Some(9)),
+        db.update(&2, |v| {
+            v.push(9);
+            v.pop()
+        })
+    );
+    assert_eq!(before, db.stats().bytes_written);
+
+    // Modified in place, written once.
+    pnk!(db.get_mut(&3)).push(30);
+    assert_eq!(
+        Some(2),
+        db.update(&0, |v| {
+            v.push(10);
+            v.len()
+        })
+    );
+    assert!(db.update(&9, |v| v.push(0)).is_none());
+    assert!(before < db.stats().bytes_written);
+    assert_eq!(vec![3, 30], *pnk!(db.get(&3)));
+    assert_eq!(vec![0, 10], *pnk!(db.get(&0)));
+
+    // The cached entries are still cached.
+    let hits = db.stats().cache_hits;
+    assert_eq!(vec![3, 30], *pnk!(db.get(&3)));
+    assert_eq!(hits + 1, db.stats().cache_hits);
+
+    let meta = pnk!(serde_json::to_string(&db));
+    let db: Mapx<usize, Vec<usize>> = pnk!(serde_json::from_str(&meta));
+    assert_eq!(vec![0, 10], *pnk!(db.get(&0)));
+    assert_eq!(4, db.len());
+}
+
+#[test]
+fn t_mapx_std_traits() {
+    let mut db = pnk!(Mapx::collect_into(
+        crate::unique_path!(),
+        (0..50).map(|i| (i, gen_sample(i)))
+    ));
+    Extend::extend(&mut db, (50..100).map(|i| (i, gen_sample(i))));
+    assert_eq!(100, db.len());
+
+    let mut cnt = 0;
+    for (k, v) in &db {
+        assert_eq!(gen_sample(k), v);
+        cnt += 1;
+    }
+    assert_eq!(100, cnt);
+
+    let dbg = format!("{:?}", db);
+    assert!(dbg.contains("len: 100"));
+    assert_eq!(DEBUG_PREVIEW_CNT, dbg.matches("SampleBlock").count());
+}
+
+#[test]
+fn t_mapx_overlay() {
+    let path = crate::unique_path!();
+    let mut db: Mapx<usize, usize> = pnk!(Mapx::new(path, Some(2), false));
+    (0..10).for_each(|i| db.set_value(i, i));
+
+    // Discarded.
+    {
+        let mut ov = db.overlay();
+        assert_eq!(Some(0), ov.remove(&0));
+        assert_eq!(None, ov.insert(100, 100));
+        assert_eq!(10, ov.len());
+        assert!(ov.get(&0).is_none());
+        assert_eq!(100, *pnk!(ov.get(&100)));
+    }
+    assert_eq!(0, *pnk!(db.get(&0)));
+    assert!(db.get(&100).is_none());
+
+    let mut ov = db.overlay();
+    assert_eq!(Some(9), ov.insert(9, 90));
+    ov.unset_value(&0);
+    ov.set_value(100, 100);
+
+    // A sub-transaction which fails.
+    {
+        let mut sub = ov.overlay();
+        sub.unset_value(&1);
+        sub.unset_value(&100);
+        assert_eq!(8, sub.len());
+    }
+    assert_eq!(10, ov.len());
+
+    // A sub-transaction which succeeds.
+    let mut sub = ov.overlay();
+    assert_eq!(Some(100), sub.remove(&100));
+    sub.set_value(200, 200);
+    assert_eq!(None, sub.remove(&300));
+    sub.commit();
+
+    let mut entries = ov.iter().collect::<Vec<_>>();
+    entries.sort_unstable();
+    assert_eq!(10, entries.len());
+    assert_eq!((9, 90), entries[8]);
+    assert_eq!((200, 200), entries[9]);
+    ov.commit();
+
+    assert_eq!(10, db.len());
+    assert!(db.get(&0).is_none());
+    assert!(db.get(&100).is_none());
+    assert_eq!(90, *pnk!(db.get(&9)));
+
+    let meta = pnk!(serde_json::to_string(&db));
+    let db: Mapx<usize, usize> = pnk!(serde_json::from_str(&meta));
+    let mut entries = db.iter().collect::<Vec<_>>();
+    entries.sort_unstable();
+    assert_eq!(entries, {
+        let mut ov_entries = (1..9).map(|i| (i, i)).collect::<Vec<_>>();
+        ov_entries.push((9, 90));
+        ov_entries.push((200, 200));
+        ov_entries
+    });
+
+    // The nested maps of the replaced values are dropped after committing.
+    let mut db: Mapx<usize, Mapx<usize, usize>> = crate::new_mapx!();
+    let mut inners = vec![];
+    for i in 0..3 {
+        let mut inner = pnk!(db.new_nested());
+        inner.set_value(i, i);
+        db.insert(i, inner.clone());
+        inners.push(inner);
+    }
+    let mut ov = db.overlay();
+    ov.unset_value(&0);
+    ov.set_value(1, inners[2].clone());
+    assert_eq!(1, inners[0].len());
+    ov.commit();
+    assert!(inners[0].is_empty());
+    assert!(inners[1].is_empty());
+    assert_eq!(1, inners[2].len());
+}
+
+#[test]
+fn t_mapx_merge() {
+    use crate::merge::{Add, Append, Max};
+
+    let path = crate::unique_path!();
+    let mut counters: Mapx<usize, u64> = pnk!(Mapx::new(path.clone(), Some(2), false));
+    assert!(counters.merge(&0, &1u64).is_err());
+
+    pnk!(counters.set_merge_operator(Add));
+    (0..10).for_each(|i| {
+        assert_eq!(Some(i + 1), pnk!(counters.merge(&0, &1u64)));
+    });
+    assert_eq!(Some(5), pnk!(counters.merge(&1, &5u64)));
+    assert_eq!(2, counters.len());
+    // The operand type is checked.
+    assert!(counters.merge(&0, &1i32).is_err());
+    assert_eq!(10, *pnk!(counters.get(&0)));
+
+    // A cached value is refreshed.
+    counters.insert(2, 100);
+    assert_eq!(Some(101), pnk!(counters.merge(&2, &1u64)));
+    assert_eq!(101, *pnk!(counters.get(&2)));
+
+    // The operator is not persisted.
+    let meta = pnk!(serde_json::to_string(&counters));
+    drop(counters);
+    let mut counters: Mapx<usize, u64> = pnk!(serde_json::from_str(&meta));
+    assert_eq!(3, counters.len());
+    assert!(counters.merge(&0, &1u64).is_err());
+    pnk!(counters.set_merge_operator(Max));
+    assert_eq!(Some(10), pnk!(counters.merge(&0, &3u64)));
+    assert_eq!(Some(20), pnk!(counters.merge(&0, &20u64)));
+
+    // A user-defined one, which removes the key when the value reaches 0.
+    pnk!(counters.set_merge_operator(|old: Option<u64>, delta: u64| {
+        old.unwrap_or(0).checked_sub(delta).filter(|v| 0 < *v)
+    }));
+    assert_eq!(Some(15), pnk!(counters.merge(&0, &5u64)));
+    assert_eq!(None, pnk!(counters.merge(&0, &15u64)));
+    assert_eq!(None, pnk!(counters.merge(&3, &1u64)));
+    assert_eq!(2, counters.len());
+    assert!(counters.get(&0).is_none());
+
+    let mut lists: Mapx<usize, Vec<String>> = crate::new_mapx!();
+    pnk!(lists.set_merge_operator(Append));
+    pnk!(lists.merge(&0, &"a".to_owned()));
+    assert_eq!(
+        Some(vec!["a".to_owned(), "b".to_owned()]),
+        pnk!(lists.merge(&0, &"b".to_owned()))
+    );
+    assert_eq!(1, lists.len());
+
+    let mut nested: Mapx<usize, u64> = pnk!(lists.new_nested());
+    assert!(nested.set_merge_operator(Add).is_err());
+}
+
+#[test]
+fn t_mapx_diff() {
+    let mut a: Mapx<usize, usize> = crate::new_mapx!();
+    let mut b: Mapx<usize, usize> = crate::new_mapx!();
+    (0..10).for_each(|i| a.set_value(i, i));
+    (5..15).for_each(|i| b.set_value(i, i));
+    b.set_value(7, 70);
+
+    // Only zipping the iterators, a prefix would be equal to the whole.
+    let c: Mapx<usize, usize> = crate::new_mapx!();
+    assert!(a != c);
+    assert!(a.diff(&a).next().is_none());
+
+    let diff = a.diff(&b).collect::<Vec<_>>();
+    assert_eq!(11, diff.len());
+    assert_eq!(
+        5,
+        diff.iter().filter(|d| matches!(d, Diff::Added(..))).count()
+    );
+    assert_eq!(
+        5,
+        diff.iter()
+            .filter(|d| matches!(d, Diff::Removed(..)))
+            .count()
+    );
+    assert!(diff.contains(&Diff::Changed {
+        key: 7,
+        old: 7,
+        new: 70
+    }));
+    // The same records from the view of `b`.
+    assert_eq!(
+        diff,
+        b.diff(&a)
+            .map(|d| match d {
+                Diff::Added(k, v) => Diff::Removed(k, v),
+                Diff::Removed(k, v) => Diff::Added(k, v),
+                Diff::Changed { key, old, new } => Diff::Changed {
+                    key,
+                    old: new,
+                    new: old,
+                },
+            })
+            .collect::<Vec<_>>()
+    );
+
+    // A three-way merge into a diverged copy of `a`.
+    let mut a2: Mapx<usize, usize> = crate::new_mapx!();
+    a2.extend(a.iter());
+    a2.set_value(0, 100);
+    a2.set_value(7, 70);
+    a2.set_value(10, 1000);
+    let conflicts = a2.apply_diff(diff.clone());
+    assert_eq!(conflicts, vec![Diff::Removed(0, 0), Diff::Added(10, 10)]);
+    assert_eq!(11, a2.len());
+    assert_eq!(100, *pnk!(a2.get(&0)));
+    assert_eq!(1000, *pnk!(a2.get(&10)));
+    assert!(a2.get(&1).is_none());
+    assert_eq!(14, *pnk!(a2.get(&14)));
+
+    assert!(a.apply_diff(diff).is_empty());
+    assert!(a == b);
+    assert!(a.diff(&b).next().is_none());
+}
+
+#[test]
+fn t_mapx_change_log() {
+    use crate::changelog::Follower;
+
+    let mut db: Mapx<usize, usize> = pnk!(Mapx::new(crate::unique_path!(), Some(2), false));
+    db.insert(100, 100);
+    assert!(db.change_log_seq().is_none());
+    assert!(db.changes_since(0).is_err());
+
+    // Shared by all handles.
+    let clone = db.clone();
+    pnk!(db.enable_change_log());
+    assert_eq!(Some(0), clone.change_log_seq());
+
+    (0..10).for_each(|i| db.set_value(i, i));
+    db.unset_value(&0);
+    db.unset_value(&1000);
+    *pnk!(db.get_mut(&1)) += 10;
+    db.extend((10..20).map(|i| (i, i)));
+    let mut ov = db.overlay();
+    ov.unset_value(&2);
+    ov.set_value(3, 30);
+    ov.commit();
+    pnk!(db.set_merge_operator(crate::merge::Add));
+    pnk!(db.merge(&4, &6usize));
+
+    let changes = pnk!(pnk!(db.changes_since(0)).collect::<Result<Vec<_>>>());
+    assert_eq!(Some(25), db.change_log_seq());
+    assert_eq!(25, changes.len());
+    assert!(changes
+        .iter()
+        .enumerate()
+        .all(|(i, c)| c.seq == 1 + i as u64));
+    assert_eq!(Op::Remove(0), changes[10].op);
+    assert_eq!(Op::Insert(1, 11), changes[11].op);
+    assert_eq!(Op::Insert(4, 10), changes[24].op);
+    assert_eq!(5, pnk!(db.changes_since(20)).count());
+
+    // A follower from scratch misses the entries written before enabling.
+    let path = crate::unique_path!();
+    let mut follower = pnk!(Follower::new(pnk!(Mapx::new(path.clone(), None, false))));
+    assert_eq!(
+        20,
+        pnk!(follower.apply_all(pnk!(db.changes_since(0)).take(20).map(|c| pnk!(c))))
+    );
+    // Applied again.
+    assert!(!pnk!(follower.apply(changes[5].clone())));
+    assert!(follower.apply(changes[21].clone()).is_err());
+    assert_eq!(20, follower.last_seq());
+
+    // Resumed after reopening.
+    let meta = pnk!(serde_json::to_string(follower.local()));
+    drop(follower);
+    let local: Mapx<usize, usize> = pnk!(serde_json::from_str(&meta));
+    let mut follower = pnk!(Follower::new(local));
+    assert_eq!(20, follower.last_seq());
+    pnk!(follower.apply_all(pnk!(db.changes_since(follower.last_seq())).map(|c| pnk!(c))));
+    db.unset_value(&100);
+    assert!(db == *follower.local());
+
+    assert_eq!(20, pnk!(db.compact_change_log(21)));
+    assert!(db.changes_since(19).is_err());
+    assert_eq!(6, pnk!(db.changes_since(20)).count());
+
+    // Still enabled after reopening.
+    let meta = pnk!(serde_json::to_string(&db));
+    drop((db, clone));
+    let mut db: Mapx<usize, usize> = pnk!(serde_json::from_str(&meta));
+    db.insert(1, 1);
+    assert_eq!(Some(27), db.change_log_seq());
+
+    let mut nested: Mapx<usize, usize> = pnk!(db.new_nested());
+    assert!(nested.enable_change_log().is_err());
+    assert!(nested.apply_change(changes[0].clone()).is_err());
+}
+
+// The leader of `t_mapx_follower_process`,
+// which prints its changes since `FUNDB_LEADER_SINCE`.
+#[test]
+#[ignore]
+fn t_mapx_leader_process() {
+    let path = match std::env::var("FUNDB_LEADER_PATH") {
+        Ok(path) => path,
+        Err(_) => return,
+    };
+    let since = pnk!(pnk!(std::env::var("FUNDB_LEADER_SINCE")).parse::<u64>());
+
+    let mut db: Mapx<usize, usize> = pnk!(Mapx::new_with_storage(path, None, false, Storage::Sled));
+    pnk!(db.enable_change_log());
+    let base = db.len();
+    (base..base + 100).for_each(|i| db.set_value(i, i));
+    (base..base + 100)
+        .step_by(3)
+        .for_each(|i| db.unset_value(&i));
+    for change in pnk!(db.changes_since(since)) {
+        println!("CHANGE {}", pnk!(serde_json::to_string(&pnk!(change))));
+    }
+}
+
+#[test]
+fn t_mapx_follower_process() {
+    use crate::changelog::Follower;
+    use std::process::Command;
+
+    let leader_path = crate::unique_path!();
+    let local: Mapx<usize, usize> = crate::new_mapx!();
+    let mut follower = pnk!(Follower::new(local));
+
+    for _ in 0..2 {
+        let out = pnk!(Command::new(pnk!(std::env::current_exe()))
+            .args([
+                "--exact",
+                "mapx::test::t_mapx_leader_process",
+                "--ignored",
+                "--nocapture",
+            ])
+            .env("FUNDB_LEADER_PATH", &leader_path)
+            .env("FUNDB_LEADER_SINCE", follower.last_seq().to_string())
+            .output());
+        assert!(out.status.success());
+
+        let changes = String::from_utf8_lossy(&out.stdout)
+            .lines()
+            .filter_map(|l| l.split("CHANGE ").nth(1))
+            .map(|l| pnk!(serde_json::from_str::<Change<usize, usize>>(l)))
+            .collect::<Vec<_>>();
+        assert_eq!(134, changes.len());
+        assert_eq!(134, pnk!(follower.apply_all(changes)));
+    }
+
+    assert_eq!(268, follower.last_seq());
+    let leader = pnk!(Mapx::<usize, usize>::open_read_only(leader_path));
+    assert_eq!(Some(268), leader.change_log_seq());
+    assert!(*leader == *follower.local());
+}
+
+#[test]
+fn t_mapx_checkpoint() {
+    use crate::database::{Checkpoint, Database};
+    use std::{fs, thread};
+
+    for storage in [Storage::Sled, Storage::Log].iter().copied() {
+        let path = crate::unique_path!();
+        let mut db: Mapx<usize, Mapx<usize, usize>> =
+            pnk!(Mapx::new_with_storage(path.clone(), None, false, storage));
+        (0..10).for_each(|i| {
+            let mut inner = pnk!(db.new_nested());
+            (0..i).for_each(|j| inner.set_value(j, i * j));
+            db.insert(i, inner);
+        });
+        pnk!(db.enable_change_log());
+
+        // Keep writing during checkpointing.
+        let writer = {
+            let mut db = db.clone();
+            thread::spawn(move || {
+                (10..2000).for_each(|i| {
+                    let inner = pnk!(db.new_nested());
+                    db.insert(i, inner);
+                })
+            })
+        };
+        let cp_path = crate::unique_path!();
+        let cp = pnk!(pnk!(Database::open(&path)).checkpoint(&cp_path));
+        pnk!(writer.join().map_err(|_| eg!()));
+        assert_eq!(cp, pnk!(Checkpoint::load(&cp_path)));
+        assert_eq!(path, cp.source);
+        assert!(pnk!(Database::open(&path)).checkpoint(&cp_path).is_err());
+
+        let new_path = crate::unique_path!();
+        let restored = pnk!(Database::restore_from_checkpoint(&cp_path, &new_path));
+        assert_eq!(storage, restored.storage());
+        let mut restored: Mapx<usize, Mapx<usize, usize>> = pnk!(Mapx::new_with_storage(
+            new_path.clone(),
+            None,
+            false,
+            storage
+        ));
+
+        // A consistent snapshot of the sequential writes.
+        let len = restored.len();
+        assert!((10..=2000).contains(&len));
+        assert!((0..len).all(|i| restored.contains_key(&i)));
+        // The change being written may have been recorded without its data.
+        let seq = pnk!(restored.change_log_seq()) as usize;
+        assert!((len - 10..=len - 9).contains(&seq));
+        (0..10).for_each(|i| {
+            let inner = pnk!(restored.get(&i)).into_inner().into_owned();
+            assert_eq!(new_path, inner.get_data_path());
+            assert_eq!(i, inner.len());
+            assert!((0..i).all(|j| Some(i * j) == inner.get(&j).map(|v| *v)));
+        });
+
+        // Independent of the source.
+        let mut inner = pnk!(restored.get(&5)).into_inner().into_owned();
+        inner.set_value(100, 100);
+        assert_eq!(6, inner.len());
+        assert_eq!(5, pnk!(db.get(&5)).len());
+        let mut nested: Mapx<usize, usize> = pnk!(restored.new_nested());
+        nested.set_value(0, 0);
+        restored.insert(len, nested);
+        assert_eq!(9, pnk!(restored.get(&9)).len());
+        assert_eq!(1, pnk!(restored.get(&len)).len());
+
+        // A broken checkpoint is rejected.
+        let mut broken = cp.clone();
+        broken.trees[0].hash ^= 1;
+        pnk!(fs::write(
+            format!("{}/____checkpoint____", cp_path),
+            pnk!(serde_json::to_vec(&broken))
+        ));
+        assert!(Database::restore_from_checkpoint(&cp_path, &crate::unique_path!()).is_err());
+    }
+
+    // Nothing to back up in memory.
+    let db: Mapx<usize, usize> = pnk!(Mapx::new_with_storage(
+        crate::unique_path!(),
+        None,
+        false,
+        Storage::Memory
+    ));
+    assert!(pnk!(Database::open(db.get_data_path()))
+        .checkpoint(&crate::unique_path!())
+        .is_err());
+}
+
+#[test]
+fn t_mapx_query() {
+    use std::{
+        collections::HashSet,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    static DECODED: AtomicUsize = AtomicUsize::new(0);
+
+    // Count how many times the values are decoded.
+    #[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+    struct Balance(usize);
+
+    impl<'de> Deserialize<'de> for Balance {
+        fn deserialize<D: serde::Deserializer<'de>>(d: D) -> std::result::Result<Self, D::Error> {
+            DECODED.fetch_add(1, Ordering::Relaxed);
+            usize::deserialize(d).map(Balance)
+        }
+    }
+
+    let mut db: Mapx<usize, Balance> = pnk!(Mapx::new(crate::unique_path!(), Some(0), false));
+    db.extend((0..100).map(|i| (i, Balance(i * 10))));
+    let all = db.keys().collect::<Vec<_>>();
+
+    // Keys only, nothing is decoded.
+    DECODED.store(0, Ordering::Relaxed);
+    assert_eq!(50, db.query().filter_key(|k| 0 == k % 2).count());
+    assert_eq!(10, db.query().range(10..20).count());
+    assert_eq!(
+        [17, 18].iter().copied().collect::<HashSet<_>>(),
+        db.query()
+            .range(10..20)
+            .filter_key(|k| 5 < k % 10)
+            .offset(1)
+            .limit(2)
+            .keys()
+            .collect::<HashSet<_>>()
+    );
+    assert_eq!(0, DECODED.load(Ordering::Relaxed));
+
+    // Only the returned values are decoded.
+    let res = db
+        .query()
+        .range(..50)
+        .offset(10)
+        .limit(5)
+        .iter()
+        .collect::<Vec<_>>();
+    assert_eq!(5, res.len());
+    assert_eq!(5, DECODED.load(Ordering::Relaxed));
+    assert!(res.iter().all(|(k, v)| *k < 50 && v.0 == k * 10));
+
+    // Filters on values.
+    let res = db
+        .query()
+        .filter(|_, v| v.0 > 900)
+        .iter()
+        .map(|(k, _)| k)
+        .collect::<Vec<_>>();
+    assert_eq!(9, res.len());
+    assert!(res.iter().all(|k| *k > 90));
+
+    // The same order as `iter()`, or reversed.
+    assert_eq!(all, db.query().keys().collect::<Vec<_>>());
+    let mut rev = db.query().reverse().keys().collect::<Vec<_>>();
+    rev.reverse();
+    assert_eq!(all, rev);
+
+    // Paginated by cursors, which survive serialization.
+    for reverse in [false, true].iter().copied() {
+        let query = || {
+            let q = db.query().filter_key(|k| 0 == k % 3).limit(7);
+            if reverse {
+                q.reverse()
+            } else {
+                q
+            }
+        };
+        let mut cursor = None;
+        let mut pages = vec![];
+        loop {
+            let page = match cursor.take() {
+                Some(c) => query().after(c).page(),
+                None => query().page(),
+            };
+            assert!(page.items.len() <= 7);
+            pages.extend(page.items.into_iter().map(|(k, _)| k));
+            match page.next {
+                Some(c) => {
+                    cursor = Some(pnk!(serde_json::from_str(&pnk!(serde_json::to_string(&c)))))
+                }
+                None => break,
+            }
+        }
+        assert_eq!(query().limit(usize::MAX).keys().collect::<Vec<_>>(), pages);
+        assert_eq!(34, pages.len());
+    }
+
+    // A cursor from an iterator.
+    let mut iter = db.query().keys();
+    let first = iter.by_ref().take(30).collect::<Vec<_>>();
+    let rest = db
+        .query()
+        .after(pnk!(iter.cursor()))
+        .keys()
+        .collect::<Vec<_>>();
+    assert_eq!(all, [first, rest].concat());
+
+    // Within a nested map.
+    let mut outer: Mapx<usize, Mapx<usize, usize>> = crate::new_mapx!();
+    let mut inner: Mapx<usize, usize> = pnk!(outer.new_nested());
+    inner.extend((0..10).map(|i| (i, i)));
+    let mut other: Mapx<usize, usize> = pnk!(outer.new_nested());
+    other.extend((0..10).map(|i| (i, i)));
+    outer.insert(0, inner.clone());
+    assert_eq!(10, inner.query().count());
+    let page = inner.query().limit(4).page();
+    let next = pnk!(page.next);
+    assert_eq!(6, inner.query().after(next.clone()).count());
+    assert_eq!(3, inner.query().after(next).reverse().count());
+}
+
+#[test]
+fn t_mapx_sharded() {
+    use std::{
+        path::Path,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    let paths = (0..4).map(|_| crate::unique_path!()).collect::<Vec<_>>();
+    let mut db: ShardedMapx<usize, usize> = pnk!(ShardedMapx::new_with_storage(
+        paths.clone(),
+        None,
+        false,
+        Storage::Sled
+    ));
+    (0..1000).for_each(|i| assert!(db.insert(i, i * 2).is_none()));
+    (0..100).for_each(|i| assert_eq!(Some(i * 2), db.remove(&i)));
+    assert_eq!(900, db.len());
+    assert_eq!(paths, db.paths());
+
+    let stats = db.shard_stats();
+    assert_eq!(900, stats.iter().map(|s| s.len).sum::<usize>());
+    assert!(stats
+        .iter()
+        .all(|s| 100 < s.len && 0 < s.stats.bytes_written));
+    (100..1000).for_each(|i| {
+        assert_eq!(i * 2, *pnk!(db.get(&i)));
+        assert!(db.shards()[db.shard_of(&i)].contains_key(&i));
+    });
+
+    let sum = AtomicUsize::new(0);
+    db.par_for_each(|_, v| {
+        sum.fetch_add(v, Ordering::Relaxed);
+    });
+    assert_eq!(db.iter().map(|(_, v)| v).sum::<usize>(), sum.into_inner());
+    assert_eq!(
+        db.shard_stats().iter().map(|s| s.len).collect::<Vec<_>>(),
+        db.par_map_shards(|s| s.len())
+    );
+    // The panic of a shard is resumed as it is.
+    let e = pnk!(std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        db.par_map_shards(|s| {
+            if s.contains_key(&100) {
+                panic!("shard of 100");
+            }
+        })
+    }))
+    .err());
+    assert_eq!(Some(&"shard of 100"), e.downcast_ref::<&str>());
+    db.flush_data();
+    drop(db);
+
+    // The shards must be opened in the same order with the same number.
+    assert!(ShardedMapx::<usize, usize>::new_with_storage(
+        paths[..3].to_vec(),
+        None,
+        false,
+        Storage::Sled
+    )
+    .is_err());
+    assert!(ShardedMapx::<usize, usize>::new_with_storage(
+        paths.iter().rev().cloned().collect(),
+        None,
+        false,
+        Storage::Sled
+    )
+    .is_err());
+    let db: ShardedMapx<usize, usize> = pnk!(ShardedMapx::new_with_storage(
+        paths.clone(),
+        None,
+        false,
+        Storage::Sled
+    ));
+    assert_eq!(900, db.len());
+
+    // Keep two shards and add four.
+    let new_paths = paths[2..]
+        .iter()
+        .cloned()
+        .chain((0..4).map(|_| crate::unique_path!()))
+        .collect::<Vec<_>>();
+    let mut db = pnk!(db.reshard(new_paths.clone()));
+    assert_eq!(6, db.shard_count());
+    assert_eq!(900, db.len());
+    assert!(!Path::new(&paths[0]).exists() && !Path::new(&paths[1]).exists());
+    (100..1000).for_each(|i| {
+        assert!(db.shards()[db.shard_of(&i)].contains_key(&i));
+        assert_eq!(i * 2, *pnk!(db.get(&i)));
+    });
+    db.set_value(0, 0);
+    drop(db);
+
+    // Back to a single shard.
+    let db: ShardedMapx<usize, usize> = pnk!(ShardedMapx::new_with_storage(
+        new_paths,
+        None,
+        false,
+        Storage::Sled
+    ));
+    let db = pnk!(db.reshard(vec![paths[3].clone()]));
+    assert_eq!(901, db.len());
+    assert_eq!(901, db.shards()[0].len());
+
+    // A plain map can be resharded, an unknown non-empty one can not be a shard.
+    let path = crate::unique_path!();
+    let mut plain: Mapx<usize, usize> = pnk!(Mapx::new_with_storage(
+        path.clone(),
+        None,
+        false,
+        Storage::Sled
+    ));
+    plain.extend((0..10).map(|i| (i, i)));
+    drop(plain);
+    let shard_paths = vec![path.clone(), crate::unique_path!()];
+    assert!(ShardedMapx::<usize, usize>::new_with_storage(
+        shard_paths.clone(),
+        None,
+        false,
+        Storage::Sled
+    )
+    .is_err());
+    let db: ShardedMapx<usize, usize> = pnk!(ShardedMapx::new_with_storage(
+        vec![path],
+        None,
+        false,
+        Storage::Sled
+    ));
+    let db = pnk!(db.reshard(shard_paths));
+    assert_eq!(10, db.len());
+    assert!(db.shard_stats().iter().all(|s| 0 < s.len));
+}
+
+#[test]
+fn t_mapx_sharded_interrupted() {
+    use std::{fs, path::Path};
+
+    let open = |paths: &[String]| {
+        ShardedMapx::<usize, usize>::new_with_storage(paths.to_vec(), None, false, Storage::Sled)
+    };
+
+    let from = (0..2).map(|_| crate::unique_path!()).collect::<Vec<_>>();
+    let mut db = pnk!(open(&from));
+    (0..100).for_each(|i| db.set_value(i, i));
+    drop(db);
+
+    // Leave the manifests as a reshard killed after marking the shards.
+    let to = vec![from[1].clone(), crate::unique_path!()];
+    let mark = |path: &str, index: usize, count: usize| {
+        let meta = serde_json::json!({
+            "index": index,
+            "count": count,
+            "resharding": { "from": from, "to": to },
+        });
+        pnk!(fs::create_dir_all(path));
+        pnk!(fs::write(
+            format!("{}/____shard____", path),
+            meta.to_string()
+        ));
+    };
+    mark(&from[0], 0, 2);
+    mark(&from[1], 1, 2);
+    mark(&to[1], 1, 2);
+
+    // Either layout can be opened, the new one misses the entries not moved yet.
+    assert!(open(&[from[1].clone(), from[0].clone()]).is_err());
+    let mut db = pnk!(open(&to));
+    assert!(db.len() < 100);
+    (0..50).for_each(|i| db.set_value(i, i));
+    drop(db);
+
+    let db = pnk!(open(&from));
+    let db = pnk!(db.reshard(to.clone()));
+    assert_eq!(100, db.len());
+    (0..100).for_each(|i| assert_eq!(i, *pnk!(db.get(&i))));
+    assert!(!Path::new(&from[0]).exists());
+    drop(db);
+
+    // The marks are cleared once the reshard finishes.
+    assert!(open(&from).is_err());
+    assert_eq!(100, pnk!(open(&to)).len());
+}
+
+#[cfg(feature = "tracing")]
+#[test]
+fn t_mapx_tracing() {
+    use std::{
+        fmt::Write,
+        sync::{
+            atomic::{AtomicU64, Ordering},
+            Arc, Mutex,
+        },
+    };
+    use tracing::{
+        field::{Field, Visit},
+        span, Event, Metadata, Subscriber,
+    };
+
+    // Collect the names and fields of all spans and events.
+    #[derive(Default)]
+    struct Collector {
+        next_id: AtomicU64,
+        records: Arc<Mutex<Vec<String>>>,
+    }
+
+    struct Fields<'a>(&'a mut String);
+
+    impl<'a> Visit for Fields<'a> {
+        fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+            let _ = write!(self.0, " {}={:?}", field.name(), value);
+        }
+    }
+
+    impl Subscriber for Collector {
+        fn enabled(&self, _: &Metadata<'_>) -> bool {
+            true
+        }
+        fn new_span(&self, span: &span::Attributes<'_>) -> span::Id {
+            let mut record = span.metadata().name().to_owned();
+            span.record(&mut Fields(&mut record));
+            self.records.lock().unwrap().push(record);
+            span::Id::from_u64(1 + self.next_id.fetch_add(1, Ordering::Relaxed))
+        }
+        fn record(&self, _: &span::Id, _: &span::Record<'_>) {}
+        fn record_follows_from(&self, _: &span::Id, _: &span::Id) {}
+        fn event(&self, event: &Event<'_>) {
+            let mut record = String::new();
+            event.record(&mut Fields(&mut record));
+            self.records.lock().unwrap().push(record);
+        }
+        fn enter(&self, _: &span::Id) {}
+        fn exit(&self, _: &span::Id) {}
+    }
+
+    let collector = Collector::default();
+    let records = Arc::clone(&collector.records);
+    let path = crate::unique_path!();
+    tracing::subscriber::with_default(collector, || {
+        let mut db: Mapx<usize, usize> = pnk!(Mapx::new_with_storage(
+            path.clone(),
+            Some(1),
+            false,
+            Storage::Sled
+        ));
+        (0..3).for_each(|i| assert!(db.insert(i, i).is_none()));
+        assert_eq!(Some(1), db.remove(&1));
+        db.flush_data();
+        drop(db);
+
+        // Read from disk.
+        let db: Mapx<usize, usize> = pnk!(Mapx::new_with_storage(
+            path.clone(),
+            Some(1),
+            false,
+            Storage::Sled
+        ));
+        assert!(db.get(&0).is_some());
+    });
+
+    let records = records.lock().unwrap();
+    let has = |name: &str, fields: &[&str]| {
+        records
+            .iter()
+            .any(|r| r.contains(name) && fields.iter().all(|f| r.contains(f)))
+    };
+    assert!(has(
+        "fundb.load_or_create",
+        &[&format!("path={}", path), "storage=Sled"]
+    ));
+    assert!(has("fundb.write_db_len", &["len=0"]));
+    assert!(has("fundb.insert", &["key_size=8", "value_size="]));
+    assert!(has("fundb.remove", &["key_size=8"]));
+    assert!(has("fundb.get", &["key_size=8"]));
+    assert!(has("fundb.evict", &[&format!("path={}", path)]));
+    assert!(has("fundb.flush", &[&format!("path={}", path)]));
+    assert!(records.iter().any(|r| r.starts_with(" value_size=")));
+}
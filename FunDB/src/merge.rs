@@ -0,0 +1,83 @@
+//!
+//! # Typed Merge Operators
+//!
+//! A merge operator combines the current value of a key with an operand
+//! inside the storage backend, see `Mapx::set_merge_operator` and `Mapx::merge`,
+//! so a counter or a list can be updated without reading it out first.
+//!
+
+use serde::{de::DeserializeOwned, Serialize};
+use std::ops;
+
+/// Combine the current value of a key with an operand.
+///
+/// Any `Fn(Option<V>, V) -> Option<V>` is an operator whose operand is `V`.
+pub trait MergeOperator<V>: Send + Sync + 'static {
+    /// The type of the operands.
+    type Operand: Serialize + DeserializeOwned + 'static;
+
+    /// Return the new value, `None` means removing the key.
+    fn merge(&self, old: Option<V>, operand: Self::Operand) -> Option<V>;
+}
+
+/// Add the operand to the value, a missing value is taken as the default one.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Add;
+
+impl<V> MergeOperator<V> for Add
+where
+    V: ops::Add<Output = V> + Default + Serialize + DeserializeOwned + 'static,
+{
+    type Operand = V;
+
+    fn merge(&self, old: Option<V>, operand: V) -> Option<V> {
+        Some(old.unwrap_or_default() + operand)
+    }
+}
+
+/// Keep the larger one of the value and the operand.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Max;
+
+impl<V> MergeOperator<V> for Max
+where
+    V: Ord + Serialize + DeserializeOwned + 'static,
+{
+    type Operand = V;
+
+    fn merge(&self, old: Option<V>, operand: V) -> Option<V> {
+        Some(match old {
+            Some(old) => old.max(operand),
+            None => operand,
+        })
+    }
+}
+
+/// Push the operand to the end of a list, a missing list is taken as an empty one.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Append;
+
+impl<T> MergeOperator<Vec<T>> for Append
+where
+    T: Serialize + DeserializeOwned + 'static,
+{
+    type Operand = T;
+
+    fn merge(&self, old: Option<Vec<T>>, operand: T) -> Option<Vec<T>> {
+        let mut list = old.unwrap_or_default();
+        list.push(operand);
+        Some(list)
+    }
+}
+
+impl<V, F> MergeOperator<V> for F
+where
+    V: Serialize + DeserializeOwned + 'static,
+    F: Fn(Option<V>, V) -> Option<V> + Send + Sync + 'static,
+{
+    type Operand = V;
+
+    fn merge(&self, old: Option<V>, operand: V) -> Option<V> {
+        self(old, operand)
+    }
+}
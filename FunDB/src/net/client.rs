@@ -0,0 +1,484 @@
+//!
+//! # Client of a FunDB Server
+//!
+
+use super::protocol::{read_frame, write_frame, Kind, Request, Response, MAX_RANGE_LEN};
+use crate::changelog::{Change, Op};
+use ruc::*;
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    collections::VecDeque,
+    fmt,
+    hash::Hash,
+    io::{Read, Write},
+    marker::PhantomData,
+    net::{TcpStream, ToSocketAddrs},
+    ops::{Bound, Range, RangeBounds},
+    sync::{Arc, Mutex},
+};
+
+trait Stream: Read + Write + Send {}
+
+impl<S: Read + Write + Send> Stream for S {}
+
+// How to open another connection to the same server.
+#[derive(Debug, Clone)]
+pub(super) enum Addr {
+    Tcp(std::net::SocketAddr),
+    #[cfg(unix)]
+    Unix(std::path::PathBuf),
+}
+
+impl Addr {
+    fn connect(&self) -> Result<Box<dyn Stream>> {
+        match self {
+            Addr::Tcp(addr) => {
+                let stream = TcpStream::connect(addr).c(d!())?;
+                stream.set_nodelay(true).c(d!())?;
+                Ok(Box::new(stream))
+            }
+            #[cfg(unix)]
+            Addr::Unix(path) => std::os::unix::net::UnixStream::connect(path)
+                .c(d!())
+                .map(|s| Box::new(s) as Box<dyn Stream>),
+        }
+    }
+}
+
+/// A connection to a [Server](super::Server), shared by all handles got from it,
+/// whose requests are sent one by one.
+#[derive(Clone)]
+pub struct Client {
+    pub(super) addr: Addr,
+    conn: Arc<Mutex<Box<dyn Stream>>>,
+}
+
+impl fmt::Debug for Client {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Client").field("addr", &self.addr).finish()
+    }
+}
+
+impl Client {
+    /// Connect to a server by TCP.
+    pub fn connect(addr: impl ToSocketAddrs) -> Result<Self> {
+        let addr = addr
+            .to_socket_addrs()
+            .c(d!())?
+            .next()
+            .c(d!("no address to connect"))?;
+        Client::new(Addr::Tcp(addr)).c(d!())
+    }
+
+    /// Connect to a server by a Unix socket.
+    #[cfg(unix)]
+    pub fn connect_unix(path: impl AsRef<std::path::Path>) -> Result<Self> {
+        Client::new(Addr::Unix(path.as_ref().to_owned())).c(d!())
+    }
+
+    fn new(addr: Addr) -> Result<Self> {
+        let conn = addr.connect().c(d!())?;
+        Ok(Client {
+            addr,
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    /// Get the map named `name`, which is created if it does not exist.
+    pub fn mapx<K, V>(&self, name: &str) -> RemoteMapx<K, V>
+    where
+        K: Clone + Eq + PartialEq + Hash + Serialize + DeserializeOwned + fmt::Debug,
+        V: Clone + Eq + PartialEq + Serialize + DeserializeOwned + fmt::Debug,
+    {
+        RemoteMapx {
+            client: self.clone(),
+            name: name.to_owned(),
+            _pd: PhantomData,
+        }
+    }
+
+    /// Get the vector named `name`, which is created if it does not exist.
+    pub fn vecx<T>(&self, name: &str) -> RemoteVecx<T>
+    where
+        T: Eq + PartialEq + Clone + Serialize + DeserializeOwned + fmt::Debug,
+    {
+        RemoteVecx {
+            client: self.clone(),
+            name: name.to_owned(),
+            _pd: PhantomData,
+        }
+    }
+
+    fn request(&self, req: &Request) -> Result<Response> {
+        let mut conn = self.conn.lock().unwrap();
+        write_frame(&mut *conn, req).c(d!())?;
+        match read_frame(&mut *conn).c(d!())? {
+            Some(Response::Error(e)) => Err(eg!(e)),
+            Some(resp) => Ok(resp),
+            None => Err(eg!("the connection is closed by the server")),
+        }
+    }
+
+    // A watch occupies a new connection.
+    fn watch<K, V>(&self, name: &str, kind: Kind, since: Option<u64>) -> Result<Watcher<K, V>> {
+        let mut conn = self.addr.connect().c(d!())?;
+        let req = Request::Watch {
+            name: name.to_owned(),
+            kind,
+            since,
+        };
+        write_frame(&mut conn, &req).c(d!())?;
+        match read_frame(&mut conn).c(d!())? {
+            Some(Response::Watching(seq)) => Ok(Watcher {
+                conn,
+                seq,
+                _pd: PhantomData,
+            }),
+            Some(Response::Error(e)) => Err(eg!(e)),
+            _ => Err(eg!("unexpected response")),
+        }
+    }
+}
+
+///////////////////////////////////////////////
+// Begin of the implementation of RemoteMapx //
+/*********************************************/
+
+/// A [Mapx](crate::Mapx) on a server, with the same methods,
+/// every one of which is a request that may fail.
+#[derive(Debug, Clone)]
+pub struct RemoteMapx<K, V> {
+    client: Client,
+    name: String,
+    _pd: PhantomData<(K, V)>,
+}
+
+impl<K, V> RemoteMapx<K, V>
+where
+    K: Clone + Eq + PartialEq + Hash + Serialize + DeserializeOwned + fmt::Debug,
+    V: Clone + Eq + PartialEq + Serialize + DeserializeOwned + fmt::Debug,
+{
+    /// Imitate the behavior of 'HashMap<_>.get(...)'
+    pub fn get(&self, key: &K) -> Result<Option<V>> {
+        let req = Request::MapGet {
+            name: self.name.clone(),
+            key: encode_key(key).c(d!())?,
+        };
+        self.client.request(&req).c(d!()).and_then(decode_value)
+    }
+
+    /// Imitate the behavior of 'HashMap<_>.contains_key(...)'
+    pub fn contains_key(&self, key: &K) -> Result<bool> {
+        let req = Request::MapContainsKey {
+            name: self.name.clone(),
+            key: encode_key(key).c(d!())?,
+        };
+        match self.client.request(&req).c(d!())? {
+            Response::Bool(b) => Ok(b),
+            _ => Err(eg!("unexpected response")),
+        }
+    }
+
+    /// Imitate the behavior of 'HashMap<_>.len()'.
+    pub fn len(&self) -> Result<usize> {
+        let req = Request::MapLen {
+            name: self.name.clone(),
+        };
+        self.client.request(&req).c(d!()).and_then(decode_len)
+    }
+
+    /// A helper func
+    pub fn is_empty(&self) -> Result<bool> {
+        self.len().c(d!()).map(|len| 0 == len)
+    }
+
+    /// Imitate the behavior of 'HashMap<_>.insert(...)'.
+    pub fn insert(&self, key: K, value: V) -> Result<Option<V>> {
+        let req = Request::MapInsert {
+            name: self.name.clone(),
+            key: encode_key(&key).c(d!())?,
+            value: serde_json::to_vec(&value).c(d!())?,
+        };
+        self.client.request(&req).c(d!()).and_then(decode_value)
+    }
+
+    /// Similar with `insert`, but ignore if the old value is exist.
+    pub fn set_value(&self, key: K, value: V) -> Result<()> {
+        self.insert(key, value).c(d!()).map(|_| ())
+    }
+
+    /// Imitate the behavior of 'HashMap<_>.remove(...)'.
+    pub fn remove(&self, key: &K) -> Result<Option<V>> {
+        let req = Request::MapRemove {
+            name: self.name.clone(),
+            key: encode_key(key).c(d!())?,
+        };
+        self.client.request(&req).c(d!()).and_then(decode_value)
+    }
+
+    /// Similar with `remove`, but ignore if the old value is exist.
+    pub fn unset_value(&self, key: &K) -> Result<()> {
+        self.remove(key).c(d!()).map(|_| ())
+    }
+
+    /// Iterate over all entries in the order of the map on the server,
+    /// they are got page by page.
+    pub fn iter(&self) -> RemoteMapxIter<K, V> {
+        RemoteMapxIter {
+            map: self.clone(),
+            lo: Bound::Unbounded,
+            hi: Bound::Unbounded,
+            buf: VecDeque::new(),
+            done: false,
+        }
+    }
+
+    /// Iterate over the entries whose keys are within `range` like `iter`,
+    /// the bounds are compared with the keys in their encoded forms,
+    /// which is not the order of `Ord` in general.
+    pub fn range<R: RangeBounds<K>>(&self, range: R) -> Result<RemoteMapxIter<K, V>> {
+        Ok(RemoteMapxIter {
+            lo: encode_bound(range.start_bound()).c(d!())?,
+            hi: encode_bound(range.end_bound()).c(d!())?,
+            ..self.iter()
+        })
+    }
+
+    /// Receive the changes after `since` by a new connection,
+    /// or the ones from now on if it is `None`.
+    ///
+    /// The change log of the map is enabled on the server.
+    pub fn watch(&self, since: Option<u64>) -> Result<Watcher<K, V>> {
+        self.client.watch(&self.name, Kind::Map, since).c(d!())
+    }
+}
+
+/// Iter over the entries of a [RemoteMapx](self::RemoteMapx),
+/// a page of entries is requested when the got ones run out.
+pub struct RemoteMapxIter<K, V> {
+    map: RemoteMapx<K, V>,
+    // The key of the last entry got is excluded from the next page.
+    lo: Bound<Vec<u8>>,
+    hi: Bound<Vec<u8>>,
+    buf: VecDeque<(Vec<u8>, Vec<u8>)>,
+    done: bool,
+}
+
+impl<K, V> RemoteMapxIter<K, V> {
+    fn fetch(&mut self) -> Result<()> {
+        let req = Request::MapRange {
+            name: self.map.name.clone(),
+            lo: self.lo.clone(),
+            hi: self.hi.clone(),
+            limit: MAX_RANGE_LEN,
+        };
+        match self.map.client.request(&req).c(d!())? {
+            Response::Entries(entries) => {
+                self.done = (entries.len() as u64) < MAX_RANGE_LEN;
+                if let Some((k, _)) = entries.last() {
+                    self.lo = Bound::Excluded(k.clone());
+                }
+                self.buf.extend(entries);
+                Ok(())
+            }
+            _ => Err(eg!("unexpected response")),
+        }
+    }
+}
+
+impl<K, V> Iterator for RemoteMapxIter<K, V>
+where
+    K: DeserializeOwned,
+    V: DeserializeOwned,
+{
+    type Item = Result<(K, V)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.buf.is_empty() && !self.done {
+            if let Err(e) = self.fetch().c(d!()) {
+                self.done = true;
+                return Some(Err(e));
+            }
+        }
+        let (k, v) = self.buf.pop_front()?;
+        Some(
+            bincode::deserialize(&k)
+                .c(d!())
+                .and_then(|k| serde_json::from_slice(&v).c(d!()).map(|v| (k, v))),
+        )
+    }
+}
+
+/*******************************************/
+// End of the implementation of RemoteMapx //
+/////////////////////////////////////////////
+
+///////////////////////////////////////////////
+// Begin of the implementation of RemoteVecx //
+/*********************************************/
+
+/// A [Vecx](crate::Vecx) on a server, with the same methods,
+/// every one of which is a request that may fail.
+#[derive(Debug, Clone)]
+pub struct RemoteVecx<T> {
+    client: Client,
+    name: String,
+    _pd: PhantomData<T>,
+}
+
+impl<T> RemoteVecx<T>
+where
+    T: Eq + PartialEq + Clone + Serialize + DeserializeOwned + fmt::Debug,
+{
+    /// Imitate the behavior of 'Vec<_>.get(...)'
+    pub fn get(&self, idx: usize) -> Result<Option<T>> {
+        let req = Request::VecGet {
+            name: self.name.clone(),
+            idx: idx as u64,
+        };
+        self.client.request(&req).c(d!()).and_then(decode_value)
+    }
+
+    /// Get the items in `range`, which is cut at the end of the vector,
+    /// a long range is got by many requests.
+    pub fn get_range(&self, range: Range<usize>) -> Result<Vec<T>> {
+        let mut items = vec![];
+        let mut start = range.start as u64;
+        while start < range.end as u64 {
+            let req = Request::VecRange {
+                name: self.name.clone(),
+                start,
+                end: range.end as u64,
+            };
+            let page = match self.client.request(&req).c(d!())? {
+                Response::Values(page) => page,
+                _ => return Err(eg!("unexpected response")),
+            };
+            for v in page.iter() {
+                items.push(serde_json::from_slice(v).c(d!())?);
+            }
+            if (page.len() as u64) < MAX_RANGE_LEN {
+                break;
+            }
+            start += MAX_RANGE_LEN;
+        }
+        Ok(items)
+    }
+
+    /// Imitate the behavior of 'Vec<_>.last()'
+    pub fn last(&self) -> Result<Option<T>> {
+        match self.len().c(d!())?.checked_sub(1) {
+            Some(idx) => self.get(idx).c(d!()),
+            None => Ok(None),
+        }
+    }
+
+    /// Imitate the behavior of 'Vec<_>.len()'
+    pub fn len(&self) -> Result<usize> {
+        let req = Request::VecLen {
+            name: self.name.clone(),
+        };
+        self.client.request(&req).c(d!()).and_then(decode_len)
+    }
+
+    /// A helper func
+    pub fn is_empty(&self) -> Result<bool> {
+        self.len().c(d!()).map(|len| 0 == len)
+    }
+
+    /// Imitate the behavior of 'Vec<_>.push(...)'
+    pub fn push(&self, b: T) -> Result<()> {
+        let req = Request::VecPush {
+            name: self.name.clone(),
+            value: serde_json::to_vec(&b).c(d!())?,
+        };
+        self.client.request(&req).c(d!()).map(|_| ())
+    }
+
+    /// Receive the pushes after `since` by a new connection,
+    /// or the ones from now on if it is `None`.
+    pub fn watch(&self, since: Option<u64>) -> Result<Watcher<usize, T>> {
+        self.client.watch(&self.name, Kind::Vec, since).c(d!())
+    }
+}
+
+/*******************************************/
+// End of the implementation of RemoteVecx //
+/////////////////////////////////////////////
+
+/// Receive the changes of a remote collection,
+/// `next` blocks until a change arrives.
+pub struct Watcher<K, V> {
+    conn: Box<dyn Stream>,
+    // The sequence number of the last change received.
+    seq: u64,
+    _pd: PhantomData<(K, V)>,
+}
+
+impl<K, V> Watcher<K, V> {
+    /// The sequence number of the last change received,
+    /// a new watcher may continue from it.
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+}
+
+impl<K, V> Iterator for Watcher<K, V>
+where
+    K: DeserializeOwned,
+    V: DeserializeOwned,
+{
+    type Item = Result<Change<K, V>>;
+
+    // `None` if the connection is closed.
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let resp = match read_frame(&mut self.conn).c(d!()) {
+                Ok(resp) => resp?,
+                Err(e) => return Some(Err(e)),
+            };
+            let (seq, op) = match resp {
+                Response::Change { seq, op } => (seq, op),
+                Response::Watching(_) => continue,
+                Response::Error(e) => return Some(Err(eg!(e))),
+                _ => return Some(Err(eg!("unexpected response"))),
+            };
+
+            self.seq = seq;
+            let op = match op {
+                Op::Insert(k, v) => bincode::deserialize(&k)
+                    .c(d!())
+                    .and_then(|k| serde_json::from_slice(&v).c(d!()).map(|v| Op::Insert(k, v))),
+                Op::Remove(k) => bincode::deserialize(&k).c(d!()).map(Op::Remove),
+            };
+            return Some(op.map(|op| Change { seq, op }));
+        }
+    }
+}
+
+#[inline(always)]
+fn encode_key<K: Serialize>(key: &K) -> Result<Vec<u8>> {
+    bincode::serialize(key).c(d!())
+}
+
+fn encode_bound<K: Serialize>(b: Bound<&K>) -> Result<Bound<Vec<u8>>> {
+    Ok(match b {
+        Bound::Included(k) => Bound::Included(encode_key(k).c(d!())?),
+        Bound::Excluded(k) => Bound::Excluded(encode_key(k).c(d!())?),
+        Bound::Unbounded => Bound::Unbounded,
+    })
+}
+
+fn decode_value<V: DeserializeOwned>(resp: Response) -> Result<Option<V>> {
+    match resp {
+        Response::Value(v) => v.map(|v| serde_json::from_slice(&v).c(d!())).transpose(),
+        _ => Err(eg!("unexpected response")),
+    }
+}
+
+fn decode_len(resp: Response) -> Result<usize> {
+    match resp {
+        Response::Len(len) => Ok(len as usize),
+        _ => Err(eg!("unexpected response")),
+    }
+}
@@ -0,0 +1,367 @@
+//!
+//! # A Server Sharing Collections with Other Processes
+//!
+//! The collections in the data directory of a [Server](self::Server) are named,
+//! a [Client](self::Client) gets typed handles of them by their names,
+//! which are created on the server when they are used for the first time.
+//!
+//! Connections are served by TCP or Unix sockets, one thread for each of them.
+//!
+
+mod client;
+mod protocol;
+#[cfg(test)]
+mod test;
+
+pub use client::{Client, RemoteMapx, RemoteMapxIter, RemoteVecx, Watcher};
+
+use crate::{changelog::Op, Mapx, Vecx};
+use protocol::{read_frame, write_frame, Kind, Request, Response, MAX_RANGE_LEN};
+use ruc::*;
+use serde_json::Value;
+use std::{
+    collections::HashMap,
+    io::{Read, Write},
+    net::TcpListener,
+    ops::Bound,
+    sync::{Arc, Condvar, Mutex},
+    thread,
+    time::Duration,
+};
+
+// How long a watcher waits before checking its connection again.
+const WATCH_INTERVAL: Duration = Duration::from_millis(500);
+
+// A served collection, the watchers are woken up after each write.
+struct Shared<C> {
+    db: Mutex<C>,
+    changed: Condvar,
+}
+
+impl<C> Shared<C> {
+    fn new(db: C) -> Arc<Self> {
+        Arc::new(Shared {
+            db: Mutex::new(db),
+            changed: Condvar::new(),
+        })
+    }
+
+    // Run a write operation and wake up the watchers.
+    fn write<R>(&self, ops: impl FnOnce(&mut C) -> R) -> R {
+        let r = ops(&mut self.db.lock().unwrap());
+        self.changed.notify_all();
+        r
+    }
+}
+
+// The served collections, which are shared by all connections.
+type SharedMapx = Arc<Shared<Mapx<Vec<u8>, Value>>>;
+type SharedVecx = Arc<Shared<Vecx<Value>>>;
+
+// A change with the encoded key and the JSON of the value.
+type RawChange = (u64, Op<Vec<u8>, Vec<u8>>);
+
+// The change log interface of the served collections.
+trait Watched {
+    fn enable(&mut self) -> Result<()>;
+    fn seq(&self) -> u64;
+    fn changes(&self, since: u64) -> Result<Vec<RawChange>>;
+}
+
+impl Watched for Mapx<Vec<u8>, Value> {
+    fn enable(&mut self) -> Result<()> {
+        self.enable_change_log().c(d!())
+    }
+
+    fn seq(&self) -> u64 {
+        self.change_log_seq().unwrap_or(0)
+    }
+
+    fn changes(&self, since: u64) -> Result<Vec<RawChange>> {
+        self.changes_since(since)
+            .c(d!())?
+            .map(|c| {
+                let c = c.c(d!())?;
+                let op = match c.op {
+                    Op::Insert(k, v) => Op::Insert(k, serde_json::to_vec(&v).c(d!())?),
+                    Op::Remove(k) => Op::Remove(k),
+                };
+                Ok((c.seq, op))
+            })
+            .collect()
+    }
+}
+
+impl Watched for Vecx<Value> {
+    fn enable(&mut self) -> Result<()> {
+        self.enable_change_log().c(d!())
+    }
+
+    fn seq(&self) -> u64 {
+        self.change_log_seq().unwrap_or(0)
+    }
+
+    fn changes(&self, since: u64) -> Result<Vec<RawChange>> {
+        self.changes_since(since)
+            .c(d!())?
+            .map(|c| {
+                let c = c.c(d!())?;
+                let op = match c.op {
+                    Op::Insert(idx, v) => Op::Insert(
+                        bincode::serialize(&idx).c(d!())?,
+                        serde_json::to_vec(&v).c(d!())?,
+                    ),
+                    Op::Remove(idx) => Op::Remove(bincode::serialize(&idx).c(d!())?),
+                };
+                Ok((c.seq, op))
+            })
+            .collect()
+    }
+}
+
+///////////////////////////////////////////
+// Begin of the implementation of Server //
+/*****************************************/
+
+/// Serve the collections in a data directory,
+/// maps are stored in `<root>/map/<name>` and vectors in `<root>/vec/<name>`.
+pub struct Server {
+    root: String,
+    maps: Mutex<HashMap<String, SharedMapx>>,
+    vecs: Mutex<HashMap<String, SharedVecx>>,
+}
+
+impl Server {
+    /// Create a server of the collections in `root`.
+    pub fn new(root: String) -> Arc<Self> {
+        Arc::new(Server {
+            root,
+            maps: Mutex::new(HashMap::new()),
+            vecs: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Serve the connections of a TCP listener,
+    /// a connection which fails to be accepted is logged and skipped.
+    pub fn serve_tcp(self: &Arc<Self>, listener: TcpListener) -> Result<()> {
+        for stream in listener.incoming() {
+            match stream
+                .c(d!())
+                .and_then(|s| s.set_nodelay(true).c(d!()).map(|_| s))
+            {
+                Ok(stream) => self.spawn(stream),
+                Err(e) => e.print(),
+            }
+        }
+        Ok(())
+    }
+
+    /// Serve the connections of a Unix socket listener,
+    /// a connection which fails to be accepted is logged and skipped.
+    #[cfg(unix)]
+    pub fn serve_unix(self: &Arc<Self>, listener: std::os::unix::net::UnixListener) -> Result<()> {
+        for stream in listener.incoming() {
+            match stream.c(d!()) {
+                Ok(stream) => self.spawn(stream),
+                Err(e) => e.print(),
+            }
+        }
+        Ok(())
+    }
+
+    fn spawn<S: Read + Write + Send + 'static>(self: &Arc<Self>, stream: S) {
+        let server = Arc::clone(self);
+        thread::spawn(move || {
+            if let Err(e) = server.handle(stream) {
+                e.print();
+            }
+        });
+    }
+
+    // Serve requests one by one until the client closes the connection.
+    fn handle<S: Read + Write>(&self, mut stream: S) -> Result<()> {
+        while let Some(req) = read_frame(&mut stream).c(d!())? {
+            if let Request::Watch { name, kind, since } = req {
+                return match kind {
+                    Kind::Map => self.map(&name).and_then(|m| watch(&m, &mut stream, since)),
+                    Kind::Vec => self.vec(&name).and_then(|v| watch(&v, &mut stream, since)),
+                }
+                .or_else(|e| write_frame(&mut stream, &Response::Error(e.to_string())));
+            }
+            let resp = self
+                .respond(req)
+                .unwrap_or_else(|e| Response::Error(e.to_string()));
+            write_frame(&mut stream, &resp).c(d!())?;
+        }
+        Ok(())
+    }
+
+    fn respond(&self, req: Request) -> Result<Response> {
+        let resp = match req {
+            Request::MapGet { name, key } => {
+                let m = self.map(&name).c(d!())?;
+                let db = m.db.lock().unwrap();
+                Response::Value(db.get(&key).map(|v| to_json(&v)).transpose().c(d!())?)
+            }
+            Request::MapInsert { name, key, value } => {
+                let value = serde_json::from_slice(&value).c(d!())?;
+                let old = self.map(&name).c(d!())?.write(|m| m.insert(key, value));
+                Response::Value(old.map(|v| to_json(&v)).transpose().c(d!())?)
+            }
+            Request::MapRemove { name, key } => {
+                let old = self.map(&name).c(d!())?.write(|m| m.remove(&key));
+                Response::Value(old.map(|v| to_json(&v)).transpose().c(d!())?)
+            }
+            Request::MapContainsKey { name, key } => {
+                let m = self.map(&name).c(d!())?;
+                let exists = m.db.lock().unwrap().contains_key(&key);
+                Response::Bool(exists)
+            }
+            Request::MapLen { name } => {
+                let m = self.map(&name).c(d!())?;
+                let len = m.db.lock().unwrap().len();
+                Response::Len(len as u64)
+            }
+            Request::MapRange {
+                name,
+                lo,
+                hi,
+                limit,
+            } => {
+                let m = self.map(&name).c(d!())?;
+                let db = m.db.lock().unwrap();
+                let entries = db
+                    .get_encoded_range(as_ref(&lo), as_ref(&hi), limit.min(MAX_RANGE_LEN) as usize)
+                    .into_iter()
+                    .map(|(k, v)| to_json(&v).map(|v| (k, v)))
+                    .collect::<Result<Vec<_>>>()
+                    .c(d!())?;
+                Response::Entries(entries)
+            }
+            Request::VecGet { name, idx } => {
+                let v = self.vec(&name).c(d!())?;
+                let db = v.db.lock().unwrap();
+                let item = db.get(idx as usize);
+                Response::Value(item.map(|v| to_json(&v)).transpose().c(d!())?)
+            }
+            Request::VecRange { name, start, end } => {
+                let v = self.vec(&name).c(d!())?;
+                let end = end.min(start.saturating_add(MAX_RANGE_LEN));
+                let items =
+                    v.db.lock()
+                        .unwrap()
+                        .get_range(start as usize..end as usize)
+                        .iter()
+                        .map(|v| to_json(v))
+                        .collect::<Result<Vec<_>>>()
+                        .c(d!())?;
+                Response::Values(items)
+            }
+            Request::VecPush { name, value } => {
+                let value = serde_json::from_slice(&value).c(d!())?;
+                self.vec(&name).c(d!())?.write(|v| v.push(value));
+                Response::Done
+            }
+            Request::VecLen { name } => {
+                let v = self.vec(&name).c(d!())?;
+                let len = v.db.lock().unwrap().len();
+                Response::Len(len as u64)
+            }
+            Request::Watch { .. } => return Err(eg!("unexpected watch request")),
+        };
+        Ok(resp)
+    }
+
+    // Open a map when it is used for the first time.
+    fn map(&self, name: &str) -> Result<SharedMapx> {
+        let mut maps = self.maps.lock().unwrap();
+        if let Some(m) = maps.get(name) {
+            return Ok(Arc::clone(m));
+        }
+        let path = self.path(Kind::Map, name).c(d!())?;
+        let m = Shared::new(Mapx::new(path, None, false).c(d!())?);
+        maps.insert(name.to_owned(), Arc::clone(&m));
+        Ok(m)
+    }
+
+    fn vec(&self, name: &str) -> Result<SharedVecx> {
+        let mut vecs = self.vecs.lock().unwrap();
+        if let Some(v) = vecs.get(name) {
+            return Ok(Arc::clone(v));
+        }
+        let path = self.path(Kind::Vec, name).c(d!())?;
+        let v = Shared::new(Vecx::new(path, None, false).c(d!())?);
+        vecs.insert(name.to_owned(), Arc::clone(&v));
+        Ok(v)
+    }
+
+    // A name can only contain ASCII letters, digits, '_' and '-'.
+    fn path(&self, kind: Kind, name: &str) -> Result<String> {
+        let valid = !name.is_empty()
+            && name
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b"_-".contains(&b));
+        if !valid {
+            return Err(eg!(format!("invalid collection name: {:?}", name)));
+        }
+        let dir = match kind {
+            Kind::Map => "map",
+            Kind::Vec => "vec",
+        };
+        Ok(format!("{}/{}/{}", self.root, dir, name))
+    }
+}
+
+// Send the changes of a collection to a watcher until the connection is closed,
+// the change log of the collection is enabled at the first time.
+fn watch<C: Watched>(
+    shared: &Shared<C>,
+    stream: &mut impl Write,
+    since: Option<u64>,
+) -> Result<()> {
+    let mut last = {
+        let mut db = shared.db.lock().unwrap();
+        db.enable().c(d!())?;
+        since.unwrap_or_else(|| db.seq())
+    };
+    write_frame(stream, &Response::Watching(last)).c(d!())?;
+
+    loop {
+        let changes = {
+            let db = shared.db.lock().unwrap();
+            let db = if db.seq() == last {
+                shared.changed.wait_timeout(db, WATCH_INTERVAL).unwrap().0
+            } else {
+                db
+            };
+            db.changes(last).c(d!())?
+        };
+
+        if changes.is_empty() {
+            // A heartbeat, which fails if the watcher has gone.
+            write_frame(stream, &Response::Watching(last)).c(d!())?;
+        }
+        for (seq, op) in changes {
+            write_frame(stream, &Response::Change { seq, op }).c(d!())?;
+            last = seq;
+        }
+    }
+}
+
+#[inline(always)]
+fn as_ref(b: &Bound<Vec<u8>>) -> Bound<&Vec<u8>> {
+    match b {
+        Bound::Included(k) => Bound::Included(k),
+        Bound::Excluded(k) => Bound::Excluded(k),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+#[inline(always)]
+fn to_json(v: &Value) -> Result<Vec<u8>> {
+    serde_json::to_vec(v).c(d!())
+}
+
+/***************************************/
+// End of the implementation of Server //
+/////////////////////////////////////////
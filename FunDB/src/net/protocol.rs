@@ -0,0 +1,126 @@
+//!
+//! # Wire Protocol
+//!
+//! Every message is a frame of a little-endian `u32` length followed by
+//! a bincode-encoded `Request` or `Response`.
+//!
+//! Keys are encoded by bincode and values by JSON on the client side,
+//! the server stores them without knowing their types.
+//!
+
+use crate::changelog::Op;
+use ruc::*;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    io::{Read, Write},
+    mem,
+    ops::Bound,
+};
+
+// Larger frames are rejected before allocating memory for them.
+const MAX_FRAME_SIZE: usize = 64 * 1024 * 1024;
+
+// At most so many entries or items are returned by a range request,
+// the client gets the rest by more requests.
+pub(super) const MAX_RANGE_LEN: u64 = 1024;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(super) enum Request {
+    MapGet {
+        name: String,
+        key: Vec<u8>,
+    },
+    MapInsert {
+        name: String,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+    MapRemove {
+        name: String,
+        key: Vec<u8>,
+    },
+    MapContainsKey {
+        name: String,
+        key: Vec<u8>,
+    },
+    MapLen {
+        name: String,
+    },
+    // The bounds are compared with the keys encoded on the server.
+    MapRange {
+        name: String,
+        lo: Bound<Vec<u8>>,
+        hi: Bound<Vec<u8>>,
+        limit: u64,
+    },
+    VecGet {
+        name: String,
+        idx: u64,
+    },
+    VecRange {
+        name: String,
+        start: u64,
+        end: u64,
+    },
+    VecPush {
+        name: String,
+        value: Vec<u8>,
+    },
+    VecLen {
+        name: String,
+    },
+    // The connection only sends changes after it.
+    Watch {
+        name: String,
+        kind: Kind,
+        since: Option<u64>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+pub(super) enum Kind {
+    Map,
+    Vec,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(super) enum Response {
+    Done,
+    Value(Option<Vec<u8>>),
+    Values(Vec<Vec<u8>>),
+    Entries(Vec<(Vec<u8>, Vec<u8>)>),
+    Bool(bool),
+    Len(u64),
+    // The first response of a watch, also sent as a heartbeat,
+    // with the sequence number of the last change sent.
+    Watching(u64),
+    Change { seq: u64, op: Op<Vec<u8>, Vec<u8>> },
+    Error(String),
+}
+
+pub(super) fn write_frame<T: Serialize>(w: &mut impl Write, msg: &T) -> Result<()> {
+    let payload = bincode::serialize(msg).c(d!())?;
+    if payload.len() > MAX_FRAME_SIZE {
+        return Err(eg!("the message is too large"));
+    }
+    w.write_all(&(payload.len() as u32).to_le_bytes()).c(d!())?;
+    w.write_all(&payload).c(d!())?;
+    w.flush().c(d!())
+}
+
+// `None` if the peer has closed the connection.
+pub(super) fn read_frame<T: DeserializeOwned>(r: &mut impl Read) -> Result<Option<T>> {
+    let mut len = [0; mem::size_of::<u32>()];
+    match r.read_exact(&mut len) {
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e).c(d!()),
+    }
+    let len = u32::from_le_bytes(len) as usize;
+    if len > MAX_FRAME_SIZE {
+        return Err(eg!("the message is too large"));
+    }
+    let mut payload = vec![0; len];
+    r.read_exact(&mut payload).c(d!())?;
+    bincode::deserialize(&payload).c(d!()).map(Some)
+}
@@ -0,0 +1,180 @@
+//!
+//! # Test Cases
+//!
+
+use super::*;
+use ruc::*;
+use serde::{Deserialize, Serialize};
+use std::{sync::mpsc, time::Duration};
+
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
+struct Account {
+    name: String,
+    balance: u64,
+}
+
+fn gen_account(i: u64) -> Account {
+    Account {
+        name: format!("account-{}", i),
+        balance: i * 100,
+    }
+}
+
+// Serve a new data directory on a random port.
+fn start_tcp() -> Client {
+    let listener = pnk!(TcpListener::bind("127.0.0.1:0"));
+    let addr = pnk!(listener.local_addr());
+    let server = Server::new(crate::unique_path!());
+    thread::spawn(move || pnk!(server.serve_tcp(listener)));
+    pnk!(Client::connect(addr))
+}
+
+#[test]
+fn t_net_mapx() {
+    let client = start_tcp();
+    let db = client.mapx::<u64, Account>("accounts");
+
+    assert_eq!(0, pnk!(db.len()));
+    assert!(pnk!(db.is_empty()));
+    (0..100).for_each(|i| {
+        assert!(pnk!(db.insert(i, gen_account(i))).is_none());
+    });
+    assert_eq!(Some(gen_account(1)), pnk!(db.insert(1, gen_account(10))));
+    assert_eq!(100, pnk!(db.len()));
+    assert_eq!(Some(gen_account(10)), pnk!(db.get(&1)));
+    assert!(pnk!(db.contains_key(&99)));
+    assert!(!pnk!(db.contains_key(&100)));
+
+    assert_eq!(Some(gen_account(2)), pnk!(db.remove(&2)));
+    assert!(pnk!(db.remove(&2)).is_none());
+    pnk!(db.unset_value(&3));
+    pnk!(db.set_value(200, gen_account(200)));
+    assert_eq!(99, pnk!(db.len()));
+
+    // Another connection sees the same map.
+    let other = pnk!(Client::connect(client_addr(&client)));
+    let db2 = other.mapx::<u64, Account>("accounts");
+    assert_eq!(Some(gen_account(200)), pnk!(db2.get(&200)));
+
+    // Different names are different maps.
+    assert!(pnk!(other.mapx::<u64, Account>("others").get(&200)).is_none());
+    assert!(other.mapx::<u64, Account>("../x").len().is_err());
+    // Still usable after a failed request.
+    assert_eq!(99, pnk!(db2.len()));
+
+    // Got page by page.
+    (1000..1000 + MAX_RANGE_LEN).for_each(|i| pnk!(db.set_value(i, gen_account(i))));
+    let entries = pnk!(db.iter().collect::<Result<Vec<_>>>());
+    assert_eq!(99 + MAX_RANGE_LEN as usize, entries.len());
+    assert!(entries
+        .iter()
+        .all(|(k, v)| *k == 1 && *v == gen_account(10) || *v == gen_account(*k)));
+    let keys = pnk!(pnk!(db.range(5..7))
+        .map(|kv| kv.map(|(k, _)| k))
+        .collect::<Result<Vec<_>>>());
+    // Bounds are compared in the little-endian encoding of the keys.
+    let mut expected = entries
+        .iter()
+        .map(|(k, _)| *k)
+        .filter(|k| (5..7).contains(&(k & 0xff)))
+        .collect::<Vec<_>>();
+    expected.sort_by_key(|k| k.to_le_bytes());
+    assert_eq!(expected, keys);
+}
+
+fn client_addr(client: &Client) -> std::net::SocketAddr {
+    match client.addr {
+        client::Addr::Tcp(addr) => addr,
+        #[cfg(unix)]
+        client::Addr::Unix(_) => unreachable!(),
+    }
+}
+
+#[test]
+fn t_net_vecx() {
+    let client = start_tcp();
+    let db = client.vecx::<Account>("blocks");
+
+    assert!(pnk!(db.last()).is_none());
+    (0..50).for_each(|i| pnk!(db.push(gen_account(i))));
+    assert_eq!(50, pnk!(db.len()));
+    assert_eq!(Some(gen_account(7)), pnk!(db.get(7)));
+    assert!(pnk!(db.get(50)).is_none());
+    assert_eq!(Some(gen_account(49)), pnk!(db.last()));
+    assert_eq!(
+        (45..50).map(gen_account).collect::<Vec<_>>(),
+        pnk!(db.get_range(45..60))
+    );
+
+    // The long range is cut by the server and got page by page.
+    (50..MAX_RANGE_LEN + 10).for_each(|i| pnk!(db.push(gen_account(i))));
+    let items = pnk!(db.get_range(5..usize::MAX));
+    assert_eq!(MAX_RANGE_LEN as usize + 5, items.len());
+    assert_eq!(
+        gen_account(MAX_RANGE_LEN + 9),
+        items[MAX_RANGE_LEN as usize + 4]
+    );
+
+    // A map and a vector may have the same name.
+    let map = client.mapx::<usize, Account>("blocks");
+    assert!(pnk!(map.is_empty()));
+}
+
+#[test]
+fn t_net_watch() {
+    let client = start_tcp();
+    let db = client.mapx::<u64, u64>("watched");
+    pnk!(db.insert(0, 0));
+
+    let (tx, rx) = mpsc::channel();
+    let watcher = pnk!(db.watch(None));
+    assert_eq!(0, watcher.seq());
+    thread::spawn(move || {
+        for change in watcher.take(3) {
+            pnk!(tx.send(pnk!(change)));
+        }
+    });
+
+    pnk!(db.insert(1, 1));
+    pnk!(db.insert(1, 2));
+    pnk!(db.remove(&0));
+    let changes = (0..3)
+        .map(|_| pnk!(rx.recv_timeout(Duration::from_secs(10))))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        changes.iter().map(|c| c.op.clone()).collect::<Vec<_>>(),
+        vec![Op::Insert(1, 1), Op::Insert(1, 2), Op::Remove(0)]
+    );
+    assert_eq!(
+        vec![1, 2, 3],
+        changes.iter().map(|c| c.seq).collect::<Vec<_>>()
+    );
+
+    // Continue from a sequence number.
+    let mut watcher = pnk!(db.watch(Some(1)));
+    assert_eq!(Op::Insert(1, 2), pnk!(pnk!(watcher.next())).op);
+
+    let blocks = client.vecx::<String>("watched");
+    let mut watcher = pnk!(blocks.watch(Some(0)));
+    pnk!(blocks.push("a".to_owned()));
+    let change = pnk!(pnk!(watcher.next()));
+    assert_eq!(Op::Insert(0, "a".to_owned()), change.op);
+}
+
+#[test]
+#[cfg(unix)]
+fn t_net_unix() {
+    let sock = format!("{}.sock", crate::unique_path!());
+    pnk!(std::fs::create_dir_all(pnk!(
+        std::path::Path::new(&sock).parent()
+    )));
+    let listener = pnk!(std::os::unix::net::UnixListener::bind(&sock));
+    let server = Server::new(crate::unique_path!());
+    thread::spawn(move || pnk!(server.serve_unix(listener)));
+
+    let client = pnk!(Client::connect_unix(&sock));
+    let db = client.mapx::<String, Vec<u8>>("files");
+    pnk!(db.insert("a".to_owned(), vec![1, 2, 3]));
+    assert_eq!(Some(vec![1, 2, 3]), pnk!(db.get(&"a".to_owned())));
+    assert_eq!(1, pnk!(db.len()));
+}
@@ -0,0 +1,184 @@
+//!
+//! # Schema Versioning
+//!
+//! Records written with a schema version above zero are tagged with it,
+//! older records are upgraded by the registered [Migration](self::Migration)s
+//! lazily when they are read, or eagerly by `Mapx::migrate_all`.
+//!
+
+use crate::kv::Kv;
+use ruc::*;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use std::{collections::BTreeMap, convert::TryInto, fmt, mem, sync::Arc};
+
+// The tree to store the metadata of a top-level collection.
+pub(crate) const META_TREE: &str = "____meta____";
+const SCHEMA_VERSION_KEY: &[u8] = b"schema_version";
+
+// A JSON document never starts with a zero byte,
+// so the tagged records can be told apart from the untagged ones.
+const TAG: u8 = 0;
+const TAG_LEN: usize = 1 + mem::size_of::<u32>();
+
+/// Upgrade the records of `source_version()` to `source_version() + 1`.
+pub trait Migration: Send + Sync {
+    /// The version of the records this migration applies to.
+    fn source_version(&self) -> u32;
+
+    /// Upgrade a record, which is decoded as a generic JSON value.
+    fn migrate(&self, value: Value) -> Result<Value>;
+}
+
+/// A `(source_version, upgrade_function)` pair is a migration.
+impl<F> Migration for (u32, F)
+where
+    F: Fn(Value) -> Result<Value> + Send + Sync,
+{
+    fn source_version(&self) -> u32 {
+        self.0
+    }
+
+    fn migrate(&self, value: Value) -> Result<Value> {
+        (self.1)(value).c(d!())
+    }
+}
+
+/// The current schema version of a collection and the migrations to reach it,
+/// the records without a version tag are treated as version zero.
+#[derive(Clone, Default)]
+pub struct Schema {
+    version: u32,
+    migrations: BTreeMap<u32, Arc<dyn Migration>>,
+}
+
+impl fmt::Debug for Schema {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Schema")
+            .field("version", &self.version)
+            .field("migrations", &self.migrations.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+impl Schema {
+    /// Create a schema without any migrations.
+    pub fn new(version: u32) -> Self {
+        Schema {
+            version,
+            migrations: BTreeMap::new(),
+        }
+    }
+
+    /// Register a migration, the one registered later wins
+    /// if there are two of them for the same version.
+    pub fn migration(mut self, m: impl Migration + 'static) -> Self {
+        self.migrations.insert(m.source_version(), Arc::new(m));
+        self
+    }
+
+    /// The current version.
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    // Check the migrations, and persist the version into `db`,
+    // a database upgraded by newer code can not be opened by an older one.
+    pub(crate) fn apply_to(&self, db: &Kv) -> Result<()> {
+        if let Some(v) = self.migrations.keys().find(|v| **v >= self.version) {
+            return Err(eg!(format!(
+                "migration from version {} is beyond the schema version {}",
+                v, self.version
+            )));
+        }
+
+        let stored = load_version(db).c(d!())?;
+        if stored > self.version {
+            return Err(eg!(format!(
+                "the database is of schema version {}, newer than {}",
+                stored, self.version
+            )));
+        }
+        if stored < self.version {
+            store_version(db, self.version).c(d!())?;
+        }
+        Ok(())
+    }
+
+    // Tag the encoded record with the current version.
+    #[inline(always)]
+    pub(crate) fn encode(&self, json: Vec<u8>) -> Vec<u8> {
+        if 0 == self.version {
+            return json;
+        }
+        let mut v = Vec::with_capacity(TAG_LEN + json.len());
+        v.push(TAG);
+        v.extend_from_slice(&self.version.to_be_bytes());
+        v.extend_from_slice(&json);
+        v
+    }
+
+    // Decode a record, upgrade it first if it is of an older version.
+    pub(crate) fn decode<V: DeserializeOwned>(&self, value: &[u8]) -> Result<V> {
+        let (mut version, json) = split(value);
+        if version == self.version {
+            return serde_json::from_slice(json).c(d!());
+        }
+        if version > self.version {
+            return Err(eg!(format!(
+                "record of schema version {}, newer than {}",
+                version, self.version
+            )));
+        }
+
+        let mut v = serde_json::from_slice::<Value>(json).c(d!())?;
+        while version < self.version {
+            v = self
+                .migrations
+                .get(&version)
+                .c(d!(format!("no migration from version {}", version)))
+                .and_then(|m| m.migrate(v).c(d!()))?;
+            version += 1;
+        }
+        serde_json::from_value(v).c(d!())
+    }
+
+    // Whether the record is of the current version.
+    #[inline(always)]
+    pub(crate) fn is_current(&self, value: &[u8]) -> bool {
+        split(value).0 == self.version
+    }
+}
+
+/// Split a record into its schema version and its JSON content.
+#[inline(always)]
+pub(crate) fn split(value: &[u8]) -> (u32, &[u8]) {
+    if value.len() >= TAG_LEN && TAG == value[0] {
+        (
+            u32::from_be_bytes(value[1..TAG_LEN].try_into().unwrap()),
+            &value[TAG_LEN..],
+        )
+    } else {
+        (0, value)
+    }
+}
+
+/// Get the schema version persisted in `db`, zero if it is not set.
+pub(crate) fn load_version(db: &Kv) -> Result<u32> {
+    db.open_tree(META_TREE)
+        .c(d!())?
+        .get(SCHEMA_VERSION_KEY)
+        .c(d!())
+        .map(|v| {
+            v.map(|v| u32::from_be_bytes(v[..].try_into().unwrap()))
+                .unwrap_or(0)
+        })
+}
+
+fn store_version(db: &Kv, version: u32) -> Result<()> {
+    db.open_tree(META_TREE)
+        .c(d!())?
+        .insert(SCHEMA_VERSION_KEY, &version.to_be_bytes()[..])
+        .c(d!())
+        .map(|_| ())
+}
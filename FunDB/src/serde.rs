@@ -4,7 +4,9 @@
 //! Used to restore an existing database.
 //!
 
+use crate::{durability::Durability, kv::Storage};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 
 pub(crate) struct FunDBVisitor;
 
@@ -27,4 +29,88 @@ impl<'de> serde::de::Visitor<'de> for FunDBVisitor {
 pub(crate) struct FunDBMeta<'a> {
     pub in_mem_cnt: usize,
     pub data_path: &'a str,
+    /// The id of a nested collection, `None` for top-level ones.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nested_id: Option<u64>,
+    /// The durability mode of a top-level collection.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub durability: Option<Durability>,
+    /// The storage backend, the default one if it is not set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub storage: Option<Storage>,
+}
+
+/// Find the collections nested in the database of `data_path` in some encoded value,
+/// which may be the whole value or a part of it, return their ids.
+pub(crate) fn nested_ids(value: &[u8], data_path: &str) -> Vec<u64> {
+    let json = crate::schema::split(value).1;
+    let mut ids = vec![];
+    if !may_hold_collections(json) {
+        return ids;
+    }
+
+    if let Ok(mut v) = serde_json::from_slice::<Value>(json) {
+        visit_metas(&mut v, &mut |s| {
+            if let Ok(meta) = serde_json::from_str::<FunDBMeta>(s) {
+                if meta.data_path == data_path {
+                    ids.extend(meta.nested_id);
+                }
+            }
+            false
+        });
+    }
+    ids
+}
+
+/// Point the collections in some encoded value, which are in the database of `from`,
+/// to the database of `to`, return `None` if there are no such collections.
+pub(crate) fn replace_data_path(value: &[u8], from: &str, to: &str) -> Option<Vec<u8>> {
+    let json = crate::schema::split(value).1;
+    if !may_hold_collections(json) {
+        return None;
+    }
+
+    let mut v = serde_json::from_slice::<Value>(json).ok()?;
+    let replaced = visit_metas(&mut v, &mut |s| {
+        let meta = match serde_json::from_str::<FunDBMeta>(s) {
+            Ok(meta) if meta.data_path == from => meta,
+            _ => return false,
+        };
+        let new = serde_json::to_string(&FunDBMeta {
+            data_path: to,
+            ..meta
+        });
+        match new {
+            Ok(new) => {
+                *s = new;
+                true
+            }
+            Err(_) => false,
+        }
+    });
+    if !replaced {
+        return None;
+    }
+    // Keep the schema version tag.
+    let mut ret = value[..value.len() - json.len()].to_vec();
+    ret.extend(serde_json::to_vec(&v).ok()?);
+    Some(ret)
+}
+
+// Most values have nothing to do with collections.
+#[inline(always)]
+fn may_hold_collections(json: &[u8]) -> bool {
+    json.windows(b"data_path".len()).any(|w| w == b"data_path")
+}
+
+// A serialized collection is a JSON string, which may be a part of a value,
+// e.g. a field of a struct, so all strings are visited recursively,
+// return whether any of them is rewritten by `f`.
+fn visit_metas(v: &mut Value, f: &mut dyn FnMut(&mut String) -> bool) -> bool {
+    match v {
+        Value::String(s) => f(s),
+        Value::Array(a) => a.iter_mut().fold(false, |r, v| visit_metas(v, f) | r),
+        Value::Object(o) => o.values_mut().fold(false, |r, v| visit_metas(v, f) | r),
+        _ => false,
+    }
 }
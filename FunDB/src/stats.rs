@@ -0,0 +1,236 @@
+//!
+//! # Statistics of Mapx/Vecx
+//!
+//! Every collection counts its own operations since it was opened,
+//! and all of them are summed up into the global statistics.
+//!
+
+use crate::helper::opened_size_on_disk;
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Instant,
+};
+
+static GLOBAL: StatsCounter = StatsCounter::new();
+
+/// A snapshot of the statistics.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Stats {
+    /// Reads served by the in-memory cache.
+    pub cache_hits: u64,
+    /// Reads that have to go to the disk.
+    pub cache_misses: u64,
+    /// Entries evicted from the in-memory cache.
+    pub cache_evictions: u64,
+    /// Encoded bytes read from sled.
+    pub bytes_read: u64,
+    /// Encoded bytes written to sled.
+    pub bytes_written: u64,
+    /// Total time spent on encoding, in nanoseconds.
+    pub encode_nanos: u64,
+    /// Total time spent on decoding, in nanoseconds.
+    pub decode_nanos: u64,
+    /// Number of flushes.
+    pub flushes: u64,
+    /// Total time spent on flushing, in nanoseconds.
+    pub flush_nanos: u64,
+    /// Size of the sled instance(s) on disk, in bytes.
+    pub size_on_disk: u64,
+}
+
+/// Get the statistics of all collections opened in this process.
+pub fn global() -> Stats {
+    GLOBAL.snapshot(opened_size_on_disk())
+}
+
+// Counters of a collection, shared by all its clones.
+#[derive(Debug, Default)]
+pub(crate) struct StatsCounter {
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
+    cache_evictions: AtomicU64,
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    encode_nanos: AtomicU64,
+    decode_nanos: AtomicU64,
+    flushes: AtomicU64,
+    flush_nanos: AtomicU64,
+}
+
+impl StatsCounter {
+    const fn new() -> Self {
+        StatsCounter {
+            cache_hits: AtomicU64::new(0),
+            cache_misses: AtomicU64::new(0),
+            cache_evictions: AtomicU64::new(0),
+            bytes_read: AtomicU64::new(0),
+            bytes_written: AtomicU64::new(0),
+            encode_nanos: AtomicU64::new(0),
+            decode_nanos: AtomicU64::new(0),
+            flushes: AtomicU64::new(0),
+            flush_nanos: AtomicU64::new(0),
+        }
+    }
+
+    #[inline(always)]
+    fn add(&self, field: fn(&StatsCounter) -> &AtomicU64, n: u64) {
+        field(self).fetch_add(n, Ordering::Relaxed);
+        field(&GLOBAL).fetch_add(n, Ordering::Relaxed);
+    }
+
+    #[inline(always)]
+    fn timed<T>(&self, field: fn(&StatsCounter) -> &AtomicU64, ops: impl FnOnce() -> T) -> T {
+        let ts = Instant::now();
+        let ret = ops();
+        self.add(field, ts.elapsed().as_nanos() as u64);
+        ret
+    }
+
+    #[inline(always)]
+    pub(crate) fn cache_hit(&self) {
+        self.add(|s| &s.cache_hits, 1);
+    }
+
+    #[inline(always)]
+    pub(crate) fn cache_miss(&self) {
+        self.add(|s| &s.cache_misses, 1);
+    }
+
+    #[inline(always)]
+    pub(crate) fn cache_eviction(&self) {
+        self.add(|s| &s.cache_evictions, 1);
+    }
+
+    #[inline(always)]
+    pub(crate) fn read(&self, n: usize) {
+        self.add(|s| &s.bytes_read, n as u64);
+    }
+
+    #[inline(always)]
+    pub(crate) fn written(&self, n: usize) {
+        self.add(|s| &s.bytes_written, n as u64);
+    }
+
+    #[inline(always)]
+    pub(crate) fn encode<T>(&self, ops: impl FnOnce() -> T) -> T {
+        self.timed(|s| &s.encode_nanos, ops)
+    }
+
+    #[inline(always)]
+    pub(crate) fn decode<T>(&self, ops: impl FnOnce() -> T) -> T {
+        self.timed(|s| &s.decode_nanos, ops)
+    }
+
+    #[inline(always)]
+    pub(crate) fn flush<T>(&self, ops: impl FnOnce() -> T) -> T {
+        self.add(|s| &s.flushes, 1);
+        self.timed(|s| &s.flush_nanos, ops)
+    }
+
+    pub(crate) fn snapshot(&self, size_on_disk: u64) -> Stats {
+        let get = |field: &AtomicU64| field.load(Ordering::Relaxed);
+        Stats {
+            cache_hits: get(&self.cache_hits),
+            cache_misses: get(&self.cache_misses),
+            cache_evictions: get(&self.cache_evictions),
+            bytes_read: get(&self.bytes_read),
+            bytes_written: get(&self.bytes_written),
+            encode_nanos: get(&self.encode_nanos),
+            decode_nanos: get(&self.decode_nanos),
+            flushes: get(&self.flushes),
+            flush_nanos: get(&self.flush_nanos),
+            size_on_disk,
+        }
+    }
+}
+
+#[cfg(feature = "prometheus")]
+impl Stats {
+    /// Render in the Prometheus text exposition format,
+    /// the `collection` label is attached if it is given, e.g. the data path.
+    pub fn render_prometheus(&self, collection: Option<&str>) -> String {
+        let labels = collection
+            .map(|c| {
+                let c = c
+                    .replace('\\', "\\\\")
+                    .replace('"', "\\\"")
+                    .replace('\n', "\\n");
+                format!("{{collection=\"{}\"}}", c)
+            })
+            .unwrap_or_default();
+
+        let seconds = |nanos: u64| nanos as f64 / 1_000_000_000.0;
+        let metrics: [(&str, &str, &str, String); 10] = [
+            (
+                "cache_hits_total",
+                "counter",
+                "Reads served by the in-memory cache.",
+                self.cache_hits.to_string(),
+            ),
+            (
+                "cache_misses_total",
+                "counter",
+                "Reads that have to go to the disk.",
+                self.cache_misses.to_string(),
+            ),
+            (
+                "cache_evictions_total",
+                "counter",
+                "Entries evicted from the in-memory cache.",
+                self.cache_evictions.to_string(),
+            ),
+            (
+                "read_bytes_total",
+                "counter",
+                "Encoded bytes read from sled.",
+                self.bytes_read.to_string(),
+            ),
+            (
+                "written_bytes_total",
+                "counter",
+                "Encoded bytes written to sled.",
+                self.bytes_written.to_string(),
+            ),
+            (
+                "encode_seconds_total",
+                "counter",
+                "Total time spent on encoding.",
+                seconds(self.encode_nanos).to_string(),
+            ),
+            (
+                "decode_seconds_total",
+                "counter",
+                "Total time spent on decoding.",
+                seconds(self.decode_nanos).to_string(),
+            ),
+            (
+                "flushes_total",
+                "counter",
+                "Number of flushes.",
+                self.flushes.to_string(),
+            ),
+            (
+                "flush_seconds_total",
+                "counter",
+                "Total time spent on flushing.",
+                seconds(self.flush_nanos).to_string(),
+            ),
+            (
+                "size_on_disk_bytes",
+                "gauge",
+                "Size of the sled instance(s) on disk.",
+                self.size_on_disk.to_string(),
+            ),
+        ];
+
+        metrics
+            .iter()
+            .map(|(name, ty, help, v)| {
+                format!(
+                    "# HELP fundb_{0} {1}\n# TYPE fundb_{0} {2}\nfundb_{0}{3} {4}\n",
+                    name, help, ty, labels, v
+                )
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,24 @@
+//!
+//! # Optional Tracing of the Storage Operations
+//!
+//! With the `tracing` feature, the storage operations are wrapped in spans
+//! of the [tracing](https://docs.rs/tracing) crate, carrying the data path
+//! of the collection and the sizes of the encoded keys and values;
+//! without it, the macros here expand to nothing.
+//!
+
+// Enter a span of `$level` until the end of the current block.
+macro_rules! trace_span {
+    ($level:ident, $name:literal $(, $($fields:tt)*)?) => {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::span!(tracing::Level::$level, $name $(, $($fields)*)?).entered();
+    };
+}
+
+// Report an event of `$level` within the current span.
+macro_rules! trace_event {
+    ($level:ident, $($args:tt)*) => {
+        #[cfg(feature = "tracing")]
+        tracing::event!(tracing::Level::$level, $($args)*);
+    };
+}
@@ -2,10 +2,43 @@
 //! # Disk Storage Implementation
 //!
 
-use crate::helper::*;
+use crate::{
+    changelog::{self, ChangeIter, ChangeLog, RawOp},
+    durability::{Durability, Flusher},
+    helper::*,
+    kv::{Kv, KvBatch, KvIter, Storage},
+    schema::META_TREE,
+    stats::{Stats, StatsCounter},
+};
 use ruc::*;
 use serde::{de::DeserializeOwned, Serialize};
-use std::{convert::TryInto, fs, iter::Iterator, marker::PhantomData, mem};
+use std::{
+    convert::TryInto,
+    fs,
+    iter::{self, Iterator},
+    marker::PhantomData,
+    mem,
+    ops::{Bound, Range},
+    path::Path,
+    sync::Arc,
+};
+
+// The version of the key format, stored in the `META_TREE`:
+//
+// - 0, `usize::to_le_bytes`, unmarked, not sorted numerically
+// - 1, `u64::to_be_bytes`
+const KEY_FORMAT: u32 = 1;
+const KEY_FORMAT_KEY: &[u8] = b"key_format";
+
+// The items of an old store are moved to this keyspace with their new keys,
+// then moved back, the `META_TREE` is marked when all of them have been moved here.
+const KEY_UPGRADE_TREE: &str = "____key_upgrade____";
+const KEY_STAGED_KEY: &[u8] = b"key_format_staged";
+
+// Number of items moved in one batch by an upgrade.
+const KEY_UPGRADE_BATCH_SIZE: usize = 1024;
+
+const KEY_SIZE: usize = mem::size_of::<u64>();
 
 /// To solve the problem of unlimited memory usage,
 /// use this to replace the original in-memory `Vec<_>`.
@@ -17,10 +50,16 @@ pub(super) struct Vecx<T>
 where
     T: PartialEq + Clone + Serialize + DeserializeOwned + std::fmt::Debug,
 {
-    db: sled::Db,
+    db: Kv,
+    storage: Storage,
     data_path: String,
-    cnter_path: String,
+    flusher: Arc<Flusher>,
     cnter: usize,
+    stats: Arc<StatsCounter>,
+    change_log: Arc<ChangeLog>,
+    // `None` for read-only vectors,
+    // dropped after the flusher to remove a temporary database at last.
+    guard: Option<Arc<DbGuard>>,
     _pd: PhantomData<T>,
 }
 
@@ -36,54 +75,179 @@ where
     /// it will use it directly;
     /// Or it will create a new one.
     #[inline(always)]
-    pub(super) fn load_or_create(path: String, is_tmp: bool) -> Result<Self> {
-        let db = sled_open(&path, is_tmp).c(d!())?;
+    pub(super) fn load_or_create(
+        path: String,
+        is_tmp: bool,
+        durability: Durability,
+        storage: Storage,
+    ) -> Result<Self> {
+        trace_span!(DEBUG, "fundb.load_or_create", path = %path, storage = ?storage);
+        let db = kv_open(&path, is_tmp, storage).c(d!())?;
+        check_key_format(&db, true).c(d!())?;
+        let guard = DbGuard::get(&path, is_tmp);
+        let change_log = ChangeLog::get(&path, &db).c(d!())?;
         let cnter_path = format!("{}/____cnter____", &path);
-        let cnter = if db.iter().next().is_none() {
+        let dirty = storage.is_persistent()
+            && Path::new(&cnter_path).exists()
+            && db_len_is_dirty(&cnter_path).c(d!())?;
+        change_log
+            .recover(&db, dirty, |batch, op| {
+                match op {
+                    RawOp::Insert(k, v) => {
+                        batch.insert(&encode_key(bincode::deserialize(&k).c(d!())?)[..], v)
+                    }
+                    RawOp::Remove(k) => {
+                        batch.remove(&encode_key(bincode::deserialize(&k).c(d!())?)[..])
+                    }
+                }
+                Ok(())
+            })
+            .c(d!())?;
+
+        let cnter = if !storage.is_persistent() {
+            db.len()
+        } else if db.is_empty() {
             fs::File::create(&cnter_path)
                 .c(d!())
                 .and_then(|_| write_db_len(&cnter_path, 0).c(d!()))
                 .map(|_| 0)?
+        } else if dirty {
+            // Not flushed before the last exit, recount it.
+            let cnter = db.len();
+            write_db_len(&cnter_path, cnter).c(d!())?;
+            cnter
         } else {
             read_db_len(&cnter_path).c(d!())?
         };
 
+        let stats = Arc::<StatsCounter>::default();
+        let flusher = Flusher::new(
+            Arc::clone(&db),
+            &path,
+            storage.is_persistent(),
+            cnter,
+            durability,
+            Arc::clone(&stats),
+        );
+
         Ok(Vecx {
             db,
+            storage,
             data_path: path,
-            cnter_path,
+            flusher: Arc::new(flusher),
             cnter,
+            stats,
+            change_log,
+            guard: Some(guard),
             _pd: PhantomData,
         })
     }
 
+    /// Open an existing database without writing anything to it,
+    /// the length is counted from the data since the counter may be stale.
+    pub(super) fn load_read_only(path: String) -> Result<Self> {
+        let (db, storage) = kv_open_read_only(&path).c(d!())?;
+        check_key_format(&db, false).c(d!())?;
+        let change_log = ChangeLog::get(&path, &db).c(d!())?;
+        let cnter = db.len();
+
+        let stats = Arc::<StatsCounter>::default();
+        let flusher = Flusher::new(
+            Arc::clone(&db),
+            &path,
+            false,
+            cnter,
+            Durability::Manual,
+            Arc::clone(&stats),
+        );
+
+        Ok(Vecx {
+            db,
+            storage,
+            data_path: path,
+            flusher: Arc::new(flusher),
+            cnter,
+            stats,
+            change_log,
+            guard: None,
+            _pd: PhantomData,
+        })
+    }
+
+    /// Get the storage backend
+    pub(super) fn storage(&self) -> Storage {
+        self.storage
+    }
+
+    /// Get the durability mode
+    pub(super) fn durability(&self) -> Durability {
+        self.flusher.durability()
+    }
+
+    /// Remove all data of this vector,
+    /// it fails if any other clones of it are alive.
+    pub(super) fn destroy(self) -> Result<()> {
+        let guard = self
+            .guard
+            .clone()
+            .c(d!("a read-only vector can not be destroyed"))?;
+        // One is held by `self`.
+        if 2 < Arc::strong_count(&guard) {
+            return Err(eg!(format!("{} is still in use", self.data_path)));
+        }
+        drop(self);
+        guard.destroy().c(d!())
+    }
+
     /// Get the storage path
     pub(super) fn get_data_path(&self) -> &str {
         self.data_path.as_str()
     }
 
+    #[inline(always)]
+    pub(super) fn stats_counter(&self) -> &StatsCounter {
+        &self.stats
+    }
+
+    /// Get the statistics since it was opened
+    pub(super) fn stats(&self) -> Stats {
+        self.stats.snapshot(self.db.size_on_disk())
+    }
+
     /// Imitate the behavior of 'Vec<_>.get(...)'
     ///
     /// Any faster/better choice other than JSON ?
     #[inline(always)]
     pub(super) fn get(&self, idx: usize) -> Option<T> {
-        self.db
-            .get(&usize::to_le_bytes(idx)[..])
-            .ok()
-            .flatten()
-            .map(|bytes| pnk!(serde_json::from_slice(&bytes)))
+        trace_span!(TRACE, "fundb.get", path = %self.data_path, key_size = KEY_SIZE);
+        self.db.get(&encode_key(idx)).ok().flatten().map(|bytes| {
+            trace_event!(TRACE, value_size = bytes.len());
+            self.stats.read(bytes.len());
+            self.stats.decode(|| pnk!(serde_json::from_slice(&bytes)))
+        })
     }
 
-    /// Imitate the behavior of 'Vec<_>.last()'
-    pub(super) fn last(&self) -> Option<T> {
-        pnk!(self.db.last()).map(|(_, v)| pnk!(serde_json::from_slice(&v)))
+    /// Read the items within `range` in one ordered scan
+    pub(super) fn get_range(&self, range: Range<usize>) -> Vec<T> {
+        if range.start >= range.end {
+            return vec![];
+        }
+        let (lo, hi) = (encode_key(range.start), encode_key(range.end));
+        trace_span!(TRACE, "fundb.get_range", path = %self.data_path, len = range.len());
+        self.db
+            .range(Bound::Included(&lo[..]), Bound::Excluded(&hi[..]))
+            .map(|kv| {
+                let (_, bytes) = pnk!(kv);
+                self.stats.read(bytes.len());
+                self.stats.decode(|| pnk!(serde_json::from_slice(&bytes)))
+            })
+            .collect()
     }
 
     /// Imitate the behavior of 'Vec<_>.len()'
     #[inline(always)]
     pub(super) fn len(&self) -> usize {
         debug_assert_eq!(self.db.len(), self.cnter);
-        debug_assert_eq!(pnk!(read_db_len(&self.cnter_path)), self.cnter);
         self.cnter
     }
 
@@ -97,14 +261,55 @@ where
     #[inline(always)]
     pub(super) fn push(&mut self, b: T) {
         let idx = self.cnter;
-        let value = pnk!(serde_json::to_vec(&b));
-        pnk!(self.db.insert(idx.to_le_bytes(), value));
+        let value = self.encode(&b);
+        trace_span!(
+            TRACE,
+            "fundb.insert",
+            path = %self.data_path,
+            key_size = KEY_SIZE,
+            value_size = value.len()
+        );
+        self.flusher.begin_write();
+        if self.change_log.is_enabled() {
+            let op = RawOp::Insert(pnk!(bincode::serialize(&idx)), value.clone());
+            pnk!(self.change_log.append(iter::once(op)));
+        }
+        pnk!(self.db.insert(&encode_key(idx), &value));
 
         // There is no `remove` like methods provided,
         // so we can increase this value directly.
         self.cnter += 1;
 
-        pnk!(write_db_len(&self.cnter_path, self.cnter));
+        self.flusher.end_write(self.cnter);
+    }
+
+    /// Imitate the behavior of 'Vec<_>.extend(...)',
+    /// all items are written in one atomic batch.
+    pub(super) fn extend<I>(&mut self, iter: I)
+    where
+        I: IntoIterator<Item = T>,
+    {
+        let mut batch = KvBatch::default();
+        let mut ops = vec![];
+        let mut idx = self.cnter;
+        for b in iter {
+            let value = self.encode(&b);
+            if self.change_log.is_enabled() {
+                ops.push(RawOp::Insert(pnk!(bincode::serialize(&idx)), value.clone()));
+            }
+            batch.insert(&encode_key(idx)[..], value);
+            idx += 1;
+        }
+
+        if idx != self.cnter {
+            self.flusher.begin_write();
+            if self.change_log.is_enabled() {
+                pnk!(self.change_log.append(ops));
+            }
+            pnk!(self.db.apply_batch(batch));
+            self.cnter = idx;
+            self.flusher.end_write(self.cnter);
+        }
     }
 
     /// Imitate the behavior of '.iter()'
@@ -113,14 +318,142 @@ where
         // todo!()
         VecxIter {
             iter: self.db.iter(),
+            remaining: self.cnter,
+            stats: Arc::clone(&self.stats),
             _pd: PhantomData,
         }
     }
 
+    /// Record every push from now on
+    pub(super) fn enable_change_log(&self) -> Result<()> {
+        self.change_log.enable().c(d!())
+    }
+
+    /// The sequence number of the last recorded change
+    pub(super) fn change_log_seq(&self) -> Option<u64> {
+        Some(self.change_log.last_seq()).filter(|_| self.change_log.is_enabled())
+    }
+
+    pub(super) fn changes_since(&self, seq: u64) -> Result<ChangeIter<usize, T>> {
+        self.change_log.since(seq).c(d!())
+    }
+
+    pub(super) fn compact_change_log(&self, seq: u64) -> Result<usize> {
+        if !self.change_log.is_enabled() {
+            return Err(eg!("the change log is not enabled"));
+        }
+        self.change_log.compact(seq).c(d!())
+    }
+
+    pub(super) fn applied_seq(&self) -> Result<u64> {
+        changelog::load_applied_seq(&self.db).c(d!())
+    }
+
+    pub(super) fn set_applied_seq(&self, seq: u64) -> Result<()> {
+        changelog::store_applied_seq(&self.db, seq).c(d!())
+    }
+
+    #[inline(always)]
+    fn encode(&self, b: &T) -> Vec<u8> {
+        let value = self.stats.encode(|| pnk!(serde_json::to_vec(b)));
+        self.stats.written(KEY_SIZE + value.len());
+        value
+    }
+
     /// Flush data to disk
     #[inline(always)]
     pub fn flush(&self) {
-        pnk!(self.db.flush());
+        pnk!(self.flusher.flush());
+    }
+
+    // Write an item without counting it,
+    // as if this process had been killed between `begin_write` and `end_write`.
+    #[cfg(test)]
+    pub(super) fn push_unfinished(&mut self, b: T) {
+        self.flusher.begin_write();
+        pnk!(self.db.insert(&encode_key(self.cnter), &self.encode(&b)));
+    }
+}
+
+#[inline(always)]
+fn encode_key(idx: usize) -> [u8; KEY_SIZE] {
+    (idx as u64).to_be_bytes()
+}
+
+#[inline(always)]
+fn decode_key(key: &[u8]) -> usize {
+    u64::from_be_bytes(key[..KEY_SIZE].try_into().unwrap()) as usize
+}
+
+// Make sure the keys are in the current format,
+// rewrite the keys of an old store if `upgrade` is set, or fail.
+//
+// A store without the format in its `META_TREE` is an old one,
+// which is upgraded in bounded batches, each of them is done only once
+// since the moved items are removed, so an interrupted upgrade is resumed by the next opener.
+fn check_key_format(db: &Kv, upgrade: bool) -> Result<()> {
+    let meta = db.open_tree(META_TREE).c(d!())?;
+    if let Some(v) = meta.get(KEY_FORMAT_KEY).c(d!())? {
+        let format = u32::from_be_bytes(v[..].try_into().c(d!())?);
+        if KEY_FORMAT != format {
+            return Err(eg!(format!("unknown key format: {}", format)));
+        }
+        return Ok(());
+    }
+
+    let staged = meta.contains_key(KEY_STAGED_KEY).c(d!())?;
+    if !upgrade {
+        if staged || !db.is_empty() {
+            return Err(eg!(
+                "the keys are in an old format, open it for writing once to upgrade"
+            ));
+        }
+        return Ok(());
+    }
+
+    let staging = db.open_tree(KEY_UPGRADE_TREE).c(d!())?;
+    if !staged {
+        // An old key may equal a new one, so they can not be in the same keyspace.
+        move_items(db, &staging, |k| {
+            let idx = match k.len() {
+                4 => u32::from_le_bytes(k.try_into().unwrap()) as usize,
+                8 => u64::from_le_bytes(k.try_into().unwrap()) as usize,
+                _ => return Err(eg!("invalid key")),
+            };
+            Ok(encode_key(idx).to_vec())
+        })
+        .c(d!())?;
+        meta.insert(KEY_STAGED_KEY, &[]).c(d!())?;
+    }
+    move_items(&staging, db, |k| Ok(k.to_vec())).c(d!())?;
+
+    let mut batch = KvBatch::default();
+    batch.insert(KEY_FORMAT_KEY, &KEY_FORMAT.to_be_bytes()[..]);
+    batch.remove(KEY_STAGED_KEY);
+    meta.apply_batch(batch).c(d!())
+}
+
+// Move all items of `from` to `to` batch by batch, with the keys mapped by `f`,
+// an item may be written twice if the last move was interrupted.
+fn move_items(from: &Kv, to: &Kv, f: impl Fn(&[u8]) -> Result<Vec<u8>>) -> Result<()> {
+    loop {
+        let items = from
+            .iter()
+            .take(KEY_UPGRADE_BATCH_SIZE)
+            .collect::<Result<Vec<_>>>()
+            .c(d!())?;
+        if items.is_empty() {
+            return Ok(());
+        }
+
+        let mut added = KvBatch::default();
+        let mut removed = KvBatch::default();
+        for (k, v) in items {
+            added.insert(f(&k).c(d!())?, v.to_vec());
+            removed.remove(k.to_vec());
+        }
+        to.apply_batch(added).c(d!())?;
+        from.apply_batch(removed).c(d!())?;
     }
 }
 
@@ -137,40 +470,51 @@ pub(super) struct VecxIter<T>
 where
     T: PartialEq + Clone + Serialize + DeserializeOwned + std::fmt::Debug,
 {
-    pub(super) iter: sled::Iter,
+    pub(super) iter: KvIter,
+    // There is no `remove` like methods provided,
+    // so the length is known exactly.
+    remaining: usize,
+    stats: Arc<StatsCounter>,
     _pd: PhantomData<T>,
 }
 
-impl<T> Iterator for VecxIter<T>
+impl<T> VecxIter<T>
 where
     T: PartialEq + Clone + Serialize + DeserializeOwned + std::fmt::Debug,
 {
-    type Item = (usize, T);
-    fn next(&mut self) -> Option<Self::Item> {
-        self.iter.next().map(|v| v.ok()).flatten().map(|(idx, v)| {
+    #[inline(always)]
+    fn decode(&mut self, kv: Result<(sled::IVec, sled::IVec)>) -> Option<(usize, T)> {
+        kv.ok().map(|(idx, v)| {
+            self.remaining = self.remaining.saturating_sub(1);
+            self.stats.read(idx.len() + v.len());
             (
-                usize::from_le_bytes(idx[..mem::size_of::<usize>()].try_into().unwrap()),
-                pnk!(serde_json::from_slice(&v)),
+                decode_key(&idx),
+                self.stats.decode(|| pnk!(serde_json::from_slice(&v))),
             )
         })
     }
 }
 
+impl<T> Iterator for VecxIter<T>
+where
+    T: PartialEq + Clone + Serialize + DeserializeOwned + std::fmt::Debug,
+{
+    type Item = (usize, T);
+    fn next(&mut self) -> Option<Self::Item> {
+        self.iter.next().and_then(|kv| self.decode(kv))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
 impl<T> DoubleEndedIterator for VecxIter<T>
 where
     T: PartialEq + Clone + Serialize + DeserializeOwned + std::fmt::Debug,
 {
     fn next_back(&mut self) -> Option<Self::Item> {
-        self.iter
-            .next_back()
-            .map(|v| v.ok())
-            .flatten()
-            .map(|(idx, v)| {
-                (
-                    usize::from_le_bytes(idx[..mem::size_of::<usize>()].try_into().unwrap()),
-                    pnk!(serde_json::from_slice(&v)),
-                )
-            })
+        self.iter.next_back().and_then(|kv| self.decode(kv))
     }
 }
 
@@ -192,7 +536,7 @@ where
     T: PartialEq + Clone + Serialize + DeserializeOwned + std::fmt::Debug,
 {
     fn eq(&self, other: &Vecx<T>) -> bool {
-        !self.iter().zip(other.iter()).any(|(i, j)| i != j)
+        self.len() == other.len() && !self.iter().zip(other.iter()).any(|(i, j)| i != j)
     }
 }
 
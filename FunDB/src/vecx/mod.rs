@@ -7,6 +7,9 @@
 mod backend;
 #[cfg(test)]
 mod test;
+mod time;
+
+pub use time::{TimeVecx, TimeVecxIter, Timed};
 
 /// Max number of entries stored in memory.
 #[cfg(not(feature = "debug_env"))]
@@ -18,16 +21,22 @@ pub const IN_MEM_CNT: usize = 1_0000;
 pub const IN_MEM_CNT: usize = 1;
 
 use crate::{
+    changelog::{Change, ChangeIter, Op, Replica},
+    durability::Durability,
     helper::*,
+    kv::Storage,
     serde::{FunDBMeta, FunDBVisitor},
+    stats::Stats,
 };
 use ruc::*;
 use serde::{de::DeserializeOwned, Serialize};
 use std::{
     borrow::Cow,
+    cmp::Ordering,
     collections::{btree_map, BTreeMap},
     fmt,
-    iter::Iterator,
+    iter::{DoubleEndedIterator, ExactSizeIterator, Iterator},
+    ops::Range,
 };
 
 /// To solve the problem of unlimited memory usage,
@@ -35,7 +44,7 @@ use std::{
 ///
 /// - Each time the program is started, a new database is created
 /// - Can ONLY be used in append-only scenes like the block storage
-#[derive(Eq, PartialEq, Debug, Clone)]
+#[derive(Clone)]
 pub struct Vecx<T>
 where
     T: Eq + PartialEq + Clone + Serialize + DeserializeOwned + fmt::Debug,
@@ -56,11 +65,68 @@ where
     /// Create an instance.
     #[inline(always)]
     pub fn new(path: String, imc: Option<usize>, is_tmp: bool) -> Result<Self> {
-        let in_disk = backend::Vecx::load_or_create(path, is_tmp).c(d!())?;
+        Self::new_with_durability(path, imc, is_tmp, Durability::default()).c(d!())
+    }
+
+    /// Create an instance with the given durability mode.
+    pub fn new_with_durability(
+        path: String,
+        imc: Option<usize>,
+        is_tmp: bool,
+        durability: Durability,
+    ) -> Result<Self> {
+        Self::open(path, imc, is_tmp, durability, Storage::default()).c(d!())
+    }
+
+    /// Create an instance on the given storage backend.
+    pub fn new_with_storage(
+        path: String,
+        imc: Option<usize>,
+        is_tmp: bool,
+        storage: Storage,
+    ) -> Result<Self> {
+        Self::open(path, imc, is_tmp, Durability::default(), storage).c(d!())
+    }
+
+    fn open(
+        path: String,
+        imc: Option<usize>,
+        is_tmp: bool,
+        durability: Durability,
+        storage: Storage,
+    ) -> Result<Self> {
+        let in_disk = backend::Vecx::load_or_create(path, is_tmp, durability, storage).c(d!())?;
+        Ok(Vecx::with_cache(in_disk, imc.unwrap_or(IN_MEM_CNT)))
+    }
+
+    /// Open an existing vector without the writer lock,
+    /// many readers and at most one writer may open the same path at the same time.
+    ///
+    /// The returned vector has no mutating methods; the items pushed by a writer
+    /// in another process after this call are not guaranteed to be visible.
+    pub fn open_read_only(path: String) -> Result<ReadOnly<Self>> {
+        backend::Vecx::load_read_only(path)
+            .c(d!())
+            .map(|in_disk| ReadOnly::new(Vecx::with_cache(in_disk, IN_MEM_CNT)))
+    }
+
+    /// Create an instance with the items of `iter`,
+    /// which are appended to the existing ones in `path` if any.
+    pub fn collect_into<I>(path: String, iter: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = T>,
+    {
+        let mut db = Self::new(path, None, false).c(d!())?;
+        db.extend(iter);
+        Ok(db)
+    }
+
+    // Preload the last `in_mem_cnt` items into memory.
+    fn with_cache(in_disk: backend::Vecx<T>, in_mem_cnt: usize) -> Self {
         let mut in_mem = BTreeMap::new();
 
         if !in_disk.is_empty() {
-            let mut lefter = IN_MEM_CNT;
+            let mut lefter = in_mem_cnt;
             let mut data = in_disk.iter().rev();
             while lefter > 0 {
                 if let Some((idx, v)) = data.next() {
@@ -72,11 +138,11 @@ where
             }
         }
 
-        Ok(Vecx {
+        Vecx {
             in_mem,
-            in_mem_cnt: imc.unwrap_or(IN_MEM_CNT),
+            in_mem_cnt,
             in_disk,
-        })
+        }
     }
 
     /// Get the storage path
@@ -84,24 +150,58 @@ where
         self.in_disk.get_data_path()
     }
 
+    /// Get the storage backend
+    pub fn storage(&self) -> Storage {
+        self.in_disk.storage()
+    }
+
+    /// Close this vector and remove its data directory,
+    /// it fails if any other clones of it are alive,
+    /// this handle is closed anyway.
+    ///
+    /// A temporary vector is removed automatically when its last clone is dropped.
+    pub fn destroy(self) -> Result<()> {
+        self.in_disk.destroy().c(d!())
+    }
+
+    /// Get the durability mode
+    pub fn durability(&self) -> Durability {
+        self.in_disk.durability()
+    }
+
     /// Imitate the behavior of 'Vec<_>.get(...)'
     ///
     /// Any faster/better choice other than JSON ?
     #[inline(always)]
     pub fn get(&self, idx: usize) -> Option<Value<T>> {
+        let stats = self.in_disk.stats_counter();
         self.in_mem
             .get(&idx)
-            .map(|v| Value::new(Cow::Borrowed(v)))
-            .or_else(|| self.in_disk.get(idx).map(|v| Value::new(Cow::Owned(v))))
+            .map(|v| {
+                stats.cache_hit();
+                Value::new(Cow::Borrowed(v))
+            })
+            .or_else(|| {
+                stats.cache_miss();
+                self.in_disk.get(idx).map(|v| Value::new(Cow::Owned(v)))
+            })
+    }
+
+    /// Get all the items within `range` in one call,
+    /// the indexes beyond the length are ignored,
+    /// and the items read from disk will not be cached.
+    pub fn get_range(&self, range: Range<usize>) -> Vec<Value<T>> {
+        self.in_disk
+            .get_range(range.start..range.end.min(self.len()))
+            .into_iter()
+            .map(|v| Value::new(Cow::Owned(v)))
+            .collect()
     }
 
     /// Imitate the behavior of 'Vec<_>.last()'
     pub fn last(&self) -> Option<Value<T>> {
-        self.in_mem
-            .values()
-            .last()
-            .map(|v| Value::new(Cow::Borrowed(v)))
-            .or_else(|| self.in_disk.last().map(|v| Value::new(Cow::Owned(v))))
+        // The cache may not hold the last item after an `extend`.
+        self.len().checked_sub(1).and_then(|idx| self.get(idx))
     }
 
     /// Imitate the behavior of 'Vec<_>.len()'
@@ -119,20 +219,48 @@ where
     /// Imitate the behavior of 'Vec<_>.push(...)'
     #[inline(always)]
     pub fn push(&mut self, b: T) {
-        if self.in_mem.len() > IN_MEM_CNT {
+        if 0 == self.in_mem_cnt {
+            self.in_disk.push(b);
+            return;
+        }
+
+        if self.in_mem.len() > self.in_mem_cnt {
             // Will get the oldest key since we use BTreeMap
             let k = pnk!(self.in_mem.keys().next().cloned());
             self.in_mem.remove(&k);
+            self.in_disk.stats_counter().cache_eviction();
+            trace_event!(TRACE, path = %self.get_data_path(), "fundb.evict");
         }
         self.in_mem.insert(self.in_disk.len(), b.clone());
         self.in_disk.push(b);
     }
 
+    /// Imitate the behavior of 'Vec<_>.extend(...)',
+    /// used to bulk-load lots of items.
+    ///
+    /// All items are written in one atomic batch without being cached.
+    pub fn extend<I>(&mut self, iter: I)
+    where
+        I: IntoIterator<Item = T>,
+    {
+        self.in_disk.extend(iter);
+    }
+
     /// Imitate the behavior of '.iter()'
     #[inline(always)]
-    pub fn iter(&self) -> Box<dyn Iterator<Item = T> + '_> {
+    pub fn iter(&self) -> VecxIter<T> {
         // todo!()
-        Box::new(self.in_disk.iter().map(|(_, v)| v))
+        VecxIter {
+            iter: self.in_disk.iter(),
+        }
+    }
+
+    /// Iterate over the latest items cached in memory only.
+    #[inline(always)]
+    pub fn iter_mem(&self) -> VecxIterMem<'_, usize, T> {
+        VecxIterMem {
+            iter: self.in_mem.iter(),
+        }
     }
 
     /// Flush data to disk
@@ -140,6 +268,61 @@ where
     pub fn flush_data(&self) {
         self.in_disk.flush();
     }
+
+    /// Get the statistics of this vector since it was opened.
+    pub fn stats(&self) -> Stats {
+        self.in_disk.stats()
+    }
+
+    /// Record every push of this vector from now on, for all handles of it,
+    /// it stays enabled after reopening; see [changelog](crate::changelog).
+    pub fn enable_change_log(&mut self) -> Result<()> {
+        self.in_disk.enable_change_log().c(d!())
+    }
+
+    /// The sequence number of the last recorded change,
+    /// `None` if the change log is not enabled.
+    pub fn change_log_seq(&self) -> Option<u64> {
+        self.in_disk.change_log_seq()
+    }
+
+    /// Iterate over the recorded pushes whose sequence numbers are greater than `seq`,
+    /// each of them is an `Op::Insert` with the index of the item.
+    pub fn changes_since(&self, seq: u64) -> Result<ChangeIter<usize, T>> {
+        self.in_disk.changes_since(seq).c(d!())
+    }
+
+    /// Remove the recorded changes before `seq`, return the number of them.
+    pub fn compact_change_log(&mut self, seq: u64) -> Result<usize> {
+        self.in_disk.compact_change_log(seq).c(d!())
+    }
+}
+
+impl<T> Replica for Vecx<T>
+where
+    T: Eq + PartialEq + Clone + Serialize + DeserializeOwned + fmt::Debug,
+{
+    type Key = usize;
+    type Value = T;
+
+    fn applied_seq(&self) -> Result<u64> {
+        self.in_disk.applied_seq().c(d!())
+    }
+
+    // An item which has been pushed is skipped.
+    fn apply_change(&mut self, change: Change<usize, T>) -> Result<()> {
+        match change.op {
+            Op::Insert(idx, v) => match idx.cmp(&self.len()) {
+                Ordering::Less => {}
+                Ordering::Equal => self.push(v),
+                Ordering::Greater => {
+                    return Err(eg!(format!("the items before {} are missing", idx)));
+                }
+            },
+            Op::Remove(_) => return Err(eg!("items can not be removed from a vector")),
+        }
+        self.in_disk.set_applied_seq(change.seq).c(d!())
+    }
 }
 
 /*******************************************/
@@ -167,9 +350,27 @@ where
         // todo!()
         self.iter.next().map(|(_, v)| v)
     }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.iter.size_hint()
+    }
 }
 
-/// Iter over [Vecx](self::Vecx).
+impl<T> DoubleEndedIterator for VecxIter<T>
+where
+    T: Eq + PartialEq + Clone + Serialize + DeserializeOwned + fmt::Debug,
+{
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.iter.next_back().map(|(_, v)| v)
+    }
+}
+
+impl<T> ExactSizeIterator for VecxIter<T> where
+    T: Eq + PartialEq + Clone + Serialize + DeserializeOwned + fmt::Debug
+{
+}
+
+/// Iter over the items of [Vecx](self::Vecx) cached in memory.
 pub struct VecxIterMem<'a, K, T>
 where
     K: 'a,
@@ -187,12 +388,94 @@ where
         // todo!()
         self.iter.next().map(|(_, v)| v.clone())
     }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.iter.size_hint()
+    }
+}
+
+impl<'a, T> DoubleEndedIterator for VecxIterMem<'a, usize, T>
+where
+    T: Eq + PartialEq + Clone + Serialize + DeserializeOwned + fmt::Debug,
+{
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.iter.next_back().map(|(_, v)| v.clone())
+    }
+}
+
+impl<'a, T> ExactSizeIterator for VecxIterMem<'a, usize, T> where
+    T: Eq + PartialEq + Clone + Serialize + DeserializeOwned + fmt::Debug
+{
 }
 
 /**********************************************/
 // End of the implementation of Iter for Vecx //
 ////////////////////////////////////////////////
 
+/////////////////////////////////////////////////////////
+// Begin of the implementation of std traits for Vecx //
+/*******************************************************/
+
+// There is no `Index`, which has to return a reference,
+// but the items read from disk are owned by nobody.
+
+impl<T> Extend<T> for Vecx<T>
+where
+    T: Eq + PartialEq + Clone + Serialize + DeserializeOwned + fmt::Debug,
+{
+    fn extend<I>(&mut self, iter: I)
+    where
+        I: IntoIterator<Item = T>,
+    {
+        Vecx::extend(self, iter)
+    }
+}
+
+impl<T> IntoIterator for &Vecx<T>
+where
+    T: Eq + PartialEq + Clone + Serialize + DeserializeOwned + fmt::Debug,
+{
+    type Item = T;
+    type IntoIter = VecxIter<T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+/// Compare the items on disk, the memory caches are ignored.
+impl<T> PartialEq for Vecx<T>
+where
+    T: Eq + PartialEq + Clone + Serialize + DeserializeOwned + fmt::Debug,
+{
+    fn eq(&self, other: &Vecx<T>) -> bool {
+        self.in_disk == other.in_disk
+    }
+}
+
+impl<T> Eq for Vecx<T> where T: Eq + PartialEq + Clone + Serialize + DeserializeOwned + fmt::Debug {}
+
+/// Show the first `DEBUG_PREVIEW_CNT` items only.
+impl<T> fmt::Debug for Vecx<T>
+where
+    T: Eq + PartialEq + Clone + Serialize + DeserializeOwned + fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Vecx")
+            .field("data_path", &self.get_data_path())
+            .field("len", &self.len())
+            .field(
+                "head",
+                &self.iter().take(DEBUG_PREVIEW_CNT).collect::<Vec<_>>(),
+            )
+            .finish()
+    }
+}
+
+/*****************************************************/
+// End of the implementation of std traits for Vecx //
+///////////////////////////////////////////////////////
+
 ///////////////////////////////////////////////////////////////////
 // Begin of the implementation of Serialize/Deserialize for Vecx //
 /*****************************************************************/
@@ -208,6 +491,9 @@ where
         let v = pnk!(serde_json::to_string(&FunDBMeta {
             in_mem_cnt: self.in_mem_cnt,
             data_path: self.get_data_path(),
+            nested_id: None,
+            durability: Some(self.durability()),
+            storage: Some(self.storage()),
         }));
 
         self.flush_data();
@@ -225,10 +511,12 @@ where
     {
         deserializer.deserialize_str(FunDBVisitor).map(|meta| {
             let meta = pnk!(serde_json::from_str::<FunDBMeta>(&meta));
-            pnk!(Vecx::new(
+            pnk!(Vecx::open(
                 meta.data_path.to_owned(),
                 Some(meta.in_mem_cnt),
-                false
+                false,
+                meta.durability.unwrap_or_default(),
+                meta.storage.unwrap_or_default()
             ))
         })
     }
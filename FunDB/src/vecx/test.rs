@@ -3,7 +3,9 @@
 //!
 
 use super::*;
+use rand::{rngs::StdRng, Rng, SeedableRng};
 use serde::{Deserialize, Serialize};
+use std::{fs, mem};
 
 #[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
 struct SampleBlock {
@@ -50,3 +52,537 @@ fn t_vecx() {
 
     assert_eq!(cnt, db_restore.len());
 }
+
+#[test]
+fn t_vecx_bulk() {
+    let cnt = 200;
+    let mut db = crate::new_vecx!();
+
+    db.push(gen_sample(0));
+    db.extend((1..cnt).map(gen_sample));
+    assert_eq!(cnt, db.len());
+    assert_eq!(
+        pnk!(db.last()).into_inner().into_owned(),
+        gen_sample(cnt - 1)
+    );
+
+    let items = db.get_range(cnt / 2..2 * cnt);
+    assert_eq!(cnt - cnt / 2, items.len());
+    items.into_iter().enumerate().for_each(|(i, b)| {
+        assert_eq!(b.idx, cnt / 2 + i);
+    });
+    assert!(db.get_range(cnt..2 * cnt).is_empty());
+    #[allow(clippy::reversed_empty_ranges)]
+    let reversed = db.get_range(10..5);
+    assert!(reversed.is_empty());
+
+    db.extend(vec![]);
+    assert_eq!(cnt, db.len());
+}
+
+#[test]
+fn t_vecx_iter() {
+    let cnt = 200;
+    let mut db = crate::new_vecx!();
+
+    (0..cnt).for_each(|i| db.push(gen_sample(i)));
+
+    let mut it = db.iter();
+    assert_eq!(cnt, it.len());
+    assert!(it.next().is_some());
+    assert!(it.next_back().is_some());
+    assert_eq!((cnt - 2, Some(cnt - 2)), it.size_hint());
+
+    let mut items = db.iter().map(|b| b.idx).collect::<Vec<_>>();
+    items.sort_unstable();
+    assert_eq!(items, (0..cnt).collect::<Vec<_>>());
+
+    assert!(db.iter_mem().len() > 0);
+    assert_eq!(pnk!(db.iter_mem().next_back()), gen_sample(cnt - 1));
+
+    // The capacity of the cache is honoured, and so is it after reopening.
+    let mut db: Vecx<SampleBlock> = pnk!(Vecx::new(crate::unique_path!(), Some(2), false));
+    (0..cnt).for_each(|i| db.push(gen_sample(i)));
+    assert!(db.iter_mem().len() <= 3);
+    assert_eq!(pnk!(db.iter_mem().next_back()), gen_sample(cnt - 1));
+    assert!(0 < db.stats().cache_evictions);
+    let db: Vecx<SampleBlock> = pnk!(serde_json::from_str(&pnk!(serde_json::to_string(&db))));
+    assert_eq!(2, db.iter_mem().len());
+    assert_eq!(
+        gen_sample(cnt - 2),
+        pnk!(db.get(cnt - 2)).into_inner().into_owned()
+    );
+}
+
+#[test]
+fn t_vecx_durability() {
+    let path = crate::unique_path!();
+    let cnter_path = format!("{}/____cnter____", &path);
+
+    {
+        let mut db: Vecx<SampleBlock> = pnk!(Vecx::new_with_durability(
+            path.clone(),
+            None,
+            false,
+            Durability::Periodic {
+                interval_ms: 0,
+                writes: 2
+            }
+        ));
+        db.push(gen_sample(0));
+        assert!(pnk!(crate::helper::db_len_is_dirty(&cnter_path)));
+        db.push(gen_sample(1));
+        assert!(!pnk!(crate::helper::db_len_is_dirty(&cnter_path)));
+        db.extend((2..5).map(gen_sample));
+        assert!(pnk!(crate::helper::db_len_is_dirty(&cnter_path)));
+    }
+
+    // Flushed when dropped.
+    assert!(!pnk!(crate::helper::db_len_is_dirty(&cnter_path)));
+    assert_eq!(5, pnk!(crate::helper::read_db_len(&cnter_path)));
+
+    pnk!(crate::helper::mark_db_len_dirty(&cnter_path, 1));
+    let db: Vecx<SampleBlock> = pnk!(Vecx::new(path, None, false));
+    assert_eq!(5, db.len());
+    assert_eq!(pnk!(db.last()).into_inner().into_owned(), gen_sample(4));
+}
+
+#[test]
+fn t_vecx_read_only() {
+    let path = crate::unique_path!();
+    let mut db = pnk!(Vecx::new_with_storage(
+        path.clone(),
+        None,
+        false,
+        Storage::Log
+    ));
+    (0..3).for_each(|i| db.push(gen_sample(i)));
+
+    let reader = pnk!(Vecx::<SampleBlock>::open_read_only(path));
+    assert_eq!(3, reader.len());
+    assert_eq!(gen_sample(2), *pnk!(reader.get(2)));
+
+    assert!(Vecx::<SampleBlock>::open_read_only(crate::unique_path!()).is_err());
+}
+
+#[test]
+fn t_vecx_key_format() {
+    let path = crate::unique_path!();
+
+    // Written in the old format of `usize::to_le_bytes`.
+    {
+        let kv = pnk!(crate::kv::open(&path, false, Storage::Log));
+        for i in 0..300 {
+            let v = pnk!(serde_json::to_vec(&gen_sample(i)));
+            pnk!(kv.insert(&i.to_le_bytes(), &v));
+        }
+        pnk!(crate::helper::write_db_len(
+            &format!("{}/____cnter____", &path),
+            300
+        ));
+    }
+    assert!(Vecx::<SampleBlock>::open_read_only(path.clone()).is_err());
+
+    let mut db: Vecx<SampleBlock> = pnk!(Vecx::new_with_storage(
+        path.clone(),
+        None,
+        false,
+        Storage::Log
+    ));
+    assert_eq!(300, db.len());
+    assert_eq!(pnk!(db.last()).into_inner().into_owned(), gen_sample(299));
+    assert!(db.iter().eq((0..300).map(gen_sample)));
+    assert!(db
+        .iter()
+        .rev()
+        .take(2)
+        .eq(vec![gen_sample(299), gen_sample(298)]));
+
+    db.push(gen_sample(300));
+    assert_eq!(pnk!(db.get(256)).into_inner().into_owned(), gen_sample(256));
+    assert_eq!(pnk!(db.last()).into_inner().into_owned(), gen_sample(300));
+
+    // Interrupted when moving the items to the staging keyspace, and when moving them back.
+    for staged in [false, true].iter().copied() {
+        let path = crate::unique_path!();
+        {
+            let kv = pnk!(crate::kv::open(&path, false, Storage::Log));
+            let staging = pnk!(kv.open_tree("____key_upgrade____"));
+            for i in 0_usize..300 {
+                let v = pnk!(serde_json::to_vec(&gen_sample(i)));
+                if i < 100 {
+                    pnk!(kv.insert(&i.to_le_bytes(), &v));
+                } else if staged || i < 200 {
+                    pnk!(staging.insert(&(i as u64).to_be_bytes(), &v));
+                } else {
+                    // Moved but not removed yet.
+                    pnk!(staging.insert(&(i as u64).to_be_bytes(), &v));
+                    pnk!(kv.insert(&i.to_le_bytes(), &v));
+                }
+            }
+            if staged {
+                // The first 100 items have been moved back.
+                for i in 0_usize..100 {
+                    pnk!(kv.remove(&i.to_le_bytes()));
+                    let v = pnk!(serde_json::to_vec(&gen_sample(i)));
+                    pnk!(kv.insert(&(i as u64).to_be_bytes(), &v));
+                }
+                let meta = pnk!(kv.open_tree(crate::schema::META_TREE));
+                pnk!(meta.insert(b"key_format_staged", &[]));
+            }
+            pnk!(crate::helper::write_db_len(
+                &format!("{}/____cnter____", &path),
+                300
+            ));
+        }
+        assert!(Vecx::<SampleBlock>::open_read_only(path.clone()).is_err());
+
+        let db: Vecx<SampleBlock> = pnk!(Vecx::new_with_storage(
+            path.clone(),
+            None,
+            false,
+            Storage::Log
+        ));
+        assert_eq!(300, db.len());
+        assert!(db.iter().eq((0..300).map(gen_sample)));
+        drop(db);
+        assert_eq!(
+            300,
+            pnk!(Vecx::<SampleBlock>::open_read_only(path))
+                .iter()
+                .count()
+        );
+    }
+}
+
+// Random operations against a `Vec`.
+#[test]
+fn t_vecx_model() {
+    for seed in 0..4 {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut db: Vecx<SampleBlock> = pnk!(Vecx::new(
+            crate::unique_path!(),
+            Some(rng.gen_range(0..4)),
+            false
+        ));
+        let mut model = vec![];
+
+        for i in 0..1000 {
+            match rng.gen_range(0..10) {
+                0..=3 => {
+                    model.push(gen_sample(i));
+                    db.push(gen_sample(i));
+                }
+                4 => {
+                    let items = (i..i + rng.gen_range(0..8))
+                        .map(gen_sample)
+                        .collect::<Vec<_>>();
+                    model.extend(items.iter().cloned());
+                    db.extend(items);
+                }
+                5..=6 => {
+                    let idx = rng.gen_range(0..=model.len());
+                    assert_eq!(model.get(idx), db.get(idx).as_deref(), "seed {}", seed);
+                }
+                7 => assert_eq!(model.last(), db.last().as_deref(), "seed {}", seed),
+                _ => {
+                    // Reopen by the serialized meta.
+                    let meta = pnk!(serde_json::to_string(&db));
+                    db = pnk!(serde_json::from_str(&meta));
+                }
+            }
+            assert_eq!(model.len(), db.len(), "seed {}", seed);
+        }
+
+        assert!(db.iter().eq(model.into_iter()), "seed {}", seed);
+    }
+}
+
+// Cut the log at random points after random writes,
+// the recovered vector must be a prefix of the one before the crash.
+#[test]
+fn t_vecx_crash() {
+    for seed in 0..8 {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let path = crate::unique_path!();
+        let open = || {
+            pnk!(Vecx::<SampleBlock>::open(
+                path.clone(),
+                Some(1),
+                false,
+                Durability::Manual,
+                Storage::Log
+            ))
+        };
+
+        let mut db = open();
+        let mut model = vec![];
+        while model.len() < 200 {
+            let items = (model.len()..model.len() + rng.gen_range(1..4))
+                .map(gen_sample)
+                .collect::<Vec<_>>();
+            model.extend(items.iter().cloned());
+            db.extend(items);
+        }
+
+        mem::forget(db);
+        crate::helper::crash_db(&path);
+        let log = format!("{}/____default____.log", &path);
+        let len = pnk!(fs::metadata(&log)).len();
+        let file = pnk!(fs::OpenOptions::new().write(true).open(&log));
+        pnk!(file.set_len(rng.gen_range(0..=len)));
+
+        let mut db = open();
+        let recovered = db.iter().collect::<Vec<_>>();
+        assert_eq!(recovered.len(), db.len(), "seed {}", seed);
+        assert_eq!(&model[..recovered.len()], &recovered[..], "seed {}", seed);
+
+        // Still writable, and the counter is correct after a clean exit.
+        db.push(gen_sample(recovered.len()));
+        drop(db);
+        crate::helper::crash_db(&path);
+        assert_eq!(recovered.len() + 1, open().len());
+    }
+}
+
+// A sled vector stopped between `begin_write` and `end_write`
+// by `t_vecx_crash_in_write_process`,
+// the counter is left dirty and the length is recounted on reopening.
+#[test]
+fn t_vecx_crash_in_write() {
+    use std::process::Command;
+
+    let path = crate::unique_path!();
+    let cnter_path = format!("{}/____cnter____", &path);
+    let out = pnk!(Command::new(pnk!(std::env::current_exe()))
+        .args([
+            "--exact",
+            "vecx::test::t_vecx_crash_in_write_process",
+            "--ignored",
+        ])
+        .env("FUNDB_CRASH_PATH", &path)
+        .output());
+    assert!(out.status.success());
+    assert!(pnk!(crate::helper::db_len_is_dirty(&cnter_path)));
+    assert_eq!(100, pnk!(crate::helper::read_db_len(&cnter_path)));
+
+    let db = open_crashed_vec(path);
+    assert_eq!(101, db.len());
+    assert_eq!(gen_sample(100), pnk!(db.get(100)).into_inner().into_owned());
+    assert!(db.iter().eq((0..101).map(gen_sample)));
+    assert!(!pnk!(crate::helper::db_len_is_dirty(&cnter_path)));
+}
+
+// Exit in a write without dropping anything, as if this process had been killed.
+#[test]
+#[ignore]
+fn t_vecx_crash_in_write_process() {
+    let path = match std::env::var("FUNDB_CRASH_PATH") {
+        Ok(path) => path,
+        Err(_) => return,
+    };
+    let cnter_path = format!("{}/____cnter____", &path);
+
+    let mut db = open_crashed_vec(path);
+    (0..100).for_each(|i| db.push(gen_sample(i)));
+    db.flush_data();
+    assert!(!pnk!(crate::helper::db_len_is_dirty(&cnter_path)));
+
+    db.in_disk.push_unfinished(gen_sample(100));
+    db.flush_data();
+    std::process::exit(0);
+}
+
+fn open_crashed_vec(path: String) -> Vecx<SampleBlock> {
+    pnk!(Vecx::open(
+        path,
+        None,
+        false,
+        Durability::Manual,
+        Storage::Sled
+    ))
+}
+
+#[test]
+fn t_vecx_std_traits() {
+    let mut db = pnk!(Vecx::collect_into(
+        crate::unique_path!(),
+        (0..50).map(gen_sample)
+    ));
+    Extend::extend(&mut db, (50..100).map(gen_sample));
+    assert_eq!(100, db.len());
+
+    let mut idx = 0;
+    for v in &db {
+        assert_eq!(gen_sample(idx), v);
+        idx += 1;
+    }
+    assert_eq!(100, idx);
+
+    let dbg = format!("{:?}", db);
+    assert!(dbg.contains("len: 100"));
+    assert_eq!(DEBUG_PREVIEW_CNT, dbg.matches("SampleBlock").count());
+}
+
+#[test]
+fn t_vecx_change_log() {
+    use crate::changelog::Follower;
+
+    let mut db: Vecx<usize> = crate::new_vecx!();
+    db.push(0);
+    pnk!(db.enable_change_log());
+    (1..10).for_each(|i| db.push(i));
+    db.extend(10..20);
+
+    let changes = pnk!(pnk!(db.changes_since(0)).collect::<Result<Vec<_>>>());
+    assert_eq!(Some(19), db.change_log_seq());
+    assert_eq!(19, changes.len());
+    assert_eq!(Op::Insert(1, 1), changes[0].op);
+    assert_eq!(Op::Insert(19, 19), changes[18].op);
+
+    // The item written before enabling is pushed first.
+    let mut local: Vecx<usize> = crate::new_vecx!();
+    local.push(0);
+    let mut follower = pnk!(Follower::new(local));
+    assert_eq!(10, pnk!(follower.apply_all(changes[..10].to_vec())));
+    assert_eq!(0, pnk!(follower.apply_all(changes[..10].to_vec())));
+    pnk!(follower.apply_all(pnk!(db.changes_since(follower.last_seq())).map(|c| pnk!(c))));
+    assert!(db == *follower.local());
+
+    // An item which has been pushed is skipped.
+    let mut local = follower.into_inner();
+    pnk!(local.apply_change(changes[3].clone()));
+    assert_eq!(20, local.len());
+    assert!(local
+        .apply_change(Change {
+            seq: 20,
+            op: Op::Insert(30, 30)
+        })
+        .is_err());
+    assert!(local
+        .apply_change(Change {
+            seq: 20,
+            op: Op::Remove(0)
+        })
+        .is_err());
+
+    assert_eq!(9, pnk!(db.compact_change_log(10)));
+    assert!(db.changes_since(8).is_err());
+    assert_eq!(10, pnk!(db.changes_since(9)).count());
+}
+
+#[test]
+fn t_vecx_checkpoint() {
+    use crate::database::Database;
+    use std::thread;
+
+    let path = crate::unique_path!();
+    let mut db: Vecx<usize> = pnk!(Vecx::new_with_storage(
+        path.clone(),
+        None,
+        false,
+        Storage::Sled
+    ));
+    db.extend(0..100);
+
+    // The length is not shared by the clones.
+    let writer = thread::spawn(move || {
+        (100..1000).for_each(|i| db.push(i));
+        db
+    });
+    let cp_path = crate::unique_path!();
+    pnk!(pnk!(Database::open(&path)).checkpoint(&cp_path));
+    let db = pnk!(writer.join().map_err(|_| eg!()));
+
+    let new_path = crate::unique_path!();
+    pnk!(Database::restore_from_checkpoint(&cp_path, &new_path));
+    let mut restored: Vecx<usize> =
+        pnk!(Vecx::new_with_storage(new_path, None, false, Storage::Sled));
+    let len = restored.len();
+    assert!((100..=1000).contains(&len));
+    assert!(restored.iter().enumerate().all(|(i, v)| i == v));
+
+    restored.push(len);
+    assert_eq!(len + 1, restored.len());
+    assert_eq!(1000, db.len());
+}
+
+#[test]
+fn t_time_vecx() {
+    use std::{ops::Bound, time::Duration};
+
+    for storage in [Storage::Sled, Storage::Log].iter().copied() {
+        let path = crate::unique_path!();
+        let mut db: TimeVecx<usize> =
+            pnk!(TimeVecx::new_with_storage(path.clone(), false, storage));
+        assert!(db.is_empty() && db.at_or_before(u64::MAX).is_none());
+
+        // Two items at every even timestamp.
+        (0..100).for_each(|i| assert_eq!(i, pnk!(db.push_at(i as u64 / 2 * 2, i))));
+        assert!(db.push_at(97, 100).is_err());
+        assert_eq!(100, db.len());
+        assert_eq!(
+            Timed {
+                idx: 5,
+                ts: 4,
+                value: 5
+            },
+            pnk!(db.get(5))
+        );
+
+        let values = |iter: TimeVecxIter<usize>| iter.map(|t| t.value).collect::<Vec<_>>();
+        assert_eq!(vec![10, 11, 12, 13], values(db.range_by_time(10..14)));
+        assert_eq!(vec![10, 11, 12, 13], values(db.range_by_time(9..13)));
+        assert_eq!(
+            vec![10, 11, 12, 13, 14, 15],
+            values(db.range_by_time(10..=14))
+        );
+        assert_eq!(vec![98, 99], values(db.range_by_time(97..)));
+        assert_eq!(vec![0, 1], values(db.range_by_time(..1)));
+        assert_eq!(4, db.range_by_time(10..14).len());
+        assert!(db
+            .range_by_time((Bound::Included(14), Bound::Excluded(10)))
+            .next()
+            .is_none());
+        assert!(db.range_by_time(200..).next().is_none());
+
+        assert_eq!(13, pnk!(db.at_or_before(12)).value);
+        assert_eq!(13, pnk!(db.at_or_before(13)).value);
+        assert_eq!(99, pnk!(db.at_or_before(u64::MAX)).value);
+        assert_eq!(100, pnk!(db.push_at(98, 100)));
+
+        // The indexes are unchanged after truncating.
+        assert_eq!(0, pnk!(db.truncate_before(0)));
+        assert_eq!(30, pnk!(db.truncate_before(29)));
+        assert_eq!(30, db.first_index());
+        assert_eq!(71, db.len());
+        assert!(db.get(29).is_none());
+        assert_eq!(30, pnk!(db.get(30)).value);
+        assert!(db.at_or_before(29).is_none());
+        assert_eq!(vec![30, 31], values(db.range_by_time(..32)));
+        assert_eq!(30, pnk!(db.iter().next()).idx);
+        assert_eq!(100, pnk!(db.iter().next_back()).idx);
+        db.flush_data();
+        drop(db);
+
+        let mut db: TimeVecx<usize> =
+            pnk!(TimeVecx::new_with_storage(path.clone(), false, storage));
+        assert_eq!((30, 71), (db.first_index(), db.len()));
+        assert!(db.push_at(97, 101).is_err());
+        assert_eq!(101, db.push(101));
+        assert!(98 < pnk!(db.last()).ts);
+
+        // Only the item just pushed is within the retention.
+        assert_eq!(71, pnk!(db.apply_retention(Duration::from_secs(60))));
+        assert_eq!(vec![101], values(db.iter()));
+        assert_eq!(102, db.push(102));
+        assert_eq!(2, pnk!(db.truncate_before(u64::MAX)));
+        assert!(db.is_empty());
+        drop(db);
+
+        let mut db: TimeVecx<usize> = pnk!(TimeVecx::new_with_storage(path, false, storage));
+        assert!(db.is_empty());
+        assert_eq!(103, db.push(103));
+        pnk!(db.destroy());
+    }
+}
@@ -0,0 +1,431 @@
+//!
+//! # A Vecx Indexed by Time
+//!
+//! Every item is stored along with its timestamp, which must not be earlier than
+//! the one of the item before it, so the items are sorted by both their indexes
+//! and their timestamps, and the lookups by time are binary searches on the indexes.
+//!
+//! The items can be truncated from the front, the indexes of the rest are unchanged.
+//!
+
+use crate::{
+    durability::{Durability, Flusher},
+    helper::*,
+    kv::{Kv, KvBatch, KvIter, Storage},
+    schema::META_TREE,
+    stats::{Stats, StatsCounter},
+};
+use ruc::*;
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    convert::TryInto,
+    fmt,
+    marker::PhantomData,
+    mem,
+    ops::{Bound, RangeBounds},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+// The index of the first item, stored in the `META_TREE`.
+const FRONT_KEY: &[u8] = b"front";
+
+const KEY_SIZE: usize = mem::size_of::<u64>();
+const TS_SIZE: usize = mem::size_of::<u64>();
+
+/// An item of a [TimeVecx](self::TimeVecx).
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Timed<T> {
+    /// The index, which is unchanged after truncating.
+    pub idx: usize,
+    /// Milliseconds since the UNIX epoch.
+    pub ts: u64,
+    /// The item itself.
+    pub value: T,
+}
+
+/////////////////////////////////////////////
+// Begin of the implementation of TimeVecx //
+/*******************************************/
+
+/// An append-only vector of items with timestamps,
+/// whose old items can be truncated from the front.
+#[derive(Debug, Clone)]
+pub struct TimeVecx<T>
+where
+    T: PartialEq + Clone + Serialize + DeserializeOwned + fmt::Debug,
+{
+    db: Kv,
+    storage: Storage,
+    data_path: String,
+    flusher: Arc<Flusher>,
+    // The indexes of the items are within `front..end`.
+    front: usize,
+    end: usize,
+    last_ts: Option<u64>,
+    stats: Arc<StatsCounter>,
+    // Dropped after the flusher to remove a temporary database at last.
+    guard: Arc<DbGuard>,
+    _pd: PhantomData<T>,
+}
+
+impl<T> TimeVecx<T>
+where
+    T: PartialEq + Clone + Serialize + DeserializeOwned + fmt::Debug,
+{
+    /// Create an instance.
+    #[inline(always)]
+    pub fn new(path: String, is_tmp: bool) -> Result<Self> {
+        Self::new_with_storage(path, is_tmp, Storage::default()).c(d!())
+    }
+
+    /// Create an instance on the given storage backend.
+    pub fn new_with_storage(path: String, is_tmp: bool, storage: Storage) -> Result<Self> {
+        Self::open(path, is_tmp, Durability::default(), storage).c(d!())
+    }
+
+    /// Create an instance with the given durability mode.
+    pub fn new_with_durability(path: String, is_tmp: bool, durability: Durability) -> Result<Self> {
+        Self::open(path, is_tmp, durability, Storage::default()).c(d!())
+    }
+
+    fn open(path: String, is_tmp: bool, durability: Durability, storage: Storage) -> Result<Self> {
+        let db = kv_open(&path, is_tmp, storage).c(d!())?;
+        let guard = DbGuard::get(&path, is_tmp);
+
+        let front = match db.open_tree(META_TREE).c(d!())?.get(FRONT_KEY).c(d!())? {
+            Some(v) => u64::from_be_bytes(v[..].try_into().c(d!())?) as usize,
+            None => 0,
+        };
+        // Finish the truncating interrupted by a crash.
+        let mut batch = KvBatch::default();
+        for kv in db.range(Bound::Unbounded, Bound::Excluded(&encode_key(front))) {
+            batch.remove(kv.c(d!())?.0.to_vec());
+        }
+        db.apply_batch(batch).c(d!())?;
+
+        let (end, last_ts) = match db.iter().next_back() {
+            Some(kv) => {
+                let (k, v) = kv.c(d!())?;
+                (decode_key(&k) + 1, Some(decode_ts(&v)))
+            }
+            None => (front, None),
+        };
+
+        let stats = Arc::<StatsCounter>::default();
+        let flusher = Flusher::new(
+            Arc::clone(&db),
+            &path,
+            false,
+            0,
+            durability,
+            Arc::clone(&stats),
+        );
+
+        Ok(TimeVecx {
+            db,
+            storage,
+            data_path: path,
+            flusher: Arc::new(flusher),
+            front,
+            end,
+            last_ts,
+            stats,
+            guard,
+            _pd: PhantomData,
+        })
+    }
+
+    /// Get the storage path
+    pub fn get_data_path(&self) -> &str {
+        self.data_path.as_str()
+    }
+
+    /// Get the storage backend
+    pub fn storage(&self) -> Storage {
+        self.storage
+    }
+
+    /// Close this vector and remove its data directory,
+    /// it fails if any other clones of it are alive,
+    /// this handle is closed anyway.
+    pub fn destroy(self) -> Result<()> {
+        let guard = Arc::clone(&self.guard);
+        // One is held by `self`.
+        if 2 < Arc::strong_count(&guard) {
+            return Err(eg!(format!("{} is still in use", self.data_path)));
+        }
+        drop(self);
+        guard.destroy().c(d!())
+    }
+
+    /// Get the statistics since it was opened
+    pub fn stats(&self) -> Stats {
+        self.stats.snapshot(self.db.size_on_disk())
+    }
+
+    /// The index of the first item, the ones before it have been truncated.
+    #[inline(always)]
+    pub fn first_index(&self) -> usize {
+        self.front
+    }
+
+    /// Number of the items not truncated.
+    #[inline(always)]
+    pub fn len(&self) -> usize {
+        self.end - self.front
+    }
+
+    /// A helper func
+    #[inline(always)]
+    pub fn is_empty(&self) -> bool {
+        self.front == self.end
+    }
+
+    /// Get the item of `idx`, `None` if it does not exist or has been truncated.
+    pub fn get(&self, idx: usize) -> Option<Timed<T>> {
+        if !(self.front..self.end).contains(&idx) {
+            return None;
+        }
+        self.db
+            .get(&encode_key(idx))
+            .ok()
+            .flatten()
+            .map(|v| self.decode(idx, &v))
+    }
+
+    /// Imitate the behavior of 'Vec<_>.last()'
+    pub fn last(&self) -> Option<Timed<T>> {
+        self.end.checked_sub(1).and_then(|idx| self.get(idx))
+    }
+
+    /// Push an item with the current time, return its index.
+    ///
+    /// If the clock goes backwards, the timestamp of the last item is used.
+    pub fn push(&mut self, value: T) -> usize {
+        let ts = now_millis().max(self.last_ts.unwrap_or(0));
+        pnk!(self.push_at(ts, value))
+    }
+
+    /// Push an item with the given timestamp, in milliseconds since the UNIX epoch,
+    /// return its index; it fails if the timestamp is earlier than the last one.
+    pub fn push_at(&mut self, ts: u64, value: T) -> Result<usize> {
+        if let Some(last_ts) = self.last_ts.filter(|last_ts| ts < *last_ts) {
+            return Err(eg!(format!(
+                "timestamp {} is earlier than the last one {}",
+                ts, last_ts
+            )));
+        }
+
+        let idx = self.end;
+        let mut v = ts.to_be_bytes().to_vec();
+        self.stats
+            .encode(|| pnk!(serde_json::to_writer(&mut v, &value)));
+        self.stats.written(KEY_SIZE + v.len());
+
+        self.flusher.begin_write();
+        self.db.insert(&encode_key(idx), &v).c(d!())?;
+        self.end += 1;
+        self.last_ts = Some(ts);
+        self.flusher.end_write(self.len());
+        Ok(idx)
+    }
+
+    /// Iterate over all items.
+    pub fn iter(&self) -> TimeVecxIter<T> {
+        self.iter_within(self.front, self.end)
+    }
+
+    /// Iterate over the items whose timestamps are within `range`.
+    pub fn range_by_time<R: RangeBounds<u64>>(&self, range: R) -> TimeVecxIter<T> {
+        let lo = match range.start_bound() {
+            Bound::Included(t) => self.partition(|ts| ts < *t),
+            Bound::Excluded(t) => self.partition(|ts| ts <= *t),
+            Bound::Unbounded => self.front,
+        };
+        let hi = match range.end_bound() {
+            Bound::Included(t) => self.partition(|ts| ts <= *t),
+            Bound::Excluded(t) => self.partition(|ts| ts < *t),
+            Bound::Unbounded => self.end,
+        };
+        self.iter_within(lo, hi.max(lo))
+    }
+
+    /// Get the last item whose timestamp is not later than `ts`.
+    pub fn at_or_before(&self, ts: u64) -> Option<Timed<T>> {
+        self.partition(|t| t <= ts)
+            .checked_sub(1)
+            .and_then(|idx| self.get(idx))
+    }
+
+    /// Remove the items whose timestamps are earlier than `ts` from the front,
+    /// return the number of them.
+    pub fn truncate_before(&mut self, ts: u64) -> Result<usize> {
+        let front = self.partition(|t| t < ts);
+        if front == self.front {
+            return Ok(0);
+        }
+
+        // The new front goes first, the rest is done by the next opener after a crash.
+        self.flusher.begin_write();
+        self.db
+            .open_tree(META_TREE)
+            .c(d!())?
+            .insert(FRONT_KEY, &(front as u64).to_be_bytes())
+            .c(d!())?;
+        let mut batch = KvBatch::default();
+        (self.front..front).for_each(|idx| batch.remove(&encode_key(idx)[..]));
+        self.db.apply_batch(batch).c(d!())?;
+
+        let n = front - self.front;
+        self.front = front;
+        self.flusher.end_write(self.len());
+        Ok(n)
+    }
+
+    /// Keep the items pushed within `max_age` before now,
+    /// return the number of the removed ones.
+    pub fn apply_retention(&mut self, max_age: Duration) -> Result<usize> {
+        let max_age = max_age.as_millis().min(u64::MAX as u128) as u64;
+        self.truncate_before(now_millis().saturating_sub(max_age))
+            .c(d!())
+    }
+
+    /// Flush data to disk
+    #[inline(always)]
+    pub fn flush_data(&self) {
+        pnk!(self.flusher.flush());
+    }
+
+    // The first index in `front..end` whose timestamp does not satisfy `pred`,
+    // only the timestamps are decoded.
+    fn partition(&self, pred: impl Fn(u64) -> bool) -> usize {
+        let (mut lo, mut hi) = (self.front, self.end);
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            let v = pnk!(pnk!(self.db.get(&encode_key(mid))));
+            self.stats.read(KEY_SIZE + TS_SIZE);
+            if pred(decode_ts(&v)) {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+        lo
+    }
+
+    fn iter_within(&self, lo: usize, hi: usize) -> TimeVecxIter<T> {
+        TimeVecxIter {
+            iter: self.db.range(
+                Bound::Included(&encode_key(lo)),
+                Bound::Excluded(&encode_key(hi)),
+            ),
+            remaining: hi - lo,
+            stats: Arc::clone(&self.stats),
+            _pd: PhantomData,
+        }
+    }
+
+    fn decode(&self, idx: usize, v: &[u8]) -> Timed<T> {
+        decode_item(&self.stats, idx, v)
+    }
+}
+
+impl<T> PartialEq for TimeVecx<T>
+where
+    T: PartialEq + Clone + Serialize + DeserializeOwned + fmt::Debug,
+{
+    fn eq(&self, other: &TimeVecx<T>) -> bool {
+        self.front == other.front
+            && self.len() == other.len()
+            && !self.iter().zip(other.iter()).any(|(i, j)| i != j)
+    }
+}
+
+impl<T> Eq for TimeVecx<T> where T: PartialEq + Clone + Serialize + DeserializeOwned + fmt::Debug {}
+
+/*****************************************/
+// End of the implementation of TimeVecx //
+///////////////////////////////////////////
+
+/// Iter over [TimeVecx](self::TimeVecx).
+pub struct TimeVecxIter<T>
+where
+    T: PartialEq + Clone + Serialize + DeserializeOwned + fmt::Debug,
+{
+    iter: KvIter,
+    remaining: usize,
+    stats: Arc<StatsCounter>,
+    _pd: PhantomData<T>,
+}
+
+impl<T> TimeVecxIter<T>
+where
+    T: PartialEq + Clone + Serialize + DeserializeOwned + fmt::Debug,
+{
+    fn decode(&mut self, kv: Result<(sled::IVec, sled::IVec)>) -> Option<Timed<T>> {
+        kv.ok().map(|(k, v)| {
+            self.remaining = self.remaining.saturating_sub(1);
+            decode_item(&self.stats, decode_key(&k), &v)
+        })
+    }
+}
+
+impl<T> Iterator for TimeVecxIter<T>
+where
+    T: PartialEq + Clone + Serialize + DeserializeOwned + fmt::Debug,
+{
+    type Item = Timed<T>;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.iter.next().and_then(|kv| self.decode(kv))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<T> DoubleEndedIterator for TimeVecxIter<T>
+where
+    T: PartialEq + Clone + Serialize + DeserializeOwned + fmt::Debug,
+{
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.iter.next_back().and_then(|kv| self.decode(kv))
+    }
+}
+
+impl<T> ExactSizeIterator for TimeVecxIter<T> where
+    T: PartialEq + Clone + Serialize + DeserializeOwned + fmt::Debug
+{
+}
+
+#[inline(always)]
+fn encode_key(idx: usize) -> [u8; KEY_SIZE] {
+    (idx as u64).to_be_bytes()
+}
+
+#[inline(always)]
+fn decode_key(key: &[u8]) -> usize {
+    u64::from_be_bytes(key[..KEY_SIZE].try_into().unwrap()) as usize
+}
+
+#[inline(always)]
+fn decode_ts(v: &[u8]) -> u64 {
+    u64::from_be_bytes(v[..TS_SIZE].try_into().unwrap())
+}
+
+fn decode_item<T: DeserializeOwned>(stats: &StatsCounter, idx: usize, v: &[u8]) -> Timed<T> {
+    stats.read(KEY_SIZE + v.len());
+    Timed {
+        idx,
+        ts: decode_ts(v),
+        value: stats.decode(|| pnk!(serde_json::from_slice(&v[TS_SIZE..]))),
+    }
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}